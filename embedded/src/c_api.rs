@@ -207,9 +207,7 @@ pub extern "C" fn avi_embedded_new(
         callback: msg_callback,
     };
 
-    let rust_config = AviEmbeddedConfig {
-        device_id: config.device_id,
-    };
+    let rust_config = AviEmbeddedConfig::new(config.device_id);
 
     let avi = AviEmbedded::new(udp_client, rust_config, buffer_static, handler);
 
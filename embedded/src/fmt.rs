@@ -0,0 +1,78 @@
+//! Logging hooks that forward to `defmt` or `log` depending on the enabled
+//! feature, and compile to nothing otherwise.
+#![allow(unused_macros)]
+
+macro_rules! debug {
+    ($($arg:tt)*) => {{
+        #[cfg(feature = "defmt")]
+        defmt::debug!($($arg)*);
+        #[cfg(feature = "log")]
+        log::debug!($($arg)*);
+        #[cfg(not(any(feature = "defmt", feature = "log")))]
+        let _ = core::format_args!($($arg)*);
+    }};
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => {{
+        #[cfg(feature = "defmt")]
+        defmt::info!($($arg)*);
+        #[cfg(feature = "log")]
+        log::info!($($arg)*);
+        #[cfg(not(any(feature = "defmt", feature = "log")))]
+        let _ = core::format_args!($($arg)*);
+    }};
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => {{
+        #[cfg(feature = "defmt")]
+        defmt::warn!($($arg)*);
+        #[cfg(feature = "log")]
+        log::warn!($($arg)*);
+        #[cfg(not(any(feature = "defmt", feature = "log")))]
+        let _ = core::format_args!($($arg)*);
+    }};
+}
+
+#[cfg(all(test, feature = "log"))]
+mod tests {
+    extern crate std;
+    use std::format;
+    use std::string::String;
+    use std::sync::Mutex;
+    use std::vec::Vec;
+
+    static LINES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    struct Capture;
+
+    impl log::Log for Capture {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            let line = format!("{} {}", record.level(), record.args());
+            LINES.lock().unwrap().push(line);
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn test_log_feature_forwards_to_the_log_facade() {
+        log::set_logger(&Capture).unwrap();
+        log::set_max_level(log::LevelFilter::Debug);
+
+        debug!("Sending Hello as device {}", 7);
+        warn!("{} receive failures in a row", 3);
+        assert_eq!(
+            *LINES.lock().unwrap(),
+            [
+                "DEBUG Sending Hello as device 7",
+                "WARN 3 receive failures in a row"
+            ]
+        );
+    }
+}
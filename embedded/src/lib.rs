@@ -4,8 +4,13 @@
 use esp_idf_hal as _;
 
 extern crate alloc;
-use avi_p2p_protocol::{DownlinkMessage, PressType, SensorValue, UplinkMessage};
+
+#[macro_use]
+mod fmt;
+
+use avi_p2p_protocol::{DownlinkMessage, PressType, SensorValue, UplinkMessage, MAX_PACKET_SIZE};
 use core::future::Future;
+use core::net::SocketAddr;
 use serde::Serialize;
 
 // C API module
@@ -44,14 +49,100 @@ pub trait UdpClient {
     type Error;
     fn send(&mut self, buf: &[u8]) -> impl Future<Output = Result<(), Self::Error>>;
     fn receive(&mut self, buf: &mut [u8]) -> impl Future<Output = Result<usize, Self::Error>>;
+
+    /// Point the socket at another gateway. Needed for failover when
+    /// `AviEmbeddedConfig::gateways` lists more than one address.
+    fn set_remote(&mut self, _addr: SocketAddr) {}
+}
+
+/// Socket capable of broadcasting, used only for gateway discovery
+pub trait DiscoverySocket {
+    type Error;
+    /// Send `buf` to the broadcast (or multicast) address on `port`
+    fn broadcast(&mut self, port: u16, buf: &[u8])
+        -> impl Future<Output = Result<(), Self::Error>>;
+    fn receive_from(
+        &mut self,
+        buf: &mut [u8],
+    ) -> impl Future<Output = Result<(usize, SocketAddr), Self::Error>>;
+}
+
+/// A gateway that answered `discover_gateway`
+pub struct GatewayInfo {
+    pub addr: SocketAddr,
+    pub name: heapless::String<32>,
+}
+
+/// Broadcast a `DiscoverGateway` on `port` and wait for the first announce.
+/// Returns `None` if the reply was not a `GatewayAnnounce`; timeouts are up to the socket.
+pub async fn discover_gateway<D: DiscoverySocket>(
+    socket: &mut D,
+    port: u16,
+) -> Result<Option<GatewayInfo>, D::Error> {
+    let mut tx_buf = [0u8; 16];
+    if let Ok(data) = postcard::to_slice(&UplinkMessage::DiscoverGateway, &mut tx_buf) {
+        socket.broadcast(port, data).await?;
+    }
+
+    let mut rx_buf = [0u8; 128];
+    let (len, from) = socket.receive_from(&mut rx_buf).await?;
+    if let Ok(DownlinkMessage::GatewayAnnounce { port, name }) =
+        postcard::from_bytes(&rx_buf[..len])
+    {
+        let mut gateway_name = heapless::String::new();
+        for c in name.chars() {
+            if gateway_name.push(c).is_err() {
+                break;
+            }
+        }
+        return Ok(Some(GatewayInfo {
+            addr: SocketAddr::new(from.ip(), port),
+            name: gateway_name,
+        }));
+    }
+
+    Ok(None)
 }
 
 pub trait MessageHandler {
     fn on_message(&mut self, topic: &str, data: &[u8]);
+
+    /// Stream data that arrived while no `EmbeddedStream::read` was waiting for it
+    fn on_stream_data(&mut self, _stream_id: u8, _data: &[u8]) {}
+
+    fn on_stream_closed(&mut self, _stream_id: u8) {}
+
+    /// The gateway asks to pause (`slow_down`) or resume writes on a stream;
+    /// chunks sent while paused may be dropped once its buffer is full
+    fn on_stream_throttle(&mut self, _stream_id: u8, _slow_down: bool) {}
 }
 
+pub const MAX_GATEWAYS: usize = 4;
+
+/// Compact topic ids remembered per session; further aliases are not
+/// acknowledged, so the gateway keeps sending those topics in full
+pub const MAX_TOPIC_ALIASES: usize = 8;
+const MAX_ALIASED_TOPIC_LEN: usize = 64;
+
 pub struct AviEmbeddedConfig {
     pub device_id: u64,
+
+    /// Gateways in priority order (e.g. from `discover_gateway`).
+    /// Empty = keep using whatever the socket is connected to.
+    pub gateways: heapless::Vec<SocketAddr, MAX_GATEWAYS>,
+
+    /// Consecutive receive failures before failing over to the next gateway
+    pub max_failures: u8,
+}
+
+impl AviEmbeddedConfig {
+    pub fn new(device_id: u64) -> Self {
+        Self {
+            device_id,
+            gateways: heapless::Vec::new(),
+            max_failures: 3,
+        }
+    }
 }
 
 pub struct AviEmbedded<'a, S: UdpClient, H: MessageHandler> {
@@ -60,6 +151,11 @@ pub struct AviEmbedded<'a, S: UdpClient, H: MessageHandler> {
     scratch_buf: &'a mut [u8],
     is_connected: bool,
     handler: H,
+    gateway_index: usize,
+    failures: u8,
+    next_stream_id: u8,
+    topic_aliases: heapless::Vec<(u16, heapless::String<MAX_ALIASED_TOPIC_LEN>), MAX_TOPIC_ALIASES>,
+    pending_alias_acks: heapless::Vec<u16, MAX_TOPIC_ALIASES>,
 }
 
 impl<'a, S: UdpClient, H: MessageHandler> AviEmbedded<'a, S, H> {
@@ -70,24 +166,48 @@ impl<'a, S: UdpClient, H: MessageHandler> AviEmbedded<'a, S, H> {
             scratch_buf: buffer,
             is_connected: false,
             handler,
+            gateway_index: 0,
+            failures: 0,
+            next_stream_id: 0,
+            topic_aliases: heapless::Vec::new(),
+            pending_alias_acks: heapless::Vec::new(),
         }
     }
 
+    /// Register with the current gateway, moving down the gateway list
+    /// until one answers with `Welcome`.
     pub async fn connect(&mut self) -> Result<(), S::Error> {
-        // 1. Send Hello
-        let hello = UplinkMessage::Hello {
-            device_id: self.config.device_id,
-        };
-        self.send_packet(&hello).await?;
+        let attempts = self.config.gateways.len().max(1);
 
-        let mut rx_buf = [0u8; 128];
-        if let Ok(len) = self.socket.receive(&mut rx_buf).await {
-            if let Ok(DownlinkMessage::Welcome) = postcard::from_bytes(&rx_buf[..len]) {
-                self.is_connected = true;
-                return Ok(());
+        for _ in 0..attempts {
+            if let Some(addr) = self.config.gateways.get(self.gateway_index) {
+                self.socket.set_remote(*addr);
+            }
+
+            // 1. Send Hello
+            debug!("Sending Hello as device {}", self.config.device_id);
+            let hello = UplinkMessage::Hello {
+                device_id: self.config.device_id,
+            };
+            self.send_packet(&hello).await?;
+
+            let mut rx_buf = [0u8; 128];
+            if let Ok(len) = self.socket.receive(&mut rx_buf).await {
+                if let Ok(DownlinkMessage::Welcome) = postcard::from_bytes(&rx_buf[..len]) {
+                    info!("Connected to gateway {}", self.gateway_index);
+                    self.is_connected = true;
+                    // Aliases belong to the previous session
+                    self.topic_aliases.clear();
+                    self.pending_alias_acks.clear();
+                    self.failures = 0;
+                    return Ok(());
+                }
             }
+
+            self.advance_gateway();
         }
 
+        warn!("No gateway answered Hello");
         self.is_connected = false;
         Ok(())
     }
@@ -96,6 +216,35 @@ impl<'a, S: UdpClient, H: MessageHandler> AviEmbedded<'a, S, H> {
         self.is_connected
     }
 
+    /// Gateway currently in use, if a gateway list is configured
+    pub fn current_gateway(&self) -> Option<SocketAddr> {
+        self.config.gateways.get(self.gateway_index).copied()
+    }
+
+    fn advance_gateway(&mut self) {
+        if !self.config.gateways.is_empty() {
+            self.gateway_index = (self.gateway_index + 1) % self.config.gateways.len();
+        }
+    }
+
+    /// Count a failed receive; after `max_failures` in a row, fail over to
+    /// the next gateway and re-register there.
+    async fn record_failure(&mut self) {
+        self.failures = self.failures.saturating_add(1);
+        if self.failures < self.config.max_failures || self.config.gateways.len() < 2 {
+            return;
+        }
+
+        warn!(
+            "{} receive failures in a row, failing over from gateway {}",
+            self.failures, self.gateway_index
+        );
+        self.failures = 0;
+        self.is_connected = false;
+        self.advance_gateway();
+        let _ = self.connect().await;
+    }
+
     // Pub/Sub Methods
     pub async fn subscribe(&mut self, topic: &str) -> Result<(), S::Error> {
         let msg = UplinkMessage::Subscribe { topic };
@@ -112,6 +261,34 @@ impl<'a, S: UdpClient, H: MessageHandler> AviEmbedded<'a, S, H> {
         self.send_packet(&msg).await
     }
 
+    /// Publish that the gateway forwards once even if it is sent again with
+    /// the same `message_id` (e.g. after a missed ack)
+    pub async fn publish_with_id(
+        &mut self,
+        message_id: u32,
+        topic: &str,
+        data: &[u8],
+    ) -> Result<(), S::Error> {
+        let msg = UplinkMessage::PublishWithId {
+            message_id,
+            topic,
+            data,
+        };
+        self.send_packet(&msg).await
+    }
+
+    /// Bind a compact id to a publish topic for this session
+    pub async fn register_topic(&mut self, topic_id: u16, topic: &str) -> Result<(), S::Error> {
+        let msg = UplinkMessage::RegisterTopic { topic_id, topic };
+        self.send_packet(&msg).await
+    }
+
+    /// Like `publish`, but to a topic registered with `register_topic`
+    pub async fn publish_by_id(&mut self, topic_id: u16, data: &[u8]) -> Result<(), S::Error> {
+        let msg = UplinkMessage::PublishById { topic_id, data };
+        self.send_packet(&msg).await
+    }
+
     // Stream Methods
     pub async fn start_stream(
         &mut self,
@@ -171,35 +348,160 @@ impl<'a, S: UdpClient, H: MessageHandler> AviEmbedded<'a, S, H> {
         self.send_packet(&msg).await
     }
 
+    /// Bind a compact id to a sensor name for this session
+    pub async fn register_sensor(&mut self, id: u16, name: &str) -> Result<(), S::Error> {
+        let msg = UplinkMessage::RegisterSensor { id, name };
+        self.send_packet(&msg).await
+    }
+
+    /// Like `update_sensor`, but references a sensor registered with `register_sensor`
+    pub async fn update_sensor_by_id(
+        &mut self,
+        id: u16,
+        data: SensorValue,
+        custom_data: &str,
+    ) -> Result<(), S::Error> {
+        let msg = UplinkMessage::SensorUpdateById {
+            id,
+            data,
+            custom_data,
+        };
+        self.send_packet(&msg).await
+    }
+
+    /// Send a vendor-specific payload for an application `BridgeHandler`
+    pub async fn send_extension(&mut self, kind: u16, data: &[u8]) -> Result<(), S::Error> {
+        let msg = UplinkMessage::Extension { kind, data };
+        self.send_packet(&msg).await
+    }
+
+    /// Report signal strength (dBm) and round-trip time to the gateway
+    pub async fn report_link_quality(&mut self, rssi: i8, latency_ms: u16) -> Result<(), S::Error> {
+        let msg = UplinkMessage::LinkQuality { rssi, latency_ms };
+        self.send_packet(&msg).await
+    }
+
+    /// Report the state of up to 32 binary inputs in one packet
+    pub async fn digital_inputs(&mut self, bitmap: u32, changed_mask: u32) -> Result<(), S::Error> {
+        let msg = UplinkMessage::DigitalInputs {
+            bitmap,
+            changed_mask,
+        };
+        self.send_packet(&msg).await
+    }
+
+    /// Open a stream to a mesh peer. The local stream id is allocated
+    /// internally; data the peer sends back is read with `EmbeddedStream::read`.
+    /// `target_peer` may be `capability:<name>` to let the gateway pick a
+    /// peer offering that capability.
+    pub async fn open_stream<'s>(
+        &'s mut self,
+        target_peer: &str,
+        reason: &str,
+    ) -> Result<EmbeddedStream<'s, 'a, S, H>, S::Error> {
+        let id = self.next_stream_id;
+        self.next_stream_id = self.next_stream_id.wrapping_add(1);
+
+        self.start_stream(id, target_peer, reason).await?;
+        debug!("Opened stream {} to {}", id, target_peer);
+        Ok(EmbeddedStream {
+            avi: self,
+            id,
+            closed: false,
+            unread: ChunkRemainder::new(),
+        })
+    }
+
     // Process incoming messages (call this in your main loop)
     pub async fn poll(&mut self) -> Result<(), S::Error> {
         let mut rx_buf = [0u8; 1024];
 
         // Non-blocking receive with timeout
-        match self.socket.receive(&mut rx_buf).await {
-            Ok(len) => {
-                if let Ok(msg) = postcard::from_bytes::<DownlinkMessage>(&rx_buf[..len]) {
-                    match msg {
-                        DownlinkMessage::Message { topic, data } => {
-                            self.handler.on_message(topic, data);
-                        }
-                        DownlinkMessage::SubscribeAck { topic: _ } => {
-                            // Subscription confirmed
-                        }
-                        DownlinkMessage::UnsubscribeAck { topic: _ } => {
-                            // Unsubscription confirmed
-                        }
-                        DownlinkMessage::Welcome => {
-                            self.is_connected = true;
-                        }
-                        DownlinkMessage::Error { reason: _ } => {
-                            // Handle error
-                        }
-                    }
+        let len = self.receive_packet(&mut rx_buf).await?;
+        if let Ok(msg) = postcard::from_bytes::<DownlinkMessage>(&rx_buf[..len]) {
+            self.dispatch(msg);
+        }
+        self.flush_alias_acks().await
+    }
+
+    /// Remember a topic alias; false if it does not fit
+    fn store_topic_alias(&mut self, topic_id: u16, topic: &str) -> bool {
+        self.topic_aliases.retain(|(id, _)| *id != topic_id);
+        let mut name = heapless::String::new();
+        if name.push_str(topic).is_err() {
+            return false;
+        }
+        self.topic_aliases.push((topic_id, name)).is_ok()
+    }
+
+    async fn flush_alias_acks(&mut self) -> Result<(), S::Error> {
+        while let Some(topic_id) = self.pending_alias_acks.pop() {
+            self.send_packet(&UplinkMessage::TopicAliasAck { topic_id })
+                .await?;
+        }
+        Ok(())
+    }
+
+    fn dispatch(&mut self, msg: DownlinkMessage<'_>) {
+        debug!("Downlink {:?}", msg);
+        match msg {
+            DownlinkMessage::Message { topic, data } => {
+                self.handler.on_message(topic, data);
+            }
+            DownlinkMessage::SubscribeAck { topic: _ } => {
+                // Subscription confirmed
+            }
+            DownlinkMessage::UnsubscribeAck { topic: _ } => {
+                // Unsubscription confirmed
+            }
+            DownlinkMessage::Welcome => {
+                self.is_connected = true;
+            }
+            DownlinkMessage::Error { reason } => {
+                warn!("Gateway reported error {}", reason);
+            }
+            DownlinkMessage::GatewayAnnounce { .. } => {
+                // Discovery reply, only meaningful before connecting
+            }
+            DownlinkMessage::StreamData {
+                local_stream_id,
+                data,
+            } => {
+                self.handler.on_stream_data(local_stream_id, data);
+            }
+            DownlinkMessage::StreamClosed { local_stream_id } => {
+                self.handler.on_stream_closed(local_stream_id);
+            }
+            DownlinkMessage::TopicAlias { topic_id, topic } => {
+                if self.store_topic_alias(topic_id, topic) {
+                    let _ = self.pending_alias_acks.push(topic_id);
                 }
-                Ok(())
             }
-            Err(e) => Err(e),
+            DownlinkMessage::AliasedMessage { topic_id, data } => {
+                match self.topic_aliases.iter().find(|(id, _)| *id == topic_id) {
+                    Some((_, topic)) => self.handler.on_message(topic, data),
+                    None => warn!("Message for unknown topic alias {}", topic_id),
+                }
+            }
+            DownlinkMessage::Throttle {
+                local_stream_id,
+                slow_down,
+            } => {
+                self.handler.on_stream_throttle(local_stream_id, slow_down);
+            }
+        }
+    }
+
+    async fn receive_packet(&mut self, buf: &mut [u8]) -> Result<usize, S::Error> {
+        match self.socket.receive(buf).await {
+            Ok(len) => {
+                self.failures = 0;
+                Ok(len)
+            }
+            Err(e) => {
+                self.record_failure().await;
+                Err(e)
+            }
         }
     }
 
@@ -210,3 +512,208 @@ impl<'a, S: UdpClient, H: MessageHandler> AviEmbedded<'a, S, H> {
         Ok(())
     }
 }
+
+/// The part of a received chunk that did not fit the reader's buffer
+struct ChunkRemainder {
+    data: heapless::Vec<u8, MAX_PACKET_SIZE>,
+    read: usize,
+}
+
+impl ChunkRemainder {
+    const fn new() -> Self {
+        Self {
+            data: heapless::Vec::new(),
+            read: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.read >= self.data.len()
+    }
+
+    /// Copy as much of `chunk` into `buf` as fits and keep the rest
+    fn fill(&mut self, chunk: &[u8], buf: &mut [u8]) -> usize {
+        let n = chunk.len().min(buf.len());
+        buf[..n].copy_from_slice(&chunk[..n]);
+        self.data.clear();
+        self.read = 0;
+        // Chunks come from one packet, so the rest always fits
+        let _ = self.data.extend_from_slice(&chunk[n..]);
+        n
+    }
+
+    /// Copy kept bytes into `buf`
+    fn take(&mut self, buf: &mut [u8]) -> usize {
+        let rest = &self.data[self.read..];
+        let n = rest.len().min(buf.len());
+        buf[..n].copy_from_slice(&rest[..n]);
+        self.read += n;
+        n
+    }
+}
+
+/// A logical stream to a mesh peer, borrowed from `AviEmbedded` while open
+pub struct EmbeddedStream<'s, 'a, S: UdpClient, H: MessageHandler> {
+    avi: &'s mut AviEmbedded<'a, S, H>,
+    id: u8,
+    closed: bool,
+    unread: ChunkRemainder,
+}
+
+impl<'s, 'a, S: UdpClient, H: MessageHandler> EmbeddedStream<'s, 'a, S, H> {
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    pub async fn write(&mut self, data: &[u8]) -> Result<(), S::Error> {
+        self.avi.send_audio(self.id, data).await
+    }
+
+    /// Wait for the next chunk from the peer and copy it into `buf`. A chunk
+    /// larger than `buf` is handed out over several reads. Returns `Ok(0)`
+    /// once the peer closed the stream and everything was read. Other
+    /// downlink traffic received meanwhile is dispatched to the
+    /// `MessageHandler` as in `poll`.
+    pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize, S::Error> {
+        if !self.unread.is_empty() {
+            return Ok(self.unread.take(buf));
+        }
+        let mut rx_buf = [0u8; MAX_PACKET_SIZE];
+
+        while !self.closed {
+            let len = self.avi.receive_packet(&mut rx_buf).await?;
+            match postcard::from_bytes::<DownlinkMessage>(&rx_buf[..len]) {
+                Ok(DownlinkMessage::StreamData {
+                    local_stream_id,
+                    data,
+                }) if local_stream_id == self.id => {
+                    return Ok(self.unread.fill(data, buf));
+                }
+                Ok(DownlinkMessage::StreamClosed { local_stream_id })
+                    if local_stream_id == self.id =>
+                {
+                    self.closed = true;
+                }
+                Ok(msg) => {
+                    self.avi.dispatch(msg);
+                    self.avi.flush_alias_acks().await?;
+                }
+                Err(_) => {}
+            }
+        }
+
+        Ok(0)
+    }
+
+    pub async fn close(self) -> Result<(), S::Error> {
+        if self.closed {
+            return Ok(());
+        }
+        self.avi.close_stream(self.id).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::pin::pin;
+    use core::task::{Context, Poll, Waker};
+
+    /// Run a future whose I/O is all fakes, so it never waits
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = pin!(future);
+        let mut cx = Context::from_waker(Waker::noop());
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+        }
+    }
+
+    /// Gateways that answer `Hello` only while listed in `alive`
+    struct FakeGateways {
+        alive: heapless::Vec<SocketAddr, MAX_GATEWAYS>,
+        remote: Option<SocketAddr>,
+        welcome: bool,
+    }
+
+    impl UdpClient for FakeGateways {
+        type Error = ();
+
+        async fn send(&mut self, _buf: &[u8]) -> Result<(), ()> {
+            self.welcome = self
+                .remote
+                .is_some_and(|remote| self.alive.contains(&remote));
+            Ok(())
+        }
+
+        async fn receive(&mut self, buf: &mut [u8]) -> Result<usize, ()> {
+            if !core::mem::take(&mut self.welcome) {
+                return Err(());
+            }
+            postcard::to_slice(&DownlinkMessage::Welcome, buf)
+                .map(|used| used.len())
+                .map_err(|_| ())
+        }
+
+        fn set_remote(&mut self, addr: SocketAddr) {
+            self.remote = Some(addr);
+        }
+    }
+
+    struct Ignore;
+
+    impl MessageHandler for Ignore {
+        fn on_message(&mut self, _topic: &str, _data: &[u8]) {}
+    }
+
+    #[test]
+    fn test_client_fails_over_to_the_next_gateway() {
+        let first: SocketAddr = "192.168.1.2:8888".parse().unwrap();
+        let second: SocketAddr = "192.168.1.3:8888".parse().unwrap();
+        let mut config = AviEmbeddedConfig::new(7);
+        config.gateways.extend([first, second]);
+
+        let socket = FakeGateways {
+            alive: heapless::Vec::from_slice(&[first]).unwrap(),
+            remote: None,
+            welcome: false,
+        };
+        let mut scratch = [0u8; 64];
+        let mut avi = AviEmbedded::new(socket, config, &mut scratch, Ignore);
+        block_on(avi.connect()).unwrap();
+        assert!(avi.is_connected());
+        assert_eq!(avi.current_gateway(), Some(first));
+
+        // The first gateway goes quiet; it is given `max_failures` receives
+        avi.socket.alive = heapless::Vec::from_slice(&[second]).unwrap();
+        let mut buf = [0u8; 16];
+        for _ in 0..2 {
+            assert!(block_on(avi.receive_packet(&mut buf)).is_err());
+            assert_eq!(avi.current_gateway(), Some(first));
+        }
+        assert!(block_on(avi.receive_packet(&mut buf)).is_err());
+        assert!(avi.is_connected());
+        assert_eq!(avi.current_gateway(), Some(second));
+    }
+
+    #[test]
+    fn test_chunk_larger_than_the_buffer_is_read_in_parts() {
+        let mut unread = ChunkRemainder::new();
+        let mut buf = [0u8; 4];
+
+        assert_eq!(unread.fill(b"hey avi!!", &mut buf), 4);
+        assert_eq!(&buf, b"hey ");
+        assert!(!unread.is_empty());
+
+        assert_eq!(unread.take(&mut buf), 4);
+        assert_eq!(&buf, b"avi!");
+        assert_eq!(unread.take(&mut buf), 1);
+        assert_eq!(&buf[..1], b"!");
+        assert!(unread.is_empty());
+
+        // A chunk that fits leaves nothing behind
+        assert_eq!(unread.fill(b"ok", &mut buf), 2);
+        assert!(unread.is_empty());
+    }
+}
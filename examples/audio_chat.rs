@@ -79,7 +79,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     );
                     println!("   Auto-accepting call...");
 
-                    if let Err(e) = handle_clone.accept_stream(stream_id).await {
+                    if let Err(e) = handle_clone.accept_stream(stream_id, None).await {
                         eprintln!("   Error accepting call: {}", e);
                     } else {
                         println!("✅ Call Accepted!");
@@ -94,7 +94,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 }
 
                 // Case B: We called someone and they accepted
-                AviEvent::StreamAccepted { peer_id, stream_id } => {
+                AviEvent::StreamAccepted {
+                    peer_id, stream_id, ..
+                } => {
                     println!(
                         "\n✅ CALL ESTABLISHED with {} (Stream {})",
                         peer_id, stream_id
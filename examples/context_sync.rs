@@ -36,7 +36,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     println!("🔗 Device Connected: {}", peer_id);
                 }
                 // --- CONTEXT UPDATE EVENT ---
-                AviEvent::ContextUpdated { peer_id, context } => {
+                AviEvent::ContextUpdated {
+                    peer_id, context, ..
+                } => {
                     // This fires whenever a peer changes their state
                     println!("\n🔄 UPDATE from {}:", peer_id);
                     // Pretty-print the updated JSON
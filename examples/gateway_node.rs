@@ -17,9 +17,15 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let gateway_handle = gateway_node.handle();
 
     // Start the UDP Bridge on Port 8888
-    EmbeddedBridge::start(gateway_handle.clone(), BridgeConfig { udp_port: 8888 })
-        .await
-        .unwrap();
+    EmbeddedBridge::start(
+        gateway_handle.clone(),
+        BridgeConfig {
+            udp_port: 8888,
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
 
     println!("✅ Gateway started.");
     println!("🌉 Bridge listening on UDP 0.0.0.0:8888");
@@ -60,7 +66,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // ==========================================
     while let Some(event) = monitor_events.recv().await {
         match event {
-            AviEvent::Message { from, topic, data } => {
+            AviEvent::Message {
+                from, topic, data, ..
+            } => {
                 let json_str = String::from_utf8_lossy(&data);
                 println!(
                     "⚡ [MESH EVENT] Topic: {}\n   From Gateway: {}\n   Payload: {}\n",
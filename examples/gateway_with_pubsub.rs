@@ -22,7 +22,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     // Start the UDP Bridge on Port 8888
     println!("🌉 Starting UDP Bridge on port 8888...");
-    EmbeddedBridge::start(gateway_handle.clone(), BridgeConfig { udp_port: 8888 }).await?;
+    EmbeddedBridge::start(
+        gateway_handle.clone(),
+        BridgeConfig {
+            udp_port: 8888,
+            ..Default::default()
+        },
+    )
+    .await?;
 
     println!("✅ Bridge listening on UDP 0.0.0.0:8888");
     println!("\n📝 Embedded devices can now connect to this gateway");
@@ -69,7 +76,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     tokio::spawn(async move {
         while let Some(event) = gateway_events.recv().await {
             match event {
-                AviEvent::Message { from, topic, data } => {
+                AviEvent::Message {
+                    from, topic, data, ..
+                } => {
                     let preview = if data.len() <= 100 {
                         String::from_utf8_lossy(&data).to_string()
                     } else {
@@ -95,7 +104,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut message_count = 0;
     while let Some(event) = monitor_events.recv().await {
         match event {
-            AviEvent::Message { from, topic, data } => {
+            AviEvent::Message {
+                from, topic, data, ..
+            } => {
                 message_count += 1;
 
                 println!("┌─────────────────────────────────────────────");
@@ -131,7 +142,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             AviEvent::PeerDisconnected { peer_id } => {
                 println!("❌ [MONITOR] Disconnected from: {}\n", peer_id);
             }
-            AviEvent::ContextUpdated { peer_id, context } => {
+            AviEvent::ContextUpdated {
+                peer_id, context, ..
+            } => {
                 println!("🔄 [MONITOR] Context updated from: {}", peer_id);
                 if let Ok(pretty) = serde_json::to_string_pretty(&context) {
                     println!("   {}\n", pretty);
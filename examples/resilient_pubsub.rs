@@ -39,7 +39,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         tokio::select! {
             event = event_rx.recv() => {
                 match event {
-                    Some(AviEvent::Message { from, topic, data }) => {
+                    Some(AviEvent::Message {
+                from, topic, data, ..
+            }) => {
                         let text = String::from_utf8_lossy(&data);
                         println!("📩 Received on [{}]: '{}' from {}", topic, text, from);
                     }
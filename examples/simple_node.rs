@@ -30,7 +30,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 AviEvent::PeerDisconnected { peer_id } => {
                     println!("🔌 Disconnected from {}", peer_id);
                 }
-                AviEvent::Message { from, topic, data } => {
+                AviEvent::Message {
+                    from, topic, data, ..
+                } => {
                     let msg = String::from_utf8_lossy(&data);
                     println!("📩 [{}] {}: {}", topic, from, msg);
                 }
@@ -48,7 +48,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     // 2. Setup Embedded Library
     let mut scratch_buffer = [0u8; 1024]; // Stack buffer in real embedded
-    let config = AviEmbeddedConfig { device_id: 5555 };
+    let config = AviEmbeddedConfig::new(5555);
 
     let mut mcu = AviEmbedded::new(socket, config, &mut scratch_buffer);
 
@@ -78,7 +78,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // 2. Setup Embedded Library
     let device_id = 5555;
     let mut scratch_buffer = [0u8; 1024];
-    let config = AviEmbeddedConfig { device_id };
+    let config = AviEmbeddedConfig::new(device_id);
     let handler = DeviceMessageHandler { device_id };
 
     let mut mcu = AviEmbedded::new(socket, config, &mut scratch_buffer, handler);
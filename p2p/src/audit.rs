@@ -0,0 +1,456 @@
+use crate::error::AviP2pError;
+use crate::events::PeerId;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tokio::sync::mpsc;
+use tracing::warn;
+
+/// Where the audit log lives and what counts as a privileged command
+#[derive(Debug, Clone, Default)]
+pub struct AuditConfig {
+    /// Append-only JSON-lines file; `None` keeps only the most recent
+    /// entries, in memory
+    pub path: Option<PathBuf>,
+
+    /// Topic prefixes carrying actuator commands (e.g. "device/", "home/lock")
+    pub command_topics: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AuditAction {
+    Command {
+        topic: String,
+        size: usize,
+    },
+    /// Top-level context keys touched by the change
+    ContextModified {
+        keys: Vec<String>,
+    },
+    StreamAccepted {
+        stream_id: u64,
+        peer: String,
+    },
+    StreamRejected {
+        stream_id: u64,
+        peer: String,
+        reason: String,
+    },
+}
+
+impl AuditAction {
+    fn kind(&self) -> &'static str {
+        match self {
+            AuditAction::Command { .. } => "command",
+            AuditAction::ContextModified { .. } => "context",
+            AuditAction::StreamAccepted { .. } => "stream_accepted",
+            AuditAction::StreamRejected { .. } => "stream_rejected",
+        }
+    }
+}
+
+/// One link of the hash chain
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub seq: u64,
+    pub timestamp: u64,
+    pub actor: String,
+    pub action: AuditAction,
+    pub prev_hash: String,
+    pub hash: String,
+}
+
+impl AuditEntry {
+    fn compute_hash(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.prev_hash.as_bytes());
+        hasher.update(self.seq.to_be_bytes());
+        hasher.update(self.timestamp.to_be_bytes());
+        hasher.update(self.actor.as_bytes());
+        hasher.update(serde_json::to_vec(&self.action).unwrap_or_default());
+        to_hex(&hasher.finalize())
+    }
+}
+
+/// Filter for `AviP2pHandle::audit_log`
+#[derive(Debug, Clone, Default)]
+pub struct AuditQuery {
+    pub actor: Option<PeerId>,
+    /// "command", "context", "stream_accepted" or "stream_rejected"
+    pub kind: Option<String>,
+    /// Only entries at or after this Unix timestamp
+    pub since: Option<u64>,
+    /// Only entries before this Unix timestamp
+    pub until: Option<u64>,
+    /// Most recent N matches
+    pub limit: Option<usize>,
+}
+
+impl AuditQuery {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        self.actor
+            .as_ref()
+            .map(|a| a.as_str() == entry.actor)
+            .unwrap_or(true)
+            && self
+                .kind
+                .as_ref()
+                .map(|k| k == entry.action.kind())
+                .unwrap_or(true)
+            && self.since.map(|t| entry.timestamp >= t).unwrap_or(true)
+            && self.until.map(|t| entry.timestamp < t).unwrap_or(true)
+    }
+}
+
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Entries kept in memory; older ones are only on disk
+const AUDIT_WINDOW: usize = 1024;
+
+pub(crate) struct AuditLog {
+    config: AuditConfig,
+    /// The most recent entries, including any the writer has not reached yet
+    recent: VecDeque<AuditEntry>,
+    next_seq: u64,
+    last_hash: String,
+    /// Entries for the file writer on the blocking pool
+    writer: Option<mpsc::UnboundedSender<AuditEntry>>,
+}
+
+impl AuditLog {
+    /// Open the log, checking the chain already on disk and keeping its tail
+    pub fn open(config: AuditConfig) -> Result<Self, AviP2pError> {
+        let mut recent = VecDeque::new();
+        let mut chain = ChainCheck::genesis();
+        let mut writer = None;
+
+        if let Some(path) = &config.path {
+            let mut broken = None;
+            for entry in read_entries(path)? {
+                let entry = entry?;
+                if broken.is_none() {
+                    broken = chain.next(&entry).err();
+                }
+                // Keep extending past a broken link so new entries follow the file
+                chain = ChainCheck {
+                    seq: entry.seq + 1,
+                    prev: entry.hash.clone(),
+                };
+                if recent.len() == AUDIT_WINDOW {
+                    recent.pop_front();
+                }
+                recent.push_back(entry);
+            }
+            if let Some(seq) = broken {
+                warn!("Audit log chain broken at entry {}", seq);
+            }
+
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| AviP2pError::Io(e.to_string()))?;
+            writer = Some(spawn_writer(file));
+        }
+
+        Ok(Self {
+            config,
+            recent,
+            next_seq: chain.seq,
+            last_hash: chain.prev,
+            writer,
+        })
+    }
+
+    pub fn is_command_topic(&self, topic: &str) -> bool {
+        self.config
+            .command_topics
+            .iter()
+            .any(|prefix| topic.starts_with(prefix.as_str()))
+    }
+
+    pub fn record(&mut self, actor: &str, action: AuditAction) {
+        let mut entry = AuditEntry {
+            seq: self.next_seq,
+            timestamp: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            actor: actor.to_string(),
+            action,
+            prev_hash: self.last_hash.clone(),
+            hash: String::new(),
+        };
+        entry.hash = entry.compute_hash();
+        self.next_seq += 1;
+        self.last_hash = entry.hash.clone();
+
+        if let Some(writer) = &self.writer {
+            let _ = writer.send(entry.clone());
+        }
+        if self.recent.len() == AUDIT_WINDOW {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(entry);
+    }
+
+    /// What `query` and `verify` need, to be run off the event loop
+    pub fn snapshot(&self) -> AuditSnapshot {
+        AuditSnapshot {
+            path: self.config.path.clone(),
+            recent: self.recent.iter().cloned().collect(),
+        }
+    }
+}
+
+/// The log as of one moment: the file plus the in-memory entries the
+/// writer may not have reached yet. Its methods read the file, so they
+/// belong on the blocking pool.
+pub(crate) struct AuditSnapshot {
+    path: Option<PathBuf>,
+    recent: Vec<AuditEntry>,
+}
+
+impl AuditSnapshot {
+    /// Every entry in order. The file is authoritative; the in-memory tail
+    /// only adds entries past its end. Without a file only the tail is known.
+    fn for_each(
+        &self,
+        mut visit: impl FnMut(&AuditEntry) -> Result<(), AviP2pError>,
+    ) -> Result<(), AviP2pError> {
+        let mut next_seq = 0;
+        if let Some(path) = &self.path {
+            for entry in read_entries(path)? {
+                let entry = entry?;
+                next_seq = entry.seq + 1;
+                visit(&entry)?;
+            }
+        }
+        for entry in self.recent.iter().filter(|e| e.seq >= next_seq) {
+            visit(entry)?;
+        }
+        Ok(())
+    }
+
+    pub fn query(&self, query: &AuditQuery) -> Result<Vec<AuditEntry>, AviP2pError> {
+        let mut matches = VecDeque::new();
+        self.for_each(|entry| {
+            if query.matches(entry) {
+                if query.limit == Some(matches.len()) {
+                    matches.pop_front();
+                }
+                if query.limit != Some(0) {
+                    matches.push_back(entry.clone());
+                }
+            }
+            Ok(())
+        })?;
+        Ok(matches.into())
+    }
+
+    /// Walk the chain; the error names the first broken link
+    pub fn verify(&self) -> Result<(), AviP2pError> {
+        let mut chain = match (&self.path, self.recent.first()) {
+            // Older entries are gone, so the chain starts at the window
+            (None, Some(first)) => ChainCheck {
+                seq: first.seq,
+                prev: first.prev_hash.clone(),
+            },
+            _ => ChainCheck::genesis(),
+        };
+        self.for_each(|entry| {
+            chain
+                .next(entry)
+                .map_err(|seq| AviP2pError::Audit(format!("Hash chain broken at entry {}", seq)))
+        })
+    }
+}
+
+/// Entries of a log file, skipping blank lines; a missing file is empty
+fn read_entries(
+    path: &Path,
+) -> Result<impl Iterator<Item = Result<AuditEntry, AviP2pError>>, AviP2pError> {
+    let lines = match File::open(path) {
+        Ok(file) => Some(BufReader::new(file).lines()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(AviP2pError::Io(e.to_string())),
+    };
+    Ok(lines.into_iter().flatten().filter_map(|line| match line {
+        Ok(line) if line.trim().is_empty() => None,
+        Ok(line) => {
+            Some(serde_json::from_str(&line).map_err(|e| AviP2pError::Serialization(e.to_string())))
+        }
+        Err(e) => Some(Err(AviP2pError::Io(e.to_string()))),
+    }))
+}
+
+/// Append entries to the file on the blocking pool, flushing whenever the
+/// queue runs dry. The writer ends once the log is dropped.
+fn spawn_writer(file: File) -> mpsc::UnboundedSender<AuditEntry> {
+    let (writer, mut entries) = mpsc::unbounded_channel::<AuditEntry>();
+    tokio::task::spawn_blocking(move || {
+        let mut file = BufWriter::new(file);
+        while let Some(entry) = entries.blocking_recv() {
+            if let Ok(line) = serde_json::to_string(&entry) {
+                let _ = writeln!(file, "{}", line);
+            }
+            if entries.is_empty() {
+                let _ = file.flush();
+            }
+        }
+        let _ = file.flush();
+    });
+    writer
+}
+
+/// Running check of the hash chain
+struct ChainCheck {
+    seq: u64,
+    prev: String,
+}
+
+impl ChainCheck {
+    fn genesis() -> Self {
+        Self {
+            seq: 0,
+            prev: GENESIS_HASH.to_string(),
+        }
+    }
+
+    /// Accept the next entry, or return its sequence position if the link is broken
+    fn next(&mut self, entry: &AuditEntry) -> Result<(), u64> {
+        if entry.seq != self.seq
+            || entry.prev_hash != self.prev
+            || entry.compute_hash() != entry.hash
+        {
+            return Err(self.seq);
+        }
+        self.seq += 1;
+        self.prev = entry.hash.clone();
+        Ok(())
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(topic: &str) -> AuditAction {
+        AuditAction::Command {
+            topic: topic.to_string(),
+            size: 4,
+        }
+    }
+
+    #[test]
+    fn test_tampering_breaks_chain() {
+        let mut log = AuditLog::open(AuditConfig::default()).unwrap();
+        log.record("peer-a", command("home/lock"));
+        log.record("peer-b", AuditAction::ContextModified { keys: vec![] });
+        assert!(log.snapshot().verify().is_ok());
+
+        log.recent[0].actor = "peer-c".to_string();
+        assert!(log.snapshot().verify().is_err());
+    }
+
+    #[test]
+    fn test_memory_log_keeps_a_verifiable_window() {
+        let mut log = AuditLog::open(AuditConfig::default()).unwrap();
+        for i in 0..AUDIT_WINDOW + 10 {
+            log.record("peer-a", command(&format!("home/{}", i)));
+        }
+        assert_eq!(log.recent.len(), AUDIT_WINDOW);
+        assert!(log.snapshot().verify().is_ok());
+
+        let last = log
+            .snapshot()
+            .query(&AuditQuery {
+                limit: Some(2),
+                ..Default::default()
+            })
+            .unwrap();
+        let seqs: Vec<u64> = last.iter().map(|e| e.seq).collect();
+        assert_eq!(seqs, vec![AUDIT_WINDOW as u64 + 8, AUDIT_WINDOW as u64 + 9]);
+    }
+
+    /// Record on a file-backed log and wait for the writer to finish
+    async fn write_log(path: &Path, actors: &[&str]) {
+        let mut log = AuditLog::open(AuditConfig {
+            path: Some(path.to_path_buf()),
+            command_topics: vec![],
+        })
+        .unwrap();
+        let expected = log.next_seq as usize + actors.len();
+        for actor in actors {
+            log.record(actor, command("home/lock"));
+        }
+        drop(log);
+        for _ in 0..100 {
+            let written = std::fs::read_to_string(path).unwrap_or_default();
+            if written.lines().count() == expected {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("audit writer did not finish");
+    }
+
+    #[tokio::test]
+    async fn test_reopened_log_continues_the_chain_from_disk() {
+        let path = std::env::temp_dir().join(format!("avi-audit-{}.jsonl", rand::random::<u64>()));
+        write_log(&path, &["peer-a", "peer-b"]).await;
+        write_log(&path, &["peer-c"]).await;
+
+        let log = AuditLog::open(AuditConfig {
+            path: Some(path.clone()),
+            command_topics: vec![],
+        })
+        .unwrap();
+        assert!(log.snapshot().verify().is_ok());
+        let by_b = log
+            .snapshot()
+            .query(&AuditQuery {
+                actor: Some(PeerId::new("peer-b")),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(by_b.len(), 1);
+        assert_eq!(by_b[0].seq, 1);
+        assert_eq!(
+            log.snapshot().query(&AuditQuery::default()).unwrap().len(),
+            3
+        );
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_tampering_on_disk_is_detected_after_reopening() {
+        let path = std::env::temp_dir().join(format!("avi-audit-{}.jsonl", rand::random::<u64>()));
+        write_log(&path, &["peer-a", "peer-b", "peer-c"]).await;
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, written.replacen("peer-b", "peer-x", 1)).unwrap();
+
+        let mut log = AuditLog::open(AuditConfig {
+            path: Some(path.clone()),
+            command_topics: vec![],
+        })
+        .unwrap();
+        let error = log.snapshot().verify().unwrap_err();
+        assert!(error.to_string().contains("entry 1"), "{}", error);
+
+        // New entries do not paper over the broken link
+        log.record("peer-d", command("home/lock"));
+        assert!(log.snapshot().verify().is_err());
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
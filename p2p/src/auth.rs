@@ -0,0 +1,384 @@
+use crate::error::AviP2pError;
+use crate::events::PeerId;
+use libp2p::identity::{ed25519, Keypair, PublicKey};
+use libp2p::PeerId as LibPeerId;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+const CERTIFICATE_DOMAIN: &[u8] = b"avi-membership-v1";
+const CHALLENGE_DOMAIN: &[u8] = b"avi-auth-challenge-v1";
+
+/// Signing authority of a household.
+/// Whoever holds the secret can enroll new nodes into the mesh.
+pub struct HouseholdCa {
+    keypair: ed25519::Keypair,
+}
+
+impl HouseholdCa {
+    /// Create a brand new household authority
+    pub fn generate() -> Self {
+        Self {
+            keypair: ed25519::Keypair::generate(),
+        }
+    }
+
+    /// Restore a household authority from its 32-byte ed25519 secret
+    pub fn from_secret(secret: [u8; 32]) -> Result<Self, AviP2pError> {
+        let secret = ed25519::SecretKey::try_from_bytes(secret)
+            .map_err(|e| AviP2pError::Authentication(e.to_string()))?;
+        Ok(Self {
+            keypair: ed25519::Keypair::from(secret),
+        })
+    }
+
+    pub fn secret(&self) -> [u8; 32] {
+        let mut out = [0u8; 32];
+        out.copy_from_slice(self.keypair.secret().as_ref());
+        out
+    }
+
+    /// Public key every member node is configured with (`AuthConfig::ca_public_key`)
+    pub fn public_key(&self) -> [u8; 32] {
+        self.keypair.public().to_bytes()
+    }
+
+    pub(crate) fn sign(&self, message: &[u8]) -> Vec<u8> {
+        self.keypair.sign(message)
+    }
+
+    /// Issue a membership certificate for a node, optionally expiring at a Unix timestamp
+    pub fn issue(
+        &self,
+        peer_id: &PeerId,
+        role: Role,
+        expires_at: Option<u64>,
+    ) -> MembershipCertificate {
+        let mut cert = MembershipCertificate {
+            peer_id: peer_id.to_string(),
+            role,
+            expires_at,
+            signature: Vec::new(),
+        };
+        cert.signature = self.keypair.sign(&cert.signing_bytes());
+        cert
+    }
+}
+
+/// Role granted to a household member by its certificate.
+/// Peers that never authenticated are treated as `Guest`.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Role {
+    Admin,
+    Device,
+    Guest,
+}
+
+impl Role {
+    fn as_byte(&self) -> u8 {
+        match self {
+            Role::Admin => 0,
+            Role::Device => 1,
+            Role::Guest => 2,
+        }
+    }
+}
+
+/// Operations subject to authorization
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub enum Operation {
+    Subscribe,
+    Publish,
+    Stream,
+    ContextWrite,
+    /// Distributing shared encryption keys
+    KeyManagement,
+}
+
+/// Which operations each role may perform
+#[derive(Debug, Clone)]
+pub struct AuthorizationPolicy {
+    rules: HashMap<Role, HashSet<Operation>>,
+}
+
+impl AuthorizationPolicy {
+    /// Policy that grants nothing to anyone
+    pub fn empty() -> Self {
+        Self {
+            rules: HashMap::new(),
+        }
+    }
+
+    pub fn allow(mut self, role: Role, operation: Operation) -> Self {
+        self.rules.entry(role).or_default().insert(operation);
+        self
+    }
+
+    pub fn deny(mut self, role: Role, operation: Operation) -> Self {
+        if let Some(ops) = self.rules.get_mut(&role) {
+            ops.remove(&operation);
+        }
+        self
+    }
+
+    pub fn is_allowed(&self, role: Role, operation: Operation) -> bool {
+        self.rules
+            .get(&role)
+            .map(|ops| ops.contains(&operation))
+            .unwrap_or(false)
+    }
+}
+
+impl Default for AuthorizationPolicy {
+    /// Admins may do everything, devices everything but rewriting shared
+    /// context, guests may only use pub/sub (on the guest topics).
+    fn default() -> Self {
+        Self::empty()
+            .allow(Role::Admin, Operation::Subscribe)
+            .allow(Role::Admin, Operation::Publish)
+            .allow(Role::Admin, Operation::Stream)
+            .allow(Role::Admin, Operation::ContextWrite)
+            .allow(Role::Admin, Operation::KeyManagement)
+            .allow(Role::Device, Operation::Subscribe)
+            .allow(Role::Device, Operation::Publish)
+            .allow(Role::Device, Operation::Stream)
+            .allow(Role::Guest, Operation::Subscribe)
+            .allow(Role::Guest, Operation::Publish)
+    }
+}
+
+/// Proof that a peer belongs to the household, signed by the `HouseholdCa`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct MembershipCertificate {
+    pub peer_id: String,
+    pub role: Role,
+    pub expires_at: Option<u64>,
+    pub signature: Vec<u8>,
+}
+
+impl MembershipCertificate {
+    fn signing_bytes(&self) -> Vec<u8> {
+        let mut bytes = CERTIFICATE_DOMAIN.to_vec();
+        bytes.extend_from_slice(&(self.peer_id.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(self.peer_id.as_bytes());
+        bytes.push(self.role.as_byte());
+        bytes.extend_from_slice(&self.expires_at.unwrap_or(0).to_be_bytes());
+        bytes
+    }
+
+    /// Check the CA signature and expiry
+    pub fn verify(&self, ca_public_key: &[u8; 32]) -> Result<(), AviP2pError> {
+        let ca = ed25519::PublicKey::try_from_bytes(ca_public_key)
+            .map_err(|e| AviP2pError::Authentication(e.to_string()))?;
+
+        if !ca.verify(&self.signing_bytes(), &self.signature) {
+            return Err(AviP2pError::Authentication(
+                "Certificate not signed by household CA".to_string(),
+            ));
+        }
+
+        if let Some(expires_at) = self.expires_at {
+            if unix_now() >= expires_at {
+                return Err(AviP2pError::Authentication(
+                    "Certificate expired".to_string(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Application-layer authentication settings.
+/// When set on `AviP2pConfig`, peers must present a membership certificate
+/// after connecting; unauthenticated peers only get the guest topics.
+#[derive(Debug, Clone)]
+pub struct AuthConfig {
+    /// Public key of the household CA
+    pub ca_public_key: [u8; 32],
+
+    /// This node's own membership certificate (can also be set later at runtime)
+    pub certificate: Option<MembershipCertificate>,
+
+    /// Topics guests (including unauthenticated peers) may use
+    pub guest_topics: Vec<String>,
+
+    /// Role-based permissions for remote and local operations
+    pub policy: AuthorizationPolicy,
+}
+
+impl AuthConfig {
+    pub fn new(ca_public_key: [u8; 32]) -> Self {
+        Self {
+            ca_public_key,
+            certificate: None,
+            guest_topics: vec![],
+            policy: AuthorizationPolicy::default(),
+        }
+    }
+}
+
+pub(crate) fn generate_nonce() -> Vec<u8> {
+    rand::random::<[u8; 32]>().to_vec()
+}
+
+pub(crate) fn sign_challenge(local_key: &Keypair, nonce: &[u8]) -> Vec<u8> {
+    local_key
+        .sign(&[CHALLENGE_DOMAIN, nonce].concat())
+        .unwrap_or_default()
+}
+
+/// Verify a challenge response: the key must belong to `peer`, it must have
+/// signed our nonce, and it must carry a valid certificate for that peer.
+/// Returns the role granted by the certificate.
+pub(crate) fn verify_response(
+    config: &AuthConfig,
+    peer: &LibPeerId,
+    nonce: &[u8],
+    public_key: &[u8],
+    signature: &[u8],
+    certificate: Option<&MembershipCertificate>,
+) -> Result<Role, AviP2pError> {
+    let key = PublicKey::try_decode_protobuf(public_key)
+        .map_err(|e| AviP2pError::Authentication(e.to_string()))?;
+
+    if &key.to_peer_id() != peer {
+        return Err(AviP2pError::Authentication(
+            "Public key does not match peer id".to_string(),
+        ));
+    }
+
+    if !key.verify(&[CHALLENGE_DOMAIN, nonce].concat(), signature) {
+        return Err(AviP2pError::Authentication(
+            "Invalid challenge signature".to_string(),
+        ));
+    }
+
+    let cert = certificate.ok_or_else(|| {
+        AviP2pError::Authentication("No membership certificate presented".to_string())
+    })?;
+
+    if cert.peer_id != peer.to_base58() {
+        return Err(AviP2pError::Authentication(
+            "Certificate issued to a different peer".to_string(),
+        ));
+    }
+
+    cert.verify(&config.ca_public_key)?;
+    Ok(cert.role)
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Member {
+        key: Keypair,
+        peer: LibPeerId,
+    }
+
+    impl Member {
+        fn new() -> Self {
+            let key = Keypair::generate_ed25519();
+            let peer = key.public().to_peer_id();
+            Self { key, peer }
+        }
+
+        fn certificate(&self, ca: &HouseholdCa, expires_at: Option<u64>) -> MembershipCertificate {
+            ca.issue(&PeerId::from(self.peer), Role::Device, expires_at)
+        }
+
+        /// Answer a challenge for `challenged` with a signature over
+        /// `signed`, presenting `certificate`
+        fn respond(
+            &self,
+            config: &AuthConfig,
+            challenged: &[u8],
+            signed: &[u8],
+            certificate: &MembershipCertificate,
+        ) -> Result<Role, AviP2pError> {
+            verify_response(
+                config,
+                &self.peer,
+                challenged,
+                &self.key.public().encode_protobuf(),
+                &sign_challenge(&self.key, signed),
+                Some(certificate),
+            )
+        }
+    }
+
+    #[test]
+    fn test_challenge_accepts_only_a_current_certificate_for_the_peer() {
+        let ca = HouseholdCa::generate();
+        let config = AuthConfig::new(ca.public_key());
+        let member = Member::new();
+        let nonce = generate_nonce();
+
+        let valid = member.certificate(&ca, Some(unix_now() + 60));
+        assert_eq!(
+            member.respond(&config, &nonce, &nonce, &valid).unwrap(),
+            Role::Device
+        );
+
+        let foreign = member.certificate(&HouseholdCa::generate(), None);
+        assert!(member.respond(&config, &nonce, &nonce, &foreign).is_err());
+
+        let expired = member.certificate(&ca, Some(unix_now() - 1));
+        assert!(member.respond(&config, &nonce, &nonce, &expired).is_err());
+
+        // Someone else's certificate, even from the household CA
+        let borrowed = Member::new().certificate(&ca, None);
+        assert!(member.respond(&config, &nonce, &nonce, &borrowed).is_err());
+
+        let mut promoted = valid.clone();
+        promoted.role = Role::Admin;
+        assert!(member.respond(&config, &nonce, &nonce, &promoted).is_err());
+    }
+
+    #[test]
+    fn test_default_policy_keeps_devices_off_shared_context() {
+        let policy = AuthorizationPolicy::default();
+        assert!(policy.is_allowed(Role::Admin, Operation::ContextWrite));
+        assert!(!policy.is_allowed(Role::Device, Operation::ContextWrite));
+        assert!(!policy.is_allowed(Role::Device, Operation::KeyManagement));
+        assert!(policy.is_allowed(Role::Device, Operation::Stream));
+        assert!(!policy.is_allowed(Role::Guest, Operation::Stream));
+
+        let strict = AuthorizationPolicy::default().deny(Role::Device, Operation::Publish);
+        assert!(!strict.is_allowed(Role::Device, Operation::Publish));
+    }
+
+    #[test]
+    fn test_challenge_response_is_bound_to_its_nonce() {
+        let ca = HouseholdCa::generate();
+        let config = AuthConfig::new(ca.public_key());
+        let member = Member::new();
+        let certificate = member.certificate(&ca, None);
+
+        // An answer recorded for an earlier challenge does not fit a new one
+        let earlier = generate_nonce();
+        let current = generate_nonce();
+        assert!(member
+            .respond(&config, &current, &earlier, &certificate)
+            .is_err());
+
+        // Nor can another key answer for the member
+        let impostor = Member::new();
+        assert!(verify_response(
+            &config,
+            &member.peer,
+            &current,
+            &impostor.key.public().encode_protobuf(),
+            &sign_challenge(&impostor.key, &current),
+            Some(&certificate),
+        )
+        .is_err());
+    }
+}
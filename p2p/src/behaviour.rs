@@ -1,24 +1,39 @@
-use crate::protocols::stream::AviStreamCodec;
+use crate::config::{KadConfig, KadMode, ProtocolLimits};
+use crate::protocols::extension::AviExtensionCodec;
+use crate::protocols::rendezvous::AviRendezvousCodec;
+use crate::protocols::stream::{AviStreamCodec, AviStreamProtocol};
 use libp2p::{
-    gossipsub, identify, identity::Keypair, kad, mdns, request_response, swarm::NetworkBehaviour,
+    gossipsub, identify,
+    identity::Keypair,
+    kad, mdns, request_response,
+    swarm::{behaviour::toggle::Toggle, NetworkBehaviour},
     PeerId as LibPeerId,
 };
 
 #[derive(NetworkBehaviour)]
 pub struct AviBehaviour {
     pub gossipsub: gossipsub::Behaviour,
+    /// Gossip for `TopicProfile::Telemetry` topics, on its own protocol
+    pub telemetry: gossipsub::Behaviour,
     pub kad: kad::Behaviour<kad::store::MemoryStore>,
     #[cfg(not(target_arch = "wasm32"))]
-    pub mdns: mdns::tokio::Behaviour,
+    pub mdns: Toggle<mdns::tokio::Behaviour>,
     pub identify: identify::Behaviour,
     pub stream: request_response::Behaviour<AviStreamCodec>,
+    pub rendezvous: request_response::Behaviour<AviRendezvousCodec>,
+    pub extension: request_response::Behaviour<AviExtensionCodec>,
 }
 
 impl AviBehaviour {
     pub fn new(
         local_key: Keypair, // Now accepts Keypair
-        pubsub_config: gossipsub::Config,
+        // Control and telemetry gossip
+        (pubsub_config, telemetry_config): (gossipsub::Config, gossipsub::Config),
         node_name: String,
+        enable_mdns: bool,
+        stream_limits: ProtocolLimits,
+        kad_settings: KadConfig,
+        stream_protocol: AviStreamProtocol,
     ) -> Self {
         let local_peer_id = LibPeerId::from(local_key.public());
 
@@ -26,7 +41,19 @@ impl AviBehaviour {
         let store = kad::store::MemoryStore::new(local_peer_id);
         let mut kad_config = kad::Config::default();
         kad_config.set_query_timeout(std::time::Duration::from_secs(60)); // Good practice for newer libp2p
-        let kad = kad::Behaviour::with_config(local_peer_id, store, kad_config);
+        kad_config
+            .set_parallelism(kad_settings.parallelism)
+            .set_record_ttl(kad_settings.record_ttl)
+            .set_replication_interval(kad_settings.replication_interval)
+            // The runtime republishes what this node owns, with jitter
+            .set_publication_interval(None)
+            .set_provider_publication_interval(None);
+        let mut kad = kad::Behaviour::with_config(local_peer_id, store, kad_config);
+        kad.set_mode(match kad_settings.mode {
+            KadMode::Auto => None,
+            KadMode::Client => Some(kad::Mode::Client),
+            KadMode::Server => Some(kad::Mode::Server),
+        });
 
         // GossipSub
         let gossipsub = gossipsub::Behaviour::new(
@@ -34,11 +61,18 @@ impl AviBehaviour {
             pubsub_config,
         )
         .expect("Valid gossipsub config");
+        let telemetry = gossipsub::Behaviour::new(
+            gossipsub::MessageAuthenticity::Signed(local_key.clone()),
+            telemetry_config,
+        )
+        .expect("Valid gossipsub config");
 
         // mDNS (Conditional compilation)
         #[cfg(not(target_arch = "wasm32"))]
-        let mdns = mdns::tokio::Behaviour::new(mdns::Config::default(), local_peer_id)
-            .expect("Failed to create mDNS behaviour");
+        let mdns = Toggle::from(enable_mdns.then(|| {
+            mdns::tokio::Behaviour::new(mdns::Config::default(), local_peer_id)
+                .expect("Failed to create mDNS behaviour")
+        }));
 
         // Identify
         let identify = identify::Behaviour::new(
@@ -51,20 +85,40 @@ impl AviBehaviour {
 
         let stream = request_response::Behaviour::new(
             // `new` expects an IntoIterator of (Protocol, ProtocolSupport) tuples
+            std::iter::once((stream_protocol, request_response::ProtocolSupport::Full)),
+            request_response::Config::default()
+                .with_request_timeout(stream_limits.request_timeout)
+                .with_max_concurrent_streams(stream_limits.max_concurrent_requests),
+        );
+
+        // Every node answers rendezvous requests; non-points refuse them
+        let rendezvous = request_response::Behaviour::new(
             std::iter::once((
-                crate::protocols::stream::AviStreamProtocol,
+                crate::protocols::rendezvous::AviRendezvousProtocol,
                 request_response::ProtocolSupport::Full,
             )),
             request_response::Config::default(),
         );
 
+        // Application protocols, routed to their handlers by name
+        let extension = request_response::Behaviour::new(
+            std::iter::once((
+                crate::protocols::extension::AviExtensionProtocol,
+                request_response::ProtocolSupport::Full,
+            )),
+            request_response::Config::default().with_request_timeout(stream_limits.request_timeout),
+        );
+
         Self {
             gossipsub,
+            telemetry,
             kad,
             #[cfg(not(target_arch = "wasm32"))] // Conditional compilation for mdns field
             mdns,
             identify,
             stream,
+            rendezvous,
+            extension,
         }
     }
 }
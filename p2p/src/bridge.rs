@@ -1,25 +1,788 @@
-use crate::{set_nested_value, AviEvent, AviP2pHandle, PeerId, StreamId};
-use avi_p2p_protocol::{DownlinkMessage, UplinkMessage, MAX_PACKET_SIZE};
+use crate::bridge_registry::BridgeRegistration;
+use crate::codec::{PostcardCodec, WireCodec};
+use crate::topic::Topic;
+use crate::{set_nested_value, AviEvent, AviP2pError, AviP2pHandle, PeerId, StreamId};
+use async_trait::async_trait;
+use avi_p2p_protocol::{
+    DownlinkMessage, SensorValue, UplinkMessage, DEFAULT_GATEWAY_PORT, ERROR_SESSION_LIMIT,
+    ERROR_UNKNOWN_SENSOR, ERROR_UNKNOWN_TOPIC, ERROR_UNSUPPORTED_CODEC, MAX_PACKET_SIZE,
+};
+use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::collections::{HashMap, HashSet};
-use std::net::SocketAddr;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::net::UdpSocket;
-use tokio::sync::Mutex;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::sync::{watch, Mutex};
+use tokio::task::JoinHandle;
+
+/// Packets queued per device address while its worker is busy
+const SESSION_QUEUE: usize = 32;
+
+/// How long session changes are gathered before the store is rewritten
+const SESSION_SAVE_DELAY: Duration = Duration::from_millis(500);
+
+/// Stream reason parameter naming the device a bridged stream comes from
+const BRIDGED_DEVICE_PARAM: &str = "bridged_device=";
+
+/// Mesh stream reason for a device's `StreamStart`: the device's own reason
+/// with the device id appended as a parameter (`audio;bridged_device=7`),
+/// so handlers registered for the base reason still accept it
+pub fn bridged_stream_reason(reason: &str, device_id: u64) -> String {
+    format!("{};{}{}", reason, BRIDGED_DEVICE_PARAM, device_id)
+}
+
+/// `StreamStart` target prefix asking for any peer with a capability
+/// (`capability:voice-processor`) instead of a specific peer id
+pub const CAPABILITY_TARGET_PREFIX: &str = "capability:";
+
+/// Whether a peer's capability document offers `name`, as an `extended`
+/// entry, a sensor or a top-level capability such as `audio`
+fn has_capability(caps: &serde_json::Value, name: &str) -> bool {
+    let offered = |value: Option<&serde_json::Value>| match value {
+        None | Some(serde_json::Value::Null) | Some(serde_json::Value::Bool(false)) => false,
+        Some(value) => value.get("Boolean") != Some(&serde_json::Value::Bool(false)),
+    };
+    offered(caps.get("extended").and_then(|e| e.get(name)))
+        || offered(caps.get("sensors").and_then(|s| s.get(name)))
+        || offered(caps.get(name))
+}
+
+/// Connected peer offering `name` that is least loaded, then has the
+/// steadiest network, going by the `health` hints in its capabilities.
+/// Peers without hints rank last.
+fn resolve_capability(
+    caps_by_peer: &serde_json::Value,
+    name: &str,
+    connected: &[PeerId],
+) -> Option<PeerId> {
+    let hint = |caps: &serde_json::Value, key: &str| {
+        caps.get("health")
+            .and_then(|health| health.get(key))
+            .and_then(serde_json::Value::as_u64)
+    };
+    connected
+        .iter()
+        .filter_map(|peer| caps_by_peer.get(peer.as_str()).map(|caps| (peer, caps)))
+        .filter(|(_, caps)| has_capability(caps, name))
+        .min_by_key(|(_, caps)| {
+            let load = hint(caps, "cpu_load_pct").unwrap_or(100);
+            let stability = hint(caps, "network_stability_pct").unwrap_or(0).min(100);
+            (load, 100 - stability)
+        })
+        .map(|(peer, _)| peer.clone())
+}
+
+/// Device a stream was bridged from, if it came through an `EmbeddedBridge`
+pub fn bridged_stream_device(reason: &str) -> Option<u64> {
+    reason
+        .split(';')
+        .skip(1)
+        .find_map(|param| param.strip_prefix(BRIDGED_DEVICE_PARAM))
+        .and_then(|id| id.parse().ok())
+}
 
 pub struct BridgeConfig {
+    /// Name this bridge registers under in `AviP2pHandle::bridges`; bridges
+    /// sharing a node need distinct namespaces
+    pub namespace: String,
+
+    /// Port for bindings that do not set their own
     pub udp_port: u16,
+
+    /// Name announced to devices looking for a gateway
+    pub name: String,
+
+    /// Sockets to listen on (empty = `0.0.0.0` on `udp_port`). Devices are
+    /// answered on the socket they reached the bridge through.
+    pub bind: Vec<BridgeBinding>,
+
+    /// Mesh topics device events are published on
+    pub topics: Arc<dyn TopicMapper>,
+
+    /// Context key sensor readings are merged under, as
+    /// `<key>.<device id>.<sensor>` (None = publish on topics only).
+    /// Defaults to `avi.sensors`, where readings have always been mirrored;
+    /// e.g. `bridged_devices` keeps them apart from the node's own context,
+    /// but consumers reading `avi.sensors` must move with it.
+    pub context_mirror: Option<String>,
+
+    /// Silence after which a device's session expires and it is announced
+    /// offline; devices should send something (e.g. a sensor update) more often
+    pub session_timeout: Duration,
+
+    /// Topics the bridge subscribes to on a device's behalf when it says
+    /// `Hello`, in addition to the ones it subscribes to itself
+    pub device_topics: HashMap<u64, Vec<String>>,
+
+    /// Application handlers, asked in order before the built-in handling
+    pub handlers: Vec<Arc<dyn BridgeHandler>>,
+
+    /// Sessions kept at once; a new device evicts the least recently seen
+    pub max_sessions: usize,
+
+    /// Sessions per source IP; further `Hello`s from it are refused
+    pub max_sessions_per_ip: usize,
+
+    /// Devices that sleep between check-ins: commands for them are held
+    /// until their next uplink instead of being sent right away
+    pub sleepy_devices: HashSet<u64>,
+
+    /// How long a command waits for its device before it is dropped
+    pub command_ttl: Duration,
+
+    /// Commands held per device; the oldest are dropped beyond this
+    pub max_queued_commands: usize,
+
+    /// Wire codecs devices may use, tried in order on packets from
+    /// unknown addresses (see `codec`)
+    pub codecs: Vec<Arc<dyn WireCodec>>,
+
+    /// JSON-lines file sessions are kept in so a gateway restart does not
+    /// orphan its devices; `None` keeps them in memory only
+    pub session_store: Option<PathBuf>,
+
+    /// Development mode for testing firmware without other mesh peers:
+    /// stream data is echoed back to the device and publishes are only logged
+    pub loopback: bool,
+
+    /// How often a `BridgeStats` event with every device's counters is
+    /// emitted (None = only on request through `BridgeHandle`)
+    pub stats_interval: Option<Duration>,
+
+    /// Context key peers publish their capabilities under, one entry per
+    /// peer id; used to resolve `capability:<name>` stream targets
+    pub capability_context: String,
+
+    /// How long `PublishWithId` ids are remembered per device to drop
+    /// retransmissions, including copies heard by the node's other bridges
+    /// (None = forward every copy)
+    pub dedupe_window: Option<Duration>,
+
+    /// Bytes of a device's stream data held while the mesh stream is
+    /// slower than the device; chunks that would go beyond are dropped
+    pub stream_buffer_limit: usize,
+
+    /// Buffered bytes at which the device is sent `Throttle` to slow down
+    pub stream_high_watermark: usize,
+
+    /// Buffered bytes at which a throttled device is told to resume
+    pub stream_low_watermark: usize,
+}
+
+impl Default for BridgeConfig {
+    fn default() -> Self {
+        Self {
+            namespace: "udp".to_string(),
+            udp_port: DEFAULT_GATEWAY_PORT,
+            name: "avi-gateway".to_string(),
+            bind: vec![],
+            topics: Arc::new(TemplateTopicMapper::default()),
+            context_mirror: Some("avi.sensors".to_string()),
+            session_timeout: Duration::from_secs(120),
+            device_topics: HashMap::new(),
+            handlers: vec![],
+            max_sessions: 256,
+            max_sessions_per_ip: 8,
+            sleepy_devices: HashSet::new(),
+            command_ttl: Duration::from_secs(300),
+            max_queued_commands: 16,
+            codecs: vec![
+                Arc::new(PostcardCodec),
+                #[cfg(feature = "cbor")]
+                Arc::new(crate::codec::CborCodec),
+            ],
+            session_store: None,
+            loopback: false,
+            stats_interval: None,
+            capability_context: "avi.device.caps".to_string(),
+            dedupe_window: Some(Duration::from_secs(30)),
+            stream_buffer_limit: 64 * 1024,
+            stream_high_watermark: 32 * 1024,
+            stream_low_watermark: 8 * 1024,
+        }
+    }
+}
+
+impl BridgeConfig {
+    fn bindings(&self) -> Vec<(SocketAddr, String)> {
+        if self.bind.is_empty() {
+            let any = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), self.udp_port);
+            return vec![(any, self.name.clone())];
+        }
+        self.bind
+            .iter()
+            .map(|b| {
+                let addr = SocketAddr::new(b.addr, b.port.unwrap_or(self.udp_port));
+                (addr, b.name.clone().unwrap_or_else(|| self.name.clone()))
+            })
+            .collect()
+    }
+}
+
+/// One UDP socket of the bridge, e.g. a VLAN interface address or `[::]`.
+/// On most systems `[::]` also accepts IPv4, so do not combine it with
+/// `0.0.0.0` on the same port.
+#[derive(Debug, Clone)]
+pub struct BridgeBinding {
+    pub addr: IpAddr,
+
+    /// Port to bind (None = `BridgeConfig::udp_port`)
+    pub port: Option<u16>,
+
+    /// Gateway name announced on this socket (None = `BridgeConfig::name`)
+    pub name: Option<String>,
+}
+
+impl BridgeBinding {
+    pub fn new(addr: IpAddr) -> Self {
+        Self {
+            addr,
+            port: None,
+            name: None,
+        }
+    }
+
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = Some(port);
+        self
+    }
+
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+}
+
+/// Maps device events to mesh topics, so installers can place bridged
+/// devices in their own namespace
+pub trait TopicMapper: Send + Sync {
+    fn button(&self, device_id: u64) -> String;
+    fn sensor(&self, device_id: u64, sensor: &str) -> String;
+    fn input(&self, device_id: u64, input: u32) -> String;
+
+    /// Where online/offline announcements for the device go
+    fn status(&self, device_id: u64) -> String {
+        Topic::device(device_id).status().into()
+    }
+
+    /// Topic mesh nodes publish commands for the device on
+    fn command(&self, device_id: u64) -> String {
+        Topic::device(device_id).command().into()
+    }
+}
+
+/// Topic templates with `{device}`, `{room}`, `{sensor}` and `{input}`
+/// placeholders. `{room}` comes from `rooms`, falling back to `default_room`.
+/// The default templates give the same topics as `Topic::device`.
+#[derive(Debug, Clone)]
+pub struct TemplateTopicMapper {
+    pub button: String,
+    pub sensor: String,
+    pub input: String,
+    pub status: String,
+    pub command: String,
+    pub rooms: HashMap<u64, String>,
+    pub default_room: String,
+}
+
+impl Default for TemplateTopicMapper {
+    fn default() -> Self {
+        Self {
+            button: "device/{device}/button".to_string(),
+            sensor: "device/{device}/sensor/{sensor}".to_string(),
+            input: "device/{device}/input/{input}".to_string(),
+            status: "device/{device}/status".to_string(),
+            command: "device/{device}/command".to_string(),
+            rooms: HashMap::new(),
+            default_room: "unassigned".to_string(),
+        }
+    }
+}
+
+impl TemplateTopicMapper {
+    /// Assign a device to a room for the `{room}` placeholder
+    pub fn with_room(mut self, device_id: u64, room: &str) -> Self {
+        self.rooms.insert(device_id, room.to_string());
+        self
+    }
+
+    fn render(&self, template: &str, device_id: u64) -> String {
+        let room = self.rooms.get(&device_id).unwrap_or(&self.default_room);
+        template
+            .replace("{device}", &device_id.to_string())
+            .replace("{room}", room)
+    }
+}
+
+impl TopicMapper for TemplateTopicMapper {
+    fn button(&self, device_id: u64) -> String {
+        self.render(&self.button, device_id)
+    }
+
+    fn sensor(&self, device_id: u64, sensor: &str) -> String {
+        self.render(&self.sensor, device_id)
+            .replace("{sensor}", sensor)
+    }
+
+    fn input(&self, device_id: u64, input: u32) -> String {
+        self.render(&self.input, device_id)
+            .replace("{input}", &input.to_string())
+    }
+
+    fn status(&self, device_id: u64) -> String {
+        self.render(&self.status, device_id)
+    }
+
+    fn command(&self, device_id: u64) -> String {
+        self.render(&self.command, device_id)
+    }
+}
+
+/// Whether a `BridgeHandler` consumed an uplink message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandlerOutcome {
+    /// Let later handlers and the built-in handling see the message
+    Continue,
+    /// Stop here
+    Handled,
+}
+
+/// Application hook for uplink packets, e.g. local automation on button
+/// presses or vendor `Extension` messages
+#[async_trait]
+pub trait BridgeHandler: Send + Sync {
+    async fn on_uplink(&self, ctx: &BridgeContext, msg: &UplinkMessage<'_>) -> HandlerOutcome;
+}
+
+/// The device a `BridgeHandler` is looking at a packet from
+pub struct BridgeContext {
+    /// None until the device has said `Hello`
+    pub device_id: Option<u64>,
+    pub addr: SocketAddr,
+    pub handle: AviP2pHandle,
+    socket: Arc<UdpSocket>,
+    codec: Arc<dyn WireCodec>,
+}
+
+impl BridgeContext {
+    /// Send a downlink packet straight back to the device
+    pub async fn reply(&self, msg: &DownlinkMessage<'_>) -> Result<(), String> {
+        let mut tx_buf = [0u8; MAX_PACKET_SIZE];
+        let data = self
+            .codec
+            .encode(msg, &mut tx_buf)
+            .ok_or("Failed to encode downlink message")?;
+        self.socket
+            .send_to(data, self.addr)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Encode and send one message, returning the bytes sent (0 on failure)
+async fn send_downlink(
+    socket: &UdpSocket,
+    codec: &dyn WireCodec,
+    addr: SocketAddr,
+    msg: &DownlinkMessage<'_>,
+) -> usize {
+    let mut tx_buf = [0u8; MAX_PACKET_SIZE];
+    match codec.encode(msg, &mut tx_buf) {
+        Some(data) => socket.send_to(data, addr).await.unwrap_or(0),
+        None => 0,
+    }
+}
+
+fn count_sent(stats: &std::sync::Mutex<DeviceStats>, sent: usize) {
+    if sent > 0 {
+        let mut stats = stats.lock().unwrap();
+        stats.packets_out += 1;
+        stats.bytes_out += sent as u64;
+    }
+}
+
+/// Traffic counters for one bridged device, kept across reconnects
+/// from the same address
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceStats {
+    pub device_id: u64,
+    pub packets_in: u64,
+    pub packets_out: u64,
+    /// Packets from the device that no codec could decode
+    pub decode_failures: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// Signal strength in dBm, if the device reports `LinkQuality`
+    pub last_rssi: Option<i8>,
+    /// Round-trip time the device measured, if it reports `LinkQuality`
+    pub last_latency_ms: Option<u16>,
+    /// Stream chunks dropped because the mesh stream fell too far behind
+    pub stream_chunks_dropped: u64,
+}
+
+struct QueuedCommand {
+    topic: String,
+    data: Vec<u8>,
+    expires: Instant,
+}
+
+/// Mesh commands waiting for their device's next uplink
+struct CommandQueues {
+    /// Command topic -> device it is for
+    topics: HashMap<String, u64>,
+    queues: HashMap<u64, VecDeque<QueuedCommand>>,
+    ttl: Duration,
+    limit: usize,
+}
+
+impl CommandQueues {
+    fn new(ttl: Duration, limit: usize) -> Self {
+        Self {
+            topics: HashMap::new(),
+            queues: HashMap::new(),
+            ttl,
+            limit: limit.max(1),
+        }
+    }
+
+    /// Start routing `topic` to `device_id`; false if it already was
+    fn watch(&mut self, topic: String, device_id: u64) -> bool {
+        self.topics.insert(topic, device_id).is_none()
+    }
+
+    fn device_for(&self, topic: &str) -> Option<u64> {
+        self.topics.get(topic).copied()
+    }
+
+    fn push(&mut self, device_id: u64, topic: String, data: Vec<u8>) {
+        let queue = self.queues.entry(device_id).or_default();
+        if queue.len() >= self.limit {
+            queue.pop_front();
+        }
+        queue.push_back(QueuedCommand {
+            topic,
+            data,
+            expires: Instant::now() + self.ttl,
+        });
+    }
+
+    /// Unexpired commands for the device, oldest first
+    fn take(&mut self, device_id: u64) -> Vec<QueuedCommand> {
+        let now = Instant::now();
+        self.queues
+            .remove(&device_id)
+            .map(|queue| queue.into_iter().filter(|c| c.expires > now).collect())
+            .unwrap_or_default()
+    }
+
+    fn prune(&mut self) {
+        let now = Instant::now();
+        self.queues.retain(|_, queue| {
+            queue.retain(|c| c.expires > now);
+            !queue.is_empty()
+        });
+    }
+}
+
+/// What became of a stream chunk offered to a `StreamBuffer`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BufferedChunk {
+    Queued,
+    /// Queued, and the buffer just passed its high watermark
+    Throttle,
+    /// The buffer is full or its forwarder is gone
+    Dropped,
+}
+
+/// Uplink chunks of a bridged stream on their way to the mesh. A forwarder
+/// task sends them in order, so a slow mesh stream holds up neither the
+/// device's other packets nor the sessions lock.
+struct StreamBuffer {
+    chunks: mpsc::UnboundedSender<Vec<u8>>,
+    /// Bytes queued that the mesh has not taken yet
+    queued: Arc<AtomicUsize>,
+    /// Whether the device was last told to slow down
+    throttled: Arc<AtomicBool>,
+}
+
+impl StreamBuffer {
+    /// Start forwarding to `mesh_id`. The forwarder tells the device to
+    /// resume once the buffer drains to the low watermark, and closes the
+    /// mesh stream after the last chunk once the buffer is dropped.
+    fn spawn(
+        handle: AviP2pHandle,
+        config: &BridgeConfig,
+        mesh_id: StreamId,
+        session: &DeviceSession,
+        addr: SocketAddr,
+        local_stream_id: u8,
+    ) -> Self {
+        let (chunks, mut pending) = mpsc::unbounded_channel::<Vec<u8>>();
+        let buffer = Self {
+            chunks,
+            queued: Arc::new(AtomicUsize::new(0)),
+            throttled: Arc::new(AtomicBool::new(false)),
+        };
+
+        let queued = buffer.queued.clone();
+        let throttled = buffer.throttled.clone();
+        let low_watermark = config.stream_low_watermark;
+        let socket = session.socket.clone();
+        let codec = session.codec.clone();
+        let stats = session.stats.clone();
+        tokio::spawn(async move {
+            while let Some(chunk) = pending.recv().await {
+                let len = chunk.len();
+                let _ = handle.send_stream_data(mesh_id, chunk).await;
+                let left = queued.fetch_sub(len, Ordering::AcqRel) - len;
+                if left <= low_watermark && throttled.swap(false, Ordering::AcqRel) {
+                    let resume = DownlinkMessage::Throttle {
+                        local_stream_id,
+                        slow_down: false,
+                    };
+                    let sent = send_downlink(&socket, codec.as_ref(), addr, &resume).await;
+                    count_sent(&stats, sent);
+                }
+            }
+            let _ = handle.close_stream(mesh_id).await;
+        });
+
+        buffer
+    }
+
+    fn push(&self, chunk: Vec<u8>, config: &BridgeConfig) -> BufferedChunk {
+        let len = chunk.len();
+        // Reserve the room first so concurrent pushes cannot both fit
+        let queued = self.queued.fetch_add(len, Ordering::AcqRel) + len;
+        if queued > config.stream_buffer_limit {
+            self.queued.fetch_sub(len, Ordering::AcqRel);
+            return BufferedChunk::Dropped;
+        }
+        if self.chunks.send(chunk).is_err() {
+            self.queued.fetch_sub(len, Ordering::AcqRel);
+            return BufferedChunk::Dropped;
+        }
+        if queued >= config.stream_high_watermark && !self.throttled.swap(true, Ordering::AcqRel) {
+            return BufferedChunk::Throttle;
+        }
+        BufferedChunk::Queued
+    }
+}
+
+/// A bound socket and what it announces to devices
+struct BridgeListener {
+    socket: Arc<UdpSocket>,
+    port: u16,
+    name: String,
 }
 
 struct DeviceSession {
     pub device_id: u64,
+    /// Socket the device talks to the bridge through
+    pub socket: Arc<UdpSocket>,
+    /// Codec the device chose in its `Hello`
+    pub codec: Arc<dyn WireCodec>,
     pub active_streams: HashMap<u8, StreamId>,
+    /// Uplink data of `active_streams` waiting for the mesh
+    stream_buffers: HashMap<u8, StreamBuffer>,
+    /// Streams echoed back in loopback mode
+    pub loopback_streams: HashSet<u8>,
     pub subscriptions: HashSet<String>,
+    /// Compact ids offered for subscribed topics
+    pub topic_ids: HashMap<String, u16>,
+    /// Ids the device confirmed, usable in `AliasedMessage`
+    pub acked_topic_ids: HashSet<u16>,
+    pub next_topic_id: u16,
+    pub sensor_names: HashMap<u16, String>,
+    /// Topics the device registered for `PublishById`
+    pub publish_topics: HashMap<u16, String>,
+    pub last_seen: Instant,
+    pub stats: Arc<std::sync::Mutex<DeviceStats>>,
+}
+
+impl DeviceSession {
+    async fn send(&self, addr: SocketAddr, msg: &DownlinkMessage<'_>) {
+        let sent = send_downlink(&self.socket, self.codec.as_ref(), addr, msg).await;
+        count_sent(&self.stats, sent);
+    }
+
+    fn to_stored(&self, addr: SocketAddr) -> StoredSession {
+        StoredSession {
+            addr,
+            device_id: self.device_id,
+            codec: self.codec.id(),
+            subscriptions: self.subscriptions.iter().cloned().collect(),
+            topic_ids: self.topic_ids.clone(),
+            next_topic_id: self.next_topic_id,
+            sensor_names: self.sensor_names.clone(),
+            publish_topics: self.publish_topics.clone(),
+            streams: self.active_streams.keys().copied().collect(),
+        }
+    }
+}
+
+/// A session as saved in `BridgeConfig::session_store`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct StoredSession {
+    addr: SocketAddr,
+    device_id: u64,
+    codec: u8,
+    subscriptions: Vec<String>,
+    topic_ids: HashMap<String, u16>,
+    next_topic_id: u16,
+    sensor_names: HashMap<u16, String>,
+    #[serde(default)]
+    publish_topics: HashMap<u16, String>,
+    /// Local ids of streams that were bridged; the mesh side does not
+    /// survive a restart, so the device is told they closed
+    streams: Vec<u8>,
+}
+
+/// Saved sessions; unreadable lines are skipped so a torn write cannot
+/// keep the gateway from starting
+fn load_sessions(path: Option<&Path>) -> HashMap<SocketAddr, StoredSession> {
+    let Some(file) = path.and_then(|path| File::open(path).ok()) else {
+        return HashMap::new();
+    };
+    BufReader::new(file)
+        .lines()
+        .map_while(Result::ok)
+        .filter_map(|line| serde_json::from_str::<StoredSession>(&line).ok())
+        .map(|stored| (stored.addr, stored))
+        .collect()
+}
+
+/// Replace the store with `sessions`, through a temporary file renamed into
+/// place so a crash mid-write leaves the previous store intact
+fn write_sessions(path: &Path, sessions: &[StoredSession]) -> std::io::Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+
+    let mut file = BufWriter::new(File::create(&temp)?);
+    for entry in sessions {
+        if let Ok(line) = serde_json::to_string(entry) {
+            writeln!(file, "{}", line)?;
+        }
+    }
+    file.into_inner()?.sync_all()?;
+    std::fs::rename(&temp, path)
+}
+
+/// Write session snapshots to the store on the blocking pool. Snapshots
+/// arriving within `SESSION_SAVE_DELAY` of each other make one write, and
+/// the last one is written once the sender is dropped.
+async fn save_sessions_task(path: PathBuf, mut snapshots: watch::Receiver<Vec<StoredSession>>) {
+    while snapshots.changed().await.is_ok() {
+        tokio::time::sleep(SESSION_SAVE_DELAY).await;
+        let sessions = snapshots.borrow_and_update().clone();
+        let path = path.clone();
+        let written = tokio::task::spawn_blocking(move || write_sessions(&path, &sessions)).await;
+        if let Ok(Err(e)) = written {
+            println!("Failed to save bridged device sessions: {}", e);
+        }
+    }
+}
+
+/// Context patch holding only one sensor reading, so merging it keeps
+/// concurrent context writes
+fn sensor_context_patch(
+    mirror: &str,
+    dev_id: u64,
+    sensor_name: &str,
+    reading: serde_json::Value,
+) -> Result<serde_json::Value, AviP2pError> {
+    let mut patch = json!({});
+    set_nested_value(
+        &mut patch,
+        &format!("{}.{}.{}", mirror, dev_id, sensor_name),
+        reading,
+    )?;
+    Ok(patch)
+}
+
+/// State shared by the bridge's socket, sweep and event tasks
+struct BridgeShared {
+    handle: AviP2pHandle,
+    config: BridgeConfig,
+    sessions: Mutex<HashMap<SocketAddr, DeviceSession>>,
+    commands: Mutex<CommandQueues>,
+    /// Sessions from before a restart, waiting for their device's first packet
+    stored: Mutex<HashMap<SocketAddr, StoredSession>>,
+    /// When saved sessions whose device never came back are given up on
+    stored_until: Instant,
+    /// Snapshots for the session store writer, dropped by `BridgeHandle::stop`
+    session_saves: std::sync::Mutex<Option<watch::Sender<Vec<StoredSession>>>>,
+    /// Device ids and message dedupe shared with the node's other bridges
+    registration: BridgeRegistration,
+    /// Socket, sweep and event tasks, aborted by `BridgeHandle::stop`
+    tasks: std::sync::Mutex<Vec<JoinHandle<()>>>,
+}
+
+/// Diagnostics and control for a running bridge, returned by
+/// `EmbeddedBridge::start`
+#[derive(Clone)]
+pub struct BridgeHandle {
+    shared: Arc<BridgeShared>,
+}
+
+impl BridgeHandle {
+    /// Counters for a device with a live session
+    pub async fn device_stats(&self, device_id: u64) -> Option<DeviceStats> {
+        self.shared
+            .sessions
+            .lock()
+            .await
+            .values()
+            .find(|session| session.device_id == device_id)
+            .map(|session| session.stats.lock().unwrap().clone())
+    }
+
+    /// Counters for every device with a live session
+    pub async fn all_device_stats(&self) -> Vec<DeviceStats> {
+        self.shared
+            .sessions
+            .lock()
+            .await
+            .values()
+            .map(|session| session.stats.lock().unwrap().clone())
+            .collect()
+    }
+
+    /// Stop the bridge: its sockets close, the devices attached through it
+    /// are announced offline and its namespace is free for a new bridge
+    pub async fn stop(&self) {
+        for task in self.shared.tasks.lock().unwrap().drain(..) {
+            task.abort();
+        }
+        // The store writer finishes the last snapshot on its own
+        self.shared.session_saves.lock().unwrap().take();
+        let sessions: Vec<DeviceSession> = self
+            .shared
+            .sessions
+            .lock()
+            .await
+            .drain()
+            .map(|(_, session)| session)
+            .collect();
+        for session in sessions {
+            EmbeddedBridge::retire_session(&self.shared, session).await;
+        }
+        self.shared.registration.unregister();
+    }
 }
 
 pub struct EmbeddedBridge {
     #[allow(dead_code)]
-    socket: Arc<UdpSocket>,
+    sockets: Vec<Arc<UdpSocket>>,
     #[allow(dead_code)]
     handle: AviP2pHandle,
 
@@ -28,132 +791,445 @@ pub struct EmbeddedBridge {
 }
 
 impl EmbeddedBridge {
-    pub async fn start(handle: AviP2pHandle, config: BridgeConfig) -> Result<(), String> {
-        let addr = format!("0.0.0.0:{}", config.udp_port);
-        let socket = UdpSocket::bind(&addr).await.map_err(|e| e.to_string())?;
-        let socket = Arc::new(socket);
+    pub async fn start(handle: AviP2pHandle, config: BridgeConfig) -> Result<BridgeHandle, String> {
+        let registration = handle.bridges().register(&config.namespace)?;
 
-        let sessions = Arc::new(Mutex::new(HashMap::new()));
+        // Bind everything first so a bad address fails the whole start
+        let mut listeners = Vec::new();
+        for (addr, name) in config.bindings() {
+            let socket = UdpSocket::bind(addr)
+                .await
+                .map_err(|e| format!("Failed to bind {}: {}", addr, e))?;
+            let port = socket.local_addr().map_err(|e| e.to_string())?.port();
+            println!("Embedded Bridge Listening on UDP {}", addr);
+            listeners.push(BridgeListener {
+                socket: Arc::new(socket),
+                port,
+                name,
+            });
+        }
 
-        println!("Embedded Bridge Listening on UDP {}", config.udp_port);
+        let commands = Mutex::new(CommandQueues::new(
+            config.command_ttl,
+            config.max_queued_commands,
+        ));
 
-        // Spawn uplink handler (embedded -> gateway)
-        let uplink_socket = socket.clone();
-        let uplink_handle = handle.clone();
-        let uplink_sessions = sessions.clone();
+        // Devices known up front get their commands queued even before
+        // they first say `Hello`
+        for device_id in config
+            .sleepy_devices
+            .iter()
+            .chain(config.device_topics.keys())
+        {
+            Self::watch_commands(&handle, &commands, &config, *device_id).await;
+        }
 
-        tokio::spawn(async move {
-            let mut buf = [0u8; MAX_PACKET_SIZE];
+        // Sessions from before a restart keep their mesh subscriptions and
+        // are welcomed back on their device's first packet
+        let stored = load_sessions(config.session_store.as_deref());
+        for session in stored.values() {
+            for topic in &session.subscriptions {
+                let _ = handle.subscribe(topic).await;
+            }
+            Self::watch_commands(&handle, &commands, &config, session.device_id).await;
+        }
+        if !stored.is_empty() {
+            println!("Restoring {} bridged device sessions", stored.len());
+        }
 
-            loop {
-                let (len, remote_addr) = match uplink_socket.recv_from(&mut buf).await {
-                    Ok(res) => res,
-                    Err(_) => continue,
-                };
+        let session_saves = config.session_store.clone().map(|path| {
+            let (saves, snapshots) = watch::channel(Vec::new());
+            tokio::spawn(save_sessions_task(path, snapshots));
+            saves
+        });
+
+        let shared = Arc::new(BridgeShared {
+            handle: handle.clone(),
+            stored_until: Instant::now() + config.session_timeout,
+            session_saves: std::sync::Mutex::new(session_saves),
+            registration,
+            tasks: std::sync::Mutex::new(Vec::new()),
+            config,
+            sessions: Mutex::new(HashMap::new()),
+            commands,
+            stored: Mutex::new(stored),
+        });
 
-                let packet: Result<UplinkMessage, _> = postcard::from_bytes(&buf[..len]);
+        // Spawn an uplink reader per socket (embedded -> gateway). Packets
+        // are handed to a worker per device address, so a device waiting on
+        // the mesh only holds up its own packets.
+        let mut tasks = Vec::new();
+        for listener in listeners {
+            let uplink_shared = shared.clone();
+            let listener = Arc::new(listener);
 
-                if let Ok(msg) = packet {
-                    Self::handle_uplink_packet(
-                        msg,
+            tasks.push(tokio::spawn(async move {
+                let mut buf = [0u8; MAX_PACKET_SIZE];
+                let mut workers: HashMap<SocketAddr, mpsc::Sender<Vec<u8>>> = HashMap::new();
+
+                loop {
+                    let (len, remote_addr) = match listener.socket.recv_from(&mut buf).await {
+                        Ok(res) => res,
+                        Err(_) => continue,
+                    };
+
+                    let mut packet = buf[..len].to_vec();
+                    if let Some(worker) = workers.get(&remote_addr) {
+                        match worker.try_send(packet) {
+                            // A device outrunning its worker loses packets, as UDP would
+                            Ok(()) | Err(TrySendError::Full(_)) => continue,
+                            Err(TrySendError::Closed(returned)) => packet = returned,
+                        }
+                    }
+
+                    workers.retain(|_, worker| !worker.is_closed());
+                    let (worker, packets) = mpsc::channel(SESSION_QUEUE);
+                    let _ = worker.try_send(packet);
+                    workers.insert(remote_addr, worker);
+                    tokio::spawn(Self::session_worker(
+                        packets,
                         remote_addr,
-                        uplink_socket.clone(),
-                        uplink_handle.clone(),
-                        uplink_sessions.clone(),
-                    )
-                    .await;
+                        listener.clone(),
+                        uplink_shared.clone(),
+                    ));
                 }
+            }));
+        }
+
+        // Expire sessions of devices that went quiet
+        let sweep_shared = shared.clone();
+        tasks.push(tokio::spawn(async move {
+            let period = (sweep_shared.config.session_timeout / 4).max(Duration::from_secs(1));
+            let mut sweep = tokio::time::interval(period);
+            loop {
+                sweep.tick().await;
+                Self::expire_sessions(&sweep_shared).await;
+                sweep_shared.commands.lock().await.prune();
             }
-        });
+        }));
 
-        // Spawn downlink handler (gateway -> embedded)
-        let downlink_socket = socket.clone();
-        let downlink_sessions = sessions.clone();
+        // Spawn downlink handler (gateway -> embedded). Stream events come
+        // through their own lossless queue, since a missed close or data
+        // chunk would leave a bridged stream out of step with its device.
+        let downlink_shared = shared.clone();
         let mut event_rx = handle.subscribe_events().await.map_err(|e| e.to_string())?;
 
-        tokio::spawn(async move {
-            while let Ok(event) = event_rx.recv().await {
-                Self::handle_downlink_event(
-                    event,
-                    downlink_socket.clone(),
-                    downlink_sessions.clone(),
-                )
-                .await;
+        tasks.push(tokio::spawn(async move {
+            while let Some(event) = event_rx.recv().await {
+                if event.stream_id().is_none() {
+                    Self::handle_downlink_event(event, &downlink_shared).await;
+                }
+            }
+        }));
+
+        let stream_shared = shared.clone();
+        let mut stream_rx = handle.subscribe_stream_events();
+        tasks.push(tokio::spawn(async move {
+            while let Some(event) = stream_rx.recv().await {
+                Self::handle_downlink_event(event, &stream_shared).await;
+            }
+        }));
+
+        let bridge = BridgeHandle { shared };
+        if let Some(period) = bridge.shared.config.stats_interval {
+            let stats_bridge = bridge.clone();
+            tasks.push(tokio::spawn(async move {
+                let mut summary = tokio::time::interval(period);
+                summary.tick().await;
+                loop {
+                    summary.tick().await;
+                    let devices = stats_bridge.all_device_stats().await;
+                    stats_bridge
+                        .shared
+                        .handle
+                        .emit_event(AviEvent::BridgeStats { devices })
+                        .await;
+                }
+            }));
+        }
+        bridge.shared.tasks.lock().unwrap().extend(tasks);
+
+        Ok(bridge)
+    }
+
+    /// Handle one address's packets in order. The worker ends once the
+    /// address has no session and nothing more arrives for a session timeout.
+    async fn session_worker(
+        mut packets: mpsc::Receiver<Vec<u8>>,
+        addr: SocketAddr,
+        listener: Arc<BridgeListener>,
+        shared: Arc<BridgeShared>,
+    ) {
+        loop {
+            match tokio::time::timeout(shared.config.session_timeout, packets.recv()).await {
+                Ok(Some(packet)) => {
+                    Self::handle_uplink_packet(&packet, addr, &listener, &shared).await;
+                }
+                Ok(None) => return,
+                Err(_) => {}
             }
-        });
 
-        Ok(())
+            let attached = shared.sessions.lock().await.contains_key(&addr)
+                || shared.stored.lock().await.contains_key(&addr);
+            if !attached {
+                // Drain what is already queued; later packets get a new worker
+                packets.close();
+            }
+        }
     }
 
     async fn handle_uplink_packet(
-        msg: UplinkMessage<'_>,
+        packet: &[u8],
         addr: SocketAddr,
-        socket: Arc<UdpSocket>,
-        handle: AviP2pHandle,
-        sessions: Arc<Mutex<HashMap<SocketAddr, DeviceSession>>>,
+        listener: &BridgeListener,
+        shared: &BridgeShared,
     ) {
-        let mut sessions_lock = sessions.lock().await;
+        let handle = &shared.handle;
+        let config = &shared.config;
+        let sessions = &shared.sessions;
+        let commands = &shared.commands;
+        let socket = &listener.socket;
+        let (mut device_id, session_codec, mut stats) = match sessions.lock().await.get_mut(&addr) {
+            Some(session) => {
+                session.last_seen = Instant::now();
+                (
+                    Some(session.device_id),
+                    Some(session.codec.clone()),
+                    Some(session.stats.clone()),
+                )
+            }
+            None => (None, None, None),
+        };
+        let stored = match device_id {
+            Some(_) => None,
+            None => shared.stored.lock().await.get(&addr).cloned(),
+        };
+        let stored_codec = stored
+            .as_ref()
+            .and_then(|stored| config.codecs.iter().find(|c| c.id() == stored.codec));
 
+        // The session's codec first; anything else may be a device
+        // reconnecting with a new `Hello`
+        let Some((msg, codec)) = session_codec
+            .iter()
+            .chain(stored_codec)
+            .chain(config.codecs.iter())
+            .find_map(|codec| codec.decode(packet).map(|msg| (msg, codec.clone())))
+        else {
+            if let Some(stats) = &stats {
+                stats.lock().unwrap().decode_failures += 1;
+            }
+            return;
+        };
+
+        // A device that rebooted too starts over with its `Hello`
+        if let Some(stored) = stored {
+            shared.stored.lock().await.remove(&addr);
+            let hello = matches!(
+                msg,
+                UplinkMessage::Hello { .. } | UplinkMessage::HelloCodec { .. }
+            );
+            if !hello {
+                device_id = Some(stored.device_id);
+                stats =
+                    Some(Self::restore_session(shared, addr, socket, codec.clone(), stored).await);
+            }
+        }
+        if let Some(stats) = &stats {
+            let mut stats = stats.lock().unwrap();
+            stats.packets_in += 1;
+            stats.bytes_in += packet.len() as u64;
+        }
+
+        // Any uplink means the device is awake to hear what was held for it
+        if let Some(device_id) = device_id {
+            let pending = commands.lock().await.take(device_id);
+            if let Some(session) = sessions.lock().await.get(&addr) {
+                Self::deliver_commands(session, addr, pending).await;
+            }
+        }
+
+        if !config.handlers.is_empty() {
+            let ctx = BridgeContext {
+                device_id,
+                addr,
+                handle: handle.clone(),
+                socket: socket.clone(),
+                codec: codec.clone(),
+            };
+            for handler in &config.handlers {
+                if handler.on_uplink(&ctx, &msg).await == HandlerOutcome::Handled {
+                    return;
+                }
+            }
+        }
+
+        let requested_codec = match &msg {
+            UplinkMessage::HelloCodec { codec, .. } => Some(*codec),
+            _ => None,
+        };
+        let known_device = device_id;
+
+        // The sessions lock is only held for bookkeeping and UDP replies,
+        // never across a mesh operation
         match msg {
-            UplinkMessage::Hello { device_id } => {
-                sessions_lock.insert(
-                    addr,
-                    DeviceSession {
+            UplinkMessage::DiscoverGateway => {
+                let announce = DownlinkMessage::GatewayAnnounce {
+                    port: listener.port,
+                    name: &listener.name,
+                };
+                send_downlink(socket, codec.as_ref(), addr, &announce).await;
+            }
+
+            UplinkMessage::Hello { device_id } | UplinkMessage::HelloCodec { device_id, .. } => {
+                let session_codec = match requested_codec {
+                    None => codec.clone(),
+                    Some(id) => match config.codecs.iter().find(|c| c.id() == id) {
+                        Some(chosen) => chosen.clone(),
+                        None => {
+                            let err = DownlinkMessage::Error {
+                                reason: ERROR_UNSUPPORTED_CODEC,
+                            };
+                            send_downlink(socket, codec.as_ref(), addr, &err).await;
+                            return;
+                        }
+                    },
+                };
+
+                Self::watch_commands(handle, commands, config, device_id).await;
+                let pending = commands.lock().await.take(device_id);
+
+                let evicted = {
+                    let mut sessions_lock = sessions.lock().await;
+                    let reconnect = sessions_lock.contains_key(&addr);
+                    let from_ip = sessions_lock
+                        .keys()
+                        .filter(|other| other.ip() == addr.ip())
+                        .count();
+                    if !reconnect && from_ip >= config.max_sessions_per_ip {
+                        drop(sessions_lock);
+                        handle.record_rejected_hello();
+                        let err = DownlinkMessage::Error {
+                            reason: ERROR_SESSION_LIMIT,
+                        };
+                        send_downlink(socket, codec.as_ref(), addr, &err).await;
+                        return;
+                    }
+                    let mut evicted = None;
+                    if !reconnect && sessions_lock.len() >= config.max_sessions.max(1) {
+                        let oldest = sessions_lock
+                            .iter()
+                            .min_by_key(|(_, session)| session.last_seen)
+                            .map(|(addr, _)| *addr);
+                        evicted = oldest.and_then(|a| sessions_lock.remove(&a));
+                    }
+
+                    // A device re-saying `Hello` keeps its counters
+                    let stats = match stats.filter(|_| known_device == Some(device_id)) {
+                        Some(stats) => stats,
+                        None => Arc::new(std::sync::Mutex::new(DeviceStats {
+                            device_id,
+                            packets_in: 1,
+                            bytes_in: packet.len() as u64,
+                            ..Default::default()
+                        })),
+                    };
+                    let session = DeviceSession {
                         device_id,
+                        socket: socket.clone(),
+                        codec: session_codec,
                         active_streams: HashMap::new(),
+                        stream_buffers: HashMap::new(),
+                        loopback_streams: HashSet::new(),
                         subscriptions: HashSet::new(),
-                    },
-                );
+                        topic_ids: HashMap::new(),
+                        acked_topic_ids: HashSet::new(),
+                        next_topic_id: 0,
+                        sensor_names: HashMap::new(),
+                        publish_topics: HashMap::new(),
+                        last_seen: Instant::now(),
+                        stats,
+                    };
+                    session.send(addr, &DownlinkMessage::Welcome).await;
+                    Self::deliver_commands(&session, addr, pending).await;
+                    sessions_lock.insert(addr, session);
+                    shared.registration.set_sessions(sessions_lock.len());
+                    Self::save_sessions(shared, &sessions_lock).await;
+                    evicted
+                };
 
-                let welcome = DownlinkMessage::Welcome;
-                let mut tx_buf = [0u8; 64];
-                if let Ok(data) = postcard::to_slice(&welcome, &mut tx_buf) {
-                    let _ = socket.send_to(data, addr).await;
+                println!("✅ Device {} connected from {}", device_id, addr);
+                if let Some(previous) = shared.registration.claim_device(device_id) {
+                    println!("Device {} moved over from bridge '{}'", device_id, previous);
+                }
+                if let Some(evicted) = evicted {
+                    println!("Evicting device {} for a new session", evicted.device_id);
+                    Self::retire_session(shared, evicted).await;
                 }
+                Self::announce_status(handle, config, device_id, true).await;
+                handle
+                    .emit_event(AviEvent::BridgedDeviceOnline {
+                        device_id,
+                        address: addr.to_string(),
+                    })
+                    .await;
 
-                println!("✅ Device {} connected from {}", device_id, addr);
+                for topic in config.device_topics.get(&device_id).into_iter().flatten() {
+                    Self::subscribe_device(shared, addr, topic).await;
+                }
             }
 
             UplinkMessage::Subscribe { topic } => {
-                if let Some(session) = sessions_lock.get_mut(&addr) {
-                    println!("📥 Device {} subscribing to: {}", session.device_id, topic);
+                if let Some(device_id) = device_id {
+                    println!("📥 Device {} subscribing to: {}", device_id, topic);
+                    Self::subscribe_device(shared, addr, topic).await;
+                }
+            }
 
-                    // Subscribe on the P2P mesh
-                    if let Ok(_) = handle.subscribe(topic).await {
-                        session.subscriptions.insert(topic.to_string());
+            // Only meaningful to application handlers
+            UplinkMessage::Extension { .. } => {}
 
-                        // Send acknowledgment
-                        let ack = DownlinkMessage::SubscribeAck { topic };
-                        let mut tx_buf = [0u8; 256];
-                        if let Ok(data) = postcard::to_slice(&ack, &mut tx_buf) {
-                            let _ = socket.send_to(data, addr).await;
-                        }
+            UplinkMessage::TopicAliasAck { topic_id } => {
+                if let Some(session) = sessions.lock().await.get_mut(&addr) {
+                    if session.topic_ids.values().any(|id| *id == topic_id) {
+                        session.acked_topic_ids.insert(topic_id);
                     }
                 }
             }
 
             UplinkMessage::Unsubscribe { topic } => {
-                if let Some(session) = sessions_lock.get_mut(&addr) {
-                    println!(
-                        "📤 Device {} unsubscribing from: {}",
-                        session.device_id, topic
-                    );
+                let unsubscribed = {
+                    let mut sessions_lock = sessions.lock().await;
+                    match sessions_lock.get_mut(&addr) {
+                        Some(session) => {
+                            println!(
+                                "📤 Device {} unsubscribing from: {}",
+                                session.device_id, topic
+                            );
 
-                    session.subscriptions.remove(topic);
-                    let _ = handle.unsubscribe(topic).await;
+                            session.subscriptions.remove(topic);
+                            if let Some(topic_id) = session.topic_ids.remove(topic) {
+                                session.acked_topic_ids.remove(&topic_id);
+                            }
 
-                    // Send acknowledgment
-                    let ack = DownlinkMessage::UnsubscribeAck { topic };
-                    let mut tx_buf = [0u8; 256];
-                    if let Ok(data) = postcard::to_slice(&ack, &mut tx_buf) {
-                        let _ = socket.send_to(data, addr).await;
+                            // Send acknowledgment
+                            session
+                                .send(addr, &DownlinkMessage::UnsubscribeAck { topic })
+                                .await;
+                            Self::save_sessions(shared, &sessions_lock).await;
+                            true
+                        }
+                        None => false,
                     }
+                };
+                if unsubscribed {
+                    let _ = handle.unsubscribe(topic).await;
                 }
             }
 
             UplinkMessage::Publish { topic, data } => {
-                if let Some(_session) = sessions_lock.get(&addr) {
-                    let _ = handle.publish(topic, data.to_vec()).await;
+                if device_id.is_some() {
+                    Self::publish(handle, config, topic, data.to_vec()).await;
                 }
             }
 
@@ -162,26 +1238,75 @@ impl EmbeddedBridge {
                 target_peer_id,
                 reason,
             } => {
-                if let Some(session) = sessions_lock.get_mut(&addr) {
-                    if target_peer_id.is_empty() {
-                        println!("Device requested stream with no target.");
-                        return;
+                let Some(device_id) = device_id else {
+                    return;
+                };
+                if config.loopback {
+                    println!("🔁 Echoing stream {} ({})", local_stream_id, reason);
+                    if let Some(session) = sessions.lock().await.get_mut(&addr) {
+                        session.loopback_streams.insert(local_stream_id);
                     }
+                    return;
+                }
+                if target_peer_id.is_empty() {
+                    println!("Device requested stream with no target.");
+                    return;
+                }
 
-                    let peer_id = PeerId::new(target_peer_id);
-                    println!(
-                        "Bridging Stream {} -> Mesh Peer {}",
-                        local_stream_id, peer_id
-                    );
+                let peer_id = match target_peer_id.strip_prefix(CAPABILITY_TARGET_PREFIX) {
+                    Some(capability) => {
+                        let caps = handle
+                            .get_ctx(&config.capability_context)
+                            .await
+                            .unwrap_or_default();
+                        let connected = handle.connected_peers().await.unwrap_or_default();
+                        match resolve_capability(&caps, capability, &connected) {
+                            Some(peer_id) => peer_id,
+                            None => {
+                                println!("No peer offers capability {}", capability);
+                                if let Some(session) = sessions.lock().await.get(&addr) {
+                                    let msg = DownlinkMessage::StreamClosed { local_stream_id };
+                                    session.send(addr, &msg).await;
+                                }
+                                return;
+                            }
+                        }
+                    }
+                    None => PeerId::new(target_peer_id),
+                };
+                println!(
+                    "Bridging Stream {} ({}) -> Mesh Peer {}",
+                    local_stream_id, reason, peer_id
+                );
 
-                    match handle.request_stream(peer_id, reason.to_string()).await {
-                        Ok(mesh_stream_id) => {
-                            session
-                                .active_streams
-                                .insert(local_stream_id, mesh_stream_id);
+                let mesh_reason = bridged_stream_reason(reason, device_id);
+                match handle.request_stream(peer_id, mesh_reason).await {
+                    Ok(mesh_stream_id) => {
+                        let mut sessions_lock = sessions.lock().await;
+                        match sessions_lock.get_mut(&addr) {
+                            Some(session) => {
+                                let buffer = StreamBuffer::spawn(
+                                    handle.clone(),
+                                    config,
+                                    mesh_stream_id,
+                                    session,
+                                    addr,
+                                    local_stream_id,
+                                );
+                                session.stream_buffers.insert(local_stream_id, buffer);
+                                session
+                                    .active_streams
+                                    .insert(local_stream_id, mesh_stream_id);
+                                Self::save_sessions(shared, &sessions_lock).await;
+                            }
+                            // The device went away while the mesh answered
+                            None => {
+                                drop(sessions_lock);
+                                let _ = handle.close_stream(mesh_stream_id).await;
+                            }
                         }
-                        Err(e) => eprintln!("Bridge Failed to open mesh stream: {}", e),
                     }
+                    Err(e) => eprintln!("Bridge Failed to open mesh stream: {}", e),
                 }
             }
 
@@ -189,18 +1314,46 @@ impl EmbeddedBridge {
                 local_stream_id,
                 data,
             } => {
-                if let Some(session) = sessions_lock.get(&addr) {
-                    if let Some(mesh_id) = session.active_streams.get(&local_stream_id) {
-                        let _ = handle.send_stream_data(*mesh_id, data.to_vec()).await;
+                let sessions_lock = sessions.lock().await;
+                let Some(session) = sessions_lock.get(&addr) else {
+                    return;
+                };
+                if session.loopback_streams.contains(&local_stream_id) {
+                    let echo = DownlinkMessage::StreamData {
+                        local_stream_id,
+                        data,
+                    };
+                    session.send(addr, &echo).await;
+                }
+                let Some(buffer) = session.stream_buffers.get(&local_stream_id) else {
+                    return;
+                };
+                match buffer.push(data.to_vec(), config) {
+                    BufferedChunk::Queued => {}
+                    BufferedChunk::Throttle => {
+                        let throttle = DownlinkMessage::Throttle {
+                            local_stream_id,
+                            slow_down: true,
+                        };
+                        session.send(addr, &throttle).await;
+                    }
+                    BufferedChunk::Dropped => {
+                        session.stats.lock().unwrap().stream_chunks_dropped += 1;
                     }
                 }
             }
 
             UplinkMessage::StreamClose { local_stream_id } => {
-                if let Some(session) = sessions_lock.get_mut(&addr) {
-                    if let Some(mesh_id) = session.active_streams.remove(&local_stream_id) {
-                        let _ = handle.close_stream(mesh_id).await;
-                    }
+                // Dropping the buffer lets its forwarder close the mesh
+                // stream once the chunks still queued have gone out
+                let mut sessions_lock = sessions.lock().await;
+                let closed = sessions_lock.get_mut(&addr).and_then(|session| {
+                    session.loopback_streams.remove(&local_stream_id);
+                    session.stream_buffers.remove(&local_stream_id);
+                    session.active_streams.remove(&local_stream_id)
+                });
+                if closed.is_some() {
+                    Self::save_sessions(shared, &sessions_lock).await;
                 }
             }
 
@@ -209,10 +1362,8 @@ impl EmbeddedBridge {
                 press_type,
                 custom_data,
             } => {
-                if let Some(session) = sessions_lock.get(&addr) {
-                    let dev_id = session.device_id;
-
-                    let topic = format!("device/{}/button", dev_id);
+                if let Some(device_id) = device_id {
+                    let topic = config.topics.button(device_id);
 
                     let payload = json!({
                         "button_id": button_id,
@@ -223,9 +1374,13 @@ impl EmbeddedBridge {
                             .unwrap_or_default().as_secs()
                     });
 
-                    let _ = handle
-                        .publish(&topic, serde_json::to_vec(&payload).unwrap())
-                        .await;
+                    Self::publish(
+                        handle,
+                        config,
+                        &topic,
+                        serde_json::to_vec(&payload).unwrap(),
+                    )
+                    .await;
                 }
             }
 
@@ -234,84 +1389,724 @@ impl EmbeddedBridge {
                 data,
                 custom_data,
             } => {
-                if let Some(session) = sessions_lock.get(&addr) {
-                    let dev_id = session.device_id;
+                if let Some(device_id) = device_id {
+                    Self::publish_sensor_update(
+                        handle,
+                        config,
+                        device_id,
+                        sensor_name,
+                        data,
+                        custom_data,
+                    )
+                    .await;
+                }
+            }
 
-                    let topic = format!("device/{}/sensor/{}", dev_id, sensor_name);
+            UplinkMessage::RegisterSensor { id, name } => {
+                let mut sessions_lock = sessions.lock().await;
+                if let Some(session) = sessions_lock.get_mut(&addr) {
+                    session.sensor_names.insert(id, name.to_string());
+                    Self::save_sessions(shared, &sessions_lock).await;
+                }
+            }
 
-                    let val = match data {
-                        avi_p2p_protocol::SensorValue::Temperature(v) => json!(v),
-                        avi_p2p_protocol::SensorValue::Humidity(v) => json!(v),
-                        avi_p2p_protocol::SensorValue::Battery(v) => json!(v),
-                        avi_p2p_protocol::SensorValue::Status(v) => json!(v),
-                        avi_p2p_protocol::SensorValue::Raw(v) => json!(v),
-                    };
+            UplinkMessage::SensorUpdateById {
+                id,
+                data,
+                custom_data,
+            } => {
+                let sensor_name = match sessions.lock().await.get(&addr) {
+                    Some(session) => {
+                        let name = session.sensor_names.get(&id).cloned();
+                        if name.is_none() {
+                            let err = DownlinkMessage::Error {
+                                reason: ERROR_UNKNOWN_SENSOR,
+                            };
+                            session.send(addr, &err).await;
+                        }
+                        name
+                    }
+                    None => None,
+                };
+                if let (Some(device_id), Some(sensor_name)) = (device_id, sensor_name) {
+                    Self::publish_sensor_update(
+                        handle,
+                        config,
+                        device_id,
+                        &sensor_name,
+                        data,
+                        custom_data,
+                    )
+                    .await;
+                }
+            }
 
-                    let payload = json!({
-                        "name": sensor_name,
-                        "data": {
-                            "value": val,
-                            "unit": match data {
-                                avi_p2p_protocol::SensorValue::Temperature(_) => "C",
-                                avi_p2p_protocol::SensorValue::Humidity(_) => "%",
-                                _ => ""
-                            },
-                            "custom": custom_data.to_string()
-                        },
-                         "ts": std::time::SystemTime::now()
-                           .duration_since(std::time::UNIX_EPOCH)
-                           .unwrap_or_default().as_secs()
-                    });
+            UplinkMessage::PublishWithId {
+                message_id,
+                topic,
+                data,
+            } => {
+                let Some(device_id) = device_id else {
+                    return;
+                };
+                if let Some(window) = config.dedupe_window {
+                    // Also drops copies another of the node's bridges forwarded
+                    let registration = &shared.registration;
+                    if !registration.first_delivery_within(device_id, message_id, window) {
+                        return;
+                    }
+                }
+                if config.loopback {
+                    Self::publish(handle, config, topic, data.to_vec()).await;
+                } else {
+                    // Mesh receivers deduplicate too, e.g. when two gateways hear the device
+                    let mesh_id = format!("{}:{}", device_id, message_id);
+                    let _ = handle.publish_with_id(topic, data.to_vec(), &mesh_id).await;
+                }
+            }
 
-                    let _ = handle
-                        .publish(&topic, serde_json::to_vec(&payload).unwrap())
-                        .await;
+            UplinkMessage::RegisterTopic { topic_id, topic } => {
+                let mut sessions_lock = sessions.lock().await;
+                if let Some(session) = sessions_lock.get_mut(&addr) {
+                    session.publish_topics.insert(topic_id, topic.to_string());
+                    Self::save_sessions(shared, &sessions_lock).await;
+                }
+            }
 
-                    match handle.get_ctx("").await {
-                        Ok(v) => {
-                            let mut current_ctx = v;
-
-                            match set_nested_value(
-                                &mut current_ctx,
-                                &format!("avi.sensors.{}.{}", dev_id, sensor_name),
-                                payload,
-                            ) {
-                                Ok(..) => handle
-                                    .update_context(current_ctx)
-                                    .await
-                                    .unwrap_or_else(|_| println!("Failed to update context")),
-                                Err(e) => eprintln!("Failed to update context: {}", e),
-                            }
+            UplinkMessage::PublishById { topic_id, data } => {
+                let topic = match sessions.lock().await.get(&addr) {
+                    Some(session) => {
+                        let topic = session.publish_topics.get(&topic_id).cloned();
+                        if topic.is_none() {
+                            let err = DownlinkMessage::Error {
+                                reason: ERROR_UNKNOWN_TOPIC,
+                            };
+                            session.send(addr, &err).await;
                         }
-                        Err(e) => eprintln!("Failed to get current context: {}", e),
+                        topic
+                    }
+                    None => None,
+                };
+                if let Some(topic) = topic {
+                    Self::publish(handle, config, &topic, data.to_vec()).await;
+                }
+            }
+
+            UplinkMessage::LinkQuality { rssi, latency_ms } => {
+                if let Some(stats) = &stats {
+                    let mut stats = stats.lock().unwrap();
+                    stats.last_rssi = Some(rssi);
+                    stats.last_latency_ms = Some(latency_ms);
+                }
+            }
+
+            UplinkMessage::DigitalInputs {
+                bitmap,
+                changed_mask,
+            } => {
+                if let Some(dev_id) = device_id {
+                    let ts = std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs();
+
+                    for input in (0..32).filter(|bit| changed_mask & (1 << bit) != 0) {
+                        let topic = config.topics.input(dev_id, input);
+                        let payload = json!({
+                            "input": input,
+                            "state": bitmap & (1 << input) != 0,
+                            "ts": ts
+                        });
+
+                        Self::publish(
+                            handle,
+                            config,
+                            &topic,
+                            serde_json::to_vec(&payload).unwrap(),
+                        )
+                        .await;
                     }
                 }
             }
         }
     }
 
-    async fn handle_downlink_event(
-        event: AviEvent,
-        socket: Arc<UdpSocket>,
-        sessions: Arc<Mutex<HashMap<SocketAddr, DeviceSession>>>,
+    /// Bring back a session saved before a restart: welcome the device
+    /// again, re-offer its topic ids and close the streams that were lost
+    async fn restore_session(
+        shared: &BridgeShared,
+        addr: SocketAddr,
+        socket: &Arc<UdpSocket>,
+        codec: Arc<dyn WireCodec>,
+        stored: StoredSession,
+    ) -> Arc<std::sync::Mutex<DeviceStats>> {
+        let stats = Arc::new(std::sync::Mutex::new(DeviceStats {
+            device_id: stored.device_id,
+            ..Default::default()
+        }));
+        let session = DeviceSession {
+            device_id: stored.device_id,
+            socket: socket.clone(),
+            codec,
+            active_streams: HashMap::new(),
+            stream_buffers: HashMap::new(),
+            loopback_streams: HashSet::new(),
+            subscriptions: stored.subscriptions.into_iter().collect(),
+            topic_ids: stored.topic_ids,
+            acked_topic_ids: HashSet::new(),
+            next_topic_id: stored.next_topic_id,
+            sensor_names: stored.sensor_names,
+            publish_topics: stored.publish_topics,
+            last_seen: Instant::now(),
+            stats: stats.clone(),
+        };
+
+        session.send(addr, &DownlinkMessage::Welcome).await;
+        for (topic, topic_id) in &session.topic_ids {
+            let alias = DownlinkMessage::TopicAlias {
+                topic_id: *topic_id,
+                topic,
+            };
+            session.send(addr, &alias).await;
+        }
+        for local_stream_id in stored.streams {
+            session
+                .send(addr, &DownlinkMessage::StreamClosed { local_stream_id })
+                .await;
+        }
+        println!("✅ Device {} restored from {}", stored.device_id, addr);
+
+        {
+            let mut sessions_lock = shared.sessions.lock().await;
+            sessions_lock.insert(addr, session);
+            shared.registration.set_sessions(sessions_lock.len());
+        }
+        shared.registration.claim_device(stored.device_id);
+        Self::announce_status(&shared.handle, &shared.config, stored.device_id, true).await;
+        shared
+            .handle
+            .emit_event(AviEvent::BridgedDeviceOnline {
+                device_id: stored.device_id,
+                address: addr.to_string(),
+            })
+            .await;
+        stats
+    }
+
+    /// Hand live sessions, and saved ones not yet restored, to the store
+    /// writer; the file itself is written later, off the sessions lock
+    async fn save_sessions(shared: &BridgeShared, sessions: &HashMap<SocketAddr, DeviceSession>) {
+        if shared.session_saves.lock().unwrap().is_none() {
+            return;
+        }
+        let stored = shared.stored.lock().await;
+        let snapshot: Vec<StoredSession> = sessions
+            .iter()
+            .map(|(addr, session)| session.to_stored(*addr))
+            .chain(stored.values().cloned())
+            .collect();
+        drop(stored);
+        if let Some(saves) = shared.session_saves.lock().unwrap().as_ref() {
+            saves.send_replace(snapshot);
+        }
+    }
+
+    /// Subscribe on the mesh for a device, acknowledge it and offer a
+    /// compact topic id for the forwarded messages
+    async fn subscribe_device(shared: &BridgeShared, addr: SocketAddr, topic: &str) {
+        if shared.handle.subscribe(topic).await.is_err() {
+            return;
+        }
+
+        let mut sessions_lock = shared.sessions.lock().await;
+        let Some(session) = sessions_lock.get_mut(&addr) else {
+            return;
+        };
+        session.subscriptions.insert(topic.to_string());
+
+        session
+            .send(addr, &DownlinkMessage::SubscribeAck { topic })
+            .await;
+
+        let topic_id = match session.topic_ids.get(topic) {
+            Some(id) => *id,
+            None => {
+                let id = session.next_topic_id;
+                session.next_topic_id = session.next_topic_id.wrapping_add(1);
+                session.topic_ids.insert(topic.to_string(), id);
+                id
+            }
+        };
+        session
+            .send(addr, &DownlinkMessage::TopicAlias { topic_id, topic })
+            .await;
+        Self::save_sessions(shared, &sessions_lock).await;
+    }
+
+    async fn watch_commands(
+        handle: &AviP2pHandle,
+        commands: &Mutex<CommandQueues>,
+        config: &BridgeConfig,
+        device_id: u64,
     ) {
-        if let AviEvent::Message { topic, data, .. } = event {
-            let sessions_lock = sessions.lock().await;
-
-            // Send to all devices subscribed to this topic
-            for (addr, session) in sessions_lock.iter() {
-                if session.subscriptions.contains(&topic) {
-                    let msg = DownlinkMessage::Message {
-                        topic: &topic,
+        let topic = config.topics.command(device_id);
+        if commands.lock().await.watch(topic.clone(), device_id) {
+            let _ = handle.subscribe(&topic).await;
+        }
+    }
+
+    async fn deliver_commands(
+        session: &DeviceSession,
+        addr: SocketAddr,
+        commands: Vec<QueuedCommand>,
+    ) {
+        for command in commands {
+            let msg = DownlinkMessage::Message {
+                topic: &command.topic,
+                data: &command.data,
+            };
+            session.send(addr, &msg).await;
+        }
+    }
+
+    async fn expire_sessions(shared: &BridgeShared) {
+        let handle = &shared.handle;
+        let config = &shared.config;
+
+        // Saved devices that never came back after a restart
+        let forgotten: Vec<StoredSession> = if Instant::now() >= shared.stored_until {
+            shared.stored.lock().await.drain().map(|(_, s)| s).collect()
+        } else {
+            vec![]
+        };
+
+        let expired: Vec<DeviceSession> = {
+            let mut sessions_lock = shared.sessions.lock().await;
+            let stale: Vec<SocketAddr> = sessions_lock
+                .iter()
+                .filter(|(_, session)| session.last_seen.elapsed() >= config.session_timeout)
+                .map(|(addr, _)| *addr)
+                .collect();
+            let expired: Vec<DeviceSession> = stale
+                .iter()
+                .filter_map(|addr| sessions_lock.remove(addr))
+                .collect();
+            shared.registration.set_sessions(sessions_lock.len());
+            if !expired.is_empty() || !forgotten.is_empty() {
+                Self::save_sessions(shared, &sessions_lock).await;
+            }
+            expired
+        };
+
+        let bridges = handle.bridges();
+        for stored in forgotten {
+            // Back through another bridge, so not offline
+            if bridges.device_bridge(stored.device_id).is_some() {
+                continue;
+            }
+            println!(
+                "⌛ Device {} did not return after restart",
+                stored.device_id
+            );
+            Self::announce_status(handle, config, stored.device_id, false).await;
+            handle
+                .emit_event(AviEvent::BridgedDeviceOffline {
+                    device_id: stored.device_id,
+                })
+                .await;
+        }
+        for session in expired {
+            println!("⌛ Device {} timed out", session.device_id);
+            Self::retire_session(shared, session).await;
+        }
+    }
+
+    /// Close what a removed session held open and announce the device
+    /// offline, unless it has since attached through another bridge
+    async fn retire_session(shared: &BridgeShared, session: DeviceSession) {
+        let handle = &shared.handle;
+        let config = &shared.config;
+        for mesh_id in session.active_streams.values() {
+            let _ = handle.close_stream(*mesh_id).await;
+        }
+        if !shared.registration.release_device(session.device_id) {
+            return;
+        }
+        Self::announce_status(handle, config, session.device_id, false).await;
+        handle
+            .emit_event(AviEvent::BridgedDeviceOffline {
+                device_id: session.device_id,
+            })
+            .await;
+    }
+
+    async fn announce_status(
+        handle: &AviP2pHandle,
+        config: &BridgeConfig,
+        device_id: u64,
+        online: bool,
+    ) {
+        let payload = json!({
+            "status": if online { "online" } else { "offline" },
+            "ts": std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        });
+        Self::publish(
+            handle,
+            config,
+            &config.topics.status(device_id),
+            serde_json::to_vec(&payload).unwrap(),
+        )
+        .await;
+    }
+
+    /// Publish on the mesh for a device; loopback mode only logs it
+    async fn publish(handle: &AviP2pHandle, config: &BridgeConfig, topic: &str, data: Vec<u8>) {
+        if config.loopback {
+            println!("🔁 {} <- {}", topic, String::from_utf8_lossy(&data));
+            return;
+        }
+        let _ = handle.publish(topic, data).await;
+    }
+
+    async fn publish_sensor_update(
+        handle: &AviP2pHandle,
+        config: &BridgeConfig,
+        dev_id: u64,
+        sensor_name: &str,
+        data: SensorValue,
+        custom_data: &str,
+    ) {
+        let topic = config.topics.sensor(dev_id, sensor_name);
+
+        let val = match data {
+            avi_p2p_protocol::SensorValue::Temperature(v) => json!(v),
+            avi_p2p_protocol::SensorValue::Humidity(v) => json!(v),
+            avi_p2p_protocol::SensorValue::Battery(v) => json!(v),
+            avi_p2p_protocol::SensorValue::Status(v) => json!(v),
+            avi_p2p_protocol::SensorValue::Raw(v) => json!(v),
+        };
+
+        let payload = json!({
+            "name": sensor_name,
+            "data": {
+                "value": val,
+                "unit": match data {
+                    avi_p2p_protocol::SensorValue::Temperature(_) => "C",
+                    avi_p2p_protocol::SensorValue::Humidity(_) => "%",
+                    _ => ""
+                },
+                "custom": custom_data.to_string()
+            },
+             "ts": std::time::SystemTime::now()
+               .duration_since(std::time::UNIX_EPOCH)
+               .unwrap_or_default().as_secs()
+        });
+
+        Self::publish(
+            handle,
+            config,
+            &topic,
+            serde_json::to_vec(&payload).unwrap(),
+        )
+        .await;
+
+        let Some(mirror) = &config.context_mirror else {
+            return;
+        };
+
+        match sensor_context_patch(mirror, dev_id, sensor_name, payload) {
+            Ok(patch) => handle
+                .update_context(patch)
+                .await
+                .unwrap_or_else(|_| println!("Failed to update context")),
+            Err(e) => eprintln!("Failed to update context: {}", e),
+        }
+    }
+
+    async fn handle_downlink_event(event: AviEvent, shared: &BridgeShared) {
+        let sessions = &shared.sessions;
+        let commands = &shared.commands;
+        let config = &shared.config;
+        match event {
+            AviEvent::Message { topic, data, .. } => {
+                let sessions_lock = sessions.lock().await;
+
+                // Commands go to their one device, now if it is awake,
+                // otherwise on its next uplink
+                let target = commands.lock().await.device_for(&topic);
+                if let Some(device_id) = target {
+                    let awake = sessions_lock
+                        .iter()
+                        .find(|(_, session)| session.device_id == device_id)
+                        .filter(|_| !config.sleepy_devices.contains(&device_id));
+                    match awake {
+                        Some((addr, session)) => {
+                            let msg = DownlinkMessage::Message {
+                                topic: &topic,
+                                data: &data,
+                            };
+                            session.send(*addr, &msg).await;
+                        }
+                        None => commands.lock().await.push(device_id, topic, data),
+                    }
+                    return;
+                }
+
+                // Send to all devices subscribed to this topic
+                for (addr, session) in sessions_lock.iter() {
+                    if session.subscriptions.contains(&topic) {
+                        // Until the device confirms the alias, send the full topic
+                        let msg = match session.topic_ids.get(&topic) {
+                            Some(topic_id) if session.acked_topic_ids.contains(topic_id) => {
+                                DownlinkMessage::AliasedMessage {
+                                    topic_id: *topic_id,
+                                    data: &data,
+                                }
+                            }
+                            _ => DownlinkMessage::Message {
+                                topic: &topic,
+                                data: &data,
+                            },
+                        };
+                        session.send(*addr, &msg).await;
+                    }
+                }
+            }
+            AviEvent::StreamData {
+                stream_id, data, ..
+            } => {
+                let sessions_lock = sessions.lock().await;
+
+                if let Some((addr, session, local_stream_id)) =
+                    Self::find_local_stream(&sessions_lock, stream_id)
+                {
+                    let msg = DownlinkMessage::StreamData {
+                        local_stream_id,
                         data: &data,
                     };
+                    session.send(addr, &msg).await;
+                }
+            }
+            // The device only knows open streams, so an unanswered open
+            // is reported as the stream closing
+            AviEvent::StreamClosed { stream_id, .. }
+            | AviEvent::StreamOpenTimeout { stream_id, .. } => {
+                let mut sessions_lock = sessions.lock().await;
 
-                    let mut tx_buf = [0u8; MAX_PACKET_SIZE];
-                    if let Ok(encoded) = postcard::to_slice(&msg, &mut tx_buf) {
-                        let _ = socket.send_to(encoded, addr).await;
+                let found = Self::find_local_stream(&sessions_lock, stream_id)
+                    .map(|(addr, _, local_stream_id)| (addr, local_stream_id));
+                if let Some((addr, local_stream_id)) = found {
+                    if let Some(session) = sessions_lock.get_mut(&addr) {
+                        session.active_streams.remove(&local_stream_id);
+                        session.stream_buffers.remove(&local_stream_id);
+                        let msg = DownlinkMessage::StreamClosed { local_stream_id };
+                        session.send(addr, &msg).await;
                     }
+                    Self::save_sessions(shared, &sessions_lock).await;
                 }
             }
+            _ => {}
+        }
+    }
+
+    /// Device address, session and local id of a bridged mesh stream
+    fn find_local_stream(
+        sessions: &HashMap<SocketAddr, DeviceSession>,
+        stream_id: StreamId,
+    ) -> Option<(SocketAddr, &DeviceSession, u8)> {
+        sessions.iter().find_map(|(addr, session)| {
+            session
+                .active_streams
+                .iter()
+                .find(|(_, mesh_id)| **mesh_id == stream_id)
+                .map(|(local_id, _)| (*addr, session, *local_id))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_queue_drops_oldest_and_expired() {
+        let mut queues = CommandQueues::new(Duration::from_secs(60), 2);
+        assert!(queues.watch("device/1/command".to_string(), 1));
+        assert!(!queues.watch("device/1/command".to_string(), 1));
+        assert_eq!(queues.device_for("device/1/command"), Some(1));
+
+        for data in [b"a", b"b", b"c"] {
+            queues.push(1, "device/1/command".to_string(), data.to_vec());
+        }
+        let taken: Vec<Vec<u8>> = queues.take(1).into_iter().map(|c| c.data).collect();
+        assert_eq!(taken, vec![b"b".to_vec(), b"c".to_vec()]);
+        assert!(queues.take(1).is_empty());
+
+        let mut expiring = CommandQueues::new(Duration::ZERO, 4);
+        expiring.push(2, "device/2/command".to_string(), b"late".to_vec());
+        assert!(expiring.take(2).is_empty());
+    }
+
+    #[test]
+    fn test_bridged_stream_reason_keeps_device_reason() {
+        let reason = bridged_stream_reason("audio;codec=opus", 42);
+        assert_eq!(reason, "audio;codec=opus;bridged_device=42");
+        assert_eq!(reason.split(';').next(), Some("audio"));
+        assert_eq!(bridged_stream_device(&reason), Some(42));
+        assert_eq!(bridged_stream_device("audio;codec=opus"), None);
+    }
+
+    #[test]
+    fn test_stream_buffer_throttles_once_then_drops_past_its_limit() {
+        let config = BridgeConfig {
+            stream_buffer_limit: 100,
+            stream_high_watermark: 50,
+            ..Default::default()
+        };
+        let (chunks, _pending) = mpsc::unbounded_channel();
+        let buffer = StreamBuffer {
+            chunks,
+            queued: Arc::new(AtomicUsize::new(0)),
+            throttled: Arc::new(AtomicBool::new(false)),
+        };
+
+        assert_eq!(buffer.push(vec![0; 30], &config), BufferedChunk::Queued);
+        assert_eq!(buffer.push(vec![0; 30], &config), BufferedChunk::Throttle);
+        assert_eq!(buffer.push(vec![0; 30], &config), BufferedChunk::Queued);
+        assert_eq!(buffer.push(vec![0; 30], &config), BufferedChunk::Dropped);
+        assert_eq!(buffer.push(vec![0; 10], &config), BufferedChunk::Queued);
+        assert_eq!(buffer.queued.load(Ordering::Acquire), 100);
+    }
+
+    #[test]
+    fn test_capability_targets_prefer_idle_connected_peers() {
+        let caps = json!({
+            "busy": {
+                "extended": { "voice-processor": { "Boolean": true } },
+                "health": { "cpu_load_pct": 90, "network_stability_pct": 99 }
+            },
+            "idle": {
+                "extended": { "voice-processor": { "Boolean": true } },
+                "health": { "cpu_load_pct": 10, "network_stability_pct": 80 }
+            },
+            "away": {
+                "extended": { "voice-processor": { "Boolean": true } },
+                "health": { "cpu_load_pct": 0, "network_stability_pct": 100 }
+            },
+            "off": { "extended": { "voice-processor": { "Boolean": false } } },
+            "speaker": { "audio": { "present": true } }
+        });
+        let connected = ["busy", "idle", "off", "speaker"].map(PeerId::new);
+
+        assert_eq!(
+            resolve_capability(&caps, "voice-processor", &connected),
+            Some(PeerId::new("idle"))
+        );
+        assert_eq!(
+            resolve_capability(&caps, "audio", &connected),
+            Some(PeerId::new("speaker"))
+        );
+        assert_eq!(resolve_capability(&caps, "display", &connected), None);
+    }
+
+    #[test]
+    fn test_session_store_skips_torn_lines() {
+        let path = std::env::temp_dir().join(format!("avi-bridge-{}.jsonl", rand::random::<u64>()));
+        let stored = StoredSession {
+            addr: "192.168.1.20:4000".parse().unwrap(),
+            device_id: 9,
+            codec: crate::codec::CODEC_POSTCARD,
+            subscriptions: vec!["lights".to_string()],
+            topic_ids: HashMap::from([("lights".to_string(), 0)]),
+            next_topic_id: 1,
+            sensor_names: HashMap::from([(1, "temp".to_string())]),
+            publish_topics: HashMap::from([(2, "device/9/log".to_string())]),
+            streams: vec![3],
+        };
+        let line = serde_json::to_string(&stored).unwrap();
+        std::fs::write(&path, format!("{}\n{{\"addr\":\"192.1", line)).unwrap();
+
+        let loaded = load_sessions(Some(&path));
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded.get(&stored.addr), Some(&stored));
+        assert!(load_sessions(None).is_empty());
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_session_saves_are_batched_and_replace_the_store() {
+        let path = std::env::temp_dir().join(format!("avi-bridge-{}.jsonl", rand::random::<u64>()));
+        std::fs::write(&path, "previous store\n").unwrap();
+        let session = |device_id: u64| StoredSession {
+            addr: format!("192.168.1.{}:4000", device_id).parse().unwrap(),
+            device_id,
+            codec: crate::codec::CODEC_POSTCARD,
+            subscriptions: vec![],
+            topic_ids: HashMap::new(),
+            next_topic_id: 0,
+            sensor_names: HashMap::new(),
+            publish_topics: HashMap::new(),
+            streams: vec![],
+        };
+
+        let (saves, snapshots) = watch::channel(Vec::new());
+        let writer = tokio::spawn(save_sessions_task(path.clone(), snapshots));
+        saves.send_replace(vec![session(1)]);
+        saves.send_replace(vec![session(1), session(2)]);
+
+        // Nothing is written until the changes have settled
+        tokio::time::sleep(SESSION_SAVE_DELAY / 2).await;
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "previous store\n");
+
+        // The last snapshot is written once the sender is gone
+        saves.send_replace(vec![session(3)]);
+        drop(saves);
+        writer.await.unwrap();
+
+        let loaded = load_sessions(Some(&path));
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded.values().next().map(|s| s.device_id), Some(3));
+        assert!(!path.with_extension("jsonl.tmp").exists());
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_sensor_mirror_patches_only_the_reading() {
+        let reading = json!({ "value": 21.5, "ts": 1700000000 });
+        let mirror = BridgeConfig::default().context_mirror.unwrap();
+        assert_eq!(
+            sensor_context_patch(&mirror, 7, "temp", reading.clone()).unwrap(),
+            json!({ "avi": { "sensors": { "7": { "temp": reading.clone() } } } })
+        );
+        assert_eq!(
+            sensor_context_patch("bridged_devices", 7, "temp", reading.clone()).unwrap(),
+            json!({ "bridged_devices": { "7": { "temp": reading } } })
+        );
+    }
+
+    #[test]
+    fn test_template_topic_mapper_places_devices_in_rooms() {
+        let default = TemplateTopicMapper::default();
+        assert_eq!(default.sensor(7, "temp"), "device/7/sensor/temp");
+        let device = Topic::device(7);
+        assert_eq!(default.button(7), device.button().as_str());
+        assert_eq!(default.sensor(7, "temp"), device.sensor("temp").as_str());
+        assert_eq!(default.input(7, 2), device.input(2).as_str());
+        assert_eq!(default.status(7), device.status().as_str());
+        assert_eq!(default.command(7), device.command().as_str());
+
+        let mapper = TemplateTopicMapper {
+            sensor: "home/{room}/{sensor}/{device}".to_string(),
+            input: "home/{room}/input/{input}".to_string(),
+            ..Default::default()
         }
+        .with_room(7, "kitchen");
+        assert_eq!(mapper.sensor(7, "temp"), "home/kitchen/temp/7");
+        assert_eq!(mapper.input(8, 3), "home/unassigned/input/3");
     }
 }
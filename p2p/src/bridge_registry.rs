@@ -0,0 +1,221 @@
+//! Bridges sharing one node.
+//!
+//! A gateway may front several device radios at once (UDP on a few ports,
+//! serial, BLE), each through its own bridge on the same `AviP2pHandle`.
+//! Every bridge registers under its own namespace, while device ids are one
+//! space across them: a device is attached to a single bridge at a time and
+//! a message id forwarded through one bridge is a duplicate on the others.
+//!
+//! `EmbeddedBridge` registers itself; other transports do the same through
+//! `AviP2pHandle::bridges`:
+//!
+//! ```ignore
+//! let serial = handle.bridges().register("serial")?;
+//! if serial.first_delivery(device_id, message_id) { /* publish */ }
+//! ```
+
+use crate::dedupe::DedupeCache;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How long message ids are remembered by bridges registered without a
+/// window of their own
+const DEFAULT_DEDUPE_WINDOW: Duration = Duration::from_secs(30);
+
+struct RegisteredBridge {
+    /// Tells a registration apart from a later one under the same namespace
+    id: u64,
+    sessions: usize,
+}
+
+#[derive(Default)]
+struct RegistryState {
+    next_id: u64,
+    bridges: HashMap<String, RegisteredBridge>,
+    /// Device id -> namespace of the bridge it is attached through
+    devices: HashMap<u64, String>,
+    /// Recently forwarded (device, message id) pairs, across all bridges
+    dedupe: Option<DedupeCache<(u64, u32)>>,
+}
+
+/// The bridges running on a node, from `AviP2pHandle::bridges`
+#[derive(Clone, Default)]
+pub struct BridgeRegistry {
+    state: Arc<Mutex<RegistryState>>,
+}
+
+impl BridgeRegistry {
+    /// Claim `namespace` for a bridge; fails if another bridge holds it
+    pub fn register(&self, namespace: &str) -> Result<BridgeRegistration, String> {
+        let mut state = self.state.lock().unwrap();
+        if state.bridges.contains_key(namespace) {
+            return Err(format!(
+                "Bridge namespace '{}' is already in use",
+                namespace
+            ));
+        }
+        state.next_id += 1;
+        let id = state.next_id;
+        state
+            .bridges
+            .insert(namespace.to_string(), RegisteredBridge { id, sessions: 0 });
+        Ok(BridgeRegistration {
+            id,
+            namespace: namespace.to_string(),
+            registry: self.clone(),
+        })
+    }
+
+    /// Namespaces of the registered bridges
+    pub fn namespaces(&self) -> Vec<String> {
+        self.state.lock().unwrap().bridges.keys().cloned().collect()
+    }
+
+    /// Namespace of the bridge `device_id` is attached through
+    pub fn device_bridge(&self, device_id: u64) -> Option<String> {
+        self.state.lock().unwrap().devices.get(&device_id).cloned()
+    }
+
+    /// Live sessions summed over all bridges
+    pub fn sessions(&self) -> usize {
+        self.state
+            .lock()
+            .unwrap()
+            .bridges
+            .values()
+            .map(|bridge| bridge.sessions)
+            .sum()
+    }
+}
+
+/// One bridge's place in the `BridgeRegistry`. Dropping it unregisters the
+/// bridge and releases the devices attached through it.
+pub struct BridgeRegistration {
+    id: u64,
+    namespace: String,
+    registry: BridgeRegistry,
+}
+
+impl BridgeRegistration {
+    pub fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    fn active(&self, state: &RegistryState) -> bool {
+        state
+            .bridges
+            .get(&self.namespace)
+            .is_some_and(|bridge| bridge.id == self.id)
+    }
+
+    /// Attach `device_id` to this bridge. Returns the namespace of the
+    /// bridge it was attached through before, if it moved.
+    pub fn claim_device(&self, device_id: u64) -> Option<String> {
+        let mut state = self.registry.state.lock().unwrap();
+        if !self.active(&state) {
+            return None;
+        }
+        state
+            .devices
+            .insert(device_id, self.namespace.clone())
+            .filter(|previous| *previous != self.namespace)
+    }
+
+    /// Detach `device_id`; false if it has since moved to another bridge
+    /// (or was never attached here), in which case it is still online
+    pub fn release_device(&self, device_id: u64) -> bool {
+        let mut state = self.registry.state.lock().unwrap();
+        if !self.active(&state) || state.devices.get(&device_id) != Some(&self.namespace) {
+            return false;
+        }
+        state.devices.remove(&device_id);
+        true
+    }
+
+    /// Record this bridge's live session count for the health report
+    pub fn set_sessions(&self, count: usize) {
+        let mut state = self.registry.state.lock().unwrap();
+        if let Some(bridge) = state.bridges.get_mut(&self.namespace) {
+            if bridge.id == self.id {
+                bridge.sessions = count;
+            }
+        }
+    }
+
+    /// Whether a device's message id is new to every bridge; records it.
+    /// The window is set by the first bridge to ask.
+    pub fn first_delivery(&self, device_id: u64, message_id: u32) -> bool {
+        self.first_delivery_within(device_id, message_id, DEFAULT_DEDUPE_WINDOW)
+    }
+
+    pub(crate) fn first_delivery_within(
+        &self,
+        device_id: u64,
+        message_id: u32,
+        window: Duration,
+    ) -> bool {
+        let mut state = self.registry.state.lock().unwrap();
+        state
+            .dedupe
+            .get_or_insert_with(|| DedupeCache::new(window))
+            .insert((device_id, message_id))
+    }
+
+    /// Leave the registry, freeing the namespace; done on drop as well
+    pub fn unregister(&self) {
+        let mut state = self.registry.state.lock().unwrap();
+        if !self.active(&state) {
+            return;
+        }
+        state.bridges.remove(&self.namespace);
+        state
+            .devices
+            .retain(|_, namespace| *namespace != self.namespace);
+    }
+}
+
+impl Drop for BridgeRegistration {
+    fn drop(&mut self) {
+        self.unregister();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bridges_share_device_ids_and_dedupe() {
+        let registry = BridgeRegistry::default();
+        let udp = registry.register("udp").unwrap();
+        let serial = registry.register("serial").unwrap();
+        assert!(registry.register("udp").is_err());
+
+        udp.set_sessions(2);
+        serial.set_sessions(1);
+        assert_eq!(registry.sessions(), 3);
+
+        // A device that moves radios is only released by its current bridge
+        assert_eq!(udp.claim_device(7), None);
+        assert_eq!(serial.claim_device(7), Some("udp".to_string()));
+        assert!(!udp.release_device(7));
+        assert_eq!(registry.device_bridge(7).as_deref(), Some("serial"));
+
+        // The same message heard on both radios is forwarded once
+        assert!(udp.first_delivery(7, 1));
+        assert!(!serial.first_delivery(7, 1));
+
+        serial.unregister();
+        assert_eq!(registry.device_bridge(7), None);
+        assert_eq!(registry.sessions(), 2);
+
+        // A stale registration cannot touch its namespace's next owner
+        let replacement = registry.register("serial").unwrap();
+        replacement.claim_device(7);
+        assert_eq!(serial.claim_device(8), None);
+        drop(serial);
+        assert_eq!(registry.device_bridge(7).as_deref(), Some("serial"));
+        assert_eq!(registry.device_bridge(8), None);
+    }
+}
@@ -0,0 +1,100 @@
+//! Wire encodings for the embedded bridge protocol.
+//!
+//! Devices speak postcard unless their `HelloCodec` asks for another codec
+//! the bridge has. Codecs are tried in `BridgeConfig::codecs` order on
+//! packets from unknown addresses, so postcard, which rejects CBOR input,
+//! must stay first.
+
+use avi_p2p_protocol::{DownlinkMessage, UplinkMessage};
+pub use avi_p2p_protocol::{CODEC_CBOR, CODEC_POSTCARD};
+
+pub trait WireCodec: Send + Sync {
+    /// Byte a device puts in `HelloCodec` to select this codec
+    fn id(&self) -> u8;
+
+    fn decode<'a>(&self, data: &'a [u8]) -> Option<UplinkMessage<'a>>;
+
+    /// Encode into `buf`, returning the used part
+    fn encode<'b>(&self, msg: &DownlinkMessage<'_>, buf: &'b mut [u8]) -> Option<&'b [u8]>;
+}
+
+/// The protocol's native encoding
+pub struct PostcardCodec;
+
+impl WireCodec for PostcardCodec {
+    fn id(&self) -> u8 {
+        CODEC_POSTCARD
+    }
+
+    fn decode<'a>(&self, data: &'a [u8]) -> Option<UplinkMessage<'a>> {
+        postcard::from_bytes(data).ok()
+    }
+
+    fn encode<'b>(&self, msg: &DownlinkMessage<'_>, buf: &'b mut [u8]) -> Option<&'b [u8]> {
+        postcard::to_slice(msg, buf).ok().map(|used| &*used)
+    }
+}
+
+/// CBOR with serde's externally tagged enums, for firmware that cannot emit postcard
+#[cfg(feature = "cbor")]
+pub struct CborCodec;
+
+#[cfg(feature = "cbor")]
+impl WireCodec for CborCodec {
+    fn id(&self) -> u8 {
+        CODEC_CBOR
+    }
+
+    fn decode<'a>(&self, data: &'a [u8]) -> Option<UplinkMessage<'a>> {
+        serde_cbor::from_slice(data).ok()
+    }
+
+    fn encode<'b>(&self, msg: &DownlinkMessage<'_>, buf: &'b mut [u8]) -> Option<&'b [u8]> {
+        use serde::Serialize;
+
+        let mut serializer = serde_cbor::Serializer::new(serde_cbor::ser::SliceWrite::new(buf));
+        msg.serialize(&mut serializer).ok()?;
+        let written = serializer.into_inner().bytes_written();
+        Some(&buf[..written])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_postcard_decodes_hello_codec() {
+        let mut buf = [0u8; 64];
+        let hello = UplinkMessage::HelloCodec {
+            device_id: 7,
+            codec: CODEC_POSTCARD,
+        };
+        let data = postcard::to_slice(&hello, &mut buf).unwrap();
+        assert!(matches!(
+            PostcardCodec.decode(data),
+            Some(UplinkMessage::HelloCodec { device_id: 7, .. })
+        ));
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn test_cbor_is_not_mistaken_for_postcard() {
+        let hello = UplinkMessage::HelloCodec {
+            device_id: 7,
+            codec: CODEC_CBOR,
+        };
+        let data = serde_cbor::to_vec(&hello).unwrap();
+        assert!(PostcardCodec.decode(&data).is_none());
+        assert!(matches!(
+            CborCodec.decode(&data),
+            Some(UplinkMessage::HelloCodec { device_id: 7, .. })
+        ));
+
+        let mut buf = [0u8; 64];
+        let ack = CborCodec
+            .encode(&DownlinkMessage::SubscribeAck { topic: "lights" }, &mut buf)
+            .unwrap();
+        assert!(!ack.is_empty());
+    }
+}
@@ -1,8 +1,23 @@
+use crate::audit::{AuditEntry, AuditQuery};
+use crate::auth::MembershipCertificate;
 use crate::error::AviP2pError;
-use crate::events::PeerId;
+use crate::events::{AviEvent, CorrelationId, PeerId, PeerInfo};
+use crate::health::{ChannelUsage, HealthReport, RuntimeStats};
+use crate::invitation::{MeshDetails, MeshInvitation};
+use crate::keys::EncryptedPayload;
+use crate::pairing::PairingPayload;
+use crate::protocols::context::ContextOp;
+use crate::protocols::crdt::CollectionKind;
+use crate::snapshot::NodeSnapshot;
+use crate::view::ContextView;
 use crate::StreamId;
 use serde_json::Value;
-use tokio::sync::oneshot;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+
+/// A banned peer and the time left on its ban (None = indefinite)
+type BannedPeer = (PeerId, Option<Duration>);
 
 #[derive(Debug)]
 pub enum Command {
@@ -15,19 +30,56 @@ pub enum Command {
         topic: String,
         respond_to: oneshot::Sender<Result<(), AviP2pError>>,
     },
+    GetSubscriptions {
+        respond_to: oneshot::Sender<Result<Vec<String>, AviP2pError>>,
+    },
+    UnsubscribeAll {
+        respond_to: oneshot::Sender<Result<(), AviP2pError>>,
+    },
     Publish {
         topic: String,
         data: Vec<u8>,
+        /// Application id receivers deduplicate on
+        message_id: Option<String>,
+        respond_to: oneshot::Sender<Result<(), AviP2pError>>,
+    },
+    /// Several publishes in one hop; one result per message, in order
+    PublishBatch {
+        messages: Vec<(String, Vec<u8>)>,
+        respond_to: oneshot::Sender<Vec<Result<(), AviP2pError>>>,
+    },
+
+    /// Several publishes sent all together or not at all
+    PublishAtomic {
+        messages: Vec<(String, Vec<u8>)>,
+        respond_to: oneshot::Sender<Result<(), AviP2pError>>,
+    },
+    /// Publish once `delay` has passed; the runtime holds the message
+    SchedulePublish {
+        topic: String,
+        data: Vec<u8>,
+        delay: Duration,
+        correlation_id: CorrelationId,
         respond_to: oneshot::Sender<Result<(), AviP2pError>>,
     },
+    /// Drop a scheduled publish; false if it already went out
+    CancelScheduledPublish {
+        correlation_id: CorrelationId,
+        respond_to: oneshot::Sender<bool>,
+    },
 
     RequestStream {
         peer_id: PeerId,
         reason: String,
+        /// Carried on the events the open ends with
+        correlation_id: CorrelationId,
         respond_to: oneshot::Sender<Result<StreamId, AviP2pError>>,
+        /// Answered once the peer accepts, rejects or the open times out
+        on_open: Option<oneshot::Sender<Result<StreamId, AviP2pError>>>,
     },
     AcceptStream {
         stream_id: StreamId,
+        payload: Option<Vec<u8>>,
         respond_to: oneshot::Sender<Result<(), AviP2pError>>,
     },
     RejectStream {
@@ -44,6 +96,15 @@ pub enum Command {
         stream_id: StreamId,
         respond_to: oneshot::Sender<Result<(), AviP2pError>>,
     },
+    GetStreamVersion {
+        stream_id: StreamId,
+        respond_to: oneshot::Sender<Result<u32, AviP2pError>>,
+    },
+    RecordStream {
+        stream_id: StreamId,
+        path: PathBuf,
+        respond_to: oneshot::Sender<Result<(), AviP2pError>>,
+    },
 
     // Queries
     GetConnectedPeers {
@@ -52,6 +113,58 @@ pub enum Command {
     DiscoverPeers {
         respond_to: oneshot::Sender<Result<(), AviP2pError>>,
     },
+    ExtensionRequest {
+        peer_id: PeerId,
+        name: String,
+        data: Vec<u8>,
+        respond_to: oneshot::Sender<Result<Vec<u8>, AviP2pError>>,
+    },
+    RendezvousDiscover {
+        namespace: String,
+        respond_to: oneshot::Sender<Result<Vec<PeerId>, AviP2pError>>,
+    },
+
+    // DHT
+    PutRecord {
+        key: String,
+        value: Vec<u8>,
+        respond_to: oneshot::Sender<Result<(), AviP2pError>>,
+    },
+    RemoveRecord {
+        key: String,
+        respond_to: oneshot::Sender<Result<(), AviP2pError>>,
+    },
+    StartProviding {
+        key: String,
+        respond_to: oneshot::Sender<Result<(), AviP2pError>>,
+    },
+    StopProviding {
+        key: String,
+        respond_to: oneshot::Sender<Result<(), AviP2pError>>,
+    },
+    GetAuthenticatedPeers {
+        respond_to: oneshot::Sender<Result<Vec<PeerId>, AviP2pError>>,
+    },
+    GetPeerInfo {
+        peer_id: PeerId,
+        respond_to: oneshot::Sender<Result<PeerInfo, AviP2pError>>,
+    },
+    GetHealth {
+        respond_to: oneshot::Sender<Result<HealthReport, AviP2pError>>,
+    },
+    GetRuntimeStats {
+        respond_to: oneshot::Sender<Result<RuntimeStats, AviP2pError>>,
+    },
+    QueryAudit {
+        query: AuditQuery,
+        respond_to: oneshot::Sender<Result<Vec<AuditEntry>, AviP2pError>>,
+    },
+    VerifyAudit {
+        respond_to: oneshot::Sender<Result<(), AviP2pError>>,
+    },
+
+    /// Deliver an event produced outside the runtime (e.g. by the bridge)
+    EmitEvent { event: AviEvent },
 
     // Lifecycle
     #[allow(dead_code)]
@@ -59,8 +172,85 @@ pub enum Command {
         respond_to: oneshot::Sender<Result<(), AviP2pError>>,
     },
 
+    SendTo {
+        peer_id: PeerId,
+        data: Vec<u8>,
+        respond_to: oneshot::Sender<Result<(), AviP2pError>>,
+    },
+    SendViaOutbox {
+        peer_id: PeerId,
+        data: Vec<u8>,
+        ttl: Option<Duration>,
+        respond_to: oneshot::Sender<Result<(), AviP2pError>>,
+    },
+
+    Pause {
+        respond_to: oneshot::Sender<Result<(), AviP2pError>>,
+    },
+    Resume {
+        respond_to: oneshot::Sender<Result<(), AviP2pError>>,
+    },
+
+    SetMembershipCertificate {
+        certificate: MembershipCertificate,
+        respond_to: oneshot::Sender<Result<(), AviP2pError>>,
+    },
+
+    RotateKey {
+        scope: String,
+        respond_to: oneshot::Sender<Result<u32, AviP2pError>>,
+    },
+    PublishEncrypted {
+        topic: String,
+        data: Vec<u8>,
+        respond_to: oneshot::Sender<Result<(), AviP2pError>>,
+    },
+    Encrypt {
+        scope: String,
+        data: Vec<u8>,
+        respond_to: oneshot::Sender<Result<EncryptedPayload, AviP2pError>>,
+    },
+    Decrypt {
+        payload: EncryptedPayload,
+        respond_to: oneshot::Sender<Result<Vec<u8>, AviP2pError>>,
+    },
+
     UpdateSelfContext {
         patch: Value, // JSON partial update
+        ttl: Option<Duration>,
+        respond_to: oneshot::Sender<Result<(), AviP2pError>>,
+    },
+
+    /// Apply `patch` only if the value at `path` equals `expected`
+    UpdateSelfContextIf {
+        path: String,
+        expected: Value,
+        patch: Value,
+        respond_to: oneshot::Sender<Result<bool, AviP2pError>>,
+    },
+
+    IncrementContextCounter {
+        path: String,
+        delta: i64,
+        respond_to: oneshot::Sender<Result<i64, AviP2pError>>,
+    },
+
+    AddToContextCollection {
+        path: String,
+        kind: CollectionKind,
+        value: Value,
+        respond_to: oneshot::Sender<Result<(), AviP2pError>>,
+    },
+
+    RemoveFromContextCollection {
+        path: String,
+        value: Value,
+        respond_to: oneshot::Sender<Result<bool, AviP2pError>>,
+    },
+
+    /// Apply a transaction's ops as one update
+    ApplyContextOps {
+        ops: Vec<ContextOp>,
         respond_to: oneshot::Sender<Result<(), AviP2pError>>,
     },
 
@@ -70,8 +260,229 @@ pub enum Command {
     },
 
     GetPeerContext {
-        #[allow(dead_code)]
         peer_id: Option<PeerId>,
+        /// Skip the cache and ask the peer directly
+        force_refresh: bool,
         respond_to: oneshot::Sender<Result<Value, AviP2pError>>,
     },
+    ContextView {
+        paths: Vec<String>,
+        respond_to: oneshot::Sender<ContextView>,
+    },
+
+    // Migration
+    ExportState {
+        respond_to: oneshot::Sender<Result<NodeSnapshot, AviP2pError>>,
+    },
+    ImportState {
+        snapshot: Box<NodeSnapshot>,
+        respond_to: oneshot::Sender<Result<(), AviP2pError>>,
+    },
+
+    /// What a `MeshInvitation` needs from this node
+    MeshDetails {
+        respond_to: oneshot::Sender<Result<MeshDetails, AviP2pError>>,
+    },
+
+    /// Hold an invitation for whoever presents `token` until it expires
+    HoldPairing {
+        token: String,
+        invitation: Box<MeshInvitation>,
+        valid_for: Duration,
+        respond_to: oneshot::Sender<Result<(), AviP2pError>>,
+    },
+    /// Dial the node behind a pairing payload and trade its token
+    FetchInvitation {
+        payload: PairingPayload,
+        respond_to: oneshot::Sender<Result<MeshInvitation, AviP2pError>>,
+    },
+
+    // Reputation
+    BanPeer {
+        peer_id: PeerId,
+        duration: Option<Duration>,
+        respond_to: oneshot::Sender<Result<(), AviP2pError>>,
+    },
+    UnbanPeer {
+        peer_id: PeerId,
+        respond_to: oneshot::Sender<Result<bool, AviP2pError>>,
+    },
+    BannedPeers {
+        respond_to: oneshot::Sender<Result<Vec<BannedPeer>, AviP2pError>>,
+    },
+    /// Stop delivering a peer's messages on `topic` (None = every topic)
+    MutePeer {
+        peer_id: PeerId,
+        topic: Option<String>,
+        respond_to: oneshot::Sender<Result<(), AviP2pError>>,
+    },
+    UnmutePeer {
+        peer_id: PeerId,
+        topic: Option<String>,
+        respond_to: oneshot::Sender<Result<bool, AviP2pError>>,
+    },
+    MutedPeers {
+        respond_to: oneshot::Sender<Vec<(PeerId, Option<String>)>>,
+    },
+}
+
+/// Priority lane a command is queued on. The runtime always drains
+/// `Control` first, then `Realtime`, then `Bulk`, so a burst of stream
+/// data cannot delay a `CloseStream` or an actuator publish.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lane {
+    Control,
+    Realtime,
+    Bulk,
+}
+
+impl Command {
+    pub fn lane(&self) -> Lane {
+        match self {
+            Command::Subscribe { .. }
+            | Command::Unsubscribe { .. }
+            | Command::UnsubscribeAll { .. }
+            | Command::RequestStream { .. }
+            | Command::AcceptStream { .. }
+            | Command::RejectStream { .. }
+            | Command::CloseStream { .. }
+            | Command::GetHealth { .. }
+            | Command::GetRuntimeStats { .. }
+            | Command::Shutdown { .. }
+            | Command::Pause { .. }
+            | Command::Resume { .. }
+            | Command::BanPeer { .. }
+            | Command::MutePeer { .. }
+            | Command::SetMembershipCertificate { .. }
+            | Command::RotateKey { .. } => Lane::Control,
+
+            Command::SendStreamData { .. }
+            | Command::DiscoverPeers { .. }
+            | Command::RendezvousDiscover { .. }
+            | Command::QueryAudit { .. }
+            | Command::VerifyAudit { .. } => Lane::Bulk,
+
+            _ => Lane::Realtime,
+        }
+    }
+}
+
+/// Handle side of the prioritized command lanes
+#[derive(Clone)]
+pub struct CommandSender {
+    control: mpsc::Sender<Command>,
+    realtime: mpsc::Sender<Command>,
+    bulk: mpsc::Sender<Command>,
+}
+
+/// Runtime side of the prioritized command lanes
+pub struct CommandReceiver {
+    control: mpsc::Receiver<Command>,
+    realtime: mpsc::Receiver<Command>,
+    bulk: mpsc::Receiver<Command>,
+}
+
+/// One bounded channel per lane, each with `capacity` slots
+pub fn command_channel(capacity: usize) -> (CommandSender, CommandReceiver) {
+    let capacity = capacity.max(1);
+    let (control_tx, control_rx) = mpsc::channel(capacity);
+    let (realtime_tx, realtime_rx) = mpsc::channel(capacity);
+    let (bulk_tx, bulk_rx) = mpsc::channel(capacity);
+    (
+        CommandSender {
+            control: control_tx,
+            realtime: realtime_tx,
+            bulk: bulk_tx,
+        },
+        CommandReceiver {
+            control: control_rx,
+            realtime: realtime_rx,
+            bulk: bulk_rx,
+        },
+    )
+}
+
+impl CommandSender {
+    pub async fn send(&self, command: Command) -> Result<(), mpsc::error::SendError<Command>> {
+        match command.lane() {
+            Lane::Control => self.control.send(command).await,
+            Lane::Realtime => self.realtime.send(command).await,
+            Lane::Bulk => self.bulk.send(command).await,
+        }
+    }
+
+    /// Combined fill level of all lanes
+    pub fn usage(&self) -> ChannelUsage {
+        [&self.control, &self.realtime, &self.bulk]
+            .into_iter()
+            .map(ChannelUsage::from_mpsc)
+            .fold(ChannelUsage::default(), |acc, lane| ChannelUsage {
+                used: acc.used + lane.used,
+                capacity: acc.capacity + lane.capacity,
+            })
+    }
+}
+
+impl CommandReceiver {
+    /// Highest-priority pending command; `None` once every lane is closed and drained
+    pub async fn recv(&mut self) -> Option<Command> {
+        tokio::select! {
+            biased;
+            Some(command) = self.control.recv() => Some(command),
+            Some(command) = self.realtime.recv() => Some(command),
+            Some(command) = self.bulk.recv() => Some(command),
+            else => None,
+        }
+    }
+
+    pub fn close(&mut self) {
+        self.control.close();
+        self.realtime.close();
+        self.bulk.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream_data(stream_id: u64) -> Command {
+        Command::SendStreamData {
+            stream_id: StreamId(stream_id),
+            data: vec![0; 512],
+            respond_to: oneshot::channel().0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_close_stream_overtakes_queued_stream_data() {
+        let (sender, mut receiver) = command_channel(64);
+        for _ in 0..32 {
+            sender.send(stream_data(1)).await.unwrap();
+        }
+        sender
+            .send(Command::Publish {
+                topic: "home/lock".to_string(),
+                data: b"open".to_vec(),
+                message_id: None,
+                respond_to: oneshot::channel().0,
+            })
+            .await
+            .unwrap();
+        sender
+            .send(Command::CloseStream {
+                stream_id: StreamId(1),
+                respond_to: oneshot::channel().0,
+            })
+            .await
+            .unwrap();
+        assert_eq!(sender.usage().used, 34);
+
+        let mut order = Vec::new();
+        for _ in 0..34 {
+            order.push(receiver.recv().await.unwrap().lane());
+        }
+        assert_eq!(order[..2], [Lane::Control, Lane::Realtime]);
+        assert!(order[2..].iter().all(|lane| *lane == Lane::Bulk));
+    }
 }
@@ -1,25 +1,445 @@
+use crate::audit::AuditConfig;
+use crate::auth::AuthConfig;
+use crate::error::AviP2pError;
+use crate::extension::ExtensionProtocol;
+use crate::invitation::{MeshInvitation, NetworkKey};
+use crate::outbox::OutboxConfig;
+use crate::queue::OverflowPolicies;
+use crate::reputation::ReputationConfig;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::num::{NonZeroU8, NonZeroUsize};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// How much redundancy gossip spends on a topic (see `AviP2pConfig::topic_profiles`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TopicProfile {
+    /// Messages that must arrive, e.g. commands; tuned by `control_gossip`
+    #[default]
+    Control,
+    /// Frequent readings where a lost one is soon replaced by the next;
+    /// tuned by `telemetry_gossip`
+    Telemetry,
+}
+
+/// Gossipsub parameters for one `TopicProfile`
+#[derive(Debug, Clone)]
+pub struct GossipTuning {
+    /// Peers kept in the mesh per topic
+    pub mesh_n: usize,
+    /// Below this the mesh is topped up on the next heartbeat
+    pub mesh_n_low: usize,
+    /// Above this the mesh is pruned on the next heartbeat
+    pub mesh_n_high: usize,
+    /// How often the mesh is maintained and gossip is emitted
+    pub heartbeat_interval: Duration,
+    /// Send own publishes to every subscribed peer, not only the mesh
+    pub flood_publish: bool,
+}
+
+impl GossipTuning {
+    /// Full redundancy (gossipsub's defaults with a fast heartbeat)
+    pub fn control() -> Self {
+        Self {
+            mesh_n: 6,
+            mesh_n_low: 5,
+            mesh_n_high: 12,
+            heartbeat_interval: Duration::from_secs(1),
+            flood_publish: true,
+        }
+    }
+
+    /// A small, lazily maintained mesh without flooding
+    pub fn telemetry() -> Self {
+        Self {
+            mesh_n: 3,
+            mesh_n_low: 2,
+            mesh_n_high: 4,
+            heartbeat_interval: Duration::from_secs(3),
+            flood_publish: false,
+        }
+    }
+}
+
+/// Profile of `topic` under `profiles`: the first pattern matching it,
+/// where a `*` segment matches any one segment
+pub(crate) fn topic_profile(profiles: &[(String, TopicProfile)], topic: &str) -> TopicProfile {
+    let matches = |pattern: &str| {
+        let mut segments = topic.split('/');
+        pattern
+            .split('/')
+            .all(|part| segments.next().is_some_and(|s| part == "*" || part == s))
+            && segments.next().is_none()
+    };
+    profiles
+        .iter()
+        .find(|(pattern, _)| matches(pattern))
+        .map(|(_, profile)| *profile)
+        .unwrap_or_default()
+}
+
+/// How context updates reach the rest of the mesh
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ContextReplication {
+    /// Every update is gossiped whole, so each peer holds every context
+    #[default]
+    Eager,
+    /// Only a notice of the changed paths is gossiped; peers fetch a
+    /// context when it is read. Suits large meshes with big contexts that
+    /// few nodes read.
+    Lazy,
+}
+
+/// What a node is deployed as. `AviP2pConfig::with_role` sets the DHT
+/// mode, stream relaying, context replication and connection limits to
+/// suit it; any of them can still be changed afterwards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeRole {
+    /// Always-on household hub: serves the DHT and rendezvous, relays
+    /// streams and keeps every context fresh
+    Hub,
+    /// Sensor, actuator or speaker, often constrained or on battery: a
+    /// DHT client with few connections that fetches contexts on demand
+    Device,
+    /// App or dashboard that reads the mesh: a DHT client holding every
+    /// context and refreshing them often
+    Controller,
+    /// Relay for peers that cannot reach each other: serves the DHT and
+    /// rendezvous with many connections and streams, and keeps no
+    /// contexts it does not read
+    Relay,
+}
+
+/// Connection encryption and peer authentication handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SecurityProtocol {
+    /// Noise XX (libp2p default)
+    #[default]
+    Noise,
+    /// TLS 1.3 with self-signed X.509 peer certificates
+    Tls,
+    /// Accept and offer both, preferring TLS; lets a fleet migrate gradually
+    TlsOrNoise,
+}
+
+/// Network transports a node can listen and dial on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TransportKind {
+    /// TCP on `listen_port`
+    Tcp,
+    /// QUIC v1 over UDP on `quic_port`
+    Quic,
+    /// WebSocket on `websocket_port`, for browser clients
+    /// (requires the `websocket` feature; always secured with Noise)
+    WebSocket,
+}
+
+/// Timeout and concurrency settings for a request-response protocol
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtocolLimits {
+    /// How long to wait for the remote to acknowledge a request
+    pub request_timeout: Duration,
+
+    /// Maximum in-flight requests per connection
+    pub max_concurrent_requests: usize,
+}
+
+impl Default for ProtocolLimits {
+    fn default() -> Self {
+        Self {
+            request_timeout: Duration::from_secs(10),
+            max_concurrent_requests: 100,
+        }
+    }
+}
+
+/// Ceiling on a topic's traffic; `None` leaves that dimension unlimited.
+/// Bursts of up to one second's worth pass through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RateLimit {
+    pub messages_per_sec: Option<u32>,
+    pub bytes_per_sec: Option<u64>,
+}
+
+/// Whether a node stores and serves DHT records for others
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KadMode {
+    /// Serve once the node has a confirmed external address (libp2p default)
+    #[default]
+    Auto,
+    /// Only query the DHT; suits battery nodes that come and go
+    Client,
+    /// Always serve records, e.g. on a powered hub
+    Server,
+}
+
+/// Kademlia DHT role and replication settings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KadConfig {
+    pub mode: KadMode,
+
+    /// Peers asked in parallel at each step of a lookup
+    pub parallelism: NonZeroUsize,
+
+    /// How long stored records live (None = until the node restarts)
+    pub record_ttl: Option<Duration>,
+
+    /// How often stored records are pushed to the closest peers again
+    /// (None = never)
+    pub replication_interval: Option<Duration>,
+
+    /// How often records and provider registrations this node owns (see
+    /// `put_record` and `start_providing`) are published again; keep it
+    /// well under `record_ttl` so they never lapse
+    pub republish_interval: Duration,
+
+    /// Up to this much random delay is added to each republish, so nodes
+    /// started together do not all republish at once
+    pub republish_jitter: Duration,
+}
+
+impl Default for KadConfig {
+    fn default() -> Self {
+        Self {
+            mode: KadMode::default(),
+            parallelism: NonZeroUsize::new(3).unwrap(),
+            record_ttl: Some(Duration::from_secs(36 * 60 * 60)),
+            replication_interval: Some(Duration::from_secs(60 * 60)),
+            republish_interval: Duration::from_secs(12 * 60 * 60),
+            republish_jitter: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+/// Registration with rendezvous points, so gateways on different networks
+/// find each other by a shared namespace instead of static bootstrap addresses
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RendezvousConfig {
+    /// Rendezvous point multiaddrs; each must end in `/p2p/<peer id>`
+    pub points: Vec<String>,
+
+    /// Namespace to register and discover under (e.g. `household-1234`)
+    pub namespace: String,
+
+    /// How long a registration lasts at the point
+    pub ttl: Duration,
+
+    /// How often to renew the registration and look for new peers
+    pub refresh_interval: Duration,
+}
+
+impl RendezvousConfig {
+    pub fn new(namespace: &str, points: Vec<String>) -> Self {
+        Self {
+            points,
+            namespace: namespace.to_string(),
+            ttl: Duration::from_secs(2 * 60 * 60),
+            refresh_interval: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+/// Ed25519 secret key bytes for the node identity. `Debug` never prints them.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdentitySecret(pub(crate) [u8; 32]);
+
+impl fmt::Debug for IdentitySecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("IdentitySecret(..)")
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct AviP2pConfig {
     /// Identity name for the node (used in Identify protocol)
     pub node_name: String,
 
+    /// Role whose presets were applied by `with_role` (None = tuned by hand)
+    pub role: Option<NodeRole>,
+
+    /// Fixed node identity (None = a fresh random identity on every start)
+    pub identity: Option<IdentitySecret>,
+
+    /// Logical deployment the node belongs to. Topics, DHT keys and the
+    /// stream protocol are scoped to it, so deployments sharing a network
+    /// or relay never see each other's traffic (None = the unscoped mesh)
+    pub mesh_namespace: Option<String>,
+
     /// Port to listen on (0 for random)
     pub listen_port: u16,
 
+    /// Transports to listen on; every transport can always be dialed
+    pub transports: Vec<TransportKind>,
+
+    /// UDP port for the QUIC listener (0 for random)
+    pub quic_port: u16,
+
+    /// TCP port for the WebSocket listener (0 for random)
+    pub websocket_port: u16,
+
     /// List of Multiaddr strings to bootstrap from
     pub bootstrap_peers: Vec<String>,
 
+    /// Addresses of a single peer dialed in parallel; lower it so peers
+    /// with many stale addresses do not tie up a socket for each
+    pub dial_concurrency_factor: NonZeroU8,
+
+    /// How long a dial may go unanswered before it is abandoned. The
+    /// transport gives up after 10s on its own, so only shorter values apply.
+    pub dial_timeout: Duration,
+
+    /// Register and discover peers at rendezvous points (None = disabled)
+    pub rendezvous: Option<RendezvousConfig>,
+
+    /// Act as a rendezvous point, holding other nodes' registrations
+    pub rendezvous_server: bool,
+
+    /// Security handshake for TCP connections (memory transport always uses Noise)
+    pub security: SecurityProtocol,
+
     /// Enable local network discovery via mDNS
     pub enable_mdns: bool,
 
     /// Enable Kademlia DHT for peer discovery
     pub enable_kad: bool,
 
+    /// DHT mode and replication tuning
+    pub kad: KadConfig,
+
     /// Maximum connected peers allowed (soft limit)
     pub max_peers: usize,
 
     /// Maximum concurrent streams
     pub max_streams: usize,
+
+    /// Largest payload `publish` accepts (before any encryption)
+    pub max_publish_size: usize,
+
+    /// Largest chunk `send_stream_data` accepts. Chunks travel JSON-encoded
+    /// in frames of at most 10 MB, so keep this under 2.5 MB.
+    pub max_stream_chunk_size: usize,
+
+    /// Accept `relay:<target>[:<reason>]` streams and pipe them to the
+    /// target, for peers that cannot reach it directly
+    pub stream_relay: bool,
+
+    /// How long an outbound stream may wait for the peer to accept or
+    /// reject it before it is dropped with `StreamOpenTimeout`
+    pub stream_open_timeout: Duration,
+
+    /// Limits for the stream protocol, which carries stream control and
+    /// data, context sync and key exchange
+    pub stream_protocol: ProtocolLimits,
+
+    /// Household membership authentication (None = every peer is trusted)
+    pub auth: Option<AuthConfig>,
+
+    /// How long a rotated-out encryption key still decrypts messages
+    pub key_grace_period: Duration,
+
+    /// Encryption keys the node starts with, e.g. from an invitation;
+    /// later epochs still arrive from peers
+    pub network_keys: Vec<NetworkKey>,
+
+    /// Publishes buffered while paused; the oldest are dropped beyond this
+    pub pause_buffer_limit: usize,
+
+    /// File the node's topic subscriptions are saved in and restored from
+    /// on the next start (None = subscriptions do not survive a restart)
+    pub subscription_store: Option<PathBuf>,
+
+    /// Chatty topics sent under a short id derived from their name (see
+    /// `compact_topic_id`) to save bytes in every message. Every node
+    /// using one of these topics must list it.
+    pub compact_topics: Vec<String>,
+
+    /// Maximum age of messages per topic. Publishes on these topics carry
+    /// their publish time and older ones are dropped on receipt, so a
+    /// reading held up by a partition cannot overwrite a fresher one.
+    /// Senders need the topic listed too; allow for clock skew between nodes.
+    pub topic_max_age: HashMap<String, Duration>,
+
+    /// Rate limits per topic, applied to this node's publishes (over the
+    /// limit they fail with `RateLimited`) and separately to what each peer
+    /// delivers (the excess is dropped and reported with `AviEvent::RateLimited`)
+    pub topic_rate_limits: HashMap<String, RateLimit>,
+
+    /// Gossip profile per topic pattern, first match wins (a `*` segment
+    /// matches any one segment, e.g. `device/*/sensor/*`); other topics are
+    /// `Control`. Each profile gossips on its own mesh, so every node using
+    /// a topic must give it the same profile.
+    pub topic_profiles: Vec<(String, TopicProfile)>,
+
+    /// Gossip parameters for `Control` topics
+    pub control_gossip: GossipTuning,
+
+    /// Gossip parameters for `Telemetry` topics
+    pub telemetry_gossip: GossipTuning,
+
+    /// How long message ids from `publish_with_id` are remembered to drop
+    /// repeated copies (None = deliver every copy)
+    pub dedupe_window: Option<Duration>,
+
+    /// Messages kept per subscribed topic so peers back from an outage can
+    /// catch up (0 = keep none)
+    pub topic_history: usize,
+
+    /// After being cut off from every peer for at most this long, ask the
+    /// peers it reconnects to for what it missed on its topics and deliver
+    /// that with `replayed` set (None = never)
+    pub backfill_after_outage: Option<Duration>,
+
+    /// Most missed messages taken per topic when backfilling
+    pub backfill_limit: usize,
+
+    /// Largest serialized context taken from a peer. Bigger updates are
+    /// rejected with `ContextRejected` and the peer's cached context is
+    /// dropped.
+    pub max_peer_context_size: usize,
+
+    /// Local context size that raises `ContextQuotaWarning` (None = never)
+    pub local_context_warn_size: Option<usize>,
+
+    /// Whether context updates are gossiped whole or as invalidations
+    pub context_replication: ContextReplication,
+
+    /// How long a cached peer context is served before `get_context`
+    /// fetches it from the peer again
+    pub peer_context_max_age: Duration,
+
+    /// How long `fetch_context` waits for a peer to answer
+    pub context_fetch_timeout: Duration,
+
+    /// Silence after which a connected peer's context is reported stale
+    pub context_stale_after: Duration,
+
+    /// Application request-response protocols to serve (see `extension`)
+    pub extensions: Vec<ExtensionProtocol>,
+
+    /// Tamper-evident log of privileged operations (None = disabled)
+    pub audit: Option<AuditConfig>,
+
+    /// Store-and-forward queue for messages to offline peers (None = disabled)
+    pub outbox: Option<OutboxConfig>,
+
+    /// Scoring of peer protocol violations and automatic bans
+    pub reputation: ReputationConfig,
+
+    /// Slots in each handle -> runtime command lane (control, realtime, bulk)
+    pub command_channel_capacity: usize,
+
+    /// Slots in the runtime -> dispatcher event queue (and the `start` receiver)
+    pub event_channel_capacity: usize,
+
+    /// Slots in each event subscriber's queue; a full queue drops events
+    /// for that subscriber only
+    pub subscriber_queue_capacity: usize,
+
+    /// What to do with events when a queue is full, per event class
+    pub event_overflow: OverflowPolicies,
 }
 
 impl AviP2pConfig {
@@ -29,18 +449,186 @@ impl AviP2pConfig {
             ..Default::default()
         }
     }
+
+    /// Use a fixed identity from raw ed25519 secret key bytes, e.g. read
+    /// from a secure element. The peer id is stable across restarts.
+    pub fn with_identity(mut self, secret: [u8; 32]) -> Self {
+        self.identity = Some(IdentitySecret(secret));
+        self
+    }
+
+    /// Derive a fixed identity deterministically from arbitrary seed
+    /// material, e.g. a per-device secret assigned in manufacturing
+    pub fn with_identity_seed(self, seed: &[u8]) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(b"avi-p2p/identity");
+        hasher.update(seed);
+        self.with_identity(hasher.finalize().into())
+    }
+
+    /// Apply the presets for `role` (see `NodeRole`)
+    pub fn with_role(mut self, role: NodeRole) -> Self {
+        self.role = Some(role);
+        match role {
+            NodeRole::Hub => {
+                self.kad.mode = KadMode::Server;
+                self.stream_relay = true;
+                self.rendezvous_server = true;
+                self.context_replication = ContextReplication::Eager;
+                self.max_peers = 50;
+                self.max_streams = 16;
+            }
+            NodeRole::Device => {
+                self.kad.mode = KadMode::Client;
+                self.stream_relay = false;
+                self.rendezvous_server = false;
+                self.context_replication = ContextReplication::Lazy;
+                self.peer_context_max_age = Duration::from_secs(60);
+                self.max_peers = 8;
+                self.max_streams = 4;
+            }
+            NodeRole::Controller => {
+                self.kad.mode = KadMode::Client;
+                self.stream_relay = false;
+                self.rendezvous_server = false;
+                self.context_replication = ContextReplication::Eager;
+                self.peer_context_max_age = Duration::from_secs(10);
+                self.max_peers = 20;
+                self.max_streams = 8;
+            }
+            NodeRole::Relay => {
+                self.kad.mode = KadMode::Server;
+                self.stream_relay = true;
+                self.rendezvous_server = true;
+                self.context_replication = ContextReplication::Lazy;
+                self.max_peers = 100;
+                self.max_streams = 64;
+            }
+        }
+        self
+    }
+
+    pub fn with_mesh_namespace(mut self, namespace: &str) -> Self {
+        self.mesh_namespace = Some(namespace.to_string());
+        self
+    }
+
+    /// Config for a node joining through a `MeshInvitation` blob: its
+    /// identity, certificate, mesh and bootstrap peers all come from the
+    /// invitation, everything else is default
+    pub fn from_invitation(node_name: &str, invitation: &[u8]) -> Result<Self, AviP2pError> {
+        let invitation = MeshInvitation::from_bytes(invitation)?;
+        let mut auth = AuthConfig::new(invitation.ca_public_key);
+        auth.certificate = Some(invitation.certificate.clone());
+        auth.guest_topics = invitation.guest_topics.clone();
+        Ok(Self {
+            node_name: node_name.to_string(),
+            identity: Some(invitation.identity()),
+            mesh_namespace: invitation.mesh_namespace.clone(),
+            bootstrap_peers: invitation.bootstrap_peers.clone(),
+            auth: Some(auth),
+            network_keys: invitation.network_keys().to_vec(),
+            ..Default::default()
+        })
+    }
+}
+
+/// Wire id a `compact_topics` entry is published under (`#` and 8 hex digits)
+pub fn compact_topic_id(topic: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(b"avi-p2p/topic");
+    hasher.update(topic.as_bytes());
+    let digest = hasher.finalize();
+    let hex: String = digest[..4].iter().map(|b| format!("{:02x}", b)).collect();
+    format!("#{}", hex)
 }
 
 impl Default for AviP2pConfig {
     fn default() -> Self {
         Self {
             node_name: "avi-node".to_string(),
+            role: None,
+            identity: None,
+            mesh_namespace: None,
             listen_port: 0,
+            transports: vec![TransportKind::Tcp],
+            quic_port: 0,
+            websocket_port: 0,
             bootstrap_peers: vec![],
+            dial_concurrency_factor: NonZeroU8::new(8).unwrap(),
+            dial_timeout: Duration::from_secs(10),
+            rendezvous: None,
+            rendezvous_server: false,
+            security: SecurityProtocol::default(),
             enable_mdns: true,
             enable_kad: true,
+            kad: KadConfig::default(),
             max_peers: 10,
             max_streams: 5,
+            max_publish_size: 1024 * 1024,
+            max_stream_chunk_size: 2 * 1024 * 1024,
+            stream_open_timeout: Duration::from_secs(10),
+            stream_relay: false,
+            stream_protocol: ProtocolLimits::default(),
+            auth: None,
+            key_grace_period: Duration::from_secs(600),
+            network_keys: vec![],
+            pause_buffer_limit: 100,
+            subscription_store: None,
+            compact_topics: vec![],
+            topic_max_age: HashMap::new(),
+            topic_rate_limits: HashMap::new(),
+            topic_profiles: vec![("device/*/sensor/*".to_string(), TopicProfile::Telemetry)],
+            control_gossip: GossipTuning::control(),
+            telemetry_gossip: GossipTuning::telemetry(),
+            dedupe_window: None,
+            topic_history: 32,
+            backfill_after_outage: Some(Duration::from_secs(300)),
+            backfill_limit: 16,
+            max_peer_context_size: 1024 * 1024,
+            local_context_warn_size: Some(256 * 1024),
+            context_replication: ContextReplication::Eager,
+            peer_context_max_age: Duration::from_secs(30),
+            context_fetch_timeout: Duration::from_secs(5),
+            context_stale_after: Duration::from_secs(300),
+            extensions: vec![],
+            audit: None,
+            outbox: None,
+            reputation: ReputationConfig::default(),
+            command_channel_capacity: 100,
+            event_channel_capacity: 100,
+            subscriber_queue_capacity: 1000,
+            event_overflow: OverflowPolicies::default(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_role_presets_can_be_overridden() {
+        let hub = AviP2pConfig::new("hub").with_role(NodeRole::Hub);
+        assert_eq!(hub.role, Some(NodeRole::Hub));
+        assert_eq!(hub.kad.mode, KadMode::Server);
+        assert!(hub.stream_relay);
+
+        let mut device = AviP2pConfig::new("speaker").with_role(NodeRole::Device);
+        assert_eq!(device.kad.mode, KadMode::Client);
+        assert_eq!(device.context_replication, ContextReplication::Lazy);
+        assert!(device.max_peers < hub.max_peers);
+        device.max_peers = 12;
+        assert_eq!(device.max_peers, 12);
+    }
+
+    #[test]
+    fn test_topic_profiles_match_whole_segments() {
+        let profiles = AviP2pConfig::default().topic_profiles;
+        let profile = |topic| topic_profile(&profiles, topic);
+        assert_eq!(profile("device/7/sensor/temp"), TopicProfile::Telemetry);
+        assert_eq!(profile("device/7/command"), TopicProfile::Control);
+        assert_eq!(profile("device/7/sensor/temp/raw"), TopicProfile::Control);
+        assert_eq!(profile("device/7/sensor"), TopicProfile::Control);
+    }
+}
@@ -0,0 +1,97 @@
+//! Duplicate suppression keyed on application message ids.
+//!
+//! A publisher that may retransmit (e.g. a device that lost the ack) gives
+//! the message an id; the id travels in a small header and receivers with a
+//! dedupe window drop repeats seen within it. Nodes strip the header whether
+//! or not they deduplicate.
+
+use std::collections::{HashSet, VecDeque};
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+/// Marks a payload carrying a message id; followed by the id length and bytes
+const ID_MAGIC: &[u8; 4] = b"AVIi";
+
+/// Longest message id the header can carry
+pub(crate) const MAX_MESSAGE_ID_LEN: usize = u8::MAX as usize;
+
+pub(crate) fn tag(data: Vec<u8>, message_id: &str) -> Vec<u8> {
+    let id = &message_id.as_bytes()[..message_id.len().min(MAX_MESSAGE_ID_LEN)];
+    let mut tagged = Vec::with_capacity(ID_MAGIC.len() + 1 + id.len() + data.len());
+    tagged.extend_from_slice(ID_MAGIC);
+    tagged.push(id.len() as u8);
+    tagged.extend_from_slice(id);
+    tagged.extend_from_slice(&data);
+    tagged
+}
+
+/// Message id and payload of a possibly tagged message
+pub(crate) fn untag(mut data: Vec<u8>) -> (Option<String>, Vec<u8>) {
+    if data.len() <= ID_MAGIC.len() || !data.starts_with(ID_MAGIC) {
+        return (None, data);
+    }
+    let end = ID_MAGIC.len() + 1 + data[ID_MAGIC.len()] as usize;
+    let Some(id) = data.get(ID_MAGIC.len() + 1..end) else {
+        return (None, data);
+    };
+    let id = String::from_utf8_lossy(id).into_owned();
+    data.drain(..end);
+    (Some(id), data)
+}
+
+/// Keys seen within the last `window`
+pub(crate) struct DedupeCache<K> {
+    window: Duration,
+    seen: HashSet<K>,
+    order: VecDeque<(Instant, K)>,
+}
+
+impl<K: Hash + Eq + Clone> DedupeCache<K> {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Record `key`; false if it was already seen within the window
+    pub fn insert(&mut self, key: K) -> bool {
+        let now = Instant::now();
+        while let Some((at, _)) = self.order.front() {
+            if now.duration_since(*at) < self.window {
+                break;
+            }
+            if let Some((_, old)) = self.order.pop_front() {
+                self.seen.remove(&old);
+            }
+        }
+
+        if !self.seen.insert(key.clone()) {
+            return false;
+        }
+        self.order.push_back((now, key));
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tagged_repeats_are_caught_within_window() {
+        let tagged = tag(b"on".to_vec(), "lamp-42");
+        assert_eq!(untag(tagged), (Some("lamp-42".to_string()), b"on".to_vec()));
+        assert_eq!(untag(b"AVI".to_vec()), (None, b"AVI".to_vec()));
+
+        let mut cache = DedupeCache::new(Duration::from_secs(60));
+        assert!(cache.insert("lamp-42"));
+        assert!(!cache.insert("lamp-42"));
+        assert!(cache.insert("lamp-43"));
+
+        let mut expired = DedupeCache::new(Duration::ZERO);
+        assert!(expired.insert(1));
+        assert!(expired.insert(1));
+    }
+}
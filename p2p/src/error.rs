@@ -1,3 +1,4 @@
+use crate::auth::{Operation, Role};
 use crate::events::PeerId;
 use crate::StreamId;
 use thiserror::Error;
@@ -13,6 +14,15 @@ pub enum AviP2pError {
     #[error("Stream not found: {0:?}")]
     StreamNotFound(StreamId),
 
+    #[error("Stream {0:?} was not accepted in time")]
+    StreamOpenTimeout(StreamId),
+
+    #[error("Stream rejected: {0}")]
+    StreamRejected(String),
+
+    #[error("No common stream version (ours {local}, peer's {remote})")]
+    IncompatibleStreamVersion { local: u32, remote: u32 },
+
     #[error("Invalid topic: {0}")]
     InvalidTopic(String),
 
@@ -36,6 +46,57 @@ pub enum AviP2pError {
 
     #[error("Serialization Path Error: {0}")]
     InvalidPath(String),
+
+    #[error("Authentication error: {0}")]
+    Authentication(String),
+
+    #[error("Encryption error: {0}")]
+    Encryption(String),
+
+    #[error("Audit log error: {0}")]
+    Audit(String),
+
+    #[error("{operation:?} not permitted for role {role:?}")]
+    Unauthorized { role: Role, operation: Operation },
+
+    #[error("Timed out fetching context from {0:?}")]
+    ContextFetchTimeout(PeerId),
+
+    #[error("Invalid configuration: {0}")]
+    Config(String),
+
+    #[error("Extension request failed: {0}")]
+    Extension(String),
+
+    #[error("Send refused by interceptor: {0}")]
+    Intercepted(String),
+
+    #[error("Payload of {actual} bytes exceeds the {limit} byte limit")]
+    PayloadTooLarge { limit: usize, actual: usize },
+
+    #[error("Publish rate limit reached on topic: {0}")]
+    RateLimited(String),
+
+    #[error("Snapshot belongs to {0:?}; start the node with its identity")]
+    SnapshotIdentity(PeerId),
+
+    #[error("Stream peer is {actual:?}, expected {expected:?}")]
+    StreamPeerMismatch { expected: PeerId, actual: PeerId },
+
+    #[error("Message {index} of the atomic publish (on {topic}) was refused, so none were sent: {reason}")]
+    PublishRolledBack {
+        index: usize,
+        topic: String,
+        reason: Box<AviP2pError>,
+    },
+
+    #[error("Atomic publish stopped after {sent} messages were sent; {topic} failed: {reason}")]
+    PublishPartiallySent {
+        /// Messages handed to gossip before the failure, from the start
+        sent: usize,
+        topic: String,
+        reason: Box<AviP2pError>,
+    },
 }
 
 impl AviP2pError {}
@@ -44,5 +105,7 @@ impl AviP2pError {}
 pub enum StreamCloseReason {
     LocalClose,
     RemoteClose,
+    /// The remote did not acknowledge a stream message in time
+    Timeout,
     Error(String),
 }
@@ -1,7 +1,10 @@
 use std::fmt;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
 
-#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+static NEXT_CORRELATION_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub struct PeerId(pub(crate) String);
 
 impl PeerId {
@@ -37,8 +40,50 @@ impl TryFrom<PeerId> for libp2p::PeerId {
     }
 }
 
+use crate::auth::Role;
+use crate::bridge::DeviceStats;
 use crate::error::StreamCloseReason;
+use crate::protocols::context::ContextChange;
+use crate::queue::EventClass;
+use crate::reputation::Violation;
 use crate::StreamId;
+use std::time::Duration;
+
+/// What a peer reported about itself through the identify protocol
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerInfo {
+    /// Software and version the peer advertises (its node name by default)
+    pub agent_version: String,
+    /// Identify protocol version (e.g. `/avi/1.0.0`)
+    pub protocol_version: String,
+    /// Protocols the peer supports
+    pub protocols: Vec<String>,
+    /// Addresses the peer listens on
+    pub listen_addresses: Vec<String>,
+    /// Our address as observed by the peer (how the world sees us)
+    pub observed_address: String,
+}
+
+/// Ties the events an asynchronous call leads to back to the call, e.g.
+/// `StreamAccepted` to the `request_stream` that opened the stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct CorrelationId(pub u64);
+
+impl CorrelationId {
+    /// A fresh id, unique within this process
+    pub fn next() -> Self {
+        Self(NEXT_CORRELATION_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// What a node publishes into the DHT
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DhtEntryKind {
+    /// A key/value record (`put_record`)
+    Record,
+    /// A registration as provider of a key (`start_providing`)
+    Provider,
+}
 
 #[derive(Debug, Clone)]
 pub enum AviEvent {
@@ -62,11 +107,35 @@ pub enum AviEvent {
         peer_id: PeerId,
     },
 
+    PeerAuthenticated {
+        peer_id: PeerId,
+        role: Role,
+    },
+
+    PeerAuthenticationFailed {
+        peer_id: PeerId,
+        reason: String,
+    },
+
+    PeerIdentified {
+        peer_id: PeerId,
+        info: PeerInfo,
+    },
+
     // PubSub
     Message {
         from: PeerId,
         topic: String,
         data: Vec<u8>,
+        /// Missed during an outage and fetched from a peer's history
+        /// afterwards, so possibly minutes old
+        replayed: bool,
+    },
+
+    /// Payload addressed to this node only
+    DirectMessage {
+        from: PeerId,
+        data: Vec<u8>,
     },
 
     //  streaming
@@ -79,12 +148,32 @@ pub enum AviEvent {
     StreamAccepted {
         peer_id: PeerId,
         stream_id: StreamId,
+        /// Id of the `request_stream` call that opened the stream
+        correlation_id: Option<CorrelationId>,
+        /// Data the peer answered the acceptance with
+        payload: Option<Vec<u8>>,
     },
 
     StreamRejected {
         peer_id: PeerId,
         stream_id: StreamId,
         reason: String,
+        correlation_id: Option<CorrelationId>,
+    },
+
+    /// An outbound stream was neither accepted nor rejected in time
+    StreamOpenTimeout {
+        peer_id: PeerId,
+        stream_id: StreamId,
+        correlation_id: Option<CorrelationId>,
+    },
+
+    /// The end-to-end handshake on an `e2e` stream completed. `peer_id` is
+    /// the identity proven inside the stream, which for a relayed stream is
+    /// the far end rather than the relay.
+    StreamSecured {
+        peer_id: PeerId,
+        stream_id: StreamId,
     },
 
     StreamData {
@@ -102,5 +191,130 @@ pub enum AviEvent {
     ContextUpdated {
         peer_id: PeerId,
         context: serde_json::Value,
+        /// Exactly which keys changed, with their old and new values
+        changes: Vec<ContextChange>,
+    },
+
+    ContextRejected {
+        from: PeerId,
+        reason: String,
+    },
+
+    /// `from` went over `topic`'s rate limit; its messages there are dropped
+    /// until it slows down. Reported once per burst.
+    RateLimited {
+        from: PeerId,
+        topic: String,
+    },
+
+    /// Anti-entropy with `peer_id` finished: this node now holds every
+    /// context update the peer had. `updated` is false if it already did.
+    ContextSynced {
+        peer_id: PeerId,
+        updated: bool,
+    },
+
+    /// `peer_id` was disconnected and is refused until `duration` is over
+    /// (None = until `unban_peer`). `violation` is the one that took it over
+    /// the ban threshold, None for a ban placed with `ban_peer`.
+    PeerBanned {
+        peer_id: PeerId,
+        violation: Option<Violation>,
+        duration: Option<Duration>,
+    },
+
+    /// A ban was lifted with `unban_peer`
+    PeerUnbanned {
+        peer_id: PeerId,
+    },
+
+    /// A peer changed its context under `ContextReplication::Lazy`. The
+    /// cached copy is dropped; the next `get_context` fetches it.
+    ContextInvalidated {
+        peer_id: PeerId,
+        /// Changed paths (empty = the whole context)
+        paths: Vec<String>,
+    },
+
+    /// The local context grew to `size` bytes, past
+    /// `local_context_warn_size`. Reported once until it shrinks again;
+    /// peers reject contexts over their `max_peer_context_size`.
+    ContextQuotaWarning {
+        size: usize,
+        limit: usize,
+    },
+
+    /// A connected peer has not published a context update for `since`
+    ContextStale {
+        peer_id: PeerId,
+        since: Duration,
+    },
+
+    KeyRotated {
+        from: PeerId,
+        scope: String,
+        epoch: u32,
+    },
+
+    /// Publishing (or republishing) a DHT entry this node owns failed; it
+    /// is retried on the next republish
+    DhtPublishFailed {
+        key: String,
+        kind: DhtEntryKind,
+        error: String,
+    },
+
+    /// A publish scheduled with `publish_after` or `publish_at` was due
+    /// but could not be sent
+    ScheduledPublishFailed {
+        correlation_id: CorrelationId,
+        topic: String,
+        error: String,
+    },
+
+    /// A device said `Hello` to this node's embedded bridge
+    BridgedDeviceOnline {
+        device_id: u64,
+        address: String,
     },
+
+    /// A bridged device's session expired
+    BridgedDeviceOffline {
+        device_id: u64,
+    },
+
+    /// Periodic counters for every bridged device (`BridgeConfig::stats_interval`)
+    BridgeStats {
+        devices: Vec<DeviceStats>,
+    },
+}
+
+impl AviEvent {
+    /// Category used to pick the event queue's overflow policy
+    pub fn class(&self) -> EventClass {
+        match self {
+            AviEvent::Message { .. } | AviEvent::DirectMessage { .. } => EventClass::Message,
+            AviEvent::StreamData { .. } => EventClass::StreamData,
+            AviEvent::ContextUpdated { .. }
+            | AviEvent::ContextRejected { .. }
+            | AviEvent::ContextSynced { .. }
+            | AviEvent::ContextInvalidated { .. }
+            | AviEvent::ContextQuotaWarning { .. }
+            | AviEvent::ContextStale { .. } => EventClass::Context,
+            _ => EventClass::Control,
+        }
+    }
+
+    /// The stream this event reports on, for opens, data and closes
+    pub fn stream_id(&self) -> Option<StreamId> {
+        match self {
+            AviEvent::StreamAccepted { stream_id, .. }
+            | AviEvent::StreamRejected { stream_id, .. }
+            | AviEvent::StreamOpenTimeout { stream_id, .. }
+            | AviEvent::StreamSecured { stream_id, .. }
+            | AviEvent::StreamData { stream_id, .. }
+            | AviEvent::StreamClosed { stream_id, .. } => Some(*stream_id),
+            _ => None,
+        }
+    }
 }
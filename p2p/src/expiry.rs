@@ -0,0 +1,52 @@
+//! Publish timestamps for topics with a maximum message age.
+//!
+//! Messages on such topics carry a small header with the time they were
+//! published, so a receiver can drop readings a partition delayed past their
+//! usefulness. Nodes strip the header whether or not they limit the topic.
+
+/// Marks a stamped payload; followed by the publish time in unix milliseconds
+const STAMP_MAGIC: &[u8; 4] = b"AVIt";
+const STAMP_LEN: usize = STAMP_MAGIC.len() + 8;
+
+pub(crate) fn stamp(data: Vec<u8>, published_ms: u64) -> Vec<u8> {
+    let mut stamped = Vec::with_capacity(STAMP_LEN + data.len());
+    stamped.extend_from_slice(STAMP_MAGIC);
+    stamped.extend_from_slice(&published_ms.to_be_bytes());
+    stamped.extend_from_slice(&data);
+    stamped
+}
+
+/// Publish time and payload of a possibly stamped message
+pub(crate) fn unstamp(mut data: Vec<u8>) -> (Option<u64>, Vec<u8>) {
+    if data.len() < STAMP_LEN || !data.starts_with(STAMP_MAGIC) {
+        return (None, data);
+    }
+    let published_ms = data[STAMP_MAGIC.len()..STAMP_LEN]
+        .try_into()
+        .map(u64::from_be_bytes)
+        .ok();
+    data.drain(..STAMP_LEN);
+    (published_ms, data)
+}
+
+pub(crate) fn now_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stamp_round_trips_and_plain_payloads_pass() {
+        let stamped = stamp(b"{\"t\":21.5}".to_vec(), 1_700_000_000_000);
+        assert_eq!(
+            unstamp(stamped),
+            (Some(1_700_000_000_000), b"{\"t\":21.5}".to_vec())
+        );
+        assert_eq!(unstamp(b"plain".to_vec()), (None, b"plain".to_vec()));
+    }
+}
@@ -0,0 +1,75 @@
+//! Application-defined request-response protocols.
+//!
+//! Downstream crates register an `ExtensionProtocol` in
+//! `AviP2pConfig::extensions`; requests to it arrive at its handler and
+//! `AviP2pHandle::extension` sends requests to the same protocol on peers.
+//! All extensions share one libp2p protocol and are routed by name, so a
+//! peer without the named handler answers with an error.
+
+use crate::command::{Command, CommandSender};
+use crate::error::AviP2pError;
+use crate::events::PeerId;
+use async_trait::async_trait;
+use std::fmt;
+use std::sync::Arc;
+use tokio::sync::oneshot;
+
+/// Answers requests on one extension protocol
+#[async_trait]
+pub trait ExtensionHandler: Send + Sync {
+    /// Reply to a request from `from`; an `Err` is returned to the caller
+    async fn on_request(&self, from: PeerId, request: Vec<u8>) -> Result<Vec<u8>, String>;
+}
+
+/// An extension protocol mounted at startup
+#[derive(Clone)]
+pub struct ExtensionProtocol {
+    /// Name requests are routed by (e.g. `acme/thermostat-schedule/1`)
+    pub name: String,
+    pub handler: Arc<dyn ExtensionHandler>,
+}
+
+impl ExtensionProtocol {
+    pub fn new(name: &str, handler: impl ExtensionHandler + 'static) -> Self {
+        Self {
+            name: name.to_string(),
+            handler: Arc::new(handler),
+        }
+    }
+}
+
+impl fmt::Debug for ExtensionProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ExtensionProtocol")
+            .field("name", &self.name)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Sends requests on one extension protocol; from `AviP2pHandle::extension`
+#[derive(Clone)]
+pub struct Extension {
+    pub(crate) name: String,
+    pub(crate) command_tx: CommandSender,
+}
+
+impl Extension {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Send `data` to the extension's handler on `peer` and wait for its reply
+    pub async fn request(&self, peer: &PeerId, data: Vec<u8>) -> Result<Vec<u8>, AviP2pError> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::ExtensionRequest {
+                peer_id: peer.clone(),
+                name: self.name.clone(),
+                data,
+                respond_to: tx,
+            })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        rx.await.map_err(|_| AviP2pError::ChannelClosed)?
+    }
+}
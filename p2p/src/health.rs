@@ -0,0 +1,100 @@
+use std::time::Duration;
+
+/// Fill level of one of the node's internal channels
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChannelUsage {
+    pub used: usize,
+    pub capacity: usize,
+}
+
+impl ChannelUsage {
+    pub(crate) fn from_mpsc<T>(tx: &tokio::sync::mpsc::Sender<T>) -> Self {
+        Self {
+            used: tx.max_capacity() - tx.capacity(),
+            capacity: tx.max_capacity(),
+        }
+    }
+
+    /// Fraction of the channel currently in use (0.0 - 1.0)
+    pub fn saturation(&self) -> f32 {
+        if self.capacity == 0 {
+            return 0.0;
+        }
+        self.used as f32 / self.capacity as f32
+    }
+}
+
+/// Structured liveness report returned by `AviP2pHandle::health`
+#[derive(Debug, Clone, Default)]
+pub struct HealthReport {
+    /// The runtime task answered the health probe
+    pub runtime_alive: bool,
+
+    /// Addresses the swarm is currently listening on
+    pub listen_addresses: Vec<String>,
+
+    /// Number of currently connected peers
+    pub connected_peers: usize,
+
+    /// At least one Kademlia bootstrap completed successfully
+    pub dht_bootstrapped: bool,
+
+    /// Devices currently attached through the node's bridges
+    pub bridge_sessions: usize,
+
+    /// `Hello`s the bridge refused because of its per-address session limit
+    pub bridge_rejected_hellos: u64,
+
+    /// Handle -> runtime command channel
+    pub command_queue: ChannelUsage,
+
+    /// Runtime -> dispatcher event channel
+    pub event_queue: ChannelUsage,
+
+    /// Fill level of each event subscriber's own queue
+    pub subscriber_queues: Vec<ChannelUsage>,
+
+    /// Events discarded from the runtime -> dispatcher queue by the
+    /// configured overflow policies (per-subscriber drops are reported
+    /// by `EventSubscription::dropped`)
+    pub dropped_events: u64,
+}
+
+impl HealthReport {
+    /// Saturation above which a channel is considered unhealthy
+    pub const SATURATION_THRESHOLD: f32 = 0.9;
+
+    /// Simple liveness verdict suitable for a watchdog or liveness probe:
+    /// the runtime is responsive, bound to at least one address, and no
+    /// internal channel is close to full. Subscriber queues are not
+    /// considered: a slow consumer only hurts itself.
+    pub fn is_healthy(&self) -> bool {
+        self.runtime_alive
+            && !self.listen_addresses.is_empty()
+            && self.command_queue.saturation() < Self::SATURATION_THRESHOLD
+            && self.event_queue.saturation() < Self::SATURATION_THRESHOLD
+    }
+}
+
+/// Runtime-internal gauges returned by `AviP2pHandle::runtime_stats`
+#[derive(Debug, Clone, Default)]
+pub struct RuntimeStats {
+    /// Handle -> runtime command lanes, combined
+    pub command_queue: ChannelUsage,
+
+    /// Runtime -> dispatcher event queue
+    pub event_queue: ChannelUsage,
+
+    /// Events discarded from the event queue by the overflow policies
+    pub dropped_events: u64,
+
+    /// Stream-protocol requests sent but not yet answered or failed
+    pub pending_outbound_requests: usize,
+
+    /// Moving average of the time the runtime spends on one command or
+    /// swarm event, during which the swarm is not polled
+    pub poll_latency: Duration,
+
+    /// Worst single command or swarm event handling time since start
+    pub max_poll_latency: Duration,
+}
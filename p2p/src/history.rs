@@ -0,0 +1,184 @@
+//! Recent messages per topic, for peers catching up after an outage.
+//!
+//! Every node keeps the last `topic_history` messages it received on each
+//! subscribed topic. A node back from a short outage (cut off from every
+//! peer) asks the peers it reconnects to for what arrived on its topics
+//! while it was gone and delivers those messages with `replayed` set.
+//! Gossip message ids keep a message held by several peers, or already
+//! received, from being delivered twice.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+/// Most payload bytes sent in one history answer; the oldest messages are
+/// left out beyond it
+pub(crate) const MAX_HISTORY_RESPONSE_BYTES: usize = 1024 * 1024;
+
+/// A message from a peer's history
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub topic: String,
+    /// Peer that published the message
+    pub author: String,
+    /// Gossip message id
+    pub id: Vec<u8>,
+    /// Payload as it travelled on the topic, still sealed on encrypted topics
+    pub data: Vec<u8>,
+    /// Time since the answering peer received it
+    pub age_ms: u64,
+}
+
+struct Stored {
+    author: String,
+    id: Vec<u8>,
+    data: Vec<u8>,
+    received: Instant,
+}
+
+pub(crate) struct TopicHistory {
+    depth: usize,
+    topics: HashMap<String, VecDeque<Stored>>,
+    /// Ids of every stored message
+    ids: HashSet<Vec<u8>>,
+}
+
+impl TopicHistory {
+    pub fn new(depth: usize) -> Self {
+        Self {
+            depth,
+            topics: HashMap::new(),
+            ids: HashSet::new(),
+        }
+    }
+
+    /// Remember a message received `age` ago; false if it is already held
+    pub fn record(
+        &mut self,
+        topic: &str,
+        author: String,
+        id: Vec<u8>,
+        data: Vec<u8>,
+        age: Duration,
+    ) -> bool {
+        if self.ids.contains(&id) {
+            return false;
+        }
+        if self.depth == 0 {
+            return true;
+        }
+        let now = Instant::now();
+        let queue = self.topics.entry(topic.to_string()).or_default();
+        if queue.len() >= self.depth {
+            if let Some(evicted) = queue.pop_front() {
+                self.ids.remove(&evicted.id);
+            }
+        }
+        self.ids.insert(id.clone());
+        queue.push_back(Stored {
+            author,
+            id,
+            data,
+            received: now.checked_sub(age).unwrap_or(now),
+        });
+        true
+    }
+
+    /// Messages on `topics` received within `window`, at most `limit` per
+    /// topic and `MAX_HISTORY_RESPONSE_BYTES` in all, oldest first
+    pub fn recent(&self, topics: &[String], window: Duration, limit: usize) -> Vec<HistoryEntry> {
+        let mut entries: Vec<HistoryEntry> = topics
+            .iter()
+            .filter_map(|topic| Some((topic, self.topics.get(topic)?)))
+            .flat_map(|(topic, queue)| {
+                queue
+                    .iter()
+                    .rev()
+                    .take_while(|stored| stored.received.elapsed() <= window)
+                    .take(limit)
+                    .map(move |stored| HistoryEntry {
+                        topic: topic.clone(),
+                        author: stored.author.clone(),
+                        id: stored.id.clone(),
+                        data: stored.data.clone(),
+                        age_ms: stored.received.elapsed().as_millis() as u64,
+                    })
+            })
+            .collect();
+        // Newest first while trimming to the byte budget
+        entries.sort_by_key(|entry| entry.age_ms);
+        let mut budget = MAX_HISTORY_RESPONSE_BYTES;
+        entries.retain(|entry| match budget.checked_sub(entry.data.len()) {
+            Some(left) => {
+                budget = left;
+                true
+            }
+            None => {
+                budget = 0;
+                false
+            }
+        });
+        entries.reverse();
+        entries
+    }
+
+    /// Whether a message with this gossip id is held
+    pub fn contains(&self, id: &[u8]) -> bool {
+        self.ids.contains(id)
+    }
+
+    /// Drop what is held for a topic no longer subscribed
+    pub fn forget(&mut self, topic: &str) {
+        for stored in self.topics.remove(topic).unwrap_or_default() {
+            self.ids.remove(&stored.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(history: &mut TopicHistory, topic: &str, id: u8, age_ms: u64) -> bool {
+        history.record(
+            topic,
+            "peer".to_string(),
+            vec![id],
+            vec![id],
+            Duration::from_millis(age_ms),
+        )
+    }
+
+    #[test]
+    fn test_recent_messages_within_window_and_depth() {
+        let mut history = TopicHistory::new(3);
+        for (id, age_ms) in [(1, 50_000), (2, 3_000), (3, 2_000), (4, 1_000)] {
+            assert!(record(&mut history, "lights", id, age_ms));
+        }
+        assert!(record(&mut history, "doors", 9, 500));
+        // Known ids are not taken twice
+        assert!(history.contains(&[4]));
+        assert!(!record(&mut history, "lights", 4, 0));
+
+        let topics = ["lights".to_string(), "doors".to_string()];
+        let ids = |entries: Vec<HistoryEntry>| -> Vec<u8> {
+            entries.into_iter().map(|entry| entry.id[0]).collect()
+        };
+        // The first message was pushed out by depth 3
+        assert_eq!(
+            ids(history.recent(&topics, Duration::from_secs(60), 10)),
+            vec![2, 3, 4, 9]
+        );
+        assert_eq!(
+            ids(history.recent(&topics, Duration::from_millis(2_500), 10)),
+            vec![3, 4, 9]
+        );
+        assert_eq!(
+            ids(history.recent(&topics[..1], Duration::from_secs(60), 1)),
+            vec![4]
+        );
+
+        history.forget("lights");
+        assert!(record(&mut history, "lights", 4, 0));
+    }
+}
@@ -0,0 +1,220 @@
+//! Application hooks on the event path.
+//!
+//! Inbound interceptors see every event after the runtime produces it and
+//! before any subscriber does. Each may pass it on unchanged, rewrite it
+//! (e.g. a schema migration shim upgrading old payloads), or drop it, and
+//! they run in the order they were added.
+//!
+//! Outbound interceptors see payloads handed to `publish` and
+//! `send_stream_data` before they leave the node, and may rewrite them
+//! (sign, redact) or refuse the send (size limits, policy).
+
+use crate::error::AviP2pError;
+use crate::events::AviEvent;
+use crate::StreamId;
+use async_trait::async_trait;
+use std::sync::{Arc, RwLock};
+
+/// Hook run on events before subscribers see them
+#[async_trait]
+pub trait InboundInterceptor: Send + Sync {
+    /// Return the event to deliver, possibly rewritten, or None to drop it
+    async fn on_event(&self, event: AviEvent) -> Option<AviEvent>;
+}
+
+#[async_trait]
+impl<F> InboundInterceptor for F
+where
+    F: Fn(AviEvent) -> Option<AviEvent> + Send + Sync,
+{
+    async fn on_event(&self, event: AviEvent) -> Option<AviEvent> {
+        self(event)
+    }
+}
+
+/// Where an outgoing payload is headed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutboundTarget {
+    Publish { topic: String },
+    StreamData { stream_id: StreamId },
+}
+
+/// Which sends an outbound interceptor runs on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InterceptScope {
+    /// Every publish and stream send
+    Global,
+    /// Publishes on this topic only
+    Topic(String),
+}
+
+impl InterceptScope {
+    fn covers(&self, target: &OutboundTarget) -> bool {
+        match (self, target) {
+            (InterceptScope::Global, _) => true,
+            (InterceptScope::Topic(scope), OutboundTarget::Publish { topic }) => scope == topic,
+            (InterceptScope::Topic(_), OutboundTarget::StreamData { .. }) => false,
+        }
+    }
+}
+
+/// Hook run on payloads before they are sent
+#[async_trait]
+pub trait OutboundInterceptor: Send + Sync {
+    /// Return the payload to send, possibly rewritten, or why it must not be sent
+    async fn on_send(&self, target: &OutboundTarget, data: Vec<u8>) -> Result<Vec<u8>, String>;
+}
+
+#[async_trait]
+impl<F> OutboundInterceptor for F
+where
+    F: Fn(&OutboundTarget, Vec<u8>) -> Result<Vec<u8>, String> + Send + Sync,
+{
+    async fn on_send(&self, target: &OutboundTarget, data: Vec<u8>) -> Result<Vec<u8>, String> {
+        self(target, data)
+    }
+}
+
+type ScopedOutbound = (InterceptScope, Arc<dyn OutboundInterceptor>);
+
+/// Interceptors registered on a node, shared by all its handles
+#[derive(Clone, Default)]
+pub(crate) struct Interceptors {
+    inbound: Arc<RwLock<Vec<Arc<dyn InboundInterceptor>>>>,
+    outbound: Arc<RwLock<Vec<ScopedOutbound>>>,
+}
+
+impl Interceptors {
+    pub fn add_inbound(&self, interceptor: Arc<dyn InboundInterceptor>) {
+        self.inbound.write().unwrap().push(interceptor);
+    }
+
+    pub fn add_outbound(&self, scope: InterceptScope, interceptor: Arc<dyn OutboundInterceptor>) {
+        self.outbound.write().unwrap().push((scope, interceptor));
+    }
+
+    /// Run the outbound interceptors covering `target`
+    pub async fn outbound(
+        &self,
+        target: OutboundTarget,
+        data: Vec<u8>,
+    ) -> Result<Vec<u8>, AviP2pError> {
+        let chain: Vec<_> = self
+            .outbound
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(scope, _)| scope.covers(&target))
+            .map(|(_, interceptor)| interceptor.clone())
+            .collect();
+        let mut data = data;
+        for interceptor in chain {
+            data = interceptor
+                .on_send(&target, data)
+                .await
+                .map_err(AviP2pError::Intercepted)?;
+        }
+        Ok(data)
+    }
+
+    /// Run the inbound chain; None if an interceptor dropped the event
+    pub async fn inbound(&self, event: AviEvent) -> Option<AviEvent> {
+        let chain = self.inbound.read().unwrap().clone();
+        let mut event = event;
+        for interceptor in chain {
+            event = interceptor.on_event(event).await?;
+        }
+        Some(event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PeerId;
+
+    fn message(topic: &str, data: &[u8]) -> AviEvent {
+        AviEvent::Message {
+            from: PeerId::new("peer"),
+            topic: topic.to_string(),
+            data: data.to_vec(),
+            replayed: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_inbound_chain_rewrites_and_drops_in_order() {
+        let interceptors = Interceptors::default();
+        interceptors.add_inbound(Arc::new(|event| match event {
+            AviEvent::Message { topic, .. } if topic == "debug" => None,
+            other => Some(other),
+        }));
+        interceptors.add_inbound(Arc::new(|event| match event {
+            AviEvent::Message {
+                from,
+                topic,
+                data,
+                replayed,
+            } if data == b"v1" => Some(AviEvent::Message {
+                from,
+                topic,
+                data: b"v2".to_vec(),
+                replayed,
+            }),
+            other => Some(other),
+        }));
+
+        assert!(interceptors
+            .inbound(message("debug", b"v1"))
+            .await
+            .is_none());
+        match interceptors.inbound(message("lights", b"v1")).await {
+            Some(AviEvent::Message { data, .. }) => assert_eq!(data, b"v2"),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_outbound_scopes_and_refusals() {
+        let interceptors = Interceptors::default();
+        interceptors.add_outbound(
+            InterceptScope::Global,
+            Arc::new(|_: &OutboundTarget, data: Vec<u8>| {
+                if data.len() > 4 {
+                    Err("payload too large".to_string())
+                } else {
+                    Ok(data)
+                }
+            }),
+        );
+        interceptors.add_outbound(
+            InterceptScope::Topic("secrets".to_string()),
+            Arc::new(|_: &OutboundTarget, _: Vec<u8>| Ok(b"***".to_vec())),
+        );
+
+        let publish = |topic: &str| OutboundTarget::Publish {
+            topic: topic.to_string(),
+        };
+        assert_eq!(
+            interceptors
+                .outbound(publish("secrets"), b"pin".to_vec())
+                .await
+                .unwrap(),
+            b"***"
+        );
+        assert_eq!(
+            interceptors
+                .outbound(publish("lights"), b"on".to_vec())
+                .await
+                .unwrap(),
+            b"on"
+        );
+        let stream = OutboundTarget::StreamData {
+            stream_id: StreamId(1),
+        };
+        assert!(matches!(
+            interceptors.outbound(stream, b"too long".to_vec()).await,
+            Err(AviP2pError::Intercepted(_))
+        ));
+    }
+}
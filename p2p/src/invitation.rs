@@ -0,0 +1,231 @@
+//! Mesh invitations: everything a new node needs to join, in one blob.
+//!
+//! An admin node writes an invitation; the new node starts from it:
+//!
+//! ```ignore
+//! let invitation = admin.create_invitation(&ca, Role::Device, Some(Duration::from_secs(3600))).await?;
+//! std::fs::write("speaker.invite", invitation.to_bytes()?)?;
+//!
+//! // On the new speaker
+//! let config = AviP2pConfig::from_invitation("speaker", &std::fs::read("speaker.invite")?)?;
+//! let (node, events) = AviP2p::start(config).await?;
+//! ```
+//!
+//! The invitation carries a fresh identity for the new node with its
+//! membership certificate, the household CA key, the mesh's shared
+//! encryption keys and addresses to bootstrap from, all signed by the
+//! household CA. It holds secrets, so it should travel like a password.
+
+use crate::auth::{HouseholdCa, MembershipCertificate, Role};
+use crate::config::IdentitySecret;
+use crate::error::AviP2pError;
+use crate::events::PeerId;
+use libp2p::identity::{ed25519, Keypair};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Invitation layout version; blobs from a newer layout are refused
+pub const INVITATION_FORMAT: u32 = 1;
+
+const INVITATION_DOMAIN: &[u8] = b"avi-invitation-v1";
+
+/// A mesh encryption key (see `AviP2pHandle::rotate_key`). `Debug` never
+/// prints the key.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NetworkKey {
+    /// Topic name or `CONTEXT_KEY_SCOPE`
+    pub scope: String,
+    pub epoch: u32,
+    pub(crate) key: [u8; 32],
+}
+
+impl fmt::Debug for NetworkKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NetworkKey")
+            .field("scope", &self.scope)
+            .field("epoch", &self.epoch)
+            .finish_non_exhaustive()
+    }
+}
+
+/// What the inviting node knows about the mesh
+#[derive(Debug, Clone)]
+pub(crate) struct MeshDetails {
+    pub peer_id: PeerId,
+    pub listen_addresses: Vec<String>,
+    pub mesh_namespace: Option<String>,
+    pub network_keys: Vec<NetworkKey>,
+    pub guest_topics: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MeshInvitation {
+    pub format: u32,
+    pub mesh_namespace: Option<String>,
+    /// Addresses of the inviting node
+    pub bootstrap_peers: Vec<String>,
+    pub(crate) network_keys: Vec<NetworkKey>,
+    /// Household CA the mesh authenticates against
+    pub ca_public_key: [u8; 32],
+    pub guest_topics: Vec<String>,
+    /// Identity issued to the invited node
+    pub(crate) identity: IdentitySecret,
+    pub certificate: MembershipCertificate,
+    /// Unix time after which the invitation is refused (None = never)
+    pub expires_at: Option<u64>,
+    signature: Vec<u8>,
+}
+
+impl MeshInvitation {
+    /// Invite a new node into the mesh under `role`, signed by `ca`
+    pub(crate) fn issue(
+        ca: &HouseholdCa,
+        mesh: MeshDetails,
+        role: Role,
+        valid_for: Option<Duration>,
+    ) -> Self {
+        let secret = ed25519::SecretKey::generate();
+        let mut identity = [0u8; 32];
+        identity.copy_from_slice(secret.as_ref());
+        let peer_id = PeerId::from(
+            Keypair::from(ed25519::Keypair::from(secret))
+                .public()
+                .to_peer_id(),
+        );
+
+        let mut invitation = Self {
+            format: INVITATION_FORMAT,
+            mesh_namespace: mesh.mesh_namespace,
+            bootstrap_peers: mesh
+                .listen_addresses
+                .iter()
+                .map(|addr| format!("{}/p2p/{}", addr, mesh.peer_id))
+                .collect(),
+            network_keys: mesh.network_keys,
+            ca_public_key: ca.public_key(),
+            guest_topics: mesh.guest_topics,
+            identity: IdentitySecret(identity),
+            certificate: ca.issue(&peer_id, role, None),
+            expires_at: valid_for.map(|valid_for| unix_now() + valid_for.as_secs()),
+            signature: Vec::new(),
+        };
+        invitation.signature = ca.sign(&invitation.signing_bytes());
+        invitation
+    }
+
+    /// Peer id the invited node will have
+    pub fn peer_id(&self) -> PeerId {
+        PeerId::new(&self.certificate.peer_id)
+    }
+
+    pub fn network_keys(&self) -> &[NetworkKey] {
+        &self.network_keys
+    }
+
+    pub fn identity(&self) -> IdentitySecret {
+        self.identity.clone()
+    }
+
+    /// Check the CA signature, the certificate and the expiry. Nodes that
+    /// already know the household CA should also compare `ca_public_key`
+    /// against it, as a forged invitation can carry its own CA.
+    pub fn verify(&self) -> Result<(), AviP2pError> {
+        let ca = ed25519::PublicKey::try_from_bytes(&self.ca_public_key)
+            .map_err(|e| AviP2pError::Authentication(e.to_string()))?;
+        if !ca.verify(&self.signing_bytes(), &self.signature) {
+            return Err(AviP2pError::Authentication(
+                "Invitation not signed by its household CA".to_string(),
+            ));
+        }
+        if let Some(expires_at) = self.expires_at {
+            if unix_now() >= expires_at {
+                return Err(AviP2pError::Authentication(
+                    "Invitation expired".to_string(),
+                ));
+            }
+        }
+        self.certificate.verify(&self.ca_public_key)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, AviP2pError> {
+        serde_json::to_vec(self).map_err(|e| AviP2pError::Serialization(e.to_string()))
+    }
+
+    /// Decode and `verify` an invitation
+    pub fn from_bytes(data: &[u8]) -> Result<Self, AviP2pError> {
+        let invitation: Self =
+            serde_json::from_slice(data).map_err(|e| AviP2pError::Serialization(e.to_string()))?;
+        if invitation.format > INVITATION_FORMAT {
+            return Err(AviP2pError::Serialization(format!(
+                "Invitation format {} is newer than {}",
+                invitation.format, INVITATION_FORMAT
+            )));
+        }
+        invitation.verify()?;
+        Ok(invitation)
+    }
+
+    fn signing_bytes(&self) -> Vec<u8> {
+        let unsigned = Self {
+            signature: Vec::new(),
+            ..self.clone()
+        };
+        let mut bytes = INVITATION_DOMAIN.to_vec();
+        bytes.extend(serde_json::to_vec(&unsigned).unwrap_or_default());
+        bytes
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mesh() -> MeshDetails {
+        MeshDetails {
+            peer_id: PeerId::new("12D3KooWhub"),
+            listen_addresses: vec!["/ip4/192.168.1.2/tcp/4001".to_string()],
+            mesh_namespace: Some("home".to_string()),
+            network_keys: vec![NetworkKey {
+                scope: "alarms".to_string(),
+                epoch: 2,
+                key: [7; 32],
+            }],
+            guest_topics: vec![],
+        }
+    }
+
+    #[test]
+    fn test_invitation_round_trips_and_refuses_tampering() {
+        let ca = HouseholdCa::generate();
+        let invitation = MeshInvitation::issue(&ca, mesh(), Role::Device, None);
+        let blob = invitation.to_bytes().unwrap();
+
+        let joined = MeshInvitation::from_bytes(&blob).unwrap();
+        assert_eq!(joined.peer_id(), invitation.peer_id());
+        assert_eq!(joined.certificate.role, Role::Device);
+        assert_eq!(
+            joined.bootstrap_peers,
+            vec!["/ip4/192.168.1.2/tcp/4001/p2p/12D3KooWhub".to_string()]
+        );
+        assert_eq!(joined.network_keys()[0].key, [7; 32]);
+        assert!(!format!("{:?}", joined.network_keys()).contains("key:"));
+
+        // Pointing the new node somewhere else breaks the signature
+        let mut redirected = joined.clone();
+        redirected.bootstrap_peers = vec!["/ip4/10.0.0.66/tcp/4001".to_string()];
+        assert!(redirected.verify().is_err());
+
+        let mut expired = MeshInvitation::issue(&ca, mesh(), Role::Device, Some(Duration::ZERO));
+        assert!(expired.verify().is_err());
+        expired.expires_at = None;
+        assert!(expired.verify().is_err());
+    }
+}
@@ -0,0 +1,210 @@
+use crate::error::AviP2pError;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Key scope used for encrypted context fields
+pub const CONTEXT_KEY_SCOPE: &str = "avi-context";
+
+/// Ciphertext tagged with the key scope and epoch it was sealed with
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct EncryptedPayload {
+    pub scope: String,
+    pub epoch: u32,
+    pub nonce: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+}
+
+struct EpochKey {
+    epoch: u32,
+    key: [u8; 32],
+    retired_at: Option<Instant>,
+}
+
+/// Shared symmetric keys per scope (a topic name or `CONTEXT_KEY_SCOPE`).
+/// Each rotation starts a new epoch; the previous epoch stays usable for
+/// decryption during the grace window so in-flight messages still open.
+pub(crate) struct KeyRing {
+    scopes: HashMap<String, Vec<EpochKey>>,
+    grace: Duration,
+}
+
+impl KeyRing {
+    pub fn new(grace: Duration) -> Self {
+        Self {
+            scopes: HashMap::new(),
+            grace,
+        }
+    }
+
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.contains_key(scope)
+    }
+
+    /// Generate a fresh key for `scope`, retiring the current one
+    pub fn rotate(&mut self, scope: &str) -> (u32, [u8; 32]) {
+        let epoch = self.current(scope).map(|(epoch, _)| epoch + 1).unwrap_or(1);
+        let key = rand::random::<[u8; 32]>();
+        self.install(scope, epoch, key);
+        (epoch, key)
+    }
+
+    /// Install a key received from a peer. Returns false if the epoch was already known.
+    pub fn install(&mut self, scope: &str, epoch: u32, key: [u8; 32]) -> bool {
+        let keys = self.scopes.entry(scope.to_string()).or_default();
+        if keys.iter().any(|k| k.epoch == epoch) {
+            return false;
+        }
+
+        let now = Instant::now();
+        let newest = keys.iter().map(|k| k.epoch).max().unwrap_or(0);
+        if epoch > newest {
+            for k in keys.iter_mut().filter(|k| k.retired_at.is_none()) {
+                k.retired_at = Some(now);
+            }
+            keys.push(EpochKey {
+                epoch,
+                key,
+                retired_at: None,
+            });
+        } else {
+            // A late re-key for an old epoch only serves decryption
+            keys.push(EpochKey {
+                epoch,
+                key,
+                retired_at: Some(now),
+            });
+        }
+        keys.sort_by_key(|k| k.epoch);
+        true
+    }
+
+    pub fn current(&self, scope: &str) -> Option<(u32, [u8; 32])> {
+        self.scopes
+            .get(scope)?
+            .iter()
+            .find(|k| k.retired_at.is_none())
+            .map(|k| (k.epoch, k.key))
+    }
+
+    /// Current key of every scope, for distribution to newly authorized peers
+    pub fn current_keys(&self) -> Vec<(String, u32, [u8; 32])> {
+        self.scopes
+            .keys()
+            .filter_map(|scope| {
+                self.current(scope)
+                    .map(|(epoch, key)| (scope.clone(), epoch, key))
+            })
+            .collect()
+    }
+
+    pub fn encrypt(&self, scope: &str, plaintext: &[u8]) -> Result<EncryptedPayload, AviP2pError> {
+        let (epoch, key) = self
+            .current(scope)
+            .ok_or_else(|| AviP2pError::Encryption(format!("No key for scope {}", scope)))?;
+
+        let nonce = rand::random::<[u8; 12]>();
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&key));
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(&nonce), plaintext)
+            .map_err(|e| AviP2pError::Encryption(e.to_string()))?;
+
+        Ok(EncryptedPayload {
+            scope: scope.to_string(),
+            epoch,
+            nonce: nonce.to_vec(),
+            ciphertext,
+        })
+    }
+
+    pub fn decrypt(&self, payload: &EncryptedPayload) -> Result<Vec<u8>, AviP2pError> {
+        let entry = self
+            .scopes
+            .get(&payload.scope)
+            .and_then(|keys| keys.iter().find(|k| k.epoch == payload.epoch))
+            .ok_or_else(|| {
+                AviP2pError::Encryption(format!(
+                    "Unknown epoch {} for scope {}",
+                    payload.epoch, payload.scope
+                ))
+            })?;
+
+        if let Some(retired_at) = entry.retired_at {
+            if retired_at.elapsed() > self.grace {
+                return Err(AviP2pError::Encryption(format!(
+                    "Epoch {} for scope {} is past its grace window",
+                    payload.epoch, payload.scope
+                )));
+            }
+        }
+
+        if payload.nonce.len() != 12 {
+            return Err(AviP2pError::Encryption("Invalid nonce".to_string()));
+        }
+
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&entry.key));
+        cipher
+            .decrypt(
+                Nonce::from_slice(&payload.nonce),
+                payload.ciphertext.as_slice(),
+            )
+            .map_err(|e| AviP2pError::Encryption(e.to_string()))
+    }
+
+    /// Forget keys whose grace window has passed
+    pub fn prune(&mut self) {
+        let grace = self.grace;
+        for keys in self.scopes.values_mut() {
+            keys.retain(|k| k.retired_at.map(|t| t.elapsed() <= grace).unwrap_or(true));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotation_keeps_previous_epoch_for_grace_window() {
+        let mut ring = KeyRing::new(Duration::from_secs(60));
+        ring.rotate("lights");
+        let old = ring.encrypt("lights", b"on").unwrap();
+
+        let (epoch, _) = ring.rotate("lights");
+        assert_eq!(epoch, 2);
+
+        let new = ring.encrypt("lights", b"off").unwrap();
+        assert_eq!(new.epoch, 2);
+        assert_eq!(ring.decrypt(&old).unwrap(), b"on");
+        assert_eq!(ring.decrypt(&new).unwrap(), b"off");
+    }
+
+    #[test]
+    fn test_expired_epoch_is_rejected() {
+        let mut ring = KeyRing::new(Duration::ZERO);
+        ring.rotate("lights");
+        let old = ring.encrypt("lights", b"on").unwrap();
+        ring.rotate("lights");
+
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(ring.decrypt(&old).is_err());
+
+        ring.prune();
+        assert_eq!(ring.current("lights").map(|(e, _)| e), Some(2));
+    }
+
+    #[test]
+    fn test_installed_key_interoperates() {
+        let mut sender = KeyRing::new(Duration::from_secs(60));
+        let mut receiver = KeyRing::new(Duration::from_secs(60));
+
+        let (epoch, key) = sender.rotate(CONTEXT_KEY_SCOPE);
+        assert!(receiver.install(CONTEXT_KEY_SCOPE, epoch, key));
+        assert!(!receiver.install(CONTEXT_KEY_SCOPE, epoch, key));
+
+        let sealed = sender.encrypt(CONTEXT_KEY_SCOPE, b"secret").unwrap();
+        assert_eq!(receiver.decrypt(&sealed).unwrap(), b"secret");
+    }
+}
@@ -8,23 +8,79 @@
 //! - Kademlia Mesh Networking
 //! - Zero libp2p type exposure
 
+pub mod audit;
+pub mod auth;
 mod behaviour;
 pub mod bridge;
+mod bridge_registry;
+pub mod codec;
 mod command;
 pub mod config;
+mod dedupe;
 mod error;
 pub mod events;
+mod expiry;
+pub mod extension;
+mod health;
+mod history;
+pub mod interceptor;
+mod invitation;
+pub mod keys;
 mod node;
+mod outbox;
+mod pairing;
 mod protocols;
+mod queue;
+mod ratelimit;
+mod recording;
+mod reputation;
 mod runtime;
+#[cfg(feature = "memory-transport")]
+pub mod sim;
+mod snapshot;
+pub mod topic;
+mod view;
 
-pub use bridge::{BridgeConfig, EmbeddedBridge};
-pub use config::AviP2pConfig;
+pub use audit::{AuditAction, AuditConfig, AuditEntry, AuditQuery};
+pub use auth::{
+    AuthConfig, AuthorizationPolicy, HouseholdCa, MembershipCertificate, Operation, Role,
+};
+pub use bridge::{
+    bridged_stream_device, bridged_stream_reason, BridgeBinding, BridgeConfig, BridgeContext,
+    BridgeHandle, BridgeHandler, DeviceStats, EmbeddedBridge, HandlerOutcome, TemplateTopicMapper,
+    TopicMapper, CAPABILITY_TARGET_PREFIX,
+};
+pub use bridge_registry::{BridgeRegistration, BridgeRegistry};
+pub use config::{
+    compact_topic_id, AviP2pConfig, ContextReplication, GossipTuning, IdentitySecret, KadConfig,
+    KadMode, NodeRole, ProtocolLimits, RateLimit, RendezvousConfig, SecurityProtocol, TopicProfile,
+    TransportKind,
+};
 pub use error::{AviP2pError, StreamCloseReason};
-pub use events::{AviEvent, PeerId};
-pub use node::{AviP2p, AviP2pHandle};
+pub use events::{AviEvent, CorrelationId, DhtEntryKind, PeerId, PeerInfo};
+pub use extension::{Extension, ExtensionHandler, ExtensionProtocol};
+pub use health::{ChannelUsage, HealthReport, RuntimeStats};
+pub use interceptor::{InboundInterceptor, InterceptScope, OutboundInterceptor, OutboundTarget};
+pub use invitation::{MeshInvitation, NetworkKey, INVITATION_FORMAT};
+pub use keys::{EncryptedPayload, CONTEXT_KEY_SCOPE};
+pub use node::{AviP2p, AviP2pHandle, ContextTransaction};
+pub use outbox::OutboxConfig;
+pub use pairing::PairingPayload;
 pub use protocols::context::{delete_nested_value, set_nested_value};
-pub use protocols::context::{AviContext, VectorClock};
+pub use protocols::context::{diff_context, AviContext, ContextChange, ContextOp, VectorClock};
+pub use protocols::crdt::{CollectionKind, OrCollection, PnCounter};
+pub use protocols::secure::{is_secure_reason, secure_reason};
 pub use protocols::stream::{
-    generate_stream_id, StreamDirection, StreamId, StreamState, StreamStatus,
+    generate_stream_id, negotiate_stream_version, StreamDirection, StreamId, StreamState,
+    StreamStatus, MIN_STREAM_VERSION, STREAM_VERSION,
+};
+pub use queue::{
+    EventClass, EventSubscription, OverflowPolicies, OverflowPolicy, StreamEventSubscription,
 };
+pub use recording::{read_recording, RecordedChunk};
+pub use reputation::{ReputationConfig, Violation};
+#[cfg(feature = "memory-transport")]
+pub use sim::SimNetwork;
+pub use snapshot::{NodeSnapshot, SNAPSHOT_FORMAT};
+pub use topic::{DeviceTopics, Topic};
+pub use view::{ContextProjection, ContextView};
@@ -1,18 +1,43 @@
+use crate::audit::{AuditEntry, AuditLog, AuditQuery};
+use crate::auth::{HouseholdCa, MembershipCertificate, Role};
 use crate::behaviour::AviBehaviour;
-use crate::command::Command;
-use crate::config::AviP2pConfig;
+use crate::bridge_registry::BridgeRegistry;
+use crate::command::{self, Command, CommandSender};
+use crate::config::{AviP2pConfig, GossipTuning, SecurityProtocol, TopicProfile, TransportKind};
 use crate::error::AviP2pError;
-use crate::events::{AviEvent, PeerId};
+use crate::events::{AviEvent, CorrelationId, PeerId, PeerInfo};
+use crate::extension::Extension;
+use crate::health::{HealthReport, RuntimeStats};
+use crate::interceptor::{
+    InboundInterceptor, InterceptScope, Interceptors, OutboundInterceptor, OutboundTarget,
+};
+use crate::invitation::MeshInvitation;
+use crate::keys::EncryptedPayload;
+use crate::outbox::Outbox;
+use crate::pairing::{self, PairingPayload};
+use crate::protocols::context::ContextOp;
+use crate::protocols::crdt::CollectionKind;
+use crate::protocols::secure::secure_reason;
+use crate::protocols::stream::AviStreamProtocol;
+use crate::queue::{self, Dispatcher, EventSubscription, StreamEventSubscription};
 use crate::runtime::Runtime;
+use crate::snapshot::NodeSnapshot;
+use crate::view::ContextView;
 use crate::StreamId;
 use tokio::sync::{mpsc, oneshot};
 
-use libp2p::{gossipsub, identity::Keypair, noise, tcp, yamux, Multiaddr, SwarmBuilder};
+use futures::StreamExt;
+use libp2p::core::muxing::StreamMuxerBox;
+use libp2p::{
+    gossipsub, identity::Keypair, noise, tcp, tls, yamux, Multiaddr, Swarm, SwarmBuilder,
+};
 use serde_json::Value;
+use std::collections::VecDeque;
+use std::path::Path;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
-use tokio::sync::broadcast;
+use std::time::{Duration, SystemTime};
 
 /// Main entry point for the AVI P2P node.
 pub struct AviP2p {
@@ -23,61 +48,247 @@ pub struct AviP2p {
 /// Cloneable handle for interacting with the P2P node.
 #[derive(Clone)]
 pub struct AviP2pHandle {
-    command_tx: mpsc::Sender<Command>,
-    event_broadcast: Arc<broadcast::Sender<AviEvent>>,
+    command_tx: CommandSender,
+    dispatcher: Dispatcher,
+    bridges: BridgeRegistry,
+    bridge_rejected_hellos: Arc<AtomicU64>,
+    context_fetch_timeout: Duration,
+    max_publish_size: usize,
+    max_stream_chunk_size: usize,
+    extensions: Arc<Vec<String>>,
+    interceptors: Interceptors,
 }
 
 impl AviP2pHandle {
     /// Subscribe to events from the P2P network
-    /// Multiple subscribers can listen independently
-    pub async fn subscribe_events(&self) -> Result<broadcast::Receiver<AviEvent>, String> {
-        Ok(self.event_broadcast.subscribe())
+    /// Multiple subscribers can listen independently; each has its own
+    /// bounded queue, so a slow subscriber never delays the others
+    pub async fn subscribe_events(&self) -> Result<EventSubscription, String> {
+        Ok(self.dispatcher.subscribe())
+    }
+
+    /// Stream accept/reject/timeout, data and close events on a queue that
+    /// never drops, for consumers keeping per-stream state such as the bridge
+    pub fn subscribe_stream_events(&self) -> StreamEventSubscription {
+        self.dispatcher.subscribe_streams()
+    }
+
+    /// Structured liveness report for watchdogs and health probes.
+    /// Never fails: an unresponsive runtime is reported as `runtime_alive: false`.
+    pub async fn health(&self) -> HealthReport {
+        let (tx, rx) = oneshot::channel();
+        let probe = async {
+            self.command_tx
+                .send(Command::GetHealth { respond_to: tx })
+                .await
+                .map_err(|_| AviP2pError::ChannelClosed)?;
+            rx.await.map_err(|_| AviP2pError::ChannelClosed)?
+        };
+
+        let mut report = match tokio::time::timeout(HEALTH_PROBE_TIMEOUT, probe).await {
+            Ok(Ok(report)) => report,
+            _ => HealthReport::default(),
+        };
+
+        report.bridge_sessions = self.bridges.sessions();
+        report.bridge_rejected_hellos = self.bridge_rejected_hellos.load(Ordering::Relaxed);
+        report.command_queue = self.command_tx.usage();
+        report.subscriber_queues = self.dispatcher.usage();
+        report
+    }
+
+    /// Runtime-internal gauges for diagnosing saturation
+    pub async fn runtime_stats(&self) -> Result<RuntimeStats, AviP2pError> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::GetRuntimeStats { respond_to: tx })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        let mut stats = rx.await.map_err(|_| AviP2pError::ChannelClosed)??;
+        stats.command_queue = self.command_tx.usage();
+        Ok(stats)
+    }
+
+    /// Run `interceptor` on every event before subscribers receive it,
+    /// after any interceptors added earlier
+    pub fn add_inbound_interceptor(&self, interceptor: impl InboundInterceptor + 'static) {
+        self.interceptors.add_inbound(Arc::new(interceptor));
+    }
+
+    /// Run `interceptor` on payloads sent with `publish` (in `scope`) or
+    /// `send_stream_data` (global scope only), after any added earlier
+    pub fn add_outbound_interceptor(
+        &self,
+        scope: InterceptScope,
+        interceptor: impl OutboundInterceptor + 'static,
+    ) {
+        self.interceptors.add_outbound(scope, Arc::new(interceptor));
+    }
+
+    /// Bridges fronting devices through this node; transports other than
+    /// `EmbeddedBridge` register here to share its device ids and dedupe
+    pub fn bridges(&self) -> BridgeRegistry {
+        self.bridges.clone()
+    }
+
+    pub(crate) fn record_rejected_hello(&self) {
+        self.bridge_rejected_hellos.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) async fn emit_event(&self, event: AviEvent) {
+        let _ = self.command_tx.send(Command::EmitEvent { event }).await;
     }
 }
 
+const HEALTH_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Chunk sends `send_stream_data_all` has outstanding at once
+const STREAM_WRITE_WINDOW: usize = 32;
+
 impl AviP2p {
     /// Create and start the P2P node.
     pub async fn start(
         config: AviP2pConfig,
     ) -> Result<(AviP2p, mpsc::Receiver<AviEvent>), AviP2pError> {
-        let local_key = Keypair::generate_ed25519();
+        let local_key = identity_keypair(&config)?;
+        let gossip = gossip_configs(&config)?;
 
-        let swarm = SwarmBuilder::with_existing_identity(local_key.clone())
-            .with_tokio()
-            .with_tcp(
-                tcp::Config::default(),
-                noise::Config::new,
-                yamux::Config::default,
-            )
-            .map_err(|e| AviP2pError::NetworkError(e.to_string()))?
-            .with_dns()
-            .map_err(|e| AviP2pError::NetworkError(e.to_string()))?
-            .with_behaviour(|key| {
-                let gossip_config = gossipsub::ConfigBuilder::default()
-                    .heartbeat_interval(Duration::from_secs(1))
-                    .validation_mode(gossipsub::ValidationMode::Strict)
-                    .max_transmit_size(1024 * 1024)
-                    .allow_self_origin(true)
+        // Each TCP security choice is a different upgrade type, hence the macro
+        macro_rules! tcp_swarm {
+            ($security:expr) => {
+                SwarmBuilder::with_existing_identity(local_key.clone())
+                    .with_tokio()
+                    .with_tcp(tcp::Config::default(), $security, yamux::Config::default)
+                    .map_err(|e| AviP2pError::NetworkError(e.to_string()))?
+                    .with_quic()
+                    .with_other_transport(websocket_transport)
+                    .map_err(|e| AviP2pError::NetworkError(e.to_string()))?
+                    .with_dns()
+                    .map_err(|e| AviP2pError::NetworkError(e.to_string()))?
+                    .with_behaviour(|key| {
+                        build_behaviour(key, &config, gossip.clone(), config.enable_mdns)
+                    })
+                    .map_err(|e| AviP2pError::NetworkError(e.to_string()))?
+                    .with_swarm_config(|c| swarm_config(c, &config))
                     .build()
-                    .expect("Valid gossipsub config");
+            };
+        }
+
+        let swarm = match config.security {
+            SecurityProtocol::Noise => tcp_swarm!(noise::Config::new),
+            SecurityProtocol::Tls => tcp_swarm!(tls::Config::new),
+            SecurityProtocol::TlsOrNoise => tcp_swarm!((tls::Config::new, noise::Config::new)),
+        };
+
+        let mut listen_addrs = Vec::new();
+        for kind in &config.transports {
+            let addr = match kind {
+                TransportKind::Tcp => format!("/ip4/0.0.0.0/tcp/{}", config.listen_port),
+                TransportKind::Quic => format!("/ip4/0.0.0.0/udp/{}/quic-v1", config.quic_port),
+                TransportKind::WebSocket => {
+                    if cfg!(not(feature = "websocket")) {
+                        return Err(AviP2pError::Config(
+                            "WebSocket transport requires the `websocket` feature".to_string(),
+                        ));
+                    }
+                    format!("/ip4/0.0.0.0/tcp/{}/ws", config.websocket_port)
+                }
+            };
+            listen_addrs.push(
+                addr.parse().map_err(|e: libp2p::multiaddr::Error| {
+                    AviP2pError::NetworkError(e.to_string())
+                })?,
+            );
+        }
+
+        Self::launch(swarm, local_key, config, listen_addrs)
+    }
+
+    /// Join a mesh from a scanned pairing payload: a short-lived node
+    /// fetches the invitation, then the node is started from it (see
+    /// `AviP2pConfig::from_invitation`).
+    pub async fn join_via_pairing(
+        node_name: &str,
+        payload: &PairingPayload,
+    ) -> Result<(AviP2p, mpsc::Receiver<AviEvent>), AviP2pError> {
+        let mut pairing_config = AviP2pConfig::new(node_name);
+        pairing_config.mesh_namespace = payload.mesh_namespace.clone();
+        pairing_config.enable_mdns = false;
+        pairing_config.enable_kad = false;
+        let (pairing_node, _events) = Self::start(pairing_config).await?;
+        let invitation = pairing_node.handle().fetch_invitation(payload).await;
+        pairing_node.shutdown().await?;
+
+        let config = AviP2pConfig::from_invitation(node_name, &invitation?.to_bytes()?)?;
+        Self::start(config).await
+    }
+
+    /// Start a node on libp2p's in-process memory transport, for hermetic tests.
+    /// It listens on `/memory/<listen_port>` (random if 0) and never uses mDNS;
+    /// connect nodes by putting e.g. `/memory/1` in `bootstrap_peers`.
+    #[cfg(feature = "memory-transport")]
+    pub async fn start_in_memory(
+        config: AviP2pConfig,
+    ) -> Result<(AviP2p, mpsc::Receiver<AviEvent>), AviP2pError> {
+        let port = match config.listen_port {
+            0 => rand::random::<u64>(),
+            port => port as u64,
+        };
+        Self::start_memory(config, port, None)
+    }
+
+    #[cfg(feature = "memory-transport")]
+    pub(crate) fn start_memory(
+        config: AviP2pConfig,
+        port: u64,
+        conditions: Option<crate::sim::SharedConditions>,
+    ) -> Result<(AviP2p, mpsc::Receiver<AviEvent>), AviP2pError> {
+        use crate::sim::SimLink;
+        use libp2p::core::{transport::MemoryTransport, upgrade, Transport};
+
+        let local_key = identity_keypair(&config)?;
+        let gossip = gossip_configs(&config)?;
 
-                AviBehaviour::new(key.clone(), gossip_config, config.node_name.clone())
+        let swarm = SwarmBuilder::with_existing_identity(local_key.clone())
+            .with_tokio()
+            .with_other_transport(|key| {
+                Ok(MemoryTransport::default()
+                    .and_then(move |conn, endpoint| {
+                        futures::future::ready(SimLink::establish(
+                            conn, port, &endpoint, conditions,
+                        ))
+                    })
+                    .upgrade(upgrade::Version::V1)
+                    .authenticate(noise::Config::new(key)?)
+                    .multiplex(yamux::Config::default()))
             })
             .map_err(|e| AviP2pError::NetworkError(e.to_string()))?
-            .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(86400)))
+            .with_behaviour(|key| build_behaviour(key, &config, gossip, false))
+            .map_err(|e| AviP2pError::NetworkError(e.to_string()))?
+            .with_swarm_config(|c| swarm_config(c, &config))
             .build();
 
-        let listen_addr: Multiaddr = format!("/ip4/0.0.0.0/tcp/{}", config.listen_port)
+        let listen_addr: Multiaddr = format!("/memory/{}", port)
             .parse()
             .map_err(|e: libp2p::multiaddr::Error| AviP2pError::NetworkError(e.to_string()))?;
 
-        let mut swarm = swarm;
-        swarm
-            .listen_on(listen_addr)
-            .map_err(|e| AviP2pError::NetworkError(e.to_string()))?;
+        Self::launch(swarm, local_key, config, vec![listen_addr])
+    }
+
+    fn launch(
+        mut swarm: Swarm<AviBehaviour>,
+        local_key: Keypair,
+        config: AviP2pConfig,
+        listen_addrs: Vec<Multiaddr>,
+    ) -> Result<(AviP2p, mpsc::Receiver<AviEvent>), AviP2pError> {
+        for addr in listen_addrs {
+            swarm
+                .listen_on(addr)
+                .map_err(|e| AviP2pError::NetworkError(e.to_string()))?;
+        }
 
-        for addr_str in config.bootstrap_peers {
-            if let Ok(ma) = Multiaddr::from_str(&addr_str) {
+        for addr_str in &config.bootstrap_peers {
+            if let Ok(ma) = Multiaddr::from_str(addr_str) {
                 if let Some(peer_id) = extract_peer_id_from_multiaddr(&ma) {
                     swarm.behaviour_mut().kad.add_address(&peer_id, ma.clone());
                 }
@@ -88,14 +299,43 @@ impl AviP2p {
             }
         }
 
-        let (command_tx, command_rx) = mpsc::channel(100);
-        let (event_tx, mut event_rx) = mpsc::channel(100);
+        if let Some(rendezvous) = &config.rendezvous {
+            for addr_str in &rendezvous.points {
+                let ma = Multiaddr::from_str(addr_str)
+                    .map_err(|e| AviP2pError::Config(format!("rendezvous point: {}", e)))?;
+                let peer_id = extract_peer_id_from_multiaddr(&ma).ok_or_else(|| {
+                    AviP2pError::Config(format!("rendezvous point {} has no /p2p/ id", addr_str))
+                })?;
+                swarm.behaviour_mut().kad.add_address(&peer_id, ma.clone());
+                if let Err(e) = swarm.dial(ma) {
+                    eprintln!("Warning: Failed to dial rendezvous point: {}", e);
+                }
+            }
+        }
+
+        let mut extension_names = std::collections::HashSet::new();
+        for extension in &config.extensions {
+            if !extension_names.insert(&extension.name) {
+                return Err(AviP2pError::Config(format!(
+                    "extension protocol {} registered twice",
+                    extension.name
+                )));
+            }
+        }
+
+        let (command_tx, command_rx) = command::command_channel(config.command_channel_capacity);
+        let (event_tx, mut event_rx) =
+            queue::event_queue(config.event_channel_capacity, config.event_overflow);
         let (shutdown_tx, shutdown_rx) = oneshot::channel();
 
-        let (event_broadcast, _) = broadcast::channel(1000);
-        let event_broadcast = Arc::new(event_broadcast);
+        let dispatcher = Dispatcher::new(config.subscriber_queue_capacity, config.event_overflow);
 
-        let runtime = Runtime::new(swarm, command_rx, event_tx);
+        let audit = config.audit.clone().map(AuditLog::open).transpose()?;
+        let outbox = config.outbox.clone().map(Outbox::open).transpose()?;
+
+        let runtime = Runtime::new(
+            swarm, local_key, &config, audit, outbox, command_rx, event_tx,
+        );
         tokio::spawn(async move {
             tokio::select! {
                 _ = runtime.run() => {},
@@ -105,18 +345,35 @@ impl AviP2p {
 
         let handle = AviP2pHandle {
             command_tx,
-            event_broadcast: event_broadcast.clone(),
+            dispatcher: dispatcher.clone(),
+            bridges: BridgeRegistry::default(),
+            bridge_rejected_hellos: Arc::new(AtomicU64::new(0)),
+            context_fetch_timeout: config.context_fetch_timeout,
+            max_publish_size: config.max_publish_size,
+            max_stream_chunk_size: config.max_stream_chunk_size,
+            extensions: Arc::new(config.extensions.iter().map(|e| e.name.clone()).collect()),
+            interceptors: Interceptors::default(),
         };
+        let interceptors = handle.interceptors.clone();
 
-        let (user_event_tx, user_event_rx) = mpsc::channel(100);
+        // The receiver returned from `start` is just another subscriber
+        let mut user_events = dispatcher.subscribe();
+        let (user_event_tx, user_event_rx) = mpsc::channel(config.event_channel_capacity.max(1));
+        tokio::spawn(async move {
+            while let Some(event) = user_events.recv().await {
+                if user_event_tx.send(event).await.is_err() {
+                    break;
+                }
+            }
+        });
 
-        let broadcast_clone = event_broadcast.clone();
         tokio::spawn(async move {
             while let Some(event) = event_rx.recv().await {
-                let _ = broadcast_clone.send(event.clone());
-
-                let _ = user_event_tx.send(event).await;
+                if let Some(event) = interceptors.inbound(event).await {
+                    dispatcher.dispatch(event);
+                }
             }
+            dispatcher.close();
         });
 
         let node = AviP2p {
@@ -164,12 +421,246 @@ impl AviP2pHandle {
         rx.await.map_err(|_| AviP2pError::ChannelClosed)?
     }
 
+    /// Topics this node is subscribed to, sorted
+    pub async fn subscriptions(&self) -> Result<Vec<String>, AviP2pError> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::GetSubscriptions { respond_to: tx })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        rx.await.map_err(|_| AviP2pError::ChannelClosed)?
+    }
+
+    /// Drop every topic subscription
+    pub async fn unsubscribe_all(&self) -> Result<(), AviP2pError> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::UnsubscribeAll { respond_to: tx })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        rx.await.map_err(|_| AviP2pError::ChannelClosed)?
+    }
+
     pub async fn publish(&self, topic: &str, data: Vec<u8>) -> Result<(), AviP2pError> {
+        let data = self
+            .interceptors
+            .outbound(
+                OutboundTarget::Publish {
+                    topic: topic.to_string(),
+                },
+                data,
+            )
+            .await?;
+        check_payload_size(self.max_publish_size, &data)?;
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::Publish {
+                topic: topic.to_string(),
+                data,
+                message_id: None,
+                respond_to: tx,
+            })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        rx.await.map_err(|_| AviP2pError::ChannelClosed)?
+    }
+
+    /// Publish several messages through one command, e.g. a sensor batch
+    /// fanned out to a topic per reading. Returns one result per message,
+    /// in order; a failed message does not stop the rest.
+    pub async fn publish_batch(
+        &self,
+        messages: Vec<(String, Vec<u8>)>,
+    ) -> Result<Vec<Result<(), AviP2pError>>, AviP2pError> {
+        let mut results = Vec::with_capacity(messages.len());
+        let mut accepted = Vec::with_capacity(messages.len());
+        for (topic, data) in messages {
+            let checked = async {
+                let data = self
+                    .interceptors
+                    .outbound(
+                        OutboundTarget::Publish {
+                            topic: topic.clone(),
+                        },
+                        data,
+                    )
+                    .await?;
+                check_payload_size(self.max_publish_size, &data)?;
+                Ok(data)
+            }
+            .await;
+            match checked {
+                Ok(data) => {
+                    accepted.push((topic, data));
+                    results.push(None);
+                }
+                Err(e) => results.push(Some(Err(e))),
+            }
+        }
+
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::PublishBatch {
+                messages: accepted,
+                respond_to: tx,
+            })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        let mut sent = rx
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?
+            .into_iter();
+
+        // Slot the runtime's results in between the ones refused up front
+        Ok(results
+            .into_iter()
+            .map(|r| r.unwrap_or_else(|| sent.next().unwrap_or(Err(AviP2pError::ChannelClosed))))
+            .collect())
+    }
+
+    /// Publish several messages as one change, e.g. a scene spread over
+    /// several topics: either every message is handed to gossip or none
+    /// is. A message refused up front (by an interceptor, the size limit,
+    /// authorization, a rate limit, or for lack of peers on its topic)
+    /// fails the call with `AviP2pError::PublishRolledBack` naming it.
+    pub async fn publish_atomic(
+        &self,
+        messages: Vec<(String, Vec<u8>)>,
+    ) -> Result<(), AviP2pError> {
+        let mut checked = Vec::with_capacity(messages.len());
+        for (index, (topic, data)) in messages.into_iter().enumerate() {
+            let data = async {
+                let data = self
+                    .interceptors
+                    .outbound(
+                        OutboundTarget::Publish {
+                            topic: topic.clone(),
+                        },
+                        data,
+                    )
+                    .await?;
+                check_payload_size(self.max_publish_size, &data)?;
+                Ok(data)
+            }
+            .await
+            .map_err(|reason| AviP2pError::PublishRolledBack {
+                index,
+                topic: topic.clone(),
+                reason: Box::new(reason),
+            })?;
+            checked.push((topic, data));
+        }
+
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::PublishAtomic {
+                messages: checked,
+                respond_to: tx,
+            })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        rx.await.map_err(|_| AviP2pError::ChannelClosed)?
+    }
+
+    /// Publish `data` on `topic` once `delay` has passed, e.g. "turn off
+    /// in 10 minutes". The runtime holds the message, so the call returns
+    /// straight away and dropping the calling task does not cancel it;
+    /// `cancel_scheduled_publish` with the returned id does. A publish
+    /// that fails when due is reported as `AviEvent::ScheduledPublishFailed`.
+    pub async fn publish_after(
+        &self,
+        topic: &str,
+        data: Vec<u8>,
+        delay: Duration,
+    ) -> Result<CorrelationId, AviP2pError> {
+        let data = self
+            .interceptors
+            .outbound(
+                OutboundTarget::Publish {
+                    topic: topic.to_string(),
+                },
+                data,
+            )
+            .await?;
+        check_payload_size(self.max_publish_size, &data)?;
+        let correlation_id = CorrelationId::next();
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::SchedulePublish {
+                topic: topic.to_string(),
+                data,
+                delay,
+                correlation_id,
+                respond_to: tx,
+            })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        rx.await.map_err(|_| AviP2pError::ChannelClosed)??;
+        Ok(correlation_id)
+    }
+
+    /// `publish_after` at a wall-clock time; a time already past publishes
+    /// right away. Later changes to the system clock do not move it.
+    pub async fn publish_at(
+        &self,
+        topic: &str,
+        data: Vec<u8>,
+        when: SystemTime,
+    ) -> Result<CorrelationId, AviP2pError> {
+        let delay = when
+            .duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO);
+        self.publish_after(topic, data, delay).await
+    }
+
+    /// Drop a publish scheduled with `publish_after` or `publish_at`.
+    /// Returns false if it was already sent (or failed).
+    pub async fn cancel_scheduled_publish(
+        &self,
+        correlation_id: CorrelationId,
+    ) -> Result<bool, AviP2pError> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::CancelScheduledPublish {
+                correlation_id,
+                respond_to: tx,
+            })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        rx.await.map_err(|_| AviP2pError::ChannelClosed)
+    }
+
+    /// Publish with an application message id. Receivers with a
+    /// `dedupe_window` deliver only the first copy, so a publisher unsure
+    /// whether a message went out can safely send it again with the same id.
+    pub async fn publish_with_id(
+        &self,
+        topic: &str,
+        data: Vec<u8>,
+        message_id: &str,
+    ) -> Result<(), AviP2pError> {
+        if message_id.len() > crate::dedupe::MAX_MESSAGE_ID_LEN {
+            return Err(AviP2pError::Serialization(format!(
+                "message id longer than {} bytes",
+                crate::dedupe::MAX_MESSAGE_ID_LEN
+            )));
+        }
+        let data = self
+            .interceptors
+            .outbound(
+                OutboundTarget::Publish {
+                    topic: topic.to_string(),
+                },
+                data,
+            )
+            .await?;
+        check_payload_size(self.max_publish_size, &data)?;
         let (tx, rx) = oneshot::channel();
         self.command_tx
             .send(Command::Publish {
                 topic: topic.to_string(),
                 data,
+                message_id: Some(message_id.to_string()),
                 respond_to: tx,
             })
             .await
@@ -177,28 +668,123 @@ impl AviP2pHandle {
         rx.await.map_err(|_| AviP2pError::ChannelClosed)?
     }
 
+    /// Ask a peer to open a stream. Returns as soon as the request is sent;
+    /// the outcome arrives as `StreamAccepted`, `StreamRejected` or, after
+    /// `stream_open_timeout` without an answer, `StreamOpenTimeout`.
     pub async fn request_stream(
         &self,
         peer_id: PeerId,
         reason: String,
+    ) -> Result<StreamId, AviP2pError> {
+        self.request_stream_correlated(peer_id, reason, CorrelationId::next())
+            .await
+    }
+
+    /// Like `request_stream`, with the id the resulting `StreamAccepted`,
+    /// `StreamRejected` or `StreamOpenTimeout` event will carry. Take it
+    /// from `CorrelationId::next()` and note it before calling, as the
+    /// event may be handled before this returns.
+    pub async fn request_stream_correlated(
+        &self,
+        peer_id: PeerId,
+        reason: String,
+        correlation_id: CorrelationId,
     ) -> Result<StreamId, AviP2pError> {
         let (tx, rx) = oneshot::channel();
         self.command_tx
             .send(Command::RequestStream {
                 peer_id,
                 reason,
+                correlation_id,
                 respond_to: tx,
+                on_open: None,
             })
             .await
             .map_err(|_| AviP2pError::ChannelClosed)?;
         rx.await.map_err(|_| AviP2pError::ChannelClosed)?
     }
 
-    pub async fn accept_stream(&self, stream_id: StreamId) -> Result<(), AviP2pError> {
+    /// Open a stream to `target` through `relay`, a peer both sides can
+    /// reach with `stream_relay` enabled. The stream id is valid toward the
+    /// relay; data, acceptance and closing pass through transparently.
+    pub async fn request_stream_via(
+        &self,
+        relay: PeerId,
+        target: &PeerId,
+        reason: &str,
+    ) -> Result<StreamId, AviP2pError> {
+        let reason = format!("{}{}:{}", crate::runtime::RELAY_PREFIX, target, reason);
+        self.request_stream(relay, reason).await
+    }
+
+    /// Like `request_stream`, but waits until the peer accepts. Fails with
+    /// `StreamRejected` or, for an unresponsive peer, `StreamOpenTimeout`.
+    pub async fn open_stream(
+        &self,
+        peer_id: PeerId,
+        reason: String,
+    ) -> Result<StreamId, AviP2pError> {
+        let (tx, rx) = oneshot::channel();
+        let (open_tx, open_rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::RequestStream {
+                peer_id,
+                reason,
+                correlation_id: CorrelationId::next(),
+                respond_to: tx,
+                on_open: Some(open_tx),
+            })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        rx.await.map_err(|_| AviP2pError::ChannelClosed)??;
+        open_rx.await.map_err(|_| AviP2pError::ChannelClosed)?
+    }
+
+    /// Like `open_stream`, with the payloads end-to-end encrypted between
+    /// this node and `peer_id`. Returns once the peer has accepted and
+    /// proven its identity inside the stream.
+    pub async fn open_secure_stream(
+        &self,
+        peer_id: PeerId,
+        reason: &str,
+    ) -> Result<StreamId, AviP2pError> {
+        self.open_stream(peer_id, secure_reason(reason)).await
+    }
+
+    /// An end-to-end encrypted stream to `target` through `relay`. The relay
+    /// only forwards sealed chunks, and the stream fails to open unless the
+    /// far end proves to be `target`.
+    pub async fn open_secure_stream_via(
+        &self,
+        relay: PeerId,
+        target: &PeerId,
+        reason: &str,
+    ) -> Result<StreamId, AviP2pError> {
+        let reason = format!(
+            "{}{}:{}",
+            crate::runtime::RELAY_PREFIX,
+            target,
+            secure_reason(reason)
+        );
+        self.open_stream(relay, reason).await
+    }
+
+    /// Accept a requested stream. `payload` goes back with the acceptance
+    /// and reaches the opener on its `StreamAccepted` event; on an `e2e`
+    /// stream it is sealed and arrives as the first data chunk instead.
+    pub async fn accept_stream(
+        &self,
+        stream_id: StreamId,
+        payload: Option<Vec<u8>>,
+    ) -> Result<(), AviP2pError> {
+        if let Some(payload) = &payload {
+            check_payload_size(self.max_stream_chunk_size, payload)?;
+        }
         let (tx, rx) = oneshot::channel();
         self.command_tx
             .send(Command::AcceptStream {
                 stream_id,
+                payload,
                 respond_to: tx,
             })
             .await
@@ -228,6 +814,11 @@ impl AviP2pHandle {
         stream_id: StreamId,
         data: Vec<u8>,
     ) -> Result<(), AviP2pError> {
+        let data = self
+            .interceptors
+            .outbound(OutboundTarget::StreamData { stream_id }, data)
+            .await?;
+        check_payload_size(self.max_stream_chunk_size, &data)?;
         let (tx, rx) = oneshot::channel();
         self.command_tx
             .send(Command::SendStreamData {
@@ -240,6 +831,51 @@ impl AviP2pHandle {
         rx.await.map_err(|_| AviP2pError::ChannelClosed)?
     }
 
+    /// Send every chunk of `chunks` on the stream, keeping up to
+    /// `STREAM_WRITE_WINDOW` sends in flight instead of waiting on each.
+    /// Resolves once all are handed to the network, with the bytes sent,
+    /// or with the first error (later chunks are then not sent).
+    pub async fn send_stream_data_all<S>(
+        &self,
+        stream_id: StreamId,
+        chunks: S,
+    ) -> Result<u64, AviP2pError>
+    where
+        S: futures::Stream<Item = Vec<u8>>,
+    {
+        let mut chunks = std::pin::pin!(chunks);
+        let mut in_flight: VecDeque<oneshot::Receiver<Result<(), AviP2pError>>> =
+            VecDeque::with_capacity(STREAM_WRITE_WINDOW);
+        let mut sent = 0;
+        while let Some(data) = chunks.next().await {
+            let data = self
+                .interceptors
+                .outbound(OutboundTarget::StreamData { stream_id }, data)
+                .await?;
+            check_payload_size(self.max_stream_chunk_size, &data)?;
+            if in_flight.len() == STREAM_WRITE_WINDOW {
+                if let Some(rx) = in_flight.pop_front() {
+                    rx.await.map_err(|_| AviP2pError::ChannelClosed)??;
+                }
+            }
+            sent += data.len() as u64;
+            let (tx, rx) = oneshot::channel();
+            self.command_tx
+                .send(Command::SendStreamData {
+                    stream_id,
+                    data,
+                    respond_to: tx,
+                })
+                .await
+                .map_err(|_| AviP2pError::ChannelClosed)?;
+            in_flight.push_back(rx);
+        }
+        for rx in in_flight {
+            rx.await.map_err(|_| AviP2pError::ChannelClosed)??;
+        }
+        Ok(sent)
+    }
+
     pub async fn close_stream(&self, stream_id: StreamId) -> Result<(), AviP2pError> {
         let (tx, rx) = oneshot::channel();
         self.command_tx
@@ -252,6 +888,40 @@ impl AviP2pHandle {
         rx.await.map_err(|_| AviP2pError::ChannelClosed)?
     }
 
+    /// Handshake version agreed for an accepted stream (the version offered
+    /// while it is still being opened)
+    pub async fn stream_version(&self, stream_id: StreamId) -> Result<u32, AviP2pError> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::GetStreamVersion {
+                stream_id,
+                respond_to: tx,
+            })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        rx.await.map_err(|_| AviP2pError::ChannelClosed)?
+    }
+
+    /// Tee the stream's data in both directions to `path`, with a timestamp
+    /// index in `<path>.idx`, until the stream closes. Read it back with
+    /// `read_recording`.
+    pub async fn record_stream(
+        &self,
+        stream_id: StreamId,
+        path: impl AsRef<Path>,
+    ) -> Result<(), AviP2pError> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::RecordStream {
+                stream_id,
+                path: path.as_ref().to_path_buf(),
+                respond_to: tx,
+            })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        rx.await.map_err(|_| AviP2pError::ChannelClosed)?
+    }
+
     pub async fn connected_peers(&self) -> Result<Vec<PeerId>, AviP2pError> {
         let (tx, rx) = oneshot::channel();
         self.command_tx
@@ -270,11 +940,28 @@ impl AviP2pHandle {
         rx.await.map_err(|_| AviP2pError::ChannelClosed)?
     }
 
-    pub async fn update_context(&self, patch: Value) -> Result<(), AviP2pError> {
+    /// Client for an extension protocol registered in `AviP2pConfig::extensions`
+    pub fn extension(&self, name: &str) -> Result<Extension, AviP2pError> {
+        if !self.extensions.iter().any(|e| e == name) {
+            return Err(AviP2pError::Config(format!(
+                "no extension protocol named {}",
+                name
+            )));
+        }
+        Ok(Extension {
+            name: name.to_string(),
+            command_tx: self.command_tx.clone(),
+        })
+    }
+
+    /// Ask every connected rendezvous point for the peers registered under
+    /// `namespace` and dial the ones not yet connected. The configured
+    /// namespace is also looked up automatically on `refresh_interval`.
+    pub async fn rendezvous_discover(&self, namespace: &str) -> Result<Vec<PeerId>, AviP2pError> {
         let (tx, rx) = oneshot::channel();
         self.command_tx
-            .send(Command::UpdateSelfContext {
-                patch,
+            .send(Command::RendezvousDiscover {
+                namespace: namespace.to_string(),
                 respond_to: tx,
             })
             .await
@@ -282,11 +969,15 @@ impl AviP2pHandle {
         rx.await.map_err(|_| AviP2pError::ChannelClosed)?
     }
 
-    pub async fn replace_context(&self, data: Value) -> Result<(), AviP2pError> {
+    /// Store `value` under `key` in the DHT. The record is owned by this
+    /// node and republished every `KadConfig::republish_interval` until
+    /// `remove_record`; failures arrive as `AviEvent::DhtPublishFailed`.
+    pub async fn put_record(&self, key: &str, value: Vec<u8>) -> Result<(), AviP2pError> {
         let (tx, rx) = oneshot::channel();
         self.command_tx
-            .send(Command::ReplaceSelfContext {
-                data,
+            .send(Command::PutRecord {
+                key: key.to_string(),
+                value,
                 respond_to: tx,
             })
             .await
@@ -294,10 +985,606 @@ impl AviP2pHandle {
         rx.await.map_err(|_| AviP2pError::ChannelClosed)?
     }
 
-    pub async fn delete_ctx(&self, path: &str) -> Result<(), AviP2pError> {
-        let mut current_ctx = self.get_ctx("").await?;
-        crate::protocols::context::delete_nested_value(&mut current_ctx, path)?;
-        self.replace_context(current_ctx).await
+    /// Stop republishing a record; copies at other peers expire with their TTL
+    pub async fn remove_record(&self, key: &str) -> Result<(), AviP2pError> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::RemoveRecord {
+                key: key.to_string(),
+                respond_to: tx,
+            })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        rx.await.map_err(|_| AviP2pError::ChannelClosed)?
+    }
+
+    /// Announce this node as a provider of `key` (e.g. a capability name),
+    /// refreshed like `put_record` until `stop_providing`
+    pub async fn start_providing(&self, key: &str) -> Result<(), AviP2pError> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::StartProviding {
+                key: key.to_string(),
+                respond_to: tx,
+            })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        rx.await.map_err(|_| AviP2pError::ChannelClosed)?
+    }
+
+    pub async fn stop_providing(&self, key: &str) -> Result<(), AviP2pError> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::StopProviding {
+                key: key.to_string(),
+                respond_to: tx,
+            })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        rx.await.map_err(|_| AviP2pError::ChannelClosed)?
+    }
+
+    /// Invite a new node into the mesh under `role`: a fresh identity with
+    /// its certificate, this node's addresses and the current encryption
+    /// keys, signed by `ca` (see `AviP2pConfig::from_invitation`). The
+    /// invitation is refused after `valid_for` (None = never). Needs
+    /// `AviP2pConfig::auth` set up for the same household.
+    pub async fn create_invitation(
+        &self,
+        ca: &HouseholdCa,
+        role: Role,
+        valid_for: Option<Duration>,
+    ) -> Result<MeshInvitation, AviP2pError> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::MeshDetails { respond_to: tx })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        let mesh = rx.await.map_err(|_| AviP2pError::ChannelClosed)??;
+        Ok(MeshInvitation::issue(ca, mesh, role, valid_for))
+    }
+
+    /// `create_invitation`, held by this node for the first peer that
+    /// presents the returned payload's token within `valid_for`. The
+    /// payload is small enough for a QR code (see `PairingPayload`).
+    pub async fn create_pairing(
+        &self,
+        ca: &HouseholdCa,
+        role: Role,
+        valid_for: Duration,
+    ) -> Result<PairingPayload, AviP2pError> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::MeshDetails { respond_to: tx })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        let mesh = rx.await.map_err(|_| AviP2pError::ChannelClosed)??;
+        let payload = PairingPayload::new(
+            mesh.peer_id.clone(),
+            mesh.listen_addresses.clone(),
+            mesh.mesh_namespace.clone(),
+            pairing::new_token(),
+        );
+        let invitation = MeshInvitation::issue(ca, mesh, role, Some(valid_for));
+
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::HoldPairing {
+                token: payload.token.clone(),
+                invitation: Box::new(invitation),
+                valid_for,
+                respond_to: tx,
+            })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        rx.await.map_err(|_| AviP2pError::ChannelClosed)??;
+        Ok(payload)
+    }
+
+    /// Trade a scanned pairing payload for the invitation it stands for,
+    /// dialing the node that holds it. This node must be in the payload's
+    /// mesh namespace. Gives up with `PeerNotFound` after 10 seconds.
+    pub async fn fetch_invitation(
+        &self,
+        payload: &PairingPayload,
+    ) -> Result<MeshInvitation, AviP2pError> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::FetchInvitation {
+                payload: payload.clone(),
+                respond_to: tx,
+            })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        tokio::time::timeout(pairing::PAIRING_TIMEOUT, rx)
+            .await
+            .map_err(|_| AviP2pError::PeerNotFound(payload.peer_id.clone()))?
+            .map_err(|_| AviP2pError::ChannelClosed)?
+    }
+
+    /// Install this node's household membership certificate (see `HouseholdCa::issue`).
+    /// Connected peers are re-challenged so they pick up the new credentials.
+    pub async fn set_membership_certificate(
+        &self,
+        certificate: MembershipCertificate,
+    ) -> Result<(), AviP2pError> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::SetMembershipCertificate {
+                certificate,
+                respond_to: tx,
+            })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        rx.await.map_err(|_| AviP2pError::ChannelClosed)?
+    }
+
+    /// Identify information a connected peer reported about itself
+    pub async fn peer_info(&self, peer_id: &PeerId) -> Result<PeerInfo, AviP2pError> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::GetPeerInfo {
+                peer_id: peer_id.clone(),
+                respond_to: tx,
+            })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        rx.await.map_err(|_| AviP2pError::ChannelClosed)?
+    }
+
+    /// Send `data` straight to a connected peer, without a topic or stream.
+    /// Resolves once the peer acknowledged it; it arrives as
+    /// `AviEvent::DirectMessage`.
+    pub async fn send_to(&self, peer_id: &PeerId, data: Vec<u8>) -> Result<(), AviP2pError> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::SendTo {
+                peer_id: peer_id.clone(),
+                data,
+                respond_to: tx,
+            })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        rx.await.map_err(|_| AviP2pError::ChannelClosed)?
+    }
+
+    /// Send `data` to a peer, storing it in the outbox while the peer is
+    /// offline. It is delivered (as `AviEvent::DirectMessage`) when the peer
+    /// reconnects, unless `ttl` (or the outbox default) expires first.
+    pub async fn send_via_outbox(
+        &self,
+        peer_id: &PeerId,
+        data: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> Result<(), AviP2pError> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::SendViaOutbox {
+                peer_id: peer_id.clone(),
+                data,
+                ttl,
+                respond_to: tx,
+            })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        rx.await.map_err(|_| AviP2pError::ChannelClosed)?
+    }
+
+    /// Enter low-power mode: drop all connections, stop dialing and
+    /// buffer publishes. Identity, subscriptions and context are kept.
+    pub async fn pause(&self) -> Result<(), AviP2pError> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::Pause { respond_to: tx })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        rx.await.map_err(|_| AviP2pError::ChannelClosed)?
+    }
+
+    /// Leave low-power mode: redial known peers and send buffered publishes
+    /// once the mesh has re-formed
+    pub async fn resume(&self) -> Result<(), AviP2pError> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::Resume { respond_to: tx })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        rx.await.map_err(|_| AviP2pError::ChannelClosed)?
+    }
+
+    /// Disconnect `peer_id` and refuse it for `duration` (None = until
+    /// `unban_peer`), whatever its reputation
+    pub async fn ban_peer(
+        &self,
+        peer_id: &PeerId,
+        duration: Option<Duration>,
+    ) -> Result<(), AviP2pError> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::BanPeer {
+                peer_id: peer_id.clone(),
+                duration,
+                respond_to: tx,
+            })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        rx.await.map_err(|_| AviP2pError::ChannelClosed)?
+    }
+
+    /// Lift a ban, manual or automatic, and clear the peer's violation
+    /// score; false if it was not banned
+    pub async fn unban_peer(&self, peer_id: &PeerId) -> Result<bool, AviP2pError> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::UnbanPeer {
+                peer_id: peer_id.clone(),
+                respond_to: tx,
+            })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        rx.await.map_err(|_| AviP2pError::ChannelClosed)?
+    }
+
+    /// Banned peers with the time left on their ban (None = indefinite)
+    pub async fn banned_peers(&self) -> Result<Vec<(PeerId, Option<Duration>)>, AviP2pError> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::BannedPeers { respond_to: tx })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        rx.await.map_err(|_| AviP2pError::ChannelClosed)?
+    }
+
+    /// Stop delivering messages `peer_id` publishes on `topic` (None =
+    /// every topic), live or replayed. Unlike `ban_peer` the connection
+    /// stays up, so streams, direct messages and context sync with the
+    /// peer carry on, and gossip still relays its messages to others.
+    pub async fn mute_peer(
+        &self,
+        peer_id: &PeerId,
+        topic: Option<&str>,
+    ) -> Result<(), AviP2pError> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::MutePeer {
+                peer_id: peer_id.clone(),
+                topic: topic.map(str::to_string),
+                respond_to: tx,
+            })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        rx.await.map_err(|_| AviP2pError::ChannelClosed)?
+    }
+
+    /// Lift a mute set with the same `topic`; false if there was none.
+    /// Unmuting one topic does not lift an every-topic mute.
+    pub async fn unmute_peer(
+        &self,
+        peer_id: &PeerId,
+        topic: Option<&str>,
+    ) -> Result<bool, AviP2pError> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::UnmutePeer {
+                peer_id: peer_id.clone(),
+                topic: topic.map(str::to_string),
+                respond_to: tx,
+            })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        rx.await.map_err(|_| AviP2pError::ChannelClosed)?
+    }
+
+    /// Muted peers with the topic each mute covers (None = every topic)
+    pub async fn muted_peers(&self) -> Result<Vec<(PeerId, Option<String>)>, AviP2pError> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::MutedPeers { respond_to: tx })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        rx.await.map_err(|_| AviP2pError::ChannelClosed)
+    }
+
+    /// Peers that completed the membership handshake
+    pub async fn authenticated_peers(&self) -> Result<Vec<PeerId>, AviP2pError> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::GetAuthenticatedPeers { respond_to: tx })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        rx.await.map_err(|_| AviP2pError::ChannelClosed)?
+    }
+
+    /// Entries of the local audit log matching `query`, oldest first
+    pub async fn audit_log(&self, query: AuditQuery) -> Result<Vec<AuditEntry>, AviP2pError> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::QueryAudit {
+                query,
+                respond_to: tx,
+            })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        rx.await.map_err(|_| AviP2pError::ChannelClosed)?
+    }
+
+    /// Re-check the audit log hash chain; fails if any entry was altered or removed
+    pub async fn verify_audit_log(&self) -> Result<(), AviP2pError> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::VerifyAudit { respond_to: tx })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        rx.await.map_err(|_| AviP2pError::ChannelClosed)?
+    }
+
+    /// Start a new key epoch for `scope` (a topic name or `CONTEXT_KEY_SCOPE`)
+    /// and distribute it to authorized peers. Returns the new epoch.
+    pub async fn rotate_key(&self, scope: &str) -> Result<u32, AviP2pError> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::RotateKey {
+                scope: scope.to_string(),
+                respond_to: tx,
+            })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        rx.await.map_err(|_| AviP2pError::ChannelClosed)?
+    }
+
+    /// Publish with the topic's current key; subscribers holding the key
+    /// receive the plaintext in `AviEvent::Message`.
+    pub async fn publish_encrypted(&self, topic: &str, data: Vec<u8>) -> Result<(), AviP2pError> {
+        let data = self
+            .interceptors
+            .outbound(
+                OutboundTarget::Publish {
+                    topic: topic.to_string(),
+                },
+                data,
+            )
+            .await?;
+        check_payload_size(self.max_publish_size, &data)?;
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::PublishEncrypted {
+                topic: topic.to_string(),
+                data,
+                respond_to: tx,
+            })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        rx.await.map_err(|_| AviP2pError::ChannelClosed)?
+    }
+
+    pub async fn encrypt(
+        &self,
+        scope: &str,
+        data: Vec<u8>,
+    ) -> Result<EncryptedPayload, AviP2pError> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::Encrypt {
+                scope: scope.to_string(),
+                data,
+                respond_to: tx,
+            })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        rx.await.map_err(|_| AviP2pError::ChannelClosed)?
+    }
+
+    pub async fn decrypt(&self, payload: EncryptedPayload) -> Result<Vec<u8>, AviP2pError> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::Decrypt {
+                payload,
+                respond_to: tx,
+            })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        rx.await.map_err(|_| AviP2pError::ChannelClosed)?
+    }
+
+    /// Seal a context value with the context key so it can be stored via `update_context`
+    pub async fn encrypt_ctx_value(&self, value: &Value) -> Result<Value, AviP2pError> {
+        let plain =
+            serde_json::to_vec(value).map_err(|e| AviP2pError::Serialization(e.to_string()))?;
+        let sealed = self.encrypt(crate::keys::CONTEXT_KEY_SCOPE, plain).await?;
+        serde_json::to_value(sealed).map_err(|e| AviP2pError::Serialization(e.to_string()))
+    }
+
+    /// Open a context value produced by `encrypt_ctx_value`
+    pub async fn decrypt_ctx_value(&self, value: Value) -> Result<Value, AviP2pError> {
+        let sealed: EncryptedPayload =
+            serde_json::from_value(value).map_err(|e| AviP2pError::Serialization(e.to_string()))?;
+        let plain = self.decrypt(sealed).await?;
+        serde_json::from_slice(&plain).map_err(|e| AviP2pError::Serialization(e.to_string()))
+    }
+
+    pub async fn update_context(&self, patch: Value) -> Result<(), AviP2pError> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::UpdateSelfContext {
+                patch,
+                ttl: None,
+                respond_to: tx,
+            })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        rx.await.map_err(|_| AviP2pError::ChannelClosed)?
+    }
+
+    /// Merge a patch whose keys expire after `ttl`, locally and on every
+    /// replica, for transient facts like "doorbell ringing"
+    pub async fn update_context_with_ttl(
+        &self,
+        patch: Value,
+        ttl: Duration,
+    ) -> Result<(), AviP2pError> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::UpdateSelfContext {
+                patch,
+                ttl: Some(ttl),
+                respond_to: tx,
+            })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        rx.await.map_err(|_| AviP2pError::ChannelClosed)?
+    }
+
+    /// Compare-and-set: merge `patch` only if the value at `path` currently
+    /// equals `expected` (`Value::Null` matches an unset key). The check and
+    /// the write happen together in the runtime, so of two local callers
+    /// racing for the same slot only one sees `Ok(true)`.
+    pub async fn update_context_if(
+        &self,
+        path: &str,
+        expected: Value,
+        patch: Value,
+    ) -> Result<bool, AviP2pError> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::UpdateSelfContextIf {
+                path: path.to_string(),
+                expected,
+                patch,
+                respond_to: tx,
+            })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        rx.await.map_err(|_| AviP2pError::ChannelClosed)?
+    }
+
+    /// Add `delta` (negative to decrement) to the shared counter at `path`.
+    /// Concurrent increments from different peers add up instead of one
+    /// overwriting the other. Returns the counter's new local value.
+    pub async fn ctx_incr(&self, path: &str, delta: i64) -> Result<i64, AviP2pError> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::IncrementContextCounter {
+                path: path.to_string(),
+                delta,
+                respond_to: tx,
+            })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        rx.await.map_err(|_| AviP2pError::ChannelClosed)?
+    }
+
+    /// Add `value` to the replicated set at `path`. Concurrent adds and
+    /// removes from different peers merge instead of overwriting the array.
+    pub async fn ctx_add_to_set(&self, path: &str, value: Value) -> Result<(), AviP2pError> {
+        self.add_to_collection(path, CollectionKind::Set, value)
+            .await
+    }
+
+    /// Remove `value` from the replicated set at `path`; returns false if absent
+    pub async fn ctx_remove_from_set(&self, path: &str, value: Value) -> Result<bool, AviP2pError> {
+        self.remove_from_collection(path, value).await
+    }
+
+    /// Append `value` to the replicated list at `path`. Concurrent appends
+    /// all survive, in a deterministic order on every peer.
+    pub async fn ctx_push_to_list(&self, path: &str, value: Value) -> Result<(), AviP2pError> {
+        self.add_to_collection(path, CollectionKind::List, value)
+            .await
+    }
+
+    /// Remove every occurrence of `value` from the replicated list at `path`
+    pub async fn ctx_remove_from_list(
+        &self,
+        path: &str,
+        value: Value,
+    ) -> Result<bool, AviP2pError> {
+        self.remove_from_collection(path, value).await
+    }
+
+    async fn add_to_collection(
+        &self,
+        path: &str,
+        kind: CollectionKind,
+        value: Value,
+    ) -> Result<(), AviP2pError> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::AddToContextCollection {
+                path: path.to_string(),
+                kind,
+                value,
+                respond_to: tx,
+            })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        rx.await.map_err(|_| AviP2pError::ChannelClosed)?
+    }
+
+    async fn remove_from_collection(&self, path: &str, value: Value) -> Result<bool, AviP2pError> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::RemoveFromContextCollection {
+                path: path.to_string(),
+                value,
+                respond_to: tx,
+            })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        rx.await.map_err(|_| AviP2pError::ChannelClosed)?
+    }
+
+    pub async fn replace_context(&self, data: Value) -> Result<(), AviP2pError> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::ReplaceSelfContext {
+                data,
+                respond_to: tx,
+            })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        rx.await.map_err(|_| AviP2pError::ChannelClosed)?
+    }
+
+    /// Snapshot of this node's identity, context, known peers,
+    /// subscriptions and compact topics, for moving it to new hardware.
+    /// The blob holds the identity's secret key; store it accordingly.
+    pub async fn export_state(&self) -> Result<Vec<u8>, AviP2pError> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::ExportState { respond_to: tx })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        rx.await
+            .map_err(|_| AviP2pError::ChannelClosed)??
+            .to_bytes()
+    }
+
+    /// Take over the state in an `export_state` blob. The node must run
+    /// with the snapshot's identity (`NodeSnapshot::identity`), otherwise
+    /// this fails with `SnapshotIdentity`.
+    pub async fn import_state(&self, blob: &[u8]) -> Result<(), AviP2pError> {
+        let snapshot = NodeSnapshot::from_bytes(blob)?;
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::ImportState {
+                snapshot: Box::new(snapshot),
+                respond_to: tx,
+            })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        rx.await.map_err(|_| AviP2pError::ChannelClosed)?
+    }
+
+    /// Start a batch of context writes applied together on `commit`
+    pub fn ctx_transaction(&self) -> ContextTransaction {
+        ContextTransaction {
+            handle: self.clone(),
+            ops: Vec::new(),
+        }
+    }
+
+    pub async fn delete_ctx(&self, path: &str) -> Result<(), AviP2pError> {
+        self.ctx_transaction().delete(path).commit().await
     }
 
     pub async fn clear_ctx(&self) -> Result<(), AviP2pError> {
@@ -313,11 +1600,54 @@ impl AviP2pHandle {
     }
 
     /// Get the context of a specific peer, or local context if None.
+    /// A peer's context comes from the local cache while it is younger than
+    /// `peer_context_max_age`, and is fetched from the peer otherwise.
     pub async fn get_context(&self, peer_id: Option<PeerId>) -> Result<Value, AviP2pError> {
+        match peer_id {
+            Some(peer_id) => self.fetch_context(peer_id, false).await,
+            None => self.request_context(None, false).await,
+        }
+    }
+
+    /// Follow `paths` in the context of every peer whose context this node
+    /// holds, itself included. The view is kept current as contexts change;
+    /// under `ContextReplication::Lazy` it covers fetched contexts only.
+    pub async fn context_view(&self, paths: &[&str]) -> Result<ContextView, AviP2pError> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::ContextView {
+                paths: paths.iter().map(|path| path.to_string()).collect(),
+                respond_to: tx,
+            })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        rx.await.map_err(|_| AviP2pError::ChannelClosed)
+    }
+
+    /// Get a peer's context; `force_refresh` bypasses the cache and asks
+    /// the peer directly. Gives up with `ContextFetchTimeout` after
+    /// `context_fetch_timeout`; dropping the future cancels the fetch.
+    pub async fn fetch_context(
+        &self,
+        peer_id: PeerId,
+        force_refresh: bool,
+    ) -> Result<Value, AviP2pError> {
+        let fetch = self.request_context(Some(peer_id.clone()), force_refresh);
+        tokio::time::timeout(self.context_fetch_timeout, fetch)
+            .await
+            .map_err(|_| AviP2pError::ContextFetchTimeout(peer_id))?
+    }
+
+    async fn request_context(
+        &self,
+        peer_id: Option<PeerId>,
+        force_refresh: bool,
+    ) -> Result<Value, AviP2pError> {
         let (tx, rx) = oneshot::channel();
         self.command_tx
             .send(Command::GetPeerContext {
                 peer_id,
+                force_refresh,
                 respond_to: tx,
             })
             .await
@@ -346,6 +1676,167 @@ impl AviP2pHandle {
     }
 }
 
+/// Context writes collected by `AviP2pHandle::ctx_transaction`. `commit`
+/// applies them in the runtime as one update with one clock tick, so there
+/// is no read-modify-write window for concurrent updates to slip into, and
+/// either every op takes effect or none does.
+pub struct ContextTransaction {
+    handle: AviP2pHandle,
+    ops: Vec<ContextOp>,
+}
+
+impl ContextTransaction {
+    pub fn set(mut self, path: &str, value: Value) -> Self {
+        self.ops.push(ContextOp::Set {
+            path: path.to_string(),
+            value,
+        });
+        self
+    }
+
+    pub fn delete(mut self, path: &str) -> Self {
+        self.ops.push(ContextOp::Delete {
+            path: path.to_string(),
+        });
+        self
+    }
+
+    pub async fn commit(self) -> Result<(), AviP2pError> {
+        if self.ops.is_empty() {
+            return Ok(());
+        }
+        let (tx, rx) = oneshot::channel();
+        self.handle
+            .command_tx
+            .send(Command::ApplyContextOps {
+                ops: self.ops,
+                respond_to: tx,
+            })
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)?;
+        rx.await.map_err(|_| AviP2pError::ChannelClosed)?
+    }
+}
+
+type BoxedTransport = libp2p::core::transport::Boxed<(libp2p::PeerId, StreamMuxerBox)>;
+
+/// WebSocket over TCP with Noise, the combination browsers speak
+#[cfg(feature = "websocket")]
+fn websocket_transport(
+    key: &Keypair,
+) -> Result<BoxedTransport, Box<dyn std::error::Error + Send + Sync>> {
+    use libp2p::core::{upgrade, Transport};
+
+    Ok(
+        libp2p::websocket::WsConfig::new(tcp::tokio::Transport::new(tcp::Config::default()))
+            .upgrade(upgrade::Version::V1)
+            .authenticate(noise::Config::new(key)?)
+            .multiplex(yamux::Config::default())
+            .map(|(peer, muxer), _| (peer, StreamMuxerBox::new(muxer)))
+            .boxed(),
+    )
+}
+
+#[cfg(not(feature = "websocket"))]
+fn websocket_transport(
+    _key: &Keypair,
+) -> Result<BoxedTransport, Box<dyn std::error::Error + Send + Sync>> {
+    use libp2p::core::{transport::dummy::DummyTransport, Transport};
+
+    Ok(DummyTransport::new().boxed())
+}
+
+fn check_payload_size(limit: usize, data: &[u8]) -> Result<(), AviP2pError> {
+    if data.len() > limit {
+        return Err(AviP2pError::PayloadTooLarge {
+            limit,
+            actual: data.len(),
+        });
+    }
+    Ok(())
+}
+
+/// The configured fixed identity, or a fresh random one
+fn identity_keypair(config: &AviP2pConfig) -> Result<Keypair, AviP2pError> {
+    match &config.identity {
+        Some(secret) => {
+            Keypair::ed25519_from_bytes(secret.0).map_err(|e| AviP2pError::Config(e.to_string()))
+        }
+        None => Ok(Keypair::generate_ed25519()),
+    }
+}
+
+fn swarm_config(c: libp2p::swarm::Config, config: &AviP2pConfig) -> libp2p::swarm::Config {
+    c.with_idle_connection_timeout(Duration::from_secs(86400))
+        .with_dial_concurrency_factor(config.dial_concurrency_factor)
+}
+
+/// Gossip message size that fits any accepted publish: encrypted payloads
+/// are JSON-encoded (up to 4 bytes per byte), plus headers and framing
+fn gossip_transmit_size(max_publish_size: usize) -> usize {
+    max_publish_size.saturating_mul(4) + 64 * 1024
+}
+
+/// Gossipsub config for one topic profile; telemetry negotiates its own
+/// protocol so its mesh is kept apart from the control one
+fn gossip_config(
+    tuning: &GossipTuning,
+    profile: TopicProfile,
+    max_publish_size: usize,
+) -> Result<gossipsub::Config, AviP2pError> {
+    let mut builder = gossipsub::ConfigBuilder::default();
+    if profile == TopicProfile::Telemetry {
+        builder.protocol_id_prefix("/avi/telemetry");
+    }
+    builder
+        .heartbeat_interval(tuning.heartbeat_interval)
+        .mesh_n(tuning.mesh_n)
+        .mesh_n_low(tuning.mesh_n_low)
+        .mesh_n_high(tuning.mesh_n_high)
+        .mesh_outbound_min((tuning.mesh_n / 2).min(tuning.mesh_n_low).min(2))
+        .flood_publish(tuning.flood_publish)
+        .validation_mode(gossipsub::ValidationMode::Strict)
+        .max_transmit_size(gossip_transmit_size(max_publish_size))
+        .allow_self_origin(true)
+        .build()
+        .map_err(|e| AviP2pError::Config(format!("{:?} gossip: {}", profile, e)))
+}
+
+/// Gossipsub configs for the control and telemetry profiles
+fn gossip_configs(
+    config: &AviP2pConfig,
+) -> Result<(gossipsub::Config, gossipsub::Config), AviP2pError> {
+    Ok((
+        gossip_config(
+            &config.control_gossip,
+            TopicProfile::Control,
+            config.max_publish_size,
+        )?,
+        gossip_config(
+            &config.telemetry_gossip,
+            TopicProfile::Telemetry,
+            config.max_publish_size,
+        )?,
+    ))
+}
+
+fn build_behaviour(
+    key: &Keypair,
+    config: &AviP2pConfig,
+    gossip: (gossipsub::Config, gossipsub::Config),
+    enable_mdns: bool,
+) -> AviBehaviour {
+    AviBehaviour::new(
+        key.clone(),
+        gossip,
+        config.node_name.clone(),
+        enable_mdns,
+        config.stream_protocol,
+        config.kad,
+        AviStreamProtocol::new(config.mesh_namespace.as_deref()),
+    )
+}
+
 fn extract_peer_id_from_multiaddr(ma: &Multiaddr) -> Option<libp2p::PeerId> {
     use libp2p::core::multiaddr::Protocol;
     ma.iter().find_map(|p| match p {
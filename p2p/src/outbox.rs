@@ -0,0 +1,186 @@
+use crate::error::AviP2pError;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Store-and-forward settings for messages to offline peers
+#[derive(Debug, Clone)]
+pub struct OutboxConfig {
+    /// JSON-lines file the outbox survives restarts in; `None` keeps it in memory
+    pub path: Option<PathBuf>,
+
+    /// Messages kept across all peers; the oldest are dropped beyond this
+    pub max_messages: usize,
+
+    /// Expiry used when a message is queued without an explicit TTL
+    pub default_ttl: Duration,
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            max_messages: 1000,
+            default_ttl: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct OutboxEntry {
+    pub id: u64,
+    pub peer: String,
+    pub data: Vec<u8>,
+    /// Unix seconds after which the message is discarded undelivered
+    pub expires_at: u64,
+}
+
+pub(crate) struct Outbox {
+    config: OutboxConfig,
+    entries: Vec<OutboxEntry>,
+    in_flight: HashSet<u64>,
+    next_id: u64,
+}
+
+impl Outbox {
+    pub fn open(config: OutboxConfig) -> Result<Self, AviP2pError> {
+        let mut entries = Vec::new();
+
+        if let Some(path) = &config.path {
+            if let Ok(existing) = File::open(path) {
+                for line in BufReader::new(existing).lines() {
+                    let line = line.map_err(|e| AviP2pError::Io(e.to_string()))?;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    let entry: OutboxEntry = serde_json::from_str(&line)
+                        .map_err(|e| AviP2pError::Serialization(e.to_string()))?;
+                    entries.push(entry);
+                }
+            }
+        }
+
+        let next_id = entries.iter().map(|e| e.id + 1).max().unwrap_or(0);
+        let mut outbox = Self {
+            config,
+            entries,
+            in_flight: HashSet::new(),
+            next_id,
+        };
+        outbox.prune_expired();
+        Ok(outbox)
+    }
+
+    /// Queue a message for `peer`; returns its outbox id
+    pub fn push(&mut self, peer: &str, data: Vec<u8>, ttl: Option<Duration>) -> u64 {
+        let ttl = ttl.unwrap_or(self.config.default_ttl);
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.entries.push(OutboxEntry {
+            id,
+            peer: peer.to_string(),
+            data,
+            expires_at: now_secs() + ttl.as_secs(),
+        });
+        if self.entries.len() > self.config.max_messages {
+            let excess = self.entries.len() - self.config.max_messages;
+            for dropped in self.entries.drain(..excess) {
+                self.in_flight.remove(&dropped.id);
+            }
+        }
+        self.persist();
+        id
+    }
+
+    /// Messages for `peer` not already being delivered; marks them in flight
+    pub fn take_pending(&mut self, peer: &str) -> Vec<OutboxEntry> {
+        let pending: Vec<OutboxEntry> = self
+            .entries
+            .iter()
+            .filter(|e| e.peer == peer && !self.in_flight.contains(&e.id))
+            .cloned()
+            .collect();
+        self.in_flight.extend(pending.iter().map(|e| e.id));
+        pending
+    }
+
+    /// The peer acknowledged the message
+    pub fn delivered(&mut self, id: u64) {
+        self.in_flight.remove(&id);
+        self.entries.retain(|e| e.id != id);
+        self.persist();
+    }
+
+    /// Delivery failed; keep the message for the next reconnection
+    pub fn failed(&mut self, id: u64) {
+        self.in_flight.remove(&id);
+    }
+
+    pub fn prune_expired(&mut self) {
+        let now = now_secs();
+        let before = self.entries.len();
+        self.entries.retain(|e| e.expires_at > now);
+        if self.entries.len() != before {
+            self.persist();
+        }
+    }
+
+    fn persist(&self) {
+        let Some(path) = &self.config.path else {
+            return;
+        };
+        if let Ok(mut file) = File::create(path) {
+            for entry in &self.entries {
+                if let Ok(line) = serde_json::to_string(entry) {
+                    let _ = writeln!(file, "{}", line);
+                }
+            }
+        }
+    }
+}
+
+fn now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_messages_survive_until_acknowledged() {
+        let path = std::env::temp_dir().join(format!("avi-outbox-{}.jsonl", rand::random::<u64>()));
+        let config = OutboxConfig {
+            path: Some(path.clone()),
+            ..Default::default()
+        };
+
+        let mut outbox = Outbox::open(config.clone()).unwrap();
+        let id = outbox.push("peer-a", b"unlock".to_vec(), None);
+        outbox.push("peer-b", b"other".to_vec(), None);
+        outbox.push("peer-a", b"expired".to_vec(), Some(Duration::ZERO));
+
+        // Reopen as after a restart: expired entries are gone, the rest remain
+        let mut outbox = Outbox::open(config.clone()).unwrap();
+        let pending = outbox.take_pending("peer-a");
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].data, b"unlock");
+        assert!(outbox.take_pending("peer-a").is_empty());
+
+        outbox.failed(id);
+        assert_eq!(outbox.take_pending("peer-a").len(), 1);
+        outbox.delivered(id);
+
+        let mut outbox = Outbox::open(config).unwrap();
+        assert!(outbox.take_pending("peer-a").is_empty());
+        assert_eq!(outbox.take_pending("peer-b").len(), 1);
+        let _ = std::fs::remove_file(path);
+    }
+}
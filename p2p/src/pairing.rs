@@ -0,0 +1,175 @@
+//! QR-code pairing.
+//!
+//! A `MeshInvitation` is too large for a QR code, so an admin node holds
+//! it and hands out a `PairingPayload` instead: its peer id, its
+//! addresses and a one-time token. The scanning side dials the admin,
+//! trades the token for the invitation and joins with it:
+//!
+//! ```ignore
+//! // On the hub, for the mobile app to show
+//! let payload = hub.create_pairing(&ca, Role::Device, Duration::from_secs(600)).await?;
+//! render_qr(&payload.to_string());
+//!
+//! // On the new speaker, from the scanned text
+//! let payload: PairingPayload = scanned.parse()?;
+//! let (node, events) = AviP2p::join_via_pairing("speaker", &payload).await?;
+//! ```
+
+use crate::error::AviP2pError;
+use crate::events::PeerId;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Leading field of the text form, naming its layout
+const PAIRING_PREFIX: &str = "AVI1";
+
+/// How long the joining side waits for the invitation
+pub(crate) const PAIRING_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What a pairing QR code carries. The text form (`Display` / `FromStr`)
+/// is `AVI1;<peer id>;<token>;<mesh namespace>;<address>;...`.
+#[derive(Clone, PartialEq, Eq)]
+pub struct PairingPayload {
+    /// Node holding the invitation
+    pub peer_id: PeerId,
+    pub addresses: Vec<String>,
+    pub mesh_namespace: Option<String>,
+    pub(crate) token: String,
+}
+
+impl PairingPayload {
+    /// Payload for an invitation held under `token`. Loopback addresses
+    /// are left out unless there is nothing else, as a scanning device
+    /// cannot reach them.
+    pub(crate) fn new(
+        peer_id: PeerId,
+        addresses: Vec<String>,
+        mesh_namespace: Option<String>,
+        token: String,
+    ) -> Self {
+        let is_loopback =
+            |addr: &String| addr.starts_with("/ip4/127.") || addr.starts_with("/ip6/::1/");
+        let addresses = if addresses.iter().all(is_loopback) {
+            addresses
+        } else {
+            addresses
+                .into_iter()
+                .filter(|addr| !is_loopback(addr))
+                .collect()
+        };
+        Self {
+            peer_id,
+            addresses,
+            mesh_namespace,
+            token,
+        }
+    }
+}
+
+impl fmt::Debug for PairingPayload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PairingPayload")
+            .field("peer_id", &self.peer_id)
+            .field("addresses", &self.addresses)
+            .field("mesh_namespace", &self.mesh_namespace)
+            .finish_non_exhaustive()
+    }
+}
+
+impl fmt::Display for PairingPayload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{};{};{};{}",
+            PAIRING_PREFIX,
+            self.peer_id,
+            self.token,
+            self.mesh_namespace.as_deref().unwrap_or("")
+        )?;
+        for addr in &self.addresses {
+            write!(f, ";{}", addr)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for PairingPayload {
+    type Err = AviP2pError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || AviP2pError::Serialization("Not an AVI pairing payload".to_string());
+        let mut fields = s.trim().split(';');
+        if fields.next() != Some(PAIRING_PREFIX) {
+            return Err(invalid());
+        }
+        let peer_id = fields
+            .next()
+            .filter(|f| !f.is_empty())
+            .ok_or_else(invalid)?;
+        let token = fields
+            .next()
+            .filter(|f| !f.is_empty())
+            .ok_or_else(invalid)?;
+        let mesh_namespace = fields.next().ok_or_else(invalid)?;
+        let addresses: Vec<String> = fields.map(str::to_string).collect();
+        if addresses.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Self {
+            peer_id: PeerId::new(peer_id),
+            addresses,
+            mesh_namespace: Some(mesh_namespace)
+                .filter(|n| !n.is_empty())
+                .map(str::to_string),
+            token: token.to_string(),
+        })
+    }
+}
+
+/// A fresh one-time token, as hex
+pub(crate) fn new_token() -> String {
+    rand::random::<[u8; 16]>()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_payload_text_round_trip() {
+        let payload = PairingPayload::new(
+            PeerId::new("12D3KooWhub"),
+            vec![
+                "/ip4/127.0.0.1/tcp/4001".to_string(),
+                "/ip4/192.168.1.2/tcp/4001".to_string(),
+                "/ip4/192.168.1.2/udp/4001/quic-v1".to_string(),
+            ],
+            Some("home".to_string()),
+            new_token(),
+        );
+        // The phone cannot reach the hub's loopback
+        assert_eq!(payload.addresses.len(), 2);
+        assert_eq!(
+            payload.to_string().parse::<PairingPayload>().unwrap(),
+            payload
+        );
+        assert!(!format!("{:?}", payload).contains(&payload.token));
+
+        let unscoped = PairingPayload::new(
+            PeerId::new("12D3KooWhub"),
+            vec!["/ip4/127.0.0.1/tcp/4001".to_string()],
+            None,
+            new_token(),
+        );
+        let parsed: PairingPayload = unscoped.to_string().parse().unwrap();
+        assert_eq!(parsed.mesh_namespace, None);
+        assert_eq!(parsed.addresses, unscoped.addresses);
+
+        assert!("AVI1;peer;token;".parse::<PairingPayload>().is_err());
+        assert!("https://example.com".parse::<PairingPayload>().is_err());
+    }
+}
@@ -1,7 +1,11 @@
+use super::crdt::{tag_actor, CollectionKind, OrCollection, PnCounter};
 use crate::AviP2pError;
+use libp2p::identity::{Keypair, PublicKey};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::time::Duration;
 
 /// Logical timestamp for causal ordering
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -55,6 +59,13 @@ impl VectorClock {
         }
     }
 
+    /// True if this clock has seen updates from some actor that `other` has not
+    pub fn has_unseen_by(&self, other: &Self) -> bool {
+        self.0
+            .iter()
+            .any(|(actor, &counter)| counter > other.0.get(actor).copied().unwrap_or(0))
+    }
+
     /// Merge another vector clock into this one by taking the maximum of each component
     pub fn merge(&mut self, other: &Self) {
         for (actor, &counter) in &other.0 {
@@ -74,6 +85,15 @@ pub struct AviContext {
     pub timestamp: u64, // Unix Timestamp
     pub vector_clock: VectorClock,
     pub data: serde_json::Value, // The "dict" (device, user, task, environment)
+    /// Dotted paths of ephemeral keys and the Unix time they expire at
+    #[serde(default)]
+    pub expires: HashMap<String, u64>,
+    /// Replicated counters, keyed by the dotted path their value shows up at
+    #[serde(default)]
+    pub counters: HashMap<String, PnCounter>,
+    /// Replicated sets and lists, keyed by the dotted path of their array
+    #[serde(default)]
+    pub collections: HashMap<String, OrCollection>,
 }
 
 impl AviContext {
@@ -93,10 +113,22 @@ impl AviContext {
                 .as_secs(),
             vector_clock: VectorClock::new(),
             data,
+            expires: HashMap::new(),
+            counters: HashMap::new(),
+            collections: HashMap::new(),
         }
     }
 
     pub fn apply_patch(&mut self, patch: serde_json::Value) {
+        // A key written again without a TTL becomes permanent
+        let mut paths = Vec::new();
+        leaf_paths(&patch, String::new(), &mut paths);
+        for path in paths {
+            if get_nested_value(&self.data, &path) != get_nested_value(&patch, &path) {
+                self.expires.remove(&path);
+            }
+        }
+
         merge_json(&mut self.data, patch);
         // Update timestamp on change
         self.timestamp = std::time::SystemTime::now()
@@ -105,7 +137,40 @@ impl AviContext {
             .as_secs();
     }
 
+    /// Apply a patch whose keys are removed again once `ttl` has passed
+    pub fn apply_patch_with_ttl(&mut self, patch: serde_json::Value, ttl: Duration) {
+        let mut paths = Vec::new();
+        leaf_paths(&patch, String::new(), &mut paths);
+
+        self.apply_patch(patch);
+        let expires_at = self.timestamp + ttl.as_secs();
+        for path in paths {
+            self.expires.insert(path, expires_at);
+        }
+    }
+
+    /// Drop keys whose TTL has passed; returns the removed paths
+    pub fn remove_expired(&mut self, now: u64) -> Vec<String> {
+        let expired: Vec<String> = self
+            .expires
+            .iter()
+            .filter(|(_, &at)| at <= now)
+            .map(|(path, _)| path.clone())
+            .collect();
+        for path in &expired {
+            self.expires.remove(path);
+            let _ = delete_nested_value(&mut self.data, path);
+        }
+        expired
+    }
+
     pub fn replace_data(&mut self, data: serde_json::Value) {
+        self.expires
+            .retain(|path, _| get_nested_value(&data, path).is_some());
+        self.counters
+            .retain(|path, _| get_nested_value(&data, path).is_some());
+        self.collections
+            .retain(|path, _| get_nested_value(&data, path).is_some());
         self.data = data;
         // Update timestamp on change
         self.timestamp = std::time::SystemTime::now()
@@ -114,9 +179,130 @@ impl AviContext {
             .as_secs();
     }
 
+    /// Apply every op in order, or none: an op that fails leaves the
+    /// context as it was. Keys written again lose their TTL, as with
+    /// `apply_patch`.
+    pub fn apply_ops(&mut self, ops: Vec<ContextOp>) -> Result<(), AviP2pError> {
+        let mut data = self.data.clone();
+        for op in ops {
+            match op {
+                ContextOp::Set { path, value } => set_nested_value(&mut data, &path, value)?,
+                ContextOp::Delete { path } => delete_nested_value(&mut data, &path)?,
+            }
+        }
+
+        self.expires
+            .retain(|path, _| get_nested_value(&self.data, path) == get_nested_value(&data, path));
+        self.replace_data(data);
+        Ok(())
+    }
+
+    /// Add `delta` to the counter at `path` on behalf of this device;
+    /// returns the counter's new value
+    pub fn increment_counter(&mut self, path: &str, delta: i64) -> Result<i64, AviP2pError> {
+        let counter = self.counters.entry(path.to_string()).or_default();
+        counter.add(&self.device_id, delta);
+        let value = counter.value();
+
+        set_nested_value(&mut self.data, path, serde_json::json!(value))?;
+        self.touch();
+        Ok(value)
+    }
+
+    /// Add `value` to the set or list at `path`, creating it if needed
+    pub fn add_to_collection(
+        &mut self,
+        path: &str,
+        kind: CollectionKind,
+        value: serde_json::Value,
+    ) -> Result<(), AviP2pError> {
+        let collection = self
+            .collections
+            .entry(path.to_string())
+            .or_insert_with(|| OrCollection::new(kind));
+        if collection.kind() != kind {
+            return Err(AviP2pError::InvalidPath(format!(
+                "'{}' is a {:?}, not a {:?}",
+                path,
+                collection.kind(),
+                kind
+            )));
+        }
+        collection.add(&self.device_id, value);
+        let items = collection.value();
+        self.touch();
+        set_nested_value(&mut self.data, path, items)
+    }
+
+    /// Remove every occurrence of `value` from the collection at `path`;
+    /// returns false if it was not there
+    pub fn remove_from_collection(
+        &mut self,
+        path: &str,
+        value: &serde_json::Value,
+    ) -> Result<bool, AviP2pError> {
+        let Some(collection) = self.collections.get_mut(path) else {
+            return Ok(false);
+        };
+        if !collection.remove(value) {
+            return Ok(false);
+        }
+        let items = collection.value();
+        self.touch();
+        set_nested_value(&mut self.data, path, items)?;
+        Ok(true)
+    }
+
+    fn touch(&mut self) {
+        self.timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+    }
+
     /// Merge another context into this one
     /// Returns true if the context was updated
-    pub fn merge(&mut self, other: AviContext) -> bool {
+    pub fn merge(&mut self, mut other: AviContext) -> bool {
+        let counters = std::mem::take(&mut other.counters);
+        let collections = std::mem::take(&mut other.collections);
+        let mut updated = self.merge_state(other);
+
+        for (path, counter) in counters {
+            self.counters.entry(path).or_default().merge(&counter);
+        }
+        for (path, collection) in collections {
+            match self.collections.get_mut(&path) {
+                Some(mine) => mine.merge(&collection),
+                None => {
+                    self.collections.insert(path, collection);
+                }
+            }
+        }
+        updated |= self.materialize_crdts();
+        updated
+    }
+
+    /// Write every counter and collection into `data`, where a plain value
+    /// merge may have overwritten it; returns true if anything changed
+    fn materialize_crdts(&mut self) -> bool {
+        let values: Vec<(&String, serde_json::Value)> = self
+            .counters
+            .iter()
+            .map(|(path, c)| (path, serde_json::json!(c.value())))
+            .chain(self.collections.iter().map(|(path, c)| (path, c.value())))
+            .collect();
+
+        let mut changed = false;
+        for (path, value) in values {
+            if get_nested_value(&self.data, path) != Some(&value) {
+                changed |= set_nested_value(&mut self.data, path, value).is_ok();
+            }
+        }
+        changed
+    }
+
+    /// Vector-clock merge of the plain JSON data
+    fn merge_state(&mut self, other: AviContext) -> bool {
         let cmp = self.vector_clock.partial_cmp(&other.vector_clock);
 
         match cmp {
@@ -130,6 +316,7 @@ impl AviContext {
                 }
                 self.timestamp = other.timestamp;
                 self.vector_clock.merge(&other.vector_clock);
+                self.expires = other.expires;
                 updated
             }
             Some(Ordering::Greater) => {
@@ -140,6 +327,7 @@ impl AviContext {
                 // might contradict with intended deletions from the newer state.
                 // But the issue description says: "But i dont want to loose that feature on update context"
                 // So for Ordering::Greater (we are newer), we keep the current behavior of deep_merge.
+                merge_expiries(&mut self.expires, other.expires);
                 if self.data != other.data {
                     deep_merge(&mut self.data, other.data, true); // prefer self
                     true
@@ -152,6 +340,7 @@ impl AviContext {
                 // Concurrent update, use "oldest wins" tie-breaker for data conflicts
                 let prefer_self = self.timestamp <= other.timestamp;
                 let mut updated = false;
+                merge_expiries(&mut self.expires, other.expires);
 
                 if self.data != other.data {
                     deep_merge(&mut self.data, other.data, prefer_self);
@@ -168,6 +357,344 @@ impl AviContext {
     }
 }
 
+/// Gossiped under `ContextReplication::Lazy` in place of the context: the
+/// paths a device changed and its clock after the change
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ContextInvalidation {
+    pub device_id: String,
+    /// Changed paths (empty = the whole context)
+    pub paths: Vec<String>,
+    pub clock: VectorClock,
+}
+
+/// What one peer has written to the context, as of its vector clock
+/// entry. Each peer signs its own and relays pass it on unchanged, so a
+/// node forwarding merged state cannot put words in another peer's mouth.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct OriginState {
+    pub actor: String,
+    /// The actor's vector clock entry after its latest write
+    pub counter: u64,
+    /// Last value the actor wrote at each leaf path (None = deleted)
+    pub writes: BTreeMap<String, Option<serde_json::Value>>,
+    /// The actor's increments and decrements of each counter
+    pub counters: BTreeMap<String, (u64, u64)>,
+    /// Collection element tags the actor added, by collection path
+    pub tags: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl OriginState {
+    pub(crate) fn new(actor: &str) -> Self {
+        Self {
+            actor: actor.to_string(),
+            ..Self::default()
+        }
+    }
+
+    /// Take in a write the actor made to its own replica, `before` being
+    /// the replica's data ahead of it
+    pub(crate) fn record(&mut self, before: &serde_json::Value, context: &AviContext) {
+        let mut changes = Vec::new();
+        leaf_diff(Some(before), Some(&context.data), "", &mut changes);
+        self.writes.extend(changes);
+        self.counters = context
+            .counters
+            .iter()
+            .map(|(path, counter)| (path.clone(), counter.totals(&self.actor)))
+            .filter(|(_, totals)| *totals != (0, 0))
+            .collect();
+        self.tags = context
+            .collections
+            .iter()
+            .map(|(path, collection)| {
+                let tags = collection.tags_of(&self.actor).cloned().collect();
+                (path.clone(), tags)
+            })
+            .filter(|(_, tags): &(String, BTreeSet<String>)| !tags.is_empty())
+            .collect();
+        self.counter = context
+            .vector_clock
+            .0
+            .get(&self.actor)
+            .copied()
+            .unwrap_or(0);
+    }
+}
+
+/// An `OriginState` signed by its actor
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SignedOrigin {
+    payload: Vec<u8>,
+    public_key: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+impl SignedOrigin {
+    pub(crate) fn sign(state: &OriginState, key: &Keypair) -> Result<Self, AviP2pError> {
+        let (payload, public_key, signature) = sign_payload(state, key)?;
+        Ok(Self {
+            payload,
+            public_key,
+            signature,
+        })
+    }
+
+    /// Check the signature and that the signer is the state's actor
+    pub(crate) fn verify(&self) -> Result<OriginState, AviP2pError> {
+        let (state, signer): (OriginState, _) =
+            open_payload(&self.payload, &self.public_key, &self.signature)?;
+        if state.actor != signer {
+            return Err(AviP2pError::Authentication(format!(
+                "Writes claimed for {} were signed by {}",
+                state.actor, signer
+            )));
+        }
+        Ok(state)
+    }
+}
+
+/// Verified origin states by actor, with the signed form they travel in
+pub(crate) type Origins = HashMap<String, (OriginState, SignedOrigin)>;
+
+/// Context state as it travels over the wire, signed by the peer named in
+/// its `device_id`, along with the signed `OriginState` of every peer
+/// whose writes it holds
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SignedContext {
+    payload: Vec<u8>,
+    public_key: Vec<u8>,
+    signature: Vec<u8>,
+    #[serde(default)]
+    origins: Vec<SignedOrigin>,
+}
+
+impl SignedContext {
+    pub(crate) fn sign(
+        context: &AviContext,
+        origins: &Origins,
+        key: &Keypair,
+    ) -> Result<Self, AviP2pError> {
+        let (payload, public_key, signature) = sign_payload(context, key)?;
+        Ok(Self {
+            payload,
+            public_key,
+            signature,
+            origins: origins.values().map(|(_, signed)| signed.clone()).collect(),
+        })
+    }
+
+    /// Size of the serialized context and origin states
+    pub(crate) fn payload_len(&self) -> usize {
+        self.payload.len()
+            + self
+                .origins
+                .iter()
+                .map(|origin| origin.payload.len())
+                .sum::<usize>()
+    }
+
+    /// Check every signature and that the signer is the context's origin;
+    /// returns the context and the origin states it carries
+    pub(crate) fn verify(&self) -> Result<(AviContext, Origins), AviP2pError> {
+        let (context, signer): (AviContext, _) =
+            open_payload(&self.payload, &self.public_key, &self.signature)?;
+        if context.device_id != signer {
+            return Err(AviP2pError::Authentication(format!(
+                "Context claims origin {} but was signed by {}",
+                context.device_id, signer
+            )));
+        }
+
+        let mut origins = Origins::new();
+        for signed in &self.origins {
+            let state = signed.verify()?;
+            if origins
+                .get(&state.actor)
+                .is_none_or(|(held, _)| held.counter < state.counter)
+            {
+                origins.insert(state.actor.clone(), (state, signed.clone()));
+            }
+        }
+        Ok((context, origins))
+    }
+}
+
+/// Check that whatever `incoming` would change in `local` on merge was
+/// signed by the peer it is attributed to, in `carried` (the origin states
+/// that came with it) or `known` (those `local` already holds): clock
+/// entries, values, deletions, counter changes and collection elements
+pub(crate) fn check_provenance(
+    incoming: &AviContext,
+    carried: &Origins,
+    local: &AviContext,
+    known: &Origins,
+) -> Result<(), AviP2pError> {
+    let origin = |actor: &str| -> Option<&OriginState> {
+        let carried = carried.get(actor).map(|(state, _)| state);
+        let known = known.get(actor).map(|(state, _)| state);
+        match (carried, known) {
+            (Some(a), Some(b)) => Some(if a.counter >= b.counter { a } else { b }),
+            (a, b) => a.or(b),
+        }
+    };
+    let unsigned = |what: String| {
+        Err(AviP2pError::Authentication(format!(
+            "Context from {} carries {} its author did not sign",
+            incoming.device_id, what
+        )))
+    };
+
+    for (actor, &count) in &incoming.vector_clock.0 {
+        let seen = local.vector_clock.0.get(actor).copied().unwrap_or(0);
+        if count > seen && origin(actor).is_none_or(|state| state.counter < count) {
+            return unsigned(format!("clock entry {}@{}", actor, count));
+        }
+    }
+
+    // Mirrors `merge_state`: which side's values win
+    let order = local.vector_clock.partial_cmp(&incoming.vector_clock);
+    let replaces = order == Some(Ordering::Less);
+    let overrides = replaces || (order.is_none() && local.timestamp > incoming.timestamp);
+    let crdt_paths: Vec<&String> = incoming
+        .counters
+        .keys()
+        .chain(incoming.collections.keys())
+        .collect();
+    let mut changes = Vec::new();
+    leaf_diff(Some(&local.data), Some(&incoming.data), "", &mut changes);
+    for (path, value) in changes {
+        if crdt_paths
+            .iter()
+            .any(|crdt| path == **crdt || path.starts_with(&format!("{}.", crdt)))
+        {
+            continue;
+        }
+        let checked = match &value {
+            Some(_) => overrides || get_nested_value(&local.data, &path).is_none(),
+            // Keys past their TTL vanish on every replica unsigned
+            None => replaces && !local.expires.contains_key(&path),
+        };
+        let signed = || {
+            carried
+                .values()
+                .chain(known.values())
+                .any(|(state, _)| state.writes.get(&path) == Some(&value))
+        };
+        if checked && !signed() {
+            return unsigned(format!("a write to {}", path));
+        }
+    }
+
+    for (path, counter) in &incoming.counters {
+        let mine = local.counters.get(path);
+        for actor in counter.actors() {
+            let (up, down) = counter.totals(actor);
+            let (seen_up, seen_down) = mine.map_or((0, 0), |c| c.totals(actor));
+            if up <= seen_up && down <= seen_down {
+                continue;
+            }
+            let (signed_up, signed_down) = origin(actor)
+                .and_then(|state| state.counters.get(path).copied())
+                .unwrap_or((0, 0));
+            if up > signed_up || down > signed_down {
+                return unsigned(format!("{}'s changes to counter {}", actor, path));
+            }
+        }
+    }
+
+    for (path, collection) in &incoming.collections {
+        let mine = local.collections.get(path);
+        for tag in collection.live_tags() {
+            if mine.is_some_and(|c| c.knows(tag)) {
+                continue;
+            }
+            if !origin(tag_actor(tag))
+                .and_then(|state| state.tags.get(path))
+                .is_some_and(|tags| tags.contains(tag))
+            {
+                return unsigned(format!("an element of {}", path));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Payload, signer's public key and signature
+type SignedParts = (Vec<u8>, Vec<u8>, Vec<u8>);
+
+/// Serialize and sign `value`
+fn sign_payload<T: Serialize>(value: &T, key: &Keypair) -> Result<SignedParts, AviP2pError> {
+    let payload =
+        serde_json::to_vec(value).map_err(|e| AviP2pError::Serialization(e.to_string()))?;
+    let signature = key
+        .sign(&payload)
+        .map_err(|e| AviP2pError::Authentication(e.to_string()))?;
+    Ok((payload, key.public().encode_protobuf(), signature))
+}
+
+/// Check a signature and decode what was signed; returns it with the
+/// signer's peer id
+fn open_payload<T: DeserializeOwned>(
+    payload: &[u8],
+    public_key: &[u8],
+    signature: &[u8],
+) -> Result<(T, String), AviP2pError> {
+    let key = PublicKey::try_decode_protobuf(public_key)
+        .map_err(|e| AviP2pError::Authentication(e.to_string()))?;
+    if !key.verify(payload, signature) {
+        return Err(AviP2pError::Authentication(
+            "Invalid context signature".to_string(),
+        ));
+    }
+    let value =
+        serde_json::from_slice(payload).map_err(|e| AviP2pError::Serialization(e.to_string()))?;
+    Ok((value, key.to_peer_id().to_base58()))
+}
+
+/// Like `diff_context`, but always down to leaves on both sides: a subtree
+/// added or removed shows up as each of its leaves
+fn leaf_diff(
+    old: Option<&serde_json::Value>,
+    new: Option<&serde_json::Value>,
+    path: &str,
+    out: &mut Vec<(String, Option<serde_json::Value>)>,
+) {
+    if old == new {
+        return;
+    }
+    let children = |value: Option<&serde_json::Value>| match value {
+        Some(serde_json::Value::Object(obj)) if !obj.is_empty() => Some(obj.clone()),
+        _ => None,
+    };
+    let (a, b) = (children(old), children(new));
+    if b.is_none() && (new.is_some() || a.is_none()) {
+        out.push((path.to_string(), new.cloned()));
+    }
+    if a.is_none() && b.is_none() {
+        return;
+    }
+    let mut keys: Vec<&String> = a
+        .iter()
+        .chain(b.iter())
+        .flat_map(|obj| obj.keys())
+        .collect();
+    keys.sort();
+    keys.dedup();
+    for key in keys {
+        let child = if path.is_empty() {
+            key.clone()
+        } else {
+            format!("{}.{}", path, key)
+        };
+        leaf_diff(
+            a.as_ref().and_then(|obj| obj.get(key)),
+            b.as_ref().and_then(|obj| obj.get(key)),
+            &child,
+            out,
+        );
+    }
+}
+
 fn deep_merge(a: &mut serde_json::Value, b: serde_json::Value, prefer_a: bool) {
     match (a, b) {
         (serde_json::Value::Object(a_obj), serde_json::Value::Object(b_obj)) => {
@@ -272,6 +799,302 @@ pub fn delete_nested_value(data: &mut serde_json::Value, path: &str) -> Result<(
     ))
 }
 
+/// One write of a context transaction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ContextOp {
+    Set {
+        path: String,
+        value: serde_json::Value,
+    },
+    Delete {
+        path: String,
+    },
+}
+
+impl ContextOp {
+    pub fn path(&self) -> &str {
+        match self {
+            ContextOp::Set { path, .. } | ContextOp::Delete { path } => path,
+        }
+    }
+}
+
+/// One changed leaf in a context update
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContextChange {
+    /// Dotted path of the changed key
+    pub path: String,
+    /// Value before the update (`None` if the key was added)
+    pub old: Option<serde_json::Value>,
+    /// Value after the update (`None` if the key was removed)
+    pub new: Option<serde_json::Value>,
+}
+
+/// Leaf-level differences between two context states
+pub fn diff_context(old: &serde_json::Value, new: &serde_json::Value) -> Vec<ContextChange> {
+    let mut changes = Vec::new();
+    diff_values(Some(old), Some(new), String::new(), &mut changes);
+    changes
+}
+
+fn diff_values(
+    old: Option<&serde_json::Value>,
+    new: Option<&serde_json::Value>,
+    path: String,
+    out: &mut Vec<ContextChange>,
+) {
+    if old == new {
+        return;
+    }
+    match (old, new) {
+        (Some(serde_json::Value::Object(a)), Some(serde_json::Value::Object(b))) => {
+            let mut keys: Vec<&String> = a.keys().chain(b.keys()).collect();
+            keys.sort();
+            keys.dedup();
+            for key in keys {
+                let child = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                diff_values(a.get(key), b.get(key), child, out);
+            }
+        }
+        _ => out.push(ContextChange {
+            path,
+            old: old.cloned(),
+            new: new.cloned(),
+        }),
+    }
+}
+
+pub(crate) fn get_nested_value<'a>(
+    data: &'a serde_json::Value,
+    path: &str,
+) -> Option<&'a serde_json::Value> {
+    path.split('.')
+        .try_fold(data, |current, key| current.get(key))
+}
+
+/// Dotted paths of every non-object value (and empty object) in `value`
+fn leaf_paths(value: &serde_json::Value, prefix: String, out: &mut Vec<String>) {
+    match value {
+        serde_json::Value::Object(obj) if !obj.is_empty() => {
+            for (k, v) in obj {
+                let path = if prefix.is_empty() {
+                    k.clone()
+                } else {
+                    format!("{}.{}", prefix, k)
+                };
+                leaf_paths(v, path, out);
+            }
+        }
+        _ if !prefix.is_empty() => out.push(prefix),
+        _ => {}
+    }
+}
+
+/// Keep the later expiry when both sides know a key as ephemeral
+fn merge_expiries(a: &mut HashMap<String, u64>, b: HashMap<String, u64>) {
+    for (path, at) in b {
+        let entry = a.entry(path).or_insert(at);
+        *entry = (*entry).max(at);
+    }
+}
+
 fn merge_json(a: &mut serde_json::Value, b: serde_json::Value) {
     deep_merge(a, b, false);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_ttl_keys_expire_and_rewrites_clear_ttl() {
+        let mut ctx = AviContext::new("dev".to_string());
+        ctx.apply_patch_with_ttl(
+            json!({ "doorbell": { "ringing": true }, "user": { "speaking": true } }),
+            Duration::from_secs(5),
+        );
+        ctx.apply_patch(json!({ "user": { "speaking": false } }));
+
+        let removed = ctx.remove_expired(ctx.timestamp + 5);
+        assert_eq!(removed, vec!["doorbell.ringing".to_string()]);
+        assert!(ctx.data["doorbell"].get("ringing").is_none());
+        assert_eq!(ctx.data["user"]["speaking"], false);
+        assert!(ctx.expires.is_empty());
+    }
+
+    #[test]
+    fn test_ops_apply_together_or_not_at_all() {
+        let mut ctx = AviContext::new("dev".to_string());
+        ctx.apply_patch_with_ttl(json!({ "timer": { "left": 30 } }), Duration::from_secs(60));
+        ctx.apply_patch(json!({ "mode": "away" }));
+
+        let failing = vec![
+            ContextOp::Delete {
+                path: "mode".to_string(),
+            },
+            ContextOp::Delete {
+                path: "missing.key".to_string(),
+            },
+        ];
+        assert!(ctx.apply_ops(failing).is_err());
+        assert_eq!(ctx.data["mode"], "away");
+
+        ctx.apply_ops(vec![
+            ContextOp::Set {
+                path: "timer.left".to_string(),
+                value: json!(10),
+            },
+            ContextOp::Delete {
+                path: "mode".to_string(),
+            },
+        ])
+        .unwrap();
+        assert_eq!(ctx.data["timer"]["left"], 10);
+        assert!(ctx.data.get("mode").is_none());
+        assert!(ctx.expires.is_empty());
+    }
+
+    #[test]
+    fn test_counters_survive_concurrent_merges() {
+        let mut a = AviContext::new("a".to_string());
+        let mut b = AviContext::new("b".to_string());
+        a.increment_counter("stats.plays", 2).unwrap();
+        a.vector_clock.increment("a");
+        b.increment_counter("stats.plays", 5).unwrap();
+        b.vector_clock.increment("b");
+
+        let snapshot = a.clone();
+        assert!(a.merge(b.clone()));
+        assert!(b.merge(snapshot));
+        assert_eq!(a.data["stats"]["plays"], 7);
+        assert_eq!(b.data["stats"]["plays"], 7);
+    }
+
+    /// A replica that signs its own writes, as the runtime does
+    struct Replica {
+        key: Keypair,
+        context: AviContext,
+        origins: Origins,
+    }
+
+    impl Replica {
+        fn new() -> Self {
+            let key = Keypair::generate_ed25519();
+            let id = key.public().to_peer_id().to_base58();
+            Self {
+                key,
+                context: AviContext::new(id),
+                origins: Origins::new(),
+            }
+        }
+
+        fn write(&mut self, patch: serde_json::Value) {
+            let before = self.context.data.clone();
+            self.context.apply_patch(patch);
+            let id = self.context.device_id.clone();
+            self.context.vector_clock.increment(&id);
+            let mut state = self
+                .origins
+                .remove(&id)
+                .map_or_else(|| OriginState::new(&id), |(state, _)| state);
+            state.record(&before, &self.context);
+            let signed = SignedOrigin::sign(&state, &self.key).unwrap();
+            self.origins.insert(id, (state, signed));
+        }
+
+        fn send(&self) -> SignedContext {
+            SignedContext::sign(&self.context, &self.origins, &self.key).unwrap()
+        }
+
+        fn receive(&mut self, signed: SignedContext) -> Result<(), AviP2pError> {
+            let (incoming, carried) = signed.verify()?;
+            check_provenance(&incoming, &carried, &self.context, &self.origins)?;
+            self.origins.extend(carried);
+            self.context.merge(incoming);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_relayed_state_keeps_its_authors_signatures() {
+        let mut lock = Replica::new();
+        let mut hub = Replica::new();
+        let mut phone = Replica::new();
+        lock.write(json!({ "door": { "locked": true } }));
+        hub.receive(lock.send()).unwrap();
+        hub.write(json!({ "mode": "away" }));
+
+        // The phone never heard from the lock, only from the hub
+        phone.receive(hub.send()).unwrap();
+        assert_eq!(phone.context.data["door"]["locked"], true);
+        assert_eq!(phone.context.data["mode"], "away");
+    }
+
+    #[test]
+    fn test_forged_state_from_a_relay_is_refused() {
+        let mut lock = Replica::new();
+        let mut hub = Replica::new();
+        lock.write(json!({ "door": { "locked": true } }));
+        let lock_id = lock.context.device_id.clone();
+        hub.receive(lock.send()).unwrap();
+
+        // The hub claims the lock unlocked itself
+        let mut forged = Replica::new();
+        forged.key = hub.key.clone();
+        forged.context = hub.context.clone();
+        forged.origins = hub.origins.clone();
+        forged.context.data["door"]["locked"] = json!(false);
+        forged.context.vector_clock.increment(&lock_id);
+        assert!(Replica::new().receive(forged.send()).is_err());
+
+        // Same write without the lock's clock entry, to a replica that
+        // holds the lock's state: the value is still the lock's to change
+        let mut phone = Replica::new();
+        phone.receive(lock.send()).unwrap();
+        let mut unsigned = hub.context.clone();
+        unsigned.data["door"]["locked"] = json!(false);
+        unsigned.vector_clock.increment(&unsigned.device_id.clone());
+        let sent = SignedContext::sign(&unsigned, &hub.origins, &hub.key).unwrap();
+        assert!(phone.receive(sent).is_err());
+        assert_eq!(phone.context.data["door"]["locked"], true);
+
+        // Counter changes credited to the lock
+        let mut counted = hub.context.clone();
+        counted.counters.insert("stats.unlocks".to_string(), {
+            let mut counter = PnCounter::default();
+            counter.add(&lock_id, 5);
+            counter
+        });
+        let sent = SignedContext::sign(&counted, &hub.origins, &hub.key).unwrap();
+        assert!(phone.receive(sent).is_err());
+
+        // A lock state signed by the hub's key
+        let mut stolen = lock.origins[&lock_id].0.clone();
+        stolen
+            .writes
+            .insert("door.locked".to_string(), Some(json!(false)));
+        assert!(SignedOrigin::sign(&stolen, &hub.key)
+            .unwrap()
+            .verify()
+            .is_err());
+    }
+
+    #[test]
+    fn test_diff_reports_changed_leaves() {
+        let old = json!({ "lights": { "kitchen": "off", "hall": "on" }, "mode": "away" });
+        let new = json!({ "lights": { "kitchen": "on", "hall": "on" }, "user": { "home": true } });
+
+        let changes = diff_context(&old, &new);
+        let paths: Vec<&str> = changes.iter().map(|c| c.path.as_str()).collect();
+        assert_eq!(paths, vec!["lights.kitchen", "mode", "user"]);
+        assert_eq!(changes[0].old, Some(json!("off")));
+        assert_eq!(changes[0].new, Some(json!("on")));
+        assert_eq!(changes[1].new, None);
+        assert_eq!(changes[2].old, None);
+    }
+}
@@ -0,0 +1,203 @@
+//! Conflict-free replicated types embedded in the context. Each one keeps
+//! per-actor state so concurrent updates from different peers combine
+//! instead of overwriting each other.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// Counter that can go up and down; merges add up every peer's changes
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PnCounter {
+    increments: HashMap<String, u64>,
+    decrements: HashMap<String, u64>,
+}
+
+impl PnCounter {
+    /// Record `delta` on behalf of `actor`
+    pub fn add(&mut self, actor: &str, delta: i64) {
+        let side = if delta >= 0 {
+            &mut self.increments
+        } else {
+            &mut self.decrements
+        };
+        *side.entry(actor.to_string()).or_insert(0) += delta.unsigned_abs();
+    }
+
+    pub fn value(&self) -> i64 {
+        let up: u64 = self.increments.values().sum();
+        let down: u64 = self.decrements.values().sum();
+        up as i64 - down as i64
+    }
+
+    /// What `actor` has added and taken away
+    pub(crate) fn totals(&self, actor: &str) -> (u64, u64) {
+        (
+            self.increments.get(actor).copied().unwrap_or(0),
+            self.decrements.get(actor).copied().unwrap_or(0),
+        )
+    }
+
+    /// Every actor with a change on either side
+    pub(crate) fn actors(&self) -> impl Iterator<Item = &String> {
+        self.increments.keys().chain(self.decrements.keys())
+    }
+
+    /// Per-actor maximum on both sides
+    pub fn merge(&mut self, other: &Self) {
+        for (mine, theirs) in [
+            (&mut self.increments, &other.increments),
+            (&mut self.decrements, &other.decrements),
+        ] {
+            for (actor, &count) in theirs {
+                let entry = mine.entry(actor.clone()).or_insert(0);
+                *entry = (*entry).max(count);
+            }
+        }
+    }
+}
+
+/// How an `OrCollection` shows up in the context data
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum CollectionKind {
+    /// Each element once, in first-added order
+    Set,
+    /// Every element in append order; concurrent appends are ordered by peer id
+    List,
+}
+
+/// Observed-remove collection: a remove only cancels the additions the
+/// remover had seen, so a concurrent re-add survives the merge
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct OrCollection {
+    kind: CollectionKind,
+    /// Lamport clock; tags sort by (clock, actor), which is the list order
+    clock: u64,
+    elements: BTreeMap<String, serde_json::Value>,
+    removed: BTreeSet<String>,
+}
+
+impl OrCollection {
+    pub fn new(kind: CollectionKind) -> Self {
+        Self {
+            kind,
+            clock: 0,
+            elements: BTreeMap::new(),
+            removed: BTreeSet::new(),
+        }
+    }
+
+    pub fn kind(&self) -> CollectionKind {
+        self.kind
+    }
+
+    pub fn add(&mut self, actor: &str, value: serde_json::Value) {
+        self.clock += 1;
+        let tag = format!("{:020}:{}", self.clock, actor);
+        self.elements.insert(tag, value);
+    }
+
+    /// Remove every observed occurrence of `value`; returns false if absent
+    pub fn remove(&mut self, value: &serde_json::Value) -> bool {
+        let tags: Vec<String> = self
+            .elements
+            .iter()
+            .filter(|(_, v)| *v == value)
+            .map(|(tag, _)| tag.clone())
+            .collect();
+        for tag in &tags {
+            self.elements.remove(tag);
+        }
+        self.removed.extend(tags.iter().cloned());
+        !tags.is_empty()
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        self.clock = self.clock.max(other.clock);
+        self.removed.extend(other.removed.iter().cloned());
+        for (tag, value) in &other.elements {
+            if !self.removed.contains(tag) {
+                self.elements.insert(tag.clone(), value.clone());
+            }
+        }
+        let removed = &self.removed;
+        self.elements.retain(|tag, _| !removed.contains(tag));
+    }
+
+    /// Tags of the elements currently held
+    pub(crate) fn live_tags(&self) -> impl Iterator<Item = &String> {
+        self.elements.keys()
+    }
+
+    /// Tags `actor` added, whether or not they were removed since
+    pub(crate) fn tags_of<'a>(&'a self, actor: &'a str) -> impl Iterator<Item = &'a String> {
+        self.elements
+            .keys()
+            .chain(self.removed.iter())
+            .filter(move |tag| tag_actor(tag) == actor)
+    }
+
+    /// Whether this replica has seen `tag`, added or removed
+    pub(crate) fn knows(&self, tag: &str) -> bool {
+        self.elements.contains_key(tag) || self.removed.contains(tag)
+    }
+
+    /// The collection as a JSON array
+    pub fn value(&self) -> serde_json::Value {
+        let mut items: Vec<serde_json::Value> = Vec::new();
+        for value in self.elements.values() {
+            if self.kind == CollectionKind::List || !items.contains(value) {
+                items.push(value.clone());
+            }
+        }
+        serde_json::Value::Array(items)
+    }
+}
+
+/// Peer that added the element under `tag`
+pub(crate) fn tag_actor(tag: &str) -> &str {
+    tag.split_once(':').map_or("", |(_, actor)| actor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_concurrent_increments_add_up() {
+        let mut a = PnCounter::default();
+        let mut b = PnCounter::default();
+        a.add("a", 3);
+        b.add("b", 2);
+        b.add("b", -1);
+
+        let snapshot = a.clone();
+        a.merge(&b);
+        b.merge(&snapshot);
+        // Merging is idempotent
+        a.merge(&b);
+
+        assert_eq!(a.value(), 4);
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn test_concurrent_add_survives_remove() {
+        let mut a = OrCollection::new(CollectionKind::Set);
+        a.add("a", json!("lamp"));
+        a.add("a", json!("tv"));
+        let mut b = a.clone();
+
+        // `a` removes the lamp while `b` concurrently re-adds it
+        assert!(a.remove(&json!("lamp")));
+        b.add("b", json!("lamp"));
+        b.add("b", json!("speaker"));
+
+        let snapshot = a.clone();
+        a.merge(&b);
+        b.merge(&snapshot);
+
+        assert_eq!(a.value(), json!(["tv", "lamp", "speaker"]));
+        assert_eq!(a, b);
+    }
+}
@@ -0,0 +1,78 @@
+use super::{read_json, write_json};
+use async_trait::async_trait;
+use futures::prelude::*;
+use libp2p::request_response::Codec;
+use serde::{Deserialize, Serialize};
+use std::io;
+
+/// Request on an application protocol, carried over the shared extension
+/// protocol and routed to the handler registered under `name`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExtensionRequest {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+/// The handler's reply, or why there was none
+pub type ExtensionResponse = Result<Vec<u8>, String>;
+
+#[derive(Debug, Clone)]
+pub struct AviExtensionProtocol;
+
+impl AsRef<str> for AviExtensionProtocol {
+    fn as_ref(&self) -> &str {
+        "/avi/ext/1.0.0"
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct AviExtensionCodec;
+
+#[async_trait]
+impl Codec for AviExtensionCodec {
+    type Protocol = AviExtensionProtocol;
+    type Request = ExtensionRequest;
+    type Response = ExtensionResponse;
+
+    async fn read_request<T>(&mut self, _: &Self::Protocol, io: &mut T) -> io::Result<Self::Request>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_json(io).await
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+    ) -> io::Result<Self::Response>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_json(io).await
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+        req: Self::Request,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_json(io, &req).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+        res: Self::Response,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_json(io, &res).await
+    }
+}
@@ -1,2 +1,45 @@
+use futures::prelude::*;
+use serde::Serialize;
+use std::io;
+
 pub mod context;
+pub mod crdt;
+pub mod extension;
+pub mod rendezvous;
+pub mod secure;
 pub mod stream;
+
+/// Largest length-prefixed JSON message the small control protocols accept
+const MAX_MESSAGE_LEN: usize = 1024 * 1024;
+
+pub(crate) async fn read_json<T, M>(io: &mut T) -> io::Result<M>
+where
+    T: AsyncRead + Unpin + Send,
+    M: serde::de::DeserializeOwned,
+{
+    let mut len_bytes = [0u8; 4];
+    io.read_exact(&mut len_bytes).await?;
+    let len = u32::from_be_bytes(len_bytes) as usize;
+    if len > MAX_MESSAGE_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Message too large",
+        ));
+    }
+
+    let mut buffer = vec![0u8; len];
+    io.read_exact(&mut buffer).await?;
+    serde_json::from_slice(&buffer).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+pub(crate) async fn write_json<T, M>(io: &mut T, msg: &M) -> io::Result<()>
+where
+    T: AsyncWrite + Unpin + Send,
+    M: Serialize,
+{
+    let encoded =
+        serde_json::to_vec(msg).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    io.write_all(&(encoded.len() as u32).to_be_bytes()).await?;
+    io.write_all(&encoded).await?;
+    io.flush().await
+}
@@ -0,0 +1,218 @@
+use super::{read_json, write_json};
+use async_trait::async_trait;
+use futures::prelude::*;
+use libp2p::request_response::Codec;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::time::{Duration, Instant};
+
+/// Longest registration a rendezvous point accepts; longer requests are capped
+pub const MAX_REGISTRATION_TTL: Duration = Duration::from_secs(72 * 60 * 60);
+
+/// Registrations kept per namespace, so one namespace cannot exhaust the point
+const MAX_REGISTRATIONS_PER_NAMESPACE: usize = 1000;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RendezvousRequest {
+    /// Register the sender under `namespace`, reachable at `addrs`
+    Register {
+        namespace: String,
+        addrs: Vec<String>,
+        ttl_secs: u64,
+    },
+    Unregister {
+        namespace: String,
+    },
+    /// List the peers registered under `namespace`
+    Discover {
+        namespace: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Registration {
+    pub peer_id: String,
+    pub addrs: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RendezvousResponse {
+    /// Registered for this long (possibly less than requested)
+    Registered {
+        ttl_secs: u64,
+    },
+    Unregistered,
+    Registrations(Vec<Registration>),
+    /// The request was refused, e.g. the node is not a rendezvous point
+    Error(String),
+}
+
+struct Entry {
+    addrs: Vec<String>,
+    expires: Instant,
+}
+
+/// Registrations held by a rendezvous point, per namespace and peer
+#[derive(Default)]
+pub(crate) struct RendezvousRegistry {
+    namespaces: HashMap<String, HashMap<String, Entry>>,
+}
+
+impl RendezvousRegistry {
+    pub fn register(
+        &mut self,
+        namespace: &str,
+        peer_id: &str,
+        addrs: Vec<String>,
+        ttl: Duration,
+        now: Instant,
+    ) -> Result<Duration, String> {
+        if addrs.is_empty() {
+            return Err("registration carries no addresses".to_string());
+        }
+        self.prune(now);
+        let ttl = ttl.min(MAX_REGISTRATION_TTL);
+        let peers = self.namespaces.entry(namespace.to_string()).or_default();
+        if !peers.contains_key(peer_id) && peers.len() >= MAX_REGISTRATIONS_PER_NAMESPACE {
+            return Err(format!("namespace {} is full", namespace));
+        }
+        peers.insert(
+            peer_id.to_string(),
+            Entry {
+                addrs,
+                expires: now + ttl,
+            },
+        );
+        Ok(ttl)
+    }
+
+    pub fn unregister(&mut self, namespace: &str, peer_id: &str) {
+        if let Some(peers) = self.namespaces.get_mut(namespace) {
+            peers.remove(peer_id);
+            if peers.is_empty() {
+                self.namespaces.remove(namespace);
+            }
+        }
+    }
+
+    /// Live registrations under `namespace`, except the asking peer's own
+    pub fn discover(&mut self, namespace: &str, asking: &str, now: Instant) -> Vec<Registration> {
+        self.prune(now);
+        self.namespaces
+            .get(namespace)
+            .map(|peers| {
+                peers
+                    .iter()
+                    .filter(|(peer_id, _)| peer_id.as_str() != asking)
+                    .map(|(peer_id, entry)| Registration {
+                        peer_id: peer_id.clone(),
+                        addrs: entry.addrs.clone(),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn prune(&mut self, now: Instant) {
+        self.namespaces.retain(|_, peers| {
+            peers.retain(|_, entry| entry.expires > now);
+            !peers.is_empty()
+        });
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AviRendezvousProtocol;
+
+impl AsRef<str> for AviRendezvousProtocol {
+    fn as_ref(&self) -> &str {
+        "/avi/rendezvous/1.0.0"
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct AviRendezvousCodec;
+
+#[async_trait]
+impl Codec for AviRendezvousCodec {
+    type Protocol = AviRendezvousProtocol;
+    type Request = RendezvousRequest;
+    type Response = RendezvousResponse;
+
+    async fn read_request<T>(&mut self, _: &Self::Protocol, io: &mut T) -> io::Result<Self::Request>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_json(io).await
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+    ) -> io::Result<Self::Response>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_json(io).await
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+        req: Self::Request,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_json(io, &req).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+        res: Self::Response,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_json(io, &res).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registrations_expire_and_exclude_the_asker() {
+        let mut registry = RendezvousRegistry::default();
+        let now = Instant::now();
+        let addrs = vec!["/ip4/10.0.0.1/tcp/4001".to_string()];
+
+        let ttl = registry
+            .register("household-1234", "a", addrs.clone(), Duration::MAX, now)
+            .unwrap();
+        assert_eq!(ttl, MAX_REGISTRATION_TTL);
+        registry
+            .register("household-1234", "b", addrs, Duration::from_secs(60), now)
+            .unwrap();
+        assert!(registry
+            .register("household-1234", "c", vec![], Duration::from_secs(60), now)
+            .is_err());
+
+        let found = registry.discover("household-1234", "a", now);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].peer_id, "b");
+
+        let later = now + Duration::from_secs(61);
+        assert!(registry.discover("household-1234", "a", later).is_empty());
+        assert_eq!(registry.discover("household-1234", "b", later).len(), 1);
+
+        registry.unregister("household-1234", "a");
+        assert!(registry.discover("household-1234", "b", later).is_empty());
+    }
+}
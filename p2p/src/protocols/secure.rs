@@ -0,0 +1,438 @@
+//! End-to-end encryption inside a logical stream.
+//!
+//! A stream whose reason carries the `e2e` parameter (see `secure_reason`)
+//! runs a Noise XX handshake over its first data chunks, so its payloads
+//! stay sealed even when a relay forwards them. Each side signs its Noise
+//! static key with its node identity, and the opener pins the result to the
+//! peer it meant to reach rather than to whoever forwarded the stream.
+//!
+//! Every chunk on the wire starts with a frame kind. Transport frames hold
+//! one or more sealed pieces, each with its own nonce, because stream chunks
+//! are separate requests and may arrive out of order.
+
+use crate::error::AviP2pError;
+use libp2p::identity::{Keypair, PublicKey};
+use libp2p::PeerId as LibPeerId;
+use serde::{Deserialize, Serialize};
+use snow::{Builder, HandshakeState, StatelessTransportState};
+
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_SHA256";
+
+/// Signed along with the Noise static key to bind it to a node identity
+const IDENTITY_DOMAIN: &[u8] = b"avi-e2e-static-key-v1";
+
+/// Stream reason parameter asking for end-to-end encryption
+const E2E_PARAM: &str = "e2e";
+
+const FRAME_HANDSHAKE: u8 = 0;
+const FRAME_TRANSPORT: u8 = 1;
+
+const MAX_NOISE_MESSAGE: usize = 65535;
+const TAG_LEN: usize = 16;
+/// Plaintext held by one sealed piece
+const MAX_PIECE: usize = MAX_NOISE_MESSAGE - TAG_LEN;
+/// Nonce and ciphertext length in front of each piece
+const PIECE_HEADER: usize = 8 + 2;
+
+/// How far behind the newest nonce a piece may arrive and still be accepted
+const REPLAY_WINDOW: u64 = 64;
+
+/// `reason` marked for an end-to-end encrypted stream
+pub fn secure_reason(reason: &str) -> String {
+    format!("{};{}", reason, E2E_PARAM)
+}
+
+/// Whether a stream reason asks for end-to-end encryption
+pub fn is_secure_reason(reason: &str) -> bool {
+    reason.split(';').skip(1).any(|param| param == E2E_PARAM)
+}
+
+/// Wire size of a chunk of `len` bytes once sealed
+pub(crate) fn sealed_len(len: usize) -> usize {
+    1 + len + len.div_ceil(MAX_PIECE).max(1) * (PIECE_HEADER + TAG_LEN)
+}
+
+fn handshake_error(why: &str) -> AviP2pError {
+    AviP2pError::Encryption(format!("stream handshake: {}", why))
+}
+
+#[derive(Serialize, Deserialize)]
+struct IdentityProof {
+    public_key: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+fn prove(identity: &Keypair, static_key: &[u8]) -> Result<Vec<u8>, AviP2pError> {
+    let signature = identity
+        .sign(&[IDENTITY_DOMAIN, static_key].concat())
+        .map_err(|e| AviP2pError::Encryption(e.to_string()))?;
+    let proof = IdentityProof {
+        public_key: identity.public().encode_protobuf(),
+        signature,
+    };
+    serde_json::to_vec(&proof).map_err(|e| AviP2pError::Serialization(e.to_string()))
+}
+
+/// The identity that signed the remote's Noise static key
+fn verify(proof: &[u8], static_key: Option<&[u8]>) -> Result<LibPeerId, AviP2pError> {
+    let proof: IdentityProof =
+        serde_json::from_slice(proof).map_err(|_| handshake_error("malformed identity proof"))?;
+    let static_key = static_key.ok_or_else(|| handshake_error("no static key"))?;
+    let key = PublicKey::try_decode_protobuf(&proof.public_key)
+        .map_err(|_| handshake_error("malformed identity key"))?;
+    if !key.verify(&[IDENTITY_DOMAIN, static_key].concat(), &proof.signature) {
+        return Err(handshake_error("static key not signed by the identity"));
+    }
+    Ok(key.to_peer_id())
+}
+
+/// Nonces accepted so far, within `REPLAY_WINDOW` of the newest
+#[derive(Default)]
+struct ReplayWindow {
+    newest: Option<u64>,
+    /// Bit `i` is set if nonce `newest - i` was accepted
+    seen: u64,
+}
+
+impl ReplayWindow {
+    fn fresh(&self, nonce: u64) -> bool {
+        match self.newest {
+            None => true,
+            Some(newest) if nonce > newest => true,
+            Some(newest) => {
+                let behind = newest - nonce;
+                behind < REPLAY_WINDOW && self.seen & (1 << behind) == 0
+            }
+        }
+    }
+
+    fn accept(&mut self, nonce: u64) {
+        match self.newest {
+            Some(newest) if nonce <= newest => self.seen |= 1 << (newest - nonce),
+            Some(newest) => {
+                let ahead = nonce - newest;
+                self.seen = if ahead >= REPLAY_WINDOW {
+                    1
+                } else {
+                    (self.seen << ahead) | 1
+                };
+                self.newest = Some(nonce);
+            }
+            None => {
+                self.seen = 1;
+                self.newest = Some(nonce);
+            }
+        }
+    }
+}
+
+enum Phase {
+    Handshake(Box<HandshakeState>),
+    Transport {
+        state: Box<StatelessTransportState>,
+        next_nonce: u64,
+        replay: ReplayWindow,
+    },
+    /// A handshake step failed; the stream has to be closed
+    Failed,
+}
+
+/// What a received chunk produced
+#[derive(Default)]
+pub(crate) struct Received {
+    /// Handshake message to send back
+    pub reply: Option<Vec<u8>>,
+    /// Decrypted stream data
+    pub data: Option<Vec<u8>>,
+    /// The remote's proven identity, once, when the handshake completes
+    pub secured: Option<LibPeerId>,
+}
+
+/// One end of an end-to-end encrypted stream
+pub(crate) struct SecureStream {
+    identity: Keypair,
+    static_key: Vec<u8>,
+    /// Identity the remote has to prove (None = any, reported when secured)
+    pinned: Option<LibPeerId>,
+    phase: Phase,
+    /// Data sent before the handshake completed
+    pending: Vec<Vec<u8>>,
+}
+
+impl SecureStream {
+    /// Opening side; returns the first handshake chunk to send
+    pub fn initiate(identity: &Keypair, pinned: LibPeerId) -> Result<(Self, Vec<u8>), AviP2pError> {
+        let mut stream = Self::new(identity, Some(pinned), true)?;
+        let first = stream.write_handshake(&[])?;
+        Ok((stream, first))
+    }
+
+    /// Accepting side; waits for the opener's first chunk
+    pub fn respond(identity: &Keypair) -> Result<Self, AviP2pError> {
+        Self::new(identity, None, false)
+    }
+
+    fn new(
+        identity: &Keypair,
+        pinned: Option<LibPeerId>,
+        initiator: bool,
+    ) -> Result<Self, AviP2pError> {
+        let params = NOISE_PARAMS
+            .parse()
+            .map_err(|_| handshake_error("unsupported parameters"))?;
+        let builder = Builder::new(params);
+        let keys = builder
+            .generate_keypair()
+            .map_err(|e| AviP2pError::Encryption(e.to_string()))?;
+        let builder = builder.local_private_key(&keys.private);
+        let state = if initiator {
+            builder.build_initiator()
+        } else {
+            builder.build_responder()
+        }
+        .map_err(|e| AviP2pError::Encryption(e.to_string()))?;
+        Ok(Self {
+            identity: identity.clone(),
+            static_key: keys.public,
+            pinned,
+            phase: Phase::Handshake(Box::new(state)),
+            pending: Vec::new(),
+        })
+    }
+
+    fn write_handshake(&mut self, payload: &[u8]) -> Result<Vec<u8>, AviP2pError> {
+        let Phase::Handshake(state) = &mut self.phase else {
+            return Err(handshake_error("already complete"));
+        };
+        let mut message = vec![0u8; MAX_NOISE_MESSAGE];
+        let len = state
+            .write_message(payload, &mut message)
+            .map_err(|e| AviP2pError::Encryption(e.to_string()))?;
+        let mut chunk = Vec::with_capacity(len + 1);
+        chunk.push(FRAME_HANDSHAKE);
+        chunk.extend_from_slice(&message[..len]);
+        Ok(chunk)
+    }
+
+    /// Handle a chunk received on the stream
+    pub fn receive(&mut self, chunk: &[u8]) -> Result<Received, AviP2pError> {
+        let Some((&kind, body)) = chunk.split_first() else {
+            return Err(AviP2pError::Encryption("empty stream chunk".to_string()));
+        };
+        match (kind, &mut self.phase) {
+            (FRAME_HANDSHAKE, Phase::Handshake(_)) => self.receive_handshake(body),
+            (FRAME_TRANSPORT, Phase::Transport { state, replay, .. }) => {
+                let data = open_pieces(state, replay, body)?;
+                Ok(Received {
+                    data: Some(data),
+                    ..Default::default()
+                })
+            }
+            _ => Err(AviP2pError::Encryption(
+                "unexpected chunk for the stream's handshake state".to_string(),
+            )),
+        }
+    }
+
+    fn receive_handshake(&mut self, message: &[u8]) -> Result<Received, AviP2pError> {
+        let Phase::Handshake(state) = &mut self.phase else {
+            unreachable!("checked by receive");
+        };
+        let mut payload = vec![0u8; MAX_NOISE_MESSAGE];
+        let len = state
+            .read_message(message, &mut payload)
+            .map_err(|e| AviP2pError::Encryption(e.to_string()))?;
+
+        // Only the messages carrying a static key carry its proof
+        let mut remote = None;
+        if len > 0 {
+            let peer = verify(&payload[..len], state.get_remote_static())?;
+            if let Some(pinned) = self.pinned.filter(|pinned| *pinned != peer) {
+                return Err(AviP2pError::StreamPeerMismatch {
+                    expected: pinned.into(),
+                    actual: peer.into(),
+                });
+            }
+            remote = Some(peer);
+        }
+
+        let mut received = Received::default();
+        if !state.is_handshake_finished() && state.is_my_turn() {
+            let proof = prove(&self.identity, &self.static_key)?;
+            received.reply = Some(self.write_handshake(&proof)?);
+        }
+
+        let Phase::Handshake(state) = &self.phase else {
+            unreachable!("checked by receive");
+        };
+
+        if state.is_handshake_finished() {
+            let Phase::Handshake(state) = std::mem::replace(&mut self.phase, Phase::Failed) else {
+                unreachable!("checked above");
+            };
+            let state = state
+                .into_stateless_transport_mode()
+                .map_err(|e| AviP2pError::Encryption(e.to_string()))?;
+            self.phase = Phase::Transport {
+                state: Box::new(state),
+                next_nonce: 0,
+                replay: ReplayWindow::default(),
+            };
+            received.secured = Some(remote.ok_or_else(|| handshake_error("no identity proof"))?);
+        }
+        Ok(received)
+    }
+
+    /// Seal `data` for sending; None while the handshake is still running,
+    /// in which case it goes out with `take_pending` once secured
+    pub fn seal(&mut self, data: Vec<u8>) -> Result<Option<Vec<u8>>, AviP2pError> {
+        let Phase::Transport {
+            state, next_nonce, ..
+        } = &mut self.phase
+        else {
+            self.pending.push(data);
+            return Ok(None);
+        };
+        let mut chunk = Vec::with_capacity(sealed_len(data.len()));
+        chunk.push(FRAME_TRANSPORT);
+        let mut sealed = vec![0u8; MAX_NOISE_MESSAGE];
+        for piece in data
+            .chunks(MAX_PIECE)
+            .chain(data.is_empty().then_some(&[][..]))
+        {
+            let len = state
+                .write_message(*next_nonce, piece, &mut sealed)
+                .map_err(|e| AviP2pError::Encryption(e.to_string()))?;
+            chunk.extend_from_slice(&next_nonce.to_be_bytes());
+            chunk.extend_from_slice(&(len as u16).to_be_bytes());
+            chunk.extend_from_slice(&sealed[..len]);
+            *next_nonce += 1;
+        }
+        Ok(Some(chunk))
+    }
+
+    /// Data held back during the handshake, sealed
+    pub fn take_pending(&mut self) -> Result<Vec<Vec<u8>>, AviP2pError> {
+        let pending = std::mem::take(&mut self.pending);
+        let mut sealed = Vec::with_capacity(pending.len());
+        for data in pending {
+            sealed.extend(self.seal(data)?);
+        }
+        Ok(sealed)
+    }
+}
+
+fn open_pieces(
+    state: &StatelessTransportState,
+    replay: &mut ReplayWindow,
+    mut body: &[u8],
+) -> Result<Vec<u8>, AviP2pError> {
+    let corrupt = || AviP2pError::Encryption("corrupt sealed stream chunk".to_string());
+    let mut data = Vec::with_capacity(body.len());
+    let mut piece = vec![0u8; MAX_NOISE_MESSAGE];
+    while !body.is_empty() {
+        if body.len() < PIECE_HEADER {
+            return Err(corrupt());
+        }
+        let nonce = u64::from_be_bytes(body[..8].try_into().map_err(|_| corrupt())?);
+        let len = u16::from_be_bytes([body[8], body[9]]) as usize;
+        let sealed = body
+            .get(PIECE_HEADER..PIECE_HEADER + len)
+            .ok_or_else(corrupt)?;
+        if !replay.fresh(nonce) {
+            return Err(AviP2pError::Encryption(format!(
+                "replayed stream chunk (nonce {})",
+                nonce
+            )));
+        }
+        let opened = state
+            .read_message(nonce, sealed, &mut piece)
+            .map_err(|_| corrupt())?;
+        replay.accept(nonce);
+        data.extend_from_slice(&piece[..opened]);
+        body = &body[PIECE_HEADER + len..];
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run the handshake between an opener pinned to `pinned` and a
+    /// responder with identity `responder`
+    fn handshake(
+        opener: &Keypair,
+        responder: &Keypair,
+        pinned: LibPeerId,
+    ) -> Result<(SecureStream, SecureStream), AviP2pError> {
+        let (mut initiator, first) = SecureStream::initiate(opener, pinned)?;
+        let mut acceptor = SecureStream::respond(responder)?;
+        let second = acceptor.receive(&first)?.reply.unwrap();
+        let third = initiator.receive(&second)?;
+        assert_eq!(third.secured, Some(responder.public().to_peer_id()));
+        let done = acceptor.receive(&third.reply.unwrap())?;
+        assert_eq!(done.secured, Some(opener.public().to_peer_id()));
+        Ok((initiator, acceptor))
+    }
+
+    #[test]
+    fn test_secure_stream_seals_data_after_handshake() {
+        let opener = Keypair::generate_ed25519();
+        let responder = Keypair::generate_ed25519();
+        let (mut initiator, mut acceptor) =
+            handshake(&opener, &responder, responder.public().to_peer_id()).unwrap();
+
+        let first = initiator.seal(b"hello".to_vec()).unwrap().unwrap();
+        let second = initiator.seal(vec![7; MAX_PIECE + 10]).unwrap().unwrap();
+        assert!(!first.windows(5).any(|w| w == b"hello"));
+        assert_eq!(second.len(), sealed_len(MAX_PIECE + 10));
+
+        // Out of order is fine, a replay is not
+        assert_eq!(
+            acceptor.receive(&second).unwrap().data.unwrap().len(),
+            MAX_PIECE + 10
+        );
+        assert_eq!(acceptor.receive(&first).unwrap().data.unwrap(), b"hello");
+        assert!(acceptor.receive(&first).is_err());
+    }
+
+    #[test]
+    fn test_secure_stream_holds_data_until_secured() {
+        let opener = Keypair::generate_ed25519();
+        let responder = Keypair::generate_ed25519();
+        let (mut initiator, first) =
+            SecureStream::initiate(&opener, responder.public().to_peer_id()).unwrap();
+        let mut acceptor = SecureStream::respond(&responder).unwrap();
+        assert_eq!(acceptor.seal(b"early".to_vec()).unwrap(), None);
+
+        let second = acceptor.receive(&first).unwrap().reply.unwrap();
+        let third = initiator.receive(&second).unwrap().reply.unwrap();
+        acceptor.receive(&third).unwrap();
+        let pending = acceptor.take_pending().unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(
+            initiator.receive(&pending[0]).unwrap().data.unwrap(),
+            b"early"
+        );
+    }
+
+    #[test]
+    fn test_secure_stream_rejects_unpinned_peer() {
+        let opener = Keypair::generate_ed25519();
+        let impostor = Keypair::generate_ed25519();
+        let expected = Keypair::generate_ed25519().public().to_peer_id();
+        assert!(matches!(
+            handshake(&opener, &impostor, expected),
+            Err(AviP2pError::StreamPeerMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_secure_reason() {
+        let reason = secure_reason("audio");
+        assert!(is_secure_reason(&reason));
+        assert!(!is_secure_reason("audio"));
+        assert!(!is_secure_reason("e2e"));
+    }
+}
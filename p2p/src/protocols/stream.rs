@@ -1,3 +1,4 @@
+use crate::auth::MembershipCertificate;
 use async_trait::async_trait;
 use futures::prelude::*;
 use libp2p::request_response::Codec;
@@ -8,6 +9,24 @@ use std::{fmt, io};
 
 static NEXT_STREAM_ID: AtomicU64 = AtomicU64::new(1);
 
+/// Newest stream handshake version this node speaks
+pub const STREAM_VERSION: u32 = 1;
+
+/// Oldest version this node can fall back to for a peer that lacks newer ones
+pub const MIN_STREAM_VERSION: u32 = 1;
+
+/// Version assumed for peers that predate versioned handshakes
+fn initial_version() -> u32 {
+    1
+}
+
+/// Version two nodes use given the newest the remote speaks: the lower of
+/// the two newest versions, if this node still supports it
+pub fn negotiate_stream_version(remote: u32) -> Option<u32> {
+    let version = remote.min(STREAM_VERSION);
+    (version >= MIN_STREAM_VERSION).then_some(version)
+}
+
 pub fn generate_stream_id() -> StreamId {
     StreamId(NEXT_STREAM_ID.fetch_add(1, std::sync::atomic::Ordering::SeqCst))
 }
@@ -24,13 +43,15 @@ pub struct StreamState {
     pub peer: LibPeerId,
     #[allow(dead_code)]
     pub reason: String,
+    /// Handshake version offered (while requested) or agreed (once accepted)
+    pub version: u32,
     #[allow(dead_code)]
     pub status: StreamStatus,
     #[allow(dead_code)]
     pub direction: StreamDirection,
 }
 
-#[derive(PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum StreamDirection {
     Inbound,
     Outbound,
@@ -47,20 +68,99 @@ impl fmt::Display for StreamId {
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum StreamMessage {
-    RequestStream { stream_id: u64, reason: String },
-    AcceptStream { stream_id: u64 },
-    RejectStream { stream_id: u64, reason: String },
-    StreamData { stream_id: u64, data: Vec<u8> },
-    CloseStream { stream_id: u64 },
-    SyncContext(super::context::AviContext),
+    RequestStream {
+        stream_id: u64,
+        reason: String,
+        /// Newest handshake version the requester speaks
+        #[serde(default = "initial_version")]
+        version: u32,
+    },
+    AcceptStream {
+        stream_id: u64,
+        /// Version both sides use from here on
+        #[serde(default = "initial_version")]
+        version: u32,
+        /// Application data answered with the acceptance, e.g. the codec
+        /// picked, so the opener can start sending without another round trip
+        #[serde(default)]
+        payload: Option<Vec<u8>>,
+    },
+    RejectStream {
+        stream_id: u64,
+        reason: String,
+        /// Set when rejected for sharing no version: the rejecter's newest
+        #[serde(default)]
+        version: Option<u32>,
+    },
+    StreamData {
+        stream_id: u64,
+        data: Vec<u8>,
+    },
+    CloseStream {
+        stream_id: u64,
+    },
+    SyncContext(super::context::SignedContext),
+    /// Anti-entropy round opener: the sender's clock, so the receiver pushes
+    /// its context only if the sender is missing updates
+    ContextSummary {
+        clock: super::context::VectorClock,
+    },
+    /// Ask the receiver to push its current context
+    ContextRequest,
+    Rekey {
+        scope: String,
+        epoch: u32,
+        key: Vec<u8>,
+    },
+    AuthChallenge {
+        nonce: Vec<u8>,
+    },
+    AuthResponse {
+        nonce: Vec<u8>,
+        public_key: Vec<u8>,
+        signature: Vec<u8>,
+        certificate: Option<MembershipCertificate>,
+    },
+    /// Point-to-point payload, e.g. delivered from the sender's outbox
+    Direct {
+        data: Vec<u8>,
+    },
+    /// Ask for messages the receiver took on `topics` within the last
+    /// `window_ms`, at most `limit` per topic
+    HistoryRequest {
+        topics: Vec<String>,
+        window_ms: u64,
+        limit: u32,
+    },
+    HistoryResponse {
+        messages: Vec<crate::history::HistoryEntry>,
+    },
+    /// Trade a pairing token for the invitation held under it
+    PairingRequest {
+        token: String,
+    },
+    /// The invitation, or None if the token is unknown, used or expired
+    PairingResponse {
+        invitation: Option<Vec<u8>>,
+    },
 }
 
-#[derive(Debug, Clone)]
-pub struct AviStreamProtocol;
+/// Stream protocol name, scoped to the mesh namespace if there is one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AviStreamProtocol(String);
+
+impl AviStreamProtocol {
+    pub fn new(namespace: Option<&str>) -> Self {
+        match namespace {
+            Some(namespace) => Self(format!("/avi/{}/stream/1.0.0", namespace)),
+            None => Self("/avi/stream/1.0.0".to_string()),
+        }
+    }
+}
 
 impl AsRef<str> for AviStreamProtocol {
     fn as_ref(&self) -> &str {
-        "/avi/stream/1.0.0"
+        &self.0
     }
 }
 
@@ -139,3 +239,24 @@ impl Codec for AviStreamCodec {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_versions_negotiate_down_and_default_for_old_peers() {
+        assert_eq!(
+            negotiate_stream_version(STREAM_VERSION + 1),
+            Some(STREAM_VERSION)
+        );
+        assert_eq!(negotiate_stream_version(MIN_STREAM_VERSION - 1), None);
+
+        // A peer from before versioning sends no version field
+        let old = br#"{"RequestStream":{"stream_id":7,"reason":"audio"}}"#;
+        match serde_json::from_slice(old).unwrap() {
+            StreamMessage::RequestStream { version, .. } => assert_eq!(version, 1),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,410 @@
+use crate::events::AviEvent;
+use crate::health::ChannelUsage;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, Notify};
+
+/// Event categories that can be given different overflow policies
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventClass {
+    /// Pub/sub messages
+    Message,
+    /// Stream payload chunks
+    StreamData,
+    /// Context updates and rejections
+    Context,
+    /// Everything else: lifecycle, peers, stream control, auth, keys
+    Control,
+}
+
+/// What the runtime does when the event queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Wait for room (backpressure on the runtime)
+    #[default]
+    Block,
+    /// Evict the oldest queued event of the same class
+    DropOldest,
+    /// Discard the incoming event
+    DropNewest,
+}
+
+/// Overflow policy per event class
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OverflowPolicies {
+    pub messages: OverflowPolicy,
+    pub stream_data: OverflowPolicy,
+    pub context: OverflowPolicy,
+    pub control: OverflowPolicy,
+}
+
+impl OverflowPolicies {
+    pub fn for_class(&self, class: EventClass) -> OverflowPolicy {
+        match class {
+            EventClass::Message => self.messages,
+            EventClass::StreamData => self.stream_data,
+            EventClass::Context => self.context,
+            EventClass::Control => self.control,
+        }
+    }
+}
+
+struct Shared {
+    queue: Mutex<VecDeque<AviEvent>>,
+    capacity: usize,
+    policies: OverflowPolicies,
+    readable: Notify,
+    writable: Notify,
+    senders: AtomicUsize,
+    receiver_closed: AtomicBool,
+    dropped: AtomicU64,
+}
+
+/// Bounded runtime -> dispatcher event queue applying `OverflowPolicies`
+pub(crate) fn event_queue(
+    capacity: usize,
+    policies: OverflowPolicies,
+) -> (EventSender, EventReceiver) {
+    let shared = Arc::new(Shared {
+        queue: Mutex::new(VecDeque::with_capacity(capacity)),
+        capacity: capacity.max(1),
+        policies,
+        readable: Notify::new(),
+        writable: Notify::new(),
+        senders: AtomicUsize::new(1),
+        receiver_closed: AtomicBool::new(false),
+        dropped: AtomicU64::new(0),
+    });
+    (
+        EventSender {
+            shared: shared.clone(),
+        },
+        EventReceiver { shared },
+    )
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct QueueClosed;
+
+pub(crate) struct EventSender {
+    shared: Arc<Shared>,
+}
+
+impl EventSender {
+    pub async fn send(&self, event: AviEvent) -> Result<(), QueueClosed> {
+        let policy = self.shared.policies.for_class(event.class());
+        let mut event = event;
+
+        loop {
+            let writable = self.shared.writable.notified();
+            tokio::pin!(writable);
+            writable.as_mut().enable();
+
+            match self.try_push(event, policy)? {
+                None => return Ok(()),
+                Some(blocked) => event = blocked,
+            }
+
+            writable.await;
+        }
+    }
+
+    /// Enqueue without waiting: a full queue applies the class policy,
+    /// with `Block` treated as `DropNewest`
+    pub fn try_send(&self, event: AviEvent) -> Result<(), QueueClosed> {
+        let policy = match self.shared.policies.for_class(event.class()) {
+            OverflowPolicy::Block => OverflowPolicy::DropNewest,
+            policy => policy,
+        };
+        self.try_push(event, policy).map(|_| ())
+    }
+
+    /// Returns the event back if the queue is full and the policy is `Block`
+    fn try_push(
+        &self,
+        event: AviEvent,
+        policy: OverflowPolicy,
+    ) -> Result<Option<AviEvent>, QueueClosed> {
+        if self.shared.receiver_closed.load(Ordering::Acquire) {
+            return Err(QueueClosed);
+        }
+        let mut queue = self.shared.queue.lock().unwrap();
+
+        if queue.len() < self.shared.capacity {
+            queue.push_back(event);
+            self.shared.readable.notify_one();
+            return Ok(None);
+        }
+
+        match policy {
+            OverflowPolicy::Block => return Ok(Some(event)),
+            OverflowPolicy::DropNewest => {}
+            OverflowPolicy::DropOldest => {
+                // Only evict within the same class; if none is queued
+                // the incoming event is the oldest of its class
+                let class = event.class();
+                if let Some(pos) = queue.iter().position(|e| e.class() == class) {
+                    queue.remove(pos);
+                    queue.push_back(event);
+                    self.shared.readable.notify_one();
+                }
+            }
+        }
+        self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+        Ok(None)
+    }
+
+    pub fn usage(&self) -> ChannelUsage {
+        usage(&self.shared)
+    }
+
+    /// Events discarded by the overflow policies so far
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+}
+
+fn usage(shared: &Shared) -> ChannelUsage {
+    ChannelUsage {
+        used: shared.queue.lock().map(|q| q.len()).unwrap_or(0),
+        capacity: shared.capacity,
+    }
+}
+
+impl Clone for EventSender {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::AcqRel);
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for EventSender {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.shared.readable.notify_one();
+        }
+    }
+}
+
+pub(crate) struct EventReceiver {
+    shared: Arc<Shared>,
+}
+
+impl EventReceiver {
+    /// Next event, or `None` once every sender is gone and the queue is drained
+    pub async fn recv(&mut self) -> Option<AviEvent> {
+        loop {
+            let readable = self.shared.readable.notified();
+            tokio::pin!(readable);
+            readable.as_mut().enable();
+
+            if let Some(event) = self.shared.queue.lock().unwrap().pop_front() {
+                self.shared.writable.notify_one();
+                return Some(event);
+            }
+            if self.shared.senders.load(Ordering::Acquire) == 0 {
+                return None;
+            }
+
+            readable.await;
+        }
+    }
+}
+
+impl Drop for EventReceiver {
+    fn drop(&mut self) {
+        self.shared.receiver_closed.store(true, Ordering::Release);
+        self.shared.writable.notify_waiters();
+    }
+}
+
+/// Fans events out to every subscriber's own bounded queue. Dispatch never
+/// waits, so a slow subscriber only loses its own events.
+#[derive(Clone)]
+pub(crate) struct Dispatcher {
+    subscribers: Arc<Mutex<Option<Vec<EventSender>>>>,
+    /// Unbounded queues that get every stream event
+    stream_subscribers: Arc<Mutex<Option<Vec<mpsc::UnboundedSender<AviEvent>>>>>,
+    capacity: usize,
+    policies: OverflowPolicies,
+}
+
+impl Dispatcher {
+    pub fn new(capacity: usize, policies: OverflowPolicies) -> Self {
+        Self {
+            subscribers: Arc::new(Mutex::new(Some(Vec::new()))),
+            stream_subscribers: Arc::new(Mutex::new(Some(Vec::new()))),
+            capacity,
+            policies,
+        }
+    }
+
+    pub fn subscribe(&self) -> EventSubscription {
+        let (tx, rx) = event_queue(self.capacity, self.policies);
+        if let Some(subscribers) = self.subscribers.lock().unwrap().as_mut() {
+            subscribers.push(tx);
+        }
+        EventSubscription { rx }
+    }
+
+    pub fn subscribe_streams(&self) -> StreamEventSubscription {
+        let (tx, rx) = mpsc::unbounded_channel();
+        if let Some(subscribers) = self.stream_subscribers.lock().unwrap().as_mut() {
+            subscribers.push(tx);
+        }
+        StreamEventSubscription { rx }
+    }
+
+    pub fn dispatch(&self, event: AviEvent) {
+        if event.stream_id().is_some() {
+            if let Some(subscribers) = self.stream_subscribers.lock().unwrap().as_mut() {
+                subscribers.retain(|tx| tx.send(event.clone()).is_ok());
+            }
+        }
+        if let Some(subscribers) = self.subscribers.lock().unwrap().as_mut() {
+            subscribers.retain(|tx| tx.try_send(event.clone()).is_ok());
+        }
+    }
+
+    /// End every subscription once its queue drains
+    pub fn close(&self) {
+        self.subscribers.lock().unwrap().take();
+        self.stream_subscribers.lock().unwrap().take();
+    }
+
+    pub fn usage(&self) -> Vec<ChannelUsage> {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .iter()
+            .flatten()
+            .map(|tx| tx.usage())
+            .collect()
+    }
+}
+
+/// A receiver of node events with its own bounded queue
+pub struct EventSubscription {
+    rx: EventReceiver,
+}
+
+impl EventSubscription {
+    /// Next event, or `None` once the node has shut down
+    pub async fn recv(&mut self) -> Option<AviEvent> {
+        self.rx.recv().await
+    }
+
+    /// Events this subscriber missed because its queue was full
+    pub fn dropped(&self) -> u64 {
+        self.rx.shared.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Current fill level of this subscriber's queue
+    pub fn usage(&self) -> ChannelUsage {
+        usage(&self.rx.shared)
+    }
+}
+
+/// Every stream event (opens, data, closes), without overflow policies:
+/// the queue grows instead of dropping, so a consumer that tracks stream
+/// state never falls out of step. It must keep up on average.
+pub struct StreamEventSubscription {
+    rx: mpsc::UnboundedReceiver<AviEvent>,
+}
+
+impl StreamEventSubscription {
+    /// Next stream event, or `None` once the node has shut down
+    pub async fn recv(&mut self) -> Option<AviEvent> {
+        self.rx.recv().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::PeerId;
+
+    fn message(n: u8) -> AviEvent {
+        AviEvent::Message {
+            from: PeerId::new("peer"),
+            topic: "t".to_string(),
+            data: vec![n],
+            replayed: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_slow_subscriber_does_not_block_others() {
+        let dispatcher = Dispatcher::new(1, OverflowPolicies::default());
+        let mut slow = dispatcher.subscribe();
+        let mut fast = dispatcher.subscribe();
+
+        dispatcher.dispatch(message(1));
+        assert!(fast.recv().await.is_some());
+        dispatcher.dispatch(message(2));
+        assert!(fast.recv().await.is_some());
+        dispatcher.close();
+
+        assert_eq!(slow.dropped(), 1);
+        assert!(
+            matches!(slow.recv().await, Some(AviEvent::Message { data, .. }) if data == vec![1])
+        );
+        assert!(slow.recv().await.is_none());
+        assert!(fast.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_stream_subscribers_get_every_stream_event() {
+        let dispatcher = Dispatcher::new(1, OverflowPolicies::default());
+        let events = dispatcher.subscribe();
+        let mut streams = dispatcher.subscribe_streams();
+
+        for n in 0..3u8 {
+            dispatcher.dispatch(AviEvent::StreamData {
+                from: PeerId::new("peer"),
+                stream_id: crate::StreamId(7),
+                data: vec![n],
+            });
+        }
+        dispatcher.dispatch(message(9));
+        dispatcher.close();
+
+        assert_eq!(events.dropped(), 3);
+        for n in 0..3u8 {
+            assert!(matches!(
+                streams.recv().await,
+                Some(AviEvent::StreamData { data, .. }) if data == vec![n]
+            ));
+        }
+        assert!(streams.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_drop_oldest_evicts_same_class_only() {
+        let policies = OverflowPolicies {
+            messages: OverflowPolicy::DropOldest,
+            ..Default::default()
+        };
+        let (tx, mut rx) = event_queue(2, policies);
+
+        tx.send(AviEvent::PeerDiscovered {
+            peer_id: PeerId::new("p"),
+        })
+        .await
+        .unwrap();
+        tx.send(message(1)).await.unwrap();
+        tx.send(message(2)).await.unwrap();
+        assert_eq!(tx.dropped(), 1);
+        drop(tx);
+
+        assert!(matches!(
+            rx.recv().await,
+            Some(AviEvent::PeerDiscovered { .. })
+        ));
+        assert!(matches!(rx.recv().await, Some(AviEvent::Message { data, .. }) if data == vec![2]));
+        assert!(rx.recv().await.is_none());
+    }
+}
@@ -0,0 +1,111 @@
+//! Per-topic rate limits on publishes and deliveries.
+//!
+//! Each limit is a pair of token buckets (messages and bytes) holding up to
+//! one second's worth of tokens, so short bursts pass while a sustained rate
+//! above the limit is cut down to it.
+
+use crate::config::RateLimit;
+use std::time::Instant;
+
+#[derive(Clone)]
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64) -> Self {
+        Self {
+            rate,
+            tokens: rate,
+            refilled: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.refilled = now;
+    }
+}
+
+/// Outcome of offering a message to a `RateLimiter`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Admission {
+    Allowed,
+    /// Over the limit; `first` is set for the first drop after an allowed message
+    Limited {
+        first: bool,
+    },
+}
+
+#[derive(Clone)]
+pub(crate) struct RateLimiter {
+    messages: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+    limited: bool,
+}
+
+impl RateLimiter {
+    pub fn new(limit: &RateLimit) -> Self {
+        Self {
+            messages: limit.messages_per_sec.map(|r| TokenBucket::new(r as f64)),
+            bytes: limit.bytes_per_sec.map(|r| TokenBucket::new(r as f64)),
+            limited: false,
+        }
+    }
+
+    /// Take tokens for a message of `size` bytes if both buckets have them
+    pub fn admit(&mut self, size: usize) -> Admission {
+        let now = Instant::now();
+        let cost = [1.0, size as f64];
+        let fits = [&mut self.messages, &mut self.bytes]
+            .into_iter()
+            .zip(cost)
+            .all(|(bucket, cost)| {
+                bucket.as_mut().is_none_or(|b| {
+                    b.refill(now);
+                    b.tokens >= cost
+                })
+            });
+        if !fits {
+            let first = !self.limited;
+            self.limited = true;
+            return Admission::Limited { first };
+        }
+
+        for (bucket, cost) in [&mut self.messages, &mut self.bytes].into_iter().zip(cost) {
+            if let Some(bucket) = bucket {
+                bucket.tokens -= cost;
+            }
+        }
+        self.limited = false;
+        Admission::Allowed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bursts_over_the_limit_are_cut_and_reported_once() {
+        let mut limiter = RateLimiter::new(&RateLimit {
+            messages_per_sec: Some(2),
+            bytes_per_sec: Some(100),
+        });
+        assert_eq!(limiter.admit(10), Admission::Allowed);
+        assert_eq!(limiter.admit(10), Admission::Allowed);
+        assert_eq!(limiter.admit(10), Admission::Limited { first: true });
+        assert_eq!(limiter.admit(10), Admission::Limited { first: false });
+
+        // A message bigger than a second's worth of bytes never fits
+        let mut bytes_only = RateLimiter::new(&RateLimit {
+            messages_per_sec: None,
+            bytes_per_sec: Some(100),
+        });
+        assert_eq!(bytes_only.admit(101), Admission::Limited { first: true });
+        assert_eq!(bytes_only.admit(100), Admission::Allowed);
+    }
+}
@@ -0,0 +1,203 @@
+use crate::error::AviP2pError;
+use crate::protocols::stream::StreamDirection;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::debug;
+
+/// Chunks a recording may have waiting for its writer before it is given up
+const RECORDING_QUEUE: usize = 1024;
+
+/// One chunk read back from a stream recording
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedChunk {
+    /// `Inbound` for data received from the peer, `Outbound` for data sent
+    pub direction: StreamDirection,
+    /// Time since the recording started
+    pub elapsed: Duration,
+    pub data: Vec<u8>,
+}
+
+/// Index line describing where a chunk sits in the data file
+#[derive(Debug, Serialize, Deserialize)]
+struct IndexEntry {
+    offset: u64,
+    len: u64,
+    inbound: bool,
+    elapsed_ms: u64,
+}
+
+/// A recording as the runtime holds it: chunks are handed to a
+/// `StreamRecorder` on the blocking pool, so a slow disk never holds up
+/// the event loop. Dropping it ends the recording once the queue is written.
+pub(crate) struct Recording {
+    chunks: mpsc::Sender<(StreamDirection, Duration, Vec<u8>)>,
+    started: Instant,
+}
+
+impl Recording {
+    pub fn start(path: &Path) -> Result<Self, AviP2pError> {
+        let mut recorder = StreamRecorder::create(path)?;
+        let (chunks, mut queue) =
+            mpsc::channel::<(StreamDirection, Duration, Vec<u8>)>(RECORDING_QUEUE);
+        tokio::task::spawn_blocking(move || {
+            while let Some((direction, elapsed, chunk)) = queue.blocking_recv() {
+                if let Err(e) = recorder.record(direction, elapsed, &chunk) {
+                    debug!("Stream recording failed: {}", e);
+                    return;
+                }
+            }
+            if let Err(e) = recorder.flush() {
+                debug!("Stream recording failed: {}", e);
+            }
+        });
+        Ok(Self {
+            chunks,
+            started: Instant::now(),
+        })
+    }
+
+    /// Queue a chunk for the writer; fails once the writer has stopped or
+    /// fallen `RECORDING_QUEUE` chunks behind
+    pub fn record(&self, direction: StreamDirection, chunk: &[u8]) -> Result<(), AviP2pError> {
+        self.chunks
+            .try_send((direction, self.started.elapsed(), chunk.to_vec()))
+            .map_err(|e| {
+                AviP2pError::Io(match e {
+                    TrySendError::Full(_) => "recording writer fell behind".to_string(),
+                    TrySendError::Closed(_) => "recording writer stopped".to_string(),
+                })
+            })
+    }
+}
+
+/// Tees stream data into `<path>` (raw chunks back to back) and
+/// `<path>.idx` (one JSON line per chunk with its offset and timestamp)
+struct StreamRecorder {
+    data: BufWriter<File>,
+    index: BufWriter<File>,
+    offset: u64,
+}
+
+impl StreamRecorder {
+    fn create(path: &Path) -> Result<Self, AviP2pError> {
+        let data = File::create(path).map_err(|e| AviP2pError::Io(e.to_string()))?;
+        let index = File::create(index_path(path)).map_err(|e| AviP2pError::Io(e.to_string()))?;
+        Ok(Self {
+            data: BufWriter::new(data),
+            index: BufWriter::new(index),
+            offset: 0,
+        })
+    }
+
+    /// Append a chunk taken `elapsed` into the recording
+    fn record(
+        &mut self,
+        direction: StreamDirection,
+        elapsed: Duration,
+        chunk: &[u8],
+    ) -> Result<(), AviP2pError> {
+        let entry = IndexEntry {
+            offset: self.offset,
+            len: chunk.len() as u64,
+            inbound: direction == StreamDirection::Inbound,
+            elapsed_ms: elapsed.as_millis() as u64,
+        };
+        let line =
+            serde_json::to_string(&entry).map_err(|e| AviP2pError::Serialization(e.to_string()))?;
+
+        self.data
+            .write_all(chunk)
+            .and_then(|_| writeln!(self.index, "{}", line))
+            .map_err(|e| AviP2pError::Io(e.to_string()))?;
+        self.offset += chunk.len() as u64;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), AviP2pError> {
+        self.data
+            .flush()
+            .and_then(|_| self.index.flush())
+            .map_err(|e| AviP2pError::Io(e.to_string()))
+    }
+}
+
+/// Read back a recording made with `AviP2pHandle::record_stream`
+pub fn read_recording(path: impl AsRef<Path>) -> Result<Vec<RecordedChunk>, AviP2pError> {
+    let path = path.as_ref();
+    let mut data = Vec::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut data))
+        .map_err(|e| AviP2pError::Io(e.to_string()))?;
+    let index = File::open(index_path(path)).map_err(|e| AviP2pError::Io(e.to_string()))?;
+
+    let mut chunks = Vec::new();
+    for line in BufReader::new(index).lines() {
+        let line = line.map_err(|e| AviP2pError::Io(e.to_string()))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: IndexEntry =
+            serde_json::from_str(&line).map_err(|e| AviP2pError::Serialization(e.to_string()))?;
+        let start = entry.offset as usize;
+        let chunk = data
+            .get(start..start + entry.len as usize)
+            .ok_or_else(|| AviP2pError::Io("recording data file is truncated".to_string()))?;
+        chunks.push(RecordedChunk {
+            direction: if entry.inbound {
+                StreamDirection::Inbound
+            } else {
+                StreamDirection::Outbound
+            },
+            elapsed: Duration::from_millis(entry.elapsed_ms),
+            data: chunk.to_vec(),
+        });
+    }
+    Ok(chunks)
+}
+
+fn index_path(path: &Path) -> PathBuf {
+    let mut index = path.as_os_str().to_owned();
+    index.push(".idx");
+    PathBuf::from(index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_recording_round_trips_with_directions() {
+        let path = std::env::temp_dir().join(format!("avi-rec-{}.bin", rand::random::<u64>()));
+
+        let recording = Recording::start(&path).unwrap();
+        recording
+            .record(StreamDirection::Outbound, b"hey avi")
+            .unwrap();
+        recording.record(StreamDirection::Inbound, b"").unwrap();
+        recording.record(StreamDirection::Inbound, b"ok").unwrap();
+        drop(recording);
+
+        // The writer finishes on its own once the recording is dropped
+        let mut chunks = Vec::new();
+        for _ in 0..100 {
+            chunks = read_recording(&path).unwrap_or_default();
+            if chunks.len() == 3 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[0].direction, StreamDirection::Outbound);
+        assert_eq!(chunks[0].data, b"hey avi");
+        assert_eq!(chunks[2].direction, StreamDirection::Inbound);
+        assert_eq!(chunks[2].data, b"ok");
+        assert!(chunks[2].elapsed >= chunks[0].elapsed);
+
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(index_path(&path));
+    }
+}
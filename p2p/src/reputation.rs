@@ -0,0 +1,167 @@
+//! Per-peer protocol violation tracking and bans.
+//!
+//! Each violation adds its penalty to the offending peer's score, which
+//! halves every `score_half_life`. A peer whose score reaches
+//! `ban_threshold` is disconnected and refused for `ban_duration`. Bans can
+//! also be placed and lifted by hand through `AviP2pHandle::ban_peer` and
+//! `unban_peer`.
+
+use libp2p::PeerId as LibPeerId;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Misbehaviour counted against a peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Violation {
+    /// A signed message (e.g. a context update) failed verification
+    BadSignature,
+    /// A payload over the node's size limits
+    OversizedPayload,
+    /// A request that could not be decoded
+    DecodeFailure,
+    /// Data for streams not open with us. A few chunks still in flight
+    /// when a stream closes are normal, so each counts for little.
+    StreamAbuse,
+}
+
+impl Violation {
+    /// Score added for one occurrence
+    pub fn penalty(&self) -> f64 {
+        match self {
+            Violation::BadSignature => 50.0,
+            Violation::OversizedPayload => 25.0,
+            Violation::DecodeFailure => 10.0,
+            Violation::StreamAbuse => 1.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReputationConfig {
+    /// Score at which a peer is banned (None = only ban by hand)
+    pub ban_threshold: Option<f64>,
+
+    /// Time for a peer's score to halve without new violations
+    pub score_half_life: Duration,
+
+    /// How long an automatic ban lasts
+    pub ban_duration: Duration,
+}
+
+impl Default for ReputationConfig {
+    fn default() -> Self {
+        Self {
+            ban_threshold: Some(100.0),
+            score_half_life: Duration::from_secs(600),
+            ban_duration: Duration::from_secs(3600),
+        }
+    }
+}
+
+struct Score {
+    points: f64,
+    updated: Instant,
+}
+
+pub(crate) struct Reputation {
+    config: ReputationConfig,
+    scores: HashMap<LibPeerId, Score>,
+    /// Banned peer -> end of the ban (None = until lifted by hand)
+    bans: HashMap<LibPeerId, Option<Instant>>,
+}
+
+impl Reputation {
+    pub fn new(config: ReputationConfig) -> Self {
+        Self {
+            config,
+            scores: HashMap::new(),
+            bans: HashMap::new(),
+        }
+    }
+
+    /// Count a violation; returns the ban length if it got the peer banned
+    pub fn record(&mut self, peer: LibPeerId, violation: Violation) -> Option<Duration> {
+        if self.is_banned(&peer) {
+            return None;
+        }
+        let now = Instant::now();
+        let half_life = self.config.score_half_life.as_secs_f64().max(f64::EPSILON);
+        let score = self.scores.entry(peer).or_insert(Score {
+            points: 0.0,
+            updated: now,
+        });
+        let elapsed = now.duration_since(score.updated).as_secs_f64();
+        score.points = score.points * 0.5f64.powf(elapsed / half_life) + violation.penalty();
+        score.updated = now;
+
+        let threshold = self.config.ban_threshold?;
+        if score.points < threshold {
+            return None;
+        }
+        let duration = self.config.ban_duration;
+        self.ban(peer, Some(duration));
+        Some(duration)
+    }
+
+    pub fn ban(&mut self, peer: LibPeerId, duration: Option<Duration>) {
+        self.scores.remove(&peer);
+        self.bans
+            .insert(peer, duration.map(|duration| Instant::now() + duration));
+    }
+
+    /// Lift a ban and forget the peer's score; false if it was not banned
+    pub fn unban(&mut self, peer: &LibPeerId) -> bool {
+        self.scores.remove(peer);
+        self.bans.remove(peer).is_some()
+    }
+
+    pub fn is_banned(&mut self, peer: &LibPeerId) -> bool {
+        match self.bans.get(peer) {
+            Some(Some(until)) if *until <= Instant::now() => {
+                self.bans.remove(peer);
+                false
+            }
+            Some(_) => true,
+            None => false,
+        }
+    }
+
+    /// Banned peers with the time left on their ban (None = indefinite)
+    pub fn banned(&mut self) -> Vec<(LibPeerId, Option<Duration>)> {
+        let now = Instant::now();
+        self.bans
+            .retain(|_, until| until.is_none_or(|until| until > now));
+        self.bans
+            .iter()
+            .map(|(peer, until)| (*peer, until.map(|until| until - now)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_violations_add_up_to_a_ban_that_can_be_lifted() {
+        let peer = LibPeerId::random();
+        let mut reputation = Reputation::new(ReputationConfig::default());
+
+        assert_eq!(reputation.record(peer, Violation::BadSignature), None);
+        assert_eq!(reputation.record(peer, Violation::OversizedPayload), None);
+        assert_eq!(
+            reputation.record(peer, Violation::BadSignature),
+            Some(Duration::from_secs(3600))
+        );
+        assert!(reputation.is_banned(&peer));
+
+        assert!(reputation.unban(&peer));
+        assert!(!reputation.is_banned(&peer));
+        // The score went with the ban
+        assert_eq!(reputation.record(peer, Violation::BadSignature), None);
+
+        reputation.ban(peer, Some(Duration::ZERO));
+        assert!(!reputation.is_banned(&peer));
+        assert!(reputation.banned().is_empty());
+    }
+}
@@ -1,20 +1,55 @@
 use futures::StreamExt;
-use std::collections::{HashMap, HashSet};
-use std::time::Duration;
-use tokio::sync::mpsc;
-use tracing::info;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, info};
 
 use libp2p::{
-    gossipsub, identify, mdns, request_response, swarm::SwarmEvent, Multiaddr, PeerId as LibPeerId,
-    Swarm,
+    gossipsub, identify,
+    identity::Keypair,
+    kad, mdns, request_response,
+    swarm::{ConnectionId, SwarmEvent},
+    Multiaddr, PeerId as LibPeerId, Swarm,
 };
 
+use crate::audit::{AuditAction, AuditLog};
+use crate::auth::{self, AuthConfig, Operation, Role};
 use crate::behaviour::{AviBehaviour, AviBehaviourEvent};
-use crate::command::Command;
+use crate::command::{Command, CommandReceiver};
+use crate::config::{
+    compact_topic_id, topic_profile, AviP2pConfig, ContextReplication, IdentitySecret, RateLimit,
+    RendezvousConfig, TopicProfile,
+};
+use crate::dedupe::{self, DedupeCache};
 use crate::error::{AviP2pError, StreamCloseReason};
-use crate::events::{AviEvent, PeerId};
-use crate::protocols::context::AviContext;
-use crate::protocols::stream::StreamMessage;
+use crate::events::{AviEvent, CorrelationId, DhtEntryKind, PeerId, PeerInfo};
+use crate::expiry;
+use crate::extension::ExtensionHandler;
+use crate::health::{HealthReport, RuntimeStats};
+use crate::history::{HistoryEntry, TopicHistory};
+use crate::invitation::{MeshDetails, MeshInvitation, NetworkKey};
+use crate::keys::{EncryptedPayload, KeyRing};
+use crate::outbox::Outbox;
+use crate::pairing::PairingPayload;
+use crate::protocols::context::{
+    check_provenance, diff_context, get_nested_value, AviContext, ContextInvalidation, ContextOp,
+    OriginState, Origins, SignedContext, SignedOrigin, VectorClock,
+};
+use crate::protocols::extension::{ExtensionRequest, ExtensionResponse};
+use crate::protocols::rendezvous::{RendezvousRegistry, RendezvousRequest, RendezvousResponse};
+use crate::protocols::secure::{self, is_secure_reason, SecureStream};
+use crate::protocols::stream::{
+    negotiate_stream_version, AviStreamProtocol, StreamMessage, MIN_STREAM_VERSION, STREAM_VERSION,
+};
+use crate::queue::EventSender;
+use crate::ratelimit::{Admission, RateLimiter};
+use crate::recording::Recording;
+use crate::reputation::{Reputation, Violation};
+use crate::snapshot::{NodeSnapshot, SNAPSHOT_FORMAT};
+use crate::view::ViewPublisher;
 use crate::{generate_stream_id, StreamDirection, StreamId, StreamState, StreamStatus};
 
 struct PeerState {
@@ -22,64 +57,439 @@ struct PeerState {
     addr: Option<String>,
 }
 
+/// Last context a peer signed, as received by us
+struct CachedContext {
+    data: serde_json::Value,
+    updated: Instant,
+    stale_reported: bool,
+    /// The peer announced a newer context; fetch before serving this one
+    invalidated: bool,
+}
+
+/// Outbound stream waiting for the peer to accept or reject it
+struct PendingOpen {
+    deadline: Instant,
+    respond_to: Option<oneshot::Sender<Result<StreamId, AviP2pError>>>,
+    /// None for streams the runtime opens itself, e.g. relay legs
+    correlation_id: Option<CorrelationId>,
+}
+
+/// DHT record or provider registration this node keeps published
+struct OwnedDhtEntry {
+    /// Record value (None for a provider registration)
+    value: Option<Vec<u8>>,
+    next_publish: Instant,
+}
+
+/// `fetch_invitation` caller waiting for the node it paired with
+struct PairingFetch {
+    token: String,
+    respond_to: oneshot::Sender<Result<MeshInvitation, AviP2pError>>,
+}
+
+/// Message from `publish_after` / `publish_at`
+struct ScheduledPublish {
+    topic: String,
+    data: Vec<u8>,
+    due: Instant,
+}
+
+/// `rendezvous_discover` caller collecting answers from every point
+struct RendezvousLookup {
+    remaining: usize,
+    found: Vec<PeerId>,
+    respond_to: oneshot::Sender<Result<Vec<PeerId>, AviP2pError>>,
+}
+
+/// Catching up after an outage: peers connecting before `until` are asked
+/// for messages from the last `window`
+struct Backfill {
+    until: Instant,
+    window: Duration,
+}
+
+/// How long after an outage newly connected peers are asked for history
+const BACKFILL_PEERS_FOR: Duration = Duration::from_secs(10);
+
+/// Extra history asked for, covering messages lost just before the outage
+/// was noticed
+const BACKFILL_MARGIN: Duration = Duration::from_secs(5);
+
+/// Internal topic peers broadcast context updates on
+const CONTEXT_UPDATES_TOPIC: &str = "avi-context-updates";
+
+/// Stream reason prefix asking this node to relay toward a target peer
+pub(crate) const RELAY_PREFIX: &str = "relay:";
+
+/// Split a `relay:<target>[:<reason>]` stream reason into the target and
+/// the reason the target is asked with. Parameters (`;key=value`) may
+/// follow the target directly and stay on the onward reason.
+fn parse_relay_reason(reason: &str) -> Option<(&str, String)> {
+    let spec = reason.strip_prefix(RELAY_PREFIX)?;
+    let (target, rest) = spec.split_at(spec.find([':', ';']).unwrap_or(spec.len()));
+    let inner = match rest.strip_prefix(':') {
+        Some(inner) => inner.to_string(),
+        None => format!("relayed{}", rest),
+    };
+    Some((target, inner))
+}
+
+/// Target of a `relay:<target>[:<reason>]` stream reason
+fn relay_target(reason: &str) -> Option<LibPeerId> {
+    let (target, _) = parse_relay_reason(reason)?;
+    LibPeerId::from_str(target).ok()
+}
+
+type ContextWaiter = oneshot::Sender<Result<serde_json::Value, AviP2pError>>;
+
+/// A handler's reply, on its way back to the swarm
+type ExtensionReply = (
+    request_response::ResponseChannel<ExtensionResponse>,
+    ExtensionResponse,
+);
+
+/// What an in-flight stream-protocol request was carrying
+enum PendingRequest {
+    Stream(u64),
+    Outbox(u64),
+    /// `send_to` caller waiting for the peer's acknowledgement
+    Direct(oneshot::Sender<Result<(), AviP2pError>>),
+    Other,
+}
+
 pub struct Runtime {
     swarm: Swarm<AviBehaviour>,
-    command_rx: mpsc::Receiver<Command>,
-    event_tx: mpsc::Sender<AviEvent>,
+    local_key: Keypair,
+    command_rx: CommandReceiver,
+    event_tx: EventSender,
 
     // State
     peers: HashMap<LibPeerId, PeerState>,
     streams: HashMap<u64, StreamState>,
+    stream_opens: HashMap<u64, PendingOpen>,
+    stream_open_timeout: Duration,
+    /// Relayed stream -> the stream on the other side of this node
+    relays: HashMap<u64, u64>,
+    stream_relay: bool,
+    /// Streams being teed to disk with `record_stream`
+    recordings: HashMap<u64, Recording>,
     topics: HashSet<String>,
+    /// File the application's subscriptions are kept in across restarts
+    subscription_store: Option<PathBuf>,
     started: bool,
     discovered_peers: HashSet<LibPeerId>,
     synced_peers: HashSet<LibPeerId>,
+    /// Peers whose summary showed updates we lack; their push ends the round
+    context_behind: HashSet<LibPeerId>,
     local_context: AviContext,
+    /// Signed writes of every peer whose state `local_context` holds
+    origins: Origins,
+    peer_contexts: HashMap<String, CachedContext>,
+    context_fetches: HashMap<String, Vec<ContextWaiter>>,
+    context_replication: ContextReplication,
+    context_views: Vec<ViewPublisher>,
+    max_peer_context_size: usize,
+    local_context_warn_size: Option<usize>,
+    /// Whether the local context is over `local_context_warn_size`
+    context_over_quota: bool,
+    peer_context_max_age: Duration,
+    context_stale_after: Duration,
+
+    /// Invitations held for pairing, by token, with when they expire
+    pairings: HashMap<String, (MeshInvitation, Instant)>,
+    /// Invitations asked for with a pairing token, by the peer holding them
+    pairing_fetches: HashMap<LibPeerId, PairingFetch>,
+
+    /// Publishes waiting for their time, by the id handed to the caller
+    scheduled_publishes: HashMap<CorrelationId, ScheduledPublish>,
 
     known_peers: HashMap<LibPeerId, Multiaddr>,
+    /// Outbound dials still connecting -> peer and when to give up on them
+    pending_dials: HashMap<ConnectionId, (LibPeerId, Instant)>,
+    dial_timeout: Duration,
+    peer_info: HashMap<LibPeerId, PeerInfo>,
+    listen_addresses: Vec<Multiaddr>,
+    dht_bootstrapped: bool,
+
+    // Authentication
+    auth: Option<AuthConfig>,
+    pending_challenges: HashMap<LibPeerId, Vec<u8>>,
+    authenticated_peers: HashMap<LibPeerId, Role>,
+    keyring: KeyRing,
+
+    audit: Option<AuditLog>,
+    outbox: Option<Outbox>,
+    reputation: Reputation,
+    /// Peers whose messages are not delivered, per topic (None = every
+    /// topic); unlike a ban the connection stays up
+    muted: HashSet<(LibPeerId, Option<String>)>,
+    /// End-to-end encryption of streams opened with an `e2e` reason
+    secure_streams: HashMap<u64, SecureStream>,
+    /// Inbound payloads over these limits count as violations
+    max_publish_size: usize,
+    max_stream_chunk_size: usize,
+
+    // Pause mode
+    paused: bool,
+    paused_publishes: VecDeque<(String, Vec<u8>)>,
+    pause_buffer_limit: usize,
+
+    // Wire id -> name of topics sent under a compact id
+    compact_topics: HashMap<String, String>,
+    /// Prefix of every gossip topic and DHT key (see `mesh_namespace`)
+    mesh_namespace: Option<String>,
+    stream_protocol: AviStreamProtocol,
+    topic_max_age: HashMap<String, Duration>,
+    topic_rate_limits: HashMap<String, RateLimit>,
+    topic_profiles: Vec<(String, TopicProfile)>,
+    publish_limiters: HashMap<String, RateLimiter>,
+    /// Deliveries are limited per sending peer
+    delivery_limiters: HashMap<(String, LibPeerId), RateLimiter>,
+    // Recently delivered (topic, message id) pairs
+    dedupe: Option<DedupeCache<(String, String)>>,
+    history: TopicHistory,
+    backfill_after_outage: Option<Duration>,
+    backfill_limit: usize,
+    /// When the node lost its last peer
+    outage_started: Option<Instant>,
+    backfill: Option<Backfill>,
+
+    // Application protocols
+    extension_handlers: HashMap<String, Arc<dyn ExtensionHandler>>,
+    extension_requests:
+        HashMap<request_response::OutboundRequestId, oneshot::Sender<Result<Vec<u8>, AviP2pError>>>,
+    /// Handlers run off the event loop and send their replies back here
+    extension_replies_tx: mpsc::UnboundedSender<ExtensionReply>,
+    extension_replies_rx: mpsc::UnboundedReceiver<ExtensionReply>,
+
+    // Rendezvous
+    rendezvous: Option<RendezvousConfig>,
+    rendezvous_points: HashSet<LibPeerId>,
+    rendezvous_next_refresh: Instant,
+    /// Registrations held for others when this node is a rendezvous point
+    rendezvous_registry: Option<RendezvousRegistry>,
+    /// Discover requests in flight -> the lookup they answer, if any
+    rendezvous_requests: HashMap<request_response::OutboundRequestId, Option<u64>>,
+    rendezvous_lookups: HashMap<u64, RendezvousLookup>,
+    next_rendezvous_lookup: u64,
+
+    // DHT entries this node owns and the publishes in flight for them
+    owned_dht: HashMap<(String, DhtEntryKind), OwnedDhtEntry>,
+    dht_publishes: HashMap<kad::QueryId, (String, DhtEntryKind)>,
+    republish_interval: Duration,
+    republish_jitter: Duration,
+
+    // Diagnostics
+    pending_requests: HashMap<request_response::OutboundRequestId, PendingRequest>,
+    poll_latency: Duration,
+    max_poll_latency: Duration,
 }
 
 impl Runtime {
     pub fn new(
         swarm: Swarm<AviBehaviour>,
-        command_rx: mpsc::Receiver<Command>,
-        event_tx: mpsc::Sender<AviEvent>,
+        local_key: Keypair,
+        config: &AviP2pConfig,
+        audit: Option<AuditLog>,
+        outbox: Option<Outbox>,
+        command_rx: CommandReceiver,
+        event_tx: EventSender,
     ) -> Self {
         let local_peer_id = swarm.local_peer_id().to_string();
         let local_context = AviContext::new(local_peer_id);
+        let (extension_replies_tx, extension_replies_rx) = mpsc::unbounded_channel();
+        let mut keyring = KeyRing::new(config.key_grace_period);
+        for key in &config.network_keys {
+            keyring.install(&key.scope, key.epoch, key.key);
+        }
 
         Self {
             swarm,
+            local_key,
             command_rx,
             event_tx,
             peers: HashMap::new(),
             streams: HashMap::new(),
+            stream_opens: HashMap::new(),
+            stream_open_timeout: config.stream_open_timeout,
+            relays: HashMap::new(),
+            stream_relay: config.stream_relay,
+            recordings: HashMap::new(),
             topics: HashSet::new(),
+            subscription_store: config.subscription_store.clone(),
             started: false,
             discovered_peers: HashSet::new(),
             synced_peers: HashSet::new(),
+            context_behind: HashSet::new(),
 
             local_context,
+            origins: Origins::new(),
+            peer_contexts: HashMap::new(),
+            context_fetches: HashMap::new(),
+            context_replication: config.context_replication,
+            context_views: Vec::new(),
+            max_peer_context_size: config.max_peer_context_size,
+            local_context_warn_size: config.local_context_warn_size,
+            context_over_quota: false,
+            peer_context_max_age: config.peer_context_max_age,
+            context_stale_after: config.context_stale_after,
             known_peers: HashMap::new(),
+            pending_dials: HashMap::new(),
+            scheduled_publishes: HashMap::new(),
+            pairings: HashMap::new(),
+            pairing_fetches: HashMap::new(),
+            dial_timeout: config.dial_timeout,
+            peer_info: HashMap::new(),
+            listen_addresses: Vec::new(),
+            dht_bootstrapped: false,
+
+            auth: config.auth.clone(),
+            pending_challenges: HashMap::new(),
+            authenticated_peers: HashMap::new(),
+            keyring,
+
+            audit,
+            outbox,
+            reputation: Reputation::new(config.reputation.clone()),
+            muted: HashSet::new(),
+            max_publish_size: config.max_publish_size,
+            max_stream_chunk_size: config.max_stream_chunk_size,
+            secure_streams: HashMap::new(),
+
+            paused: false,
+            paused_publishes: VecDeque::new(),
+            pause_buffer_limit: config.pause_buffer_limit,
+
+            mesh_namespace: config.mesh_namespace.clone(),
+            stream_protocol: AviStreamProtocol::new(config.mesh_namespace.as_deref()),
+            compact_topics: config
+                .compact_topics
+                .iter()
+                .map(|topic| (compact_topic_id(topic), topic.clone()))
+                .collect(),
+            topic_max_age: config.topic_max_age.clone(),
+            topic_rate_limits: config.topic_rate_limits.clone(),
+            topic_profiles: config.topic_profiles.clone(),
+            publish_limiters: HashMap::new(),
+            delivery_limiters: HashMap::new(),
+            dedupe: config.dedupe_window.map(DedupeCache::new),
+            history: TopicHistory::new(config.topic_history),
+            backfill_after_outage: config.backfill_after_outage,
+            backfill_limit: config.backfill_limit,
+            outage_started: None,
+            backfill: None,
+
+            extension_handlers: config
+                .extensions
+                .iter()
+                .map(|e| (e.name.clone(), e.handler.clone()))
+                .collect(),
+            extension_requests: HashMap::new(),
+            extension_replies_tx,
+            extension_replies_rx,
+
+            rendezvous: config.rendezvous.clone(),
+            rendezvous_points: config
+                .rendezvous
+                .iter()
+                .flat_map(|r| &r.points)
+                .filter_map(|addr| Multiaddr::from_str(addr).ok())
+                .filter_map(|ma| {
+                    ma.iter().find_map(|p| match p {
+                        libp2p::multiaddr::Protocol::P2p(id) => Some(id),
+                        _ => None,
+                    })
+                })
+                .collect(),
+            rendezvous_next_refresh: Instant::now(),
+            rendezvous_registry: config.rendezvous_server.then(RendezvousRegistry::default),
+            rendezvous_requests: HashMap::new(),
+            rendezvous_lookups: HashMap::new(),
+            next_rendezvous_lookup: 0,
+
+            owned_dht: HashMap::new(),
+            dht_publishes: HashMap::new(),
+            republish_interval: config.kad.republish_interval,
+            republish_jitter: config.kad.republish_jitter,
+
+            pending_requests: HashMap::new(),
+            poll_latency: Duration::ZERO,
+            max_poll_latency: Duration::ZERO,
         }
     }
 
     pub async fn run(mut self) {
         let mut heartbeat = tokio::time::interval(Duration::from_secs(5));
+        self.restore_subscriptions();
 
         loop {
+            let next_open_deadline = self.stream_opens.values().map(|o| o.deadline).min();
+            let open_deadline =
+                tokio::time::sleep_until(next_open_deadline.unwrap_or_else(Instant::now).into());
+            let next_dial_deadline = self.pending_dials.values().map(|(_, d)| *d).min();
+            let dial_deadline =
+                tokio::time::sleep_until(next_dial_deadline.unwrap_or_else(Instant::now).into());
+            let next_scheduled_publish = self.scheduled_publishes.values().map(|p| p.due).min();
+            let scheduled_publish = tokio::time::sleep_until(
+                next_scheduled_publish.unwrap_or_else(Instant::now).into(),
+            );
+
             tokio::select! {
+                _ = open_deadline, if next_open_deadline.is_some() => {
+                    self.expire_stream_opens().await;
+                }
+
+                _ = dial_deadline, if next_dial_deadline.is_some() => {
+                    self.expire_dials();
+                }
+
+                _ = scheduled_publish, if next_scheduled_publish.is_some() => {
+                    self.send_scheduled_publishes().await;
+                }
+
                 _ = heartbeat.tick() => {
-                    for (peer_id, addr) in &self.known_peers {
-                        if !self.swarm.is_connected(peer_id) {
-                            let _ = self.swarm.dial(addr.clone());
+                    if !self.paused {
+                        self.redial_known_peers();
+                        self.flush_paused_publishes();
+                        if Instant::now() >= self.rendezvous_next_refresh {
+                            self.refresh_rendezvous();
                         }
+                        self.republish_dht().await;
                     }
+                    self.keyring.prune();
+                    // Streams can also end through timeouts and disconnects
+                    self.recordings.retain(|id, _| self.streams.contains_key(id));
+                    self.secure_streams
+                        .retain(|id, _| self.streams.contains_key(id));
+                    if let Some(outbox) = &mut self.outbox {
+                        outbox.prune_expired();
+                    }
+                    self.expire_context_keys().await;
+                    self.report_stale_contexts().await;
+                    let now = Instant::now();
+                    self.pairings.retain(|_, (_, expires)| *expires > now);
+                    self.pairing_fetches.retain(|_, fetch| !fetch.respond_to.is_closed());
+                    self.context_fetches.retain(|_, waiters| {
+                        waiters.retain(|w| !w.is_closed());
+                        !waiters.is_empty()
+                    });
+                }
+
+                Some((channel, reply)) = self.extension_replies_rx.recv() => {
+                    let _ = self
+                        .swarm
+                        .behaviour_mut()
+                        .extension
+                        .send_response(channel, reply);
                 }
 
                 cmd = self.command_rx.recv() => {
                     match cmd {
-                        Some(c) => self.handle_command(c).await,
+                        Some(c) => {
+                            let started = Instant::now();
+                            self.handle_command(c).await;
+                            self.record_poll_latency(started.elapsed());
+                        }
                         None => {
                             info!("Command channel closed, shutting down");
                             break;
@@ -87,7 +497,9 @@ impl Runtime {
                     }
                 }
                 event = self.swarm.select_next_some() => {
+                    let started = Instant::now();
                     self.handle_swarm_event(event).await;
+                    self.record_poll_latency(started.elapsed());
                 }
             }
         }
@@ -97,10 +509,16 @@ impl Runtime {
         // (This section remains exactly the same as before)
         match cmd {
             Command::Subscribe { topic, respond_to } => {
-                let topic_hash = gossipsub::IdentTopic::new(&topic);
-                let res = match self.swarm.behaviour_mut().gossipsub.subscribe(&topic_hash) {
+                let local = *self.swarm.local_peer_id();
+                if let Err(e) = self.authorize(&local, Operation::Subscribe, Some(&topic)) {
+                    let _ = respond_to.send(Err(e));
+                    return;
+                }
+                let topic_hash = self.wire_topic(&topic);
+                let res = match self.gossip_for(&topic).subscribe(&topic_hash) {
                     Ok(_) => {
                         self.topics.insert(topic);
+                        self.save_subscriptions();
                         Ok(())
                     }
                     Err(e) => Err(AviP2pError::NetworkError(e.to_string())),
@@ -108,43 +526,103 @@ impl Runtime {
                 let _ = respond_to.send(res);
             }
             Command::Unsubscribe { topic, respond_to } => {
-                let topic_hash = gossipsub::IdentTopic::new(&topic);
-                let res = match self
-                    .swarm
-                    .behaviour_mut()
-                    .gossipsub
-                    .unsubscribe(&topic_hash)
-                {
+                let topic_hash = self.wire_topic(&topic);
+                let res = match self.gossip_for(&topic).unsubscribe(&topic_hash) {
                     Ok(_) => {
                         self.topics.remove(&topic);
+                        self.history.forget(&topic);
+                        self.save_subscriptions();
                         Ok(())
                     }
                     Err(e) => Err(AviP2pError::NetworkError(e.to_string())),
                 };
                 let _ = respond_to.send(res);
             }
+            Command::GetSubscriptions { respond_to } => {
+                let _ = respond_to.send(Ok(self.subscriptions()));
+            }
+            Command::UnsubscribeAll { respond_to } => {
+                let mut res = Ok(());
+                for topic in self.subscriptions() {
+                    let topic_hash = self.wire_topic(&topic);
+                    match self.gossip_for(&topic).unsubscribe(&topic_hash) {
+                        Ok(_) => {
+                            self.topics.remove(&topic);
+                            self.history.forget(&topic);
+                        }
+                        Err(e) => res = Err(AviP2pError::NetworkError(e.to_string())),
+                    }
+                }
+                self.save_subscriptions();
+                let _ = respond_to.send(res);
+            }
             Command::Publish {
                 topic,
                 data,
+                message_id,
                 respond_to,
             } => {
-                let topic_hash = gossipsub::IdentTopic::new(&topic);
-                let res = match self
-                    .swarm
-                    .behaviour_mut()
-                    .gossipsub
-                    .publish(topic_hash, data)
-                {
-                    Ok(_) => Ok(()),
-                    Err(e) => Err(AviP2pError::NetworkError(e.to_string())),
-                };
+                let res = self.publish(topic, data, message_id);
+                let _ = respond_to.send(res);
+            }
+            Command::PublishBatch {
+                messages,
+                respond_to,
+            } => {
+                let results = messages
+                    .into_iter()
+                    .map(|(topic, data)| self.publish(topic, data, None))
+                    .collect();
+                let _ = respond_to.send(results);
+            }
+            Command::PublishAtomic {
+                messages,
+                respond_to,
+            } => {
+                let res = self.publish_atomic(messages);
+                let _ = respond_to.send(res);
+            }
+            Command::SchedulePublish {
+                topic,
+                data,
+                delay,
+                correlation_id,
+                respond_to,
+            } => {
+                let local = *self.swarm.local_peer_id();
+                let res = self
+                    .authorize(&local, Operation::Publish, Some(&topic))
+                    .map(|()| {
+                        self.scheduled_publishes.insert(
+                            correlation_id,
+                            ScheduledPublish {
+                                topic,
+                                data,
+                                due: Instant::now() + delay,
+                            },
+                        );
+                    });
                 let _ = respond_to.send(res);
             }
+            Command::CancelScheduledPublish {
+                correlation_id,
+                respond_to,
+            } => {
+                let cancelled = self.scheduled_publishes.remove(&correlation_id).is_some();
+                let _ = respond_to.send(cancelled);
+            }
             Command::RequestStream {
                 peer_id,
                 reason,
+                correlation_id,
                 respond_to,
+                on_open,
             } => {
+                let local = *self.swarm.local_peer_id();
+                if let Err(e) = self.authorize(&local, Operation::Stream, None) {
+                    let _ = respond_to.send(Err(e));
+                    return;
+                }
                 let res = if let Ok(target) = LibPeerId::try_from(peer_id.clone()) {
                     let id = generate_stream_id();
                     self.streams.insert(
@@ -152,15 +630,25 @@ impl Runtime {
                         StreamState {
                             peer: target,
                             reason: reason.clone(),
+                            version: STREAM_VERSION,
                             status: StreamStatus::Requested,
                             direction: StreamDirection::Outbound,
                         },
                     );
-                    self.swarm.behaviour_mut().stream.send_request(
+                    self.stream_opens.insert(
+                        id.0,
+                        PendingOpen {
+                            deadline: Instant::now() + self.stream_open_timeout,
+                            respond_to: on_open,
+                            correlation_id: Some(correlation_id),
+                        },
+                    );
+                    self.send_stream_message(
                         &target,
                         StreamMessage::RequestStream {
                             stream_id: id.0,
                             reason,
+                            version: STREAM_VERSION,
                         },
                     );
                     Ok(id)
@@ -171,14 +659,43 @@ impl Runtime {
             }
             Command::AcceptStream {
                 stream_id,
+                mut payload,
                 respond_to,
             } => {
                 let res = if let Some(state) = self.streams.get_mut(&stream_id.0) {
                     state.status = StreamStatus::Accepted;
-                    self.swarm.behaviour_mut().stream.send_request(
-                        &state.peer,
+                    let peer = state.peer;
+                    let version = state.version;
+                    if is_secure_reason(&state.reason) {
+                        // The acceptance travels in the clear, so the payload
+                        // waits for the handshake and goes out sealed
+                        match SecureStream::respond(&self.local_key) {
+                            Ok(mut secure) => {
+                                if let Some(payload) = payload.take() {
+                                    let _ = secure.seal(payload);
+                                }
+                                self.secure_streams.insert(stream_id.0, secure);
+                            }
+                            Err(e) => {
+                                let _ = respond_to.send(Err(e));
+                                return;
+                            }
+                        }
+                    }
+                    self.send_stream_message(
+                        &peer,
                         StreamMessage::AcceptStream {
                             stream_id: stream_id.0,
+                            version,
+                            payload,
+                        },
+                    );
+                    let local = *self.swarm.local_peer_id();
+                    self.record_audit(
+                        &local.to_string(),
+                        AuditAction::StreamAccepted {
+                            stream_id: stream_id.0,
+                            peer: peer.to_string(),
                         },
                     );
                     Ok(())
@@ -187,17 +704,38 @@ impl Runtime {
                 };
                 let _ = respond_to.send(res);
             }
+            Command::GetStreamVersion {
+                stream_id,
+                respond_to,
+            } => {
+                let res = self
+                    .streams
+                    .get(&stream_id.0)
+                    .map(|state| state.version)
+                    .ok_or(AviP2pError::StreamNotFound(stream_id));
+                let _ = respond_to.send(res);
+            }
             Command::RejectStream {
                 stream_id,
                 reason,
                 respond_to,
             } => {
                 let res = if let Some(state) = self.streams.remove(&stream_id.0) {
-                    self.swarm.behaviour_mut().stream.send_request(
+                    let local = *self.swarm.local_peer_id();
+                    self.record_audit(
+                        &local.to_string(),
+                        AuditAction::StreamRejected {
+                            stream_id: stream_id.0,
+                            peer: state.peer.to_string(),
+                            reason: reason.clone(),
+                        },
+                    );
+                    self.send_stream_message(
                         &state.peer,
                         StreamMessage::RejectStream {
                             stream_id: stream_id.0,
                             reason,
+                            version: None,
                         },
                     );
                     Ok(())
@@ -211,15 +749,24 @@ impl Runtime {
                 data,
                 respond_to,
             } => {
-                let res = if let Some(state) = self.streams.get(&stream_id.0) {
-                    self.swarm.behaviour_mut().stream.send_request(
-                        &state.peer,
-                        StreamMessage::StreamData {
-                            stream_id: stream_id.0,
-                            data,
-                        },
-                    );
-                    Ok(())
+                let res = if let Some(peer) = self.streams.get(&stream_id.0).map(|s| s.peer) {
+                    self.record_stream_chunk(stream_id.0, StreamDirection::Outbound, &data);
+                    let sealed = match self.secure_streams.get_mut(&stream_id.0) {
+                        Some(secure) => secure.seal(data),
+                        None => Ok(Some(data)),
+                    };
+                    sealed.map(|data| {
+                        // Nothing to send yet if the handshake is still running
+                        if let Some(data) = data {
+                            self.send_stream_message(
+                                &peer,
+                                StreamMessage::StreamData {
+                                    stream_id: stream_id.0,
+                                    data,
+                                },
+                            );
+                        }
+                    })
                 } else {
                     Err(AviP2pError::StreamNotFound(stream_id))
                 };
@@ -230,7 +777,9 @@ impl Runtime {
                 respond_to,
             } => {
                 let res = if let Some(state) = self.streams.remove(&stream_id.0) {
-                    self.swarm.behaviour_mut().stream.send_request(
+                    self.recordings.remove(&stream_id.0);
+                    self.secure_streams.remove(&stream_id.0);
+                    self.send_stream_message(
                         &state.peer,
                         StreamMessage::CloseStream {
                             stream_id: stream_id.0,
@@ -242,6 +791,20 @@ impl Runtime {
                 };
                 let _ = respond_to.send(res);
             }
+            Command::RecordStream {
+                stream_id,
+                path,
+                respond_to,
+            } => {
+                let res = if self.streams.contains_key(&stream_id.0) {
+                    Recording::start(&path).map(|recording| {
+                        self.recordings.insert(stream_id.0, recording);
+                    })
+                } else {
+                    Err(AviP2pError::StreamNotFound(stream_id))
+                };
+                let _ = respond_to.send(res);
+            }
             Command::GetConnectedPeers { respond_to } => {
                 let peers = self.peers.keys().map(|p| PeerId::from(*p)).collect();
                 let _ = respond_to.send(Ok(peers));
@@ -250,119 +813,592 @@ impl Runtime {
                 let _ = self.swarm.behaviour_mut().kad.bootstrap();
                 let _ = respond_to.send(Ok(()));
             }
-            Command::Shutdown { respond_to } => {
+            Command::ExtensionRequest {
+                peer_id,
+                name,
+                data,
+                respond_to,
+            } => match LibPeerId::try_from(peer_id.clone()) {
+                Ok(peer) => {
+                    let request_id = self
+                        .swarm
+                        .behaviour_mut()
+                        .extension
+                        .send_request(&peer, ExtensionRequest { name, data });
+                    self.extension_requests.insert(request_id, respond_to);
+                }
+                Err(_) => {
+                    let _ = respond_to.send(Err(AviP2pError::PeerNotFound(peer_id)));
+                }
+            },
+            Command::RendezvousDiscover {
+                namespace,
+                respond_to,
+            } => {
+                let points: Vec<LibPeerId> = self
+                    .rendezvous_points
+                    .iter()
+                    .filter(|p| self.swarm.is_connected(p))
+                    .copied()
+                    .collect();
+                if points.is_empty() {
+                    let _ = respond_to.send(Err(AviP2pError::NetworkError(
+                        "no rendezvous point connected".to_string(),
+                    )));
+                    return;
+                }
+                let lookup = self.next_rendezvous_lookup;
+                self.next_rendezvous_lookup += 1;
+                self.rendezvous_lookups.insert(
+                    lookup,
+                    RendezvousLookup {
+                        remaining: points.len(),
+                        found: Vec::new(),
+                        respond_to,
+                    },
+                );
+                for point in points {
+                    let request_id = self.swarm.behaviour_mut().rendezvous.send_request(
+                        &point,
+                        RendezvousRequest::Discover {
+                            namespace: namespace.clone(),
+                        },
+                    );
+                    self.rendezvous_requests.insert(request_id, Some(lookup));
+                }
+            }
+            Command::GetPeerInfo {
+                peer_id,
+                respond_to,
+            } => {
+                let res = libp2p::PeerId::try_from(peer_id.clone())
+                    .ok()
+                    .and_then(|p| self.peer_info.get(&p).cloned())
+                    .ok_or(AviP2pError::PeerNotFound(peer_id));
+                let _ = respond_to.send(res);
+            }
+            Command::PutRecord {
+                key,
+                value,
+                respond_to,
+            } => {
+                let res = self.own_dht_entry(key, DhtEntryKind::Record, Some(value));
+                let _ = respond_to.send(res);
+            }
+            Command::RemoveRecord { key, respond_to } => {
+                self.owned_dht.remove(&(key.clone(), DhtEntryKind::Record));
+                let record_key = self.dht_key(&key);
+                self.swarm.behaviour_mut().kad.remove_record(&record_key);
                 let _ = respond_to.send(Ok(()));
-                self.command_rx.close();
             }
-
-            Command::UpdateSelfContext { patch, respond_to } => {
-                self.local_context.apply_patch(patch);
-
-                let my_id = self.local_context.device_id.clone();
-                self.local_context.vector_clock.increment(&my_id);
-
-                // 3. Broadcast to Mesh
-                if let Ok(data) = serde_json::to_vec(&self.local_context) {
-                    let topic = gossipsub::IdentTopic::new("avi-context-updates");
-                    if !self.topics.contains("avi-context-updates") {
-                        let _ = self.swarm.behaviour_mut().gossipsub.subscribe(&topic);
-                        self.topics.insert("avi-context-updates".to_string());
-                    }
-
-                    match self.swarm.behaviour_mut().gossipsub.publish(topic, data) {
-                        Ok(_) => {} // Success
-                        Err(gossipsub::PublishError::InsufficientPeers) => {
-                            info!("Context updated locally (broadcasting postponed: no peers yet)");
-                        }
-                        Err(e) => {
-                            let _ = respond_to.send(Err(AviP2pError::NetworkError(e.to_string())));
-                            return;
-                        }
-                    }
-                }
-
+            Command::StartProviding { key, respond_to } => {
+                let res = self.own_dht_entry(key, DhtEntryKind::Provider, None);
+                let _ = respond_to.send(res);
+            }
+            Command::StopProviding { key, respond_to } => {
+                self.owned_dht
+                    .remove(&(key.clone(), DhtEntryKind::Provider));
+                let record_key = self.dht_key(&key);
+                self.swarm.behaviour_mut().kad.stop_providing(&record_key);
                 let _ = respond_to.send(Ok(()));
             }
-
-            Command::ReplaceSelfContext { data, respond_to } => {
-                self.local_context.replace_data(data);
-
-                let my_id = self.local_context.device_id.clone();
-                self.local_context.vector_clock.increment(&my_id);
-
-                // Broadcast to Mesh
-                if let Ok(data) = serde_json::to_vec(&self.local_context) {
-                    let topic = gossipsub::IdentTopic::new("avi-context-updates");
-                    if !self.topics.contains("avi-context-updates") {
-                        let _ = self.swarm.behaviour_mut().gossipsub.subscribe(&topic);
-                        self.topics.insert("avi-context-updates".to_string());
-                    }
-
-                    match self.swarm.behaviour_mut().gossipsub.publish(topic, data) {
-                        Ok(_) => {} // Success
-                        Err(gossipsub::PublishError::InsufficientPeers) => {
-                            info!(
-                                "Context replaced locally (broadcasting postponed: no peers yet)"
-                            );
-                        }
-                        Err(e) => {
-                            let _ = respond_to.send(Err(AviP2pError::NetworkError(e.to_string())));
-                            return;
-                        }
-                    }
-                }
-
+            Command::GetAuthenticatedPeers { respond_to } => {
+                let peers = self
+                    .authenticated_peers
+                    .keys()
+                    .map(|p| PeerId::from(*p))
+                    .collect();
+                let _ = respond_to.send(Ok(peers));
+            }
+            Command::SetMembershipCertificate {
+                certificate,
+                respond_to,
+            } => {
+                let res = self.set_membership_certificate(certificate);
+                let _ = respond_to.send(res);
+            }
+            Command::MeshDetails { respond_to } => {
+                let res = self.mesh_details();
+                let _ = respond_to.send(res);
+            }
+            Command::HoldPairing {
+                token,
+                invitation,
+                valid_for,
+                respond_to,
+            } => {
+                self.pairings
+                    .insert(token, (*invitation, Instant::now() + valid_for));
                 let _ = respond_to.send(Ok(()));
             }
-
-            Command::GetPeerContext {
-                peer_id: _,
+            Command::FetchInvitation {
+                payload,
                 respond_to,
             } => {
-                let result = Ok(self.local_context.data.clone());
-                let _ = respond_to.send(result);
+                self.fetch_invitation(payload, respond_to);
             }
-        }
-    }
-
-    async fn handle_swarm_event(&mut self, event: SwarmEvent<AviBehaviourEvent>) {
-        match event {
-            SwarmEvent::NewListenAddr { address, .. } => {
-                if !self.started {
-                    self.started = true;
-                    let local_peer_id = *self.swarm.local_peer_id();
-                    let _ = self
-                        .event_tx
-                        .send(AviEvent::Started {
-                            local_peer_id: PeerId::from(local_peer_id),
-                            listen_addresses: vec![address.to_string()],
-                        })
-                        .await;
-                }
+            Command::GetHealth { respond_to } => {
+                let report = HealthReport {
+                    runtime_alive: true,
+                    listen_addresses: self
+                        .listen_addresses
+                        .iter()
+                        .map(|a| a.to_string())
+                        .collect(),
+                    connected_peers: self.peers.len(),
+                    dht_bootstrapped: self.dht_bootstrapped,
+                    event_queue: self.event_tx.usage(),
+                    dropped_events: self.event_tx.dropped(),
+                    ..Default::default()
+                };
+                let _ = respond_to.send(Ok(report));
             }
-
-            #[cfg(not(target_arch = "wasm32"))]
-            SwarmEvent::Behaviour(AviBehaviourEvent::Mdns(mdns::Event::Discovered(list))) => {
-                for (peer_id, multiaddr) in list {
-                    self.swarm
-                        .behaviour_mut()
-                        .kad
-                        .add_address(&peer_id, multiaddr.clone());
-                    self.known_peers.insert(peer_id, multiaddr.clone());
-
-                    if !self.swarm.is_connected(&peer_id) {
-                        if let Err(_e) = self.swarm.dial(multiaddr) {}
-                    }
-
-                    self.emit_peer_discovered(peer_id).await;
+            Command::SendTo {
+                peer_id,
+                data,
+                respond_to,
+            } => match LibPeerId::try_from(peer_id.clone()) {
+                Ok(target) if !self.paused && self.swarm.is_connected(&target) => {
+                    let request_id =
+                        self.send_stream_message(&target, StreamMessage::Direct { data });
+                    self.pending_requests
+                        .insert(request_id, PendingRequest::Direct(respond_to));
                 }
+                _ => {
+                    let _ = respond_to.send(Err(AviP2pError::PeerNotFound(peer_id)));
+                }
+            },
+            Command::SendViaOutbox {
+                peer_id,
+                data,
+                ttl,
+                respond_to,
+            } => {
+                let res = match (LibPeerId::try_from(peer_id.clone()), &mut self.outbox) {
+                    (Err(_), _) => Err(AviP2pError::PeerNotFound(peer_id)),
+                    (Ok(_), None) => Err(AviP2pError::Config("Outbox is disabled".to_string())),
+                    (Ok(target), Some(outbox)) => {
+                        outbox.push(&target.to_string(), data, ttl);
+                        if !self.paused && self.swarm.is_connected(&target) {
+                            self.flush_outbox(target);
+                        }
+                        Ok(())
+                    }
+                };
+                let _ = respond_to.send(res);
             }
-
-            SwarmEvent::Behaviour(AviBehaviourEvent::Identify(identify::Event::Received {
+            Command::BanPeer {
                 peer_id,
-                info,
-            })) => {
-                self.emit_peer_discovered(peer_id).await;
+                duration,
+                respond_to,
+            } => {
+                let res = match LibPeerId::try_from(peer_id.clone()) {
+                    Ok(peer) => {
+                        self.reputation.ban(peer, duration);
+                        self.enforce_ban(peer, None, duration).await;
+                        Ok(())
+                    }
+                    Err(_) => Err(AviP2pError::PeerNotFound(peer_id)),
+                };
+                let _ = respond_to.send(res);
+            }
+            Command::UnbanPeer {
+                peer_id,
+                respond_to,
+            } => {
+                let res = match LibPeerId::try_from(peer_id.clone()) {
+                    Ok(peer) => {
+                        let lifted = self.reputation.unban(&peer);
+                        if lifted {
+                            let _ = self.event_tx.send(AviEvent::PeerUnbanned { peer_id }).await;
+                        }
+                        Ok(lifted)
+                    }
+                    Err(_) => Err(AviP2pError::PeerNotFound(peer_id)),
+                };
+                let _ = respond_to.send(res);
+            }
+            Command::BannedPeers { respond_to } => {
+                let banned = self
+                    .reputation
+                    .banned()
+                    .into_iter()
+                    .map(|(peer, left)| (PeerId::from(peer), left))
+                    .collect();
+                let _ = respond_to.send(Ok(banned));
+            }
+            Command::MutePeer {
+                peer_id,
+                topic,
+                respond_to,
+            } => {
+                let res = match LibPeerId::try_from(peer_id.clone()) {
+                    Ok(peer) => {
+                        self.muted.insert((peer, topic));
+                        Ok(())
+                    }
+                    Err(_) => Err(AviP2pError::PeerNotFound(peer_id)),
+                };
+                let _ = respond_to.send(res);
+            }
+            Command::UnmutePeer {
+                peer_id,
+                topic,
+                respond_to,
+            } => {
+                let res = match LibPeerId::try_from(peer_id.clone()) {
+                    Ok(peer) => Ok(self.muted.remove(&(peer, topic))),
+                    Err(_) => Err(AviP2pError::PeerNotFound(peer_id)),
+                };
+                let _ = respond_to.send(res);
+            }
+            Command::MutedPeers { respond_to } => {
+                let muted = self
+                    .muted
+                    .iter()
+                    .map(|(peer, topic)| (PeerId::from(*peer), topic.clone()))
+                    .collect();
+                let _ = respond_to.send(muted);
+            }
+            Command::Pause { respond_to } => {
+                if !self.paused {
+                    info!("Pausing node: dropping connections, buffering publishes");
+                    self.paused = true;
+                    let peers: Vec<LibPeerId> = self.swarm.connected_peers().copied().collect();
+                    for peer in peers {
+                        let _ = self.swarm.disconnect_peer_id(peer);
+                    }
+                }
+                let _ = respond_to.send(Ok(()));
+            }
+            Command::Resume { respond_to } => {
+                if self.paused {
+                    info!("Resuming node");
+                    self.paused = false;
+                    self.redial_known_peers();
+                }
+                let _ = respond_to.send(Ok(()));
+            }
+            Command::GetRuntimeStats { respond_to } => {
+                let stats = RuntimeStats {
+                    event_queue: self.event_tx.usage(),
+                    dropped_events: self.event_tx.dropped(),
+                    pending_outbound_requests: self.pending_requests.len(),
+                    poll_latency: self.poll_latency,
+                    max_poll_latency: self.max_poll_latency,
+                    ..Default::default()
+                };
+                let _ = respond_to.send(Ok(stats));
+            }
+            // Both read the log file, so they run on the blocking pool
+            Command::QueryAudit { query, respond_to } => match &self.audit {
+                Some(log) => {
+                    let snapshot = log.snapshot();
+                    tokio::task::spawn_blocking(move || {
+                        let _ = respond_to.send(snapshot.query(&query));
+                    });
+                }
+                None => {
+                    let _ = respond_to
+                        .send(Err(AviP2pError::Audit("Audit log is disabled".to_string())));
+                }
+            },
+            Command::VerifyAudit { respond_to } => match &self.audit {
+                Some(log) => {
+                    let snapshot = log.snapshot();
+                    tokio::task::spawn_blocking(move || {
+                        let _ = respond_to.send(snapshot.verify());
+                    });
+                }
+                None => {
+                    let _ = respond_to
+                        .send(Err(AviP2pError::Audit("Audit log is disabled".to_string())));
+                }
+            },
+            Command::EmitEvent { event } => {
+                let _ = self.event_tx.send(event).await;
+            }
+            Command::Shutdown { respond_to } => {
+                let _ = respond_to.send(Ok(()));
+                self.command_rx.close();
+            }
+
+            Command::RotateKey { scope, respond_to } => {
+                let local = *self.swarm.local_peer_id();
+                let res = self
+                    .authorize(&local, Operation::KeyManagement, None)
+                    .map(|_| {
+                        let (epoch, key) = self.keyring.rotate(&scope);
+                        let recipients: Vec<LibPeerId> = self
+                            .peers
+                            .keys()
+                            .copied()
+                            .filter(|p| self.is_key_recipient(p))
+                            .collect();
+                        for peer in recipients {
+                            self.send_key(peer, &scope, epoch, key);
+                        }
+                        epoch
+                    });
+                let _ = respond_to.send(res);
+            }
+            Command::PublishEncrypted {
+                topic,
+                data,
+                respond_to,
+            } => {
+                let local = *self.swarm.local_peer_id();
+                let res = self
+                    .authorize(&local, Operation::Publish, Some(&topic))
+                    .and_then(|_| self.keyring.encrypt(&topic, &data))
+                    .and_then(|sealed| {
+                        serde_json::to_vec(&sealed)
+                            .map_err(|e| AviP2pError::Serialization(e.to_string()))
+                    })
+                    .and_then(|payload| self.gossip_publish(&topic, payload));
+                let _ = respond_to.send(res);
+            }
+            Command::Encrypt {
+                scope,
+                data,
+                respond_to,
+            } => {
+                let _ = respond_to.send(self.keyring.encrypt(&scope, &data));
+            }
+            Command::Decrypt {
+                payload,
+                respond_to,
+            } => {
+                let _ = respond_to.send(self.keyring.decrypt(&payload));
+            }
+
+            Command::UpdateSelfContext {
+                patch,
+                ttl,
+                respond_to,
+            } => {
+                let _ = respond_to.send(self.update_local_context(patch, ttl));
+            }
+
+            Command::UpdateSelfContextIf {
+                path,
+                expected,
+                patch,
+                respond_to,
+            } => {
+                let current = get_nested_value(&self.local_context.data, &path)
+                    .cloned()
+                    .unwrap_or(serde_json::Value::Null);
+                let res = if current == expected {
+                    self.update_local_context(patch, None).map(|_| true)
+                } else {
+                    Ok(false)
+                };
+                let _ = respond_to.send(res);
+            }
+
+            Command::IncrementContextCounter {
+                path,
+                delta,
+                respond_to,
+            } => {
+                let res = self.write_local_crdt(&path, |ctx| ctx.increment_counter(&path, delta));
+                let _ = respond_to.send(res);
+            }
+
+            Command::AddToContextCollection {
+                path,
+                kind,
+                value,
+                respond_to,
+            } => {
+                let res =
+                    self.write_local_crdt(&path, |ctx| ctx.add_to_collection(&path, kind, value));
+                let _ = respond_to.send(res);
+            }
+
+            Command::RemoveFromContextCollection {
+                path,
+                value,
+                respond_to,
+            } => {
+                let res =
+                    self.write_local_crdt(&path, |ctx| ctx.remove_from_collection(&path, &value));
+                let _ = respond_to.send(res);
+            }
+
+            Command::ApplyContextOps { ops, respond_to } => {
+                let _ = respond_to.send(self.apply_local_context_ops(ops));
+            }
+
+            Command::ReplaceSelfContext { data, respond_to } => {
+                let local = *self.swarm.local_peer_id();
+                if let Err(e) = self.authorize(&local, Operation::ContextWrite, None) {
+                    let _ = respond_to.send(Err(e));
+                    return;
+                }
+                self.record_audit(
+                    &local.to_string(),
+                    AuditAction::ContextModified {
+                        keys: top_level_keys(&data),
+                    },
+                );
+                let before = self.local_context.data.clone();
+                self.local_context.replace_data(data);
+                self.commit_local_write(&before);
+
+                // Broadcast to Mesh
+                let res = self.broadcast_local_context(Vec::new());
+                let _ = respond_to.send(res);
+            }
+
+            Command::GetPeerContext {
+                peer_id: None,
+                respond_to,
+                ..
+            } => {
+                let result = Ok(self.local_context.data.clone());
+                let _ = respond_to.send(result);
+            }
+            Command::GetPeerContext {
+                peer_id: Some(peer_id),
+                force_refresh,
+                respond_to,
+            } => {
+                let cached = self.peer_contexts.get(peer_id.as_str());
+                if let Some(cached) = cached {
+                    if !force_refresh
+                        && !cached.invalidated
+                        && cached.updated.elapsed() <= self.peer_context_max_age
+                    {
+                        let _ = respond_to.send(Ok(cached.data.clone()));
+                        return;
+                    }
+                }
+
+                match LibPeerId::try_from(peer_id.clone()) {
+                    Ok(target) if !self.paused && self.swarm.is_connected(&target) => {
+                        // Callers that timed out or gave up dropped their receiver
+                        let waiters = self.context_fetches.entry(peer_id.0).or_default();
+                        waiters.retain(|w| !w.is_closed());
+                        waiters.push(respond_to);
+                        if waiters.len() == 1 {
+                            self.send_stream_message(&target, StreamMessage::ContextRequest);
+                        }
+                    }
+                    // An old answer beats none while the peer is unreachable
+                    _ => match cached {
+                        Some(cached) if !force_refresh => {
+                            let _ = respond_to.send(Ok(cached.data.clone()));
+                        }
+                        _ => {
+                            let _ = respond_to.send(Err(AviP2pError::PeerNotFound(peer_id)));
+                        }
+                    },
+                }
+            }
+
+            Command::ContextView { paths, respond_to } => {
+                let (publisher, view) = ViewPublisher::new(paths, self.known_contexts());
+                self.context_views.push(publisher);
+                let _ = respond_to.send(view);
+            }
+
+            Command::ExportState { respond_to } => {
+                let _ = respond_to.send(Ok(self.export_snapshot()));
+            }
+            Command::ImportState {
+                snapshot,
+                respond_to,
+            } => {
+                let _ = respond_to.send(self.import_snapshot(*snapshot));
+            }
+        }
+    }
+
+    async fn handle_swarm_event(&mut self, event: SwarmEvent<AviBehaviourEvent>) {
+        match event {
+            SwarmEvent::NewListenAddr { address, .. } => {
+                self.listen_addresses.push(address.clone());
+                if !self.started {
+                    self.started = true;
+                    let local_peer_id = *self.swarm.local_peer_id();
+                    let _ = self
+                        .event_tx
+                        .send(AviEvent::Started {
+                            local_peer_id: PeerId::from(local_peer_id),
+                            listen_addresses: vec![address.to_string()],
+                        })
+                        .await;
+                }
+            }
+
+            SwarmEvent::ExpiredListenAddr { address, .. } => {
+                self.listen_addresses.retain(|a| a != &address);
+            }
+
+            SwarmEvent::Behaviour(AviBehaviourEvent::Kad(
+                kad::Event::OutboundQueryProgressed {
+                    result: kad::QueryResult::Bootstrap(Ok(_)),
+                    ..
+                },
+            )) => {
+                self.dht_bootstrapped = true;
+            }
+
+            SwarmEvent::Behaviour(AviBehaviourEvent::Kad(
+                kad::Event::OutboundQueryProgressed {
+                    id,
+                    result: kad::QueryResult::PutRecord(result),
+                    ..
+                },
+            )) => {
+                self.finish_dht_publish(id, result.err().map(|e| e.to_string()))
+                    .await;
+            }
+
+            SwarmEvent::Behaviour(AviBehaviourEvent::Kad(
+                kad::Event::OutboundQueryProgressed {
+                    id,
+                    result: kad::QueryResult::StartProviding(result),
+                    ..
+                },
+            )) => {
+                self.finish_dht_publish(id, result.err().map(|e| e.to_string()))
+                    .await;
+            }
+
+            #[cfg(not(target_arch = "wasm32"))]
+            SwarmEvent::Behaviour(AviBehaviourEvent::Mdns(mdns::Event::Discovered(list))) => {
+                for (peer_id, multiaddr) in list {
+                    self.swarm
+                        .behaviour_mut()
+                        .kad
+                        .add_address(&peer_id, multiaddr.clone());
+                    self.known_peers.insert(peer_id, multiaddr.clone());
+
+                    if !self.paused && !self.swarm.is_connected(&peer_id) {
+                        if let Err(_e) = self.swarm.dial(multiaddr) {}
+                    }
+
+                    self.emit_peer_discovered(peer_id).await;
+                }
+            }
+
+            SwarmEvent::Behaviour(AviBehaviourEvent::Identify(identify::Event::Received {
+                peer_id,
+                info,
+            })) => {
+                self.emit_peer_discovered(peer_id).await;
+
+                let peer_info = PeerInfo {
+                    agent_version: info.agent_version.clone(),
+                    protocol_version: info.protocol_version.clone(),
+                    protocols: info.protocols.iter().map(|p| p.to_string()).collect(),
+                    listen_addresses: info.listen_addrs.iter().map(|a| a.to_string()).collect(),
+                    observed_address: info.observed_addr.to_string(),
+                };
+
                 for addr in info.listen_addrs {
                     self.swarm
                         .behaviour_mut()
@@ -373,28 +1409,54 @@ impl Runtime {
                 }
 
                 // Sync context if they support our protocol
-                if info
+                if peer_info
                     .protocols
                     .iter()
-                    .any(|p| p.to_string() == "/avi/stream/1.0.0")
+                    .any(|p| p == self.stream_protocol.as_ref())
+                    && self.is_trusted(&peer_id)
                 {
-                    if !self.synced_peers.contains(&peer_id) {
-                        self.synced_peers.insert(peer_id);
-                        self.swarm.behaviour_mut().stream.send_request(
-                            &peer_id,
-                            StreamMessage::SyncContext(self.local_context.clone()),
-                        );
-                    }
+                    self.sync_context_with(peer_id);
+                    self.share_keys_with(peer_id);
+                }
+
+                // Identify re-runs periodically; only report changes
+                if self.peer_info.get(&peer_id) != Some(&peer_info) {
+                    self.peer_info.insert(peer_id, peer_info.clone());
+                    let _ = self
+                        .event_tx
+                        .send(AviEvent::PeerIdentified {
+                            peer_id: PeerId::from(peer_id),
+                            info: peer_info,
+                        })
+                        .await;
                 }
             }
 
+            SwarmEvent::Dialing {
+                peer_id: Some(peer_id),
+                connection_id,
+            } => {
+                self.pending_dials
+                    .insert(connection_id, (peer_id, Instant::now() + self.dial_timeout));
+            }
+
+            SwarmEvent::OutgoingConnectionError { connection_id, .. } => {
+                self.pending_dials.remove(&connection_id);
+            }
+
             // Connection ESTABLISHED
             SwarmEvent::ConnectionEstablished {
                 peer_id,
+                connection_id,
                 endpoint,
                 num_established,
                 ..
             } => {
+                self.pending_dials.remove(&connection_id);
+                if self.paused || self.reputation.is_banned(&peer_id) {
+                    let _ = self.swarm.disconnect_peer_id(peer_id);
+                    return;
+                }
                 if num_established.get() == 1 {
                     let addr = match endpoint {
                         libp2p::core::ConnectedPoint::Dialer { address, .. } => address.to_string(),
@@ -410,10 +1472,10 @@ impl Runtime {
                         },
                     );
 
-                    let topic = gossipsub::IdentTopic::new("avi-context-updates");
-                    if !self.topics.contains("avi-context-updates") {
+                    let topic = self.wire_topic(CONTEXT_UPDATES_TOPIC);
+                    if !self.topics.contains(CONTEXT_UPDATES_TOPIC) {
                         let _ = self.swarm.behaviour_mut().gossipsub.subscribe(&topic);
-                        self.topics.insert("avi-context-updates".to_string());
+                        self.topics.insert(CONTEXT_UPDATES_TOPIC.to_string());
                     }
 
                     let _ = self
@@ -423,118 +1485,1393 @@ impl Runtime {
                             address: addr,
                         })
                         .await;
+
+                    self.challenge_peer(peer_id);
+                    self.flush_outbox(peer_id);
+                    self.backfill_from(peer_id);
+                    if let Some(fetch) = self.pairing_fetches.get(&peer_id) {
+                        let token = fetch.token.clone();
+                        self.send_stream_message(&peer_id, StreamMessage::PairingRequest { token });
+                    }
+                    if self.rendezvous_points.contains(&peer_id) {
+                        self.register_at(peer_id);
+                    }
                 }
             }
 
             // Connection CLOSED
             SwarmEvent::ConnectionClosed {
                 peer_id,
-                num_established,
+                num_established: 0,
                 ..
             } => {
-                if num_established == 0 {
-                    self.peers.remove(&peer_id);
-                    self.discovered_peers.remove(&peer_id);
-                    self.synced_peers.remove(&peer_id);
+                self.peers.remove(&peer_id);
+                if self.peers.is_empty() && self.outage_started.is_none() {
+                    self.outage_started = Some(Instant::now());
+                }
+                self.discovered_peers.remove(&peer_id);
+                self.synced_peers.remove(&peer_id);
+                self.context_behind.remove(&peer_id);
+                self.delivery_limiters
+                    .retain(|(_, peer), _| *peer != peer_id);
+                for waiter in self
+                    .context_fetches
+                    .remove(&peer_id.to_string())
+                    .unwrap_or_default()
+                {
+                    let _ = waiter.send(Err(AviP2pError::PeerNotFound(PeerId::from(peer_id))));
+                }
+                self.pending_challenges.remove(&peer_id);
+                self.authenticated_peers.remove(&peer_id);
+                self.peer_info.remove(&peer_id);
 
-                    let ids_to_remove: Vec<u64> = self
-                        .streams
-                        .iter()
-                        .filter(|(_, state)| state.peer == peer_id)
-                        .map(|(id, _)| *id)
-                        .collect();
+                let ids_to_remove: Vec<u64> = self
+                    .streams
+                    .iter()
+                    .filter(|(_, state)| state.peer == peer_id)
+                    .map(|(id, _)| *id)
+                    .collect();
 
-                    for id in ids_to_remove {
-                        self.streams.remove(&id);
-                        let _ = self
-                            .event_tx
-                            .send(AviEvent::StreamClosed {
-                                peer_id: PeerId::from(peer_id),
-                                stream_id: StreamId(id),
-                                reason: StreamCloseReason::RemoteClose,
-                            })
-                            .await;
+                for id in ids_to_remove {
+                    self.streams.remove(&id);
+                    if self.relays.contains_key(&id) {
+                        self.close_relay(id);
+                        continue;
                     }
-
+                    self.finish_stream_open(
+                        id,
+                        Err(AviP2pError::PeerNotFound(PeerId::from(peer_id))),
+                    );
                     let _ = self
                         .event_tx
-                        .send(AviEvent::PeerDisconnected {
+                        .send(AviEvent::StreamClosed {
                             peer_id: PeerId::from(peer_id),
+                            stream_id: StreamId(id),
+                            reason: StreamCloseReason::RemoteClose,
                         })
                         .await;
                 }
-            }
-
-            SwarmEvent::Behaviour(AviBehaviourEvent::Gossipsub(gossipsub::Event::Message {
-                propagation_source,
-                message,
-                ..
-            })) => {
-                let topic = message.clone().topic.into_string();
-
-                if topic == "avi-context-updates" {
-                    if let Ok(incoming_ctx) = serde_json::from_slice::<AviContext>(&message.data) {
-                        let peer_id_str = incoming_ctx.device_id.clone();
-
-                        if self.local_context.merge(incoming_ctx) {
-                            // Notify User
-                            let _ = self
-                                .event_tx
-                                .send(AviEvent::ContextUpdated {
-                                    peer_id: PeerId::new(&peer_id_str),
-                                    context: self.local_context.data.clone(),
-                                })
-                                .await;
-                        }
-                    }
-                    return;
-                }
 
                 let _ = self
                     .event_tx
-                    .send(AviEvent::Message {
-                        from: PeerId::from(propagation_source),
-                        topic: message.topic.into_string(),
-                        data: message.data,
+                    .send(AviEvent::PeerDisconnected {
+                        peer_id: PeerId::from(peer_id),
                     })
                     .await;
             }
-            SwarmEvent::Behaviour(AviBehaviourEvent::Stream(
-                request_response::Event::Message { peer, message },
-            )) => match message {
-                request_response::Message::Request {
-                    request, channel, ..
-                } => {
-                    self.handle_stream_message(peer, request).await;
-                    let _ = self.swarm.behaviour_mut().stream.send_response(channel, ());
-                }
-                _ => {}
-            },
-            _ => {}
-        }
-    }
 
-    async fn handle_stream_message(&mut self, peer: LibPeerId, msg: StreamMessage) {
-        let peer_wrap = PeerId::from(peer);
-        match msg {
-            StreamMessage::SyncContext(incoming_ctx) => {
-                let peer_id_str = incoming_ctx.device_id.clone();
-                if self.local_context.merge(incoming_ctx) {
-                    let _ = self
-                        .event_tx
-                        .send(AviEvent::ContextUpdated {
-                            peer_id: PeerId::new(&peer_id_str),
-                            context: self.local_context.data.clone(),
-                        })
-                        .await;
-                }
+            SwarmEvent::Behaviour(
+                AviBehaviourEvent::Gossipsub(gossipsub::Event::Subscribed { .. })
+                | AviBehaviourEvent::Telemetry(gossipsub::Event::Subscribed { .. }),
+            ) => {
+                self.flush_paused_publishes();
             }
-            StreamMessage::RequestStream { stream_id, reason } => {
-                self.streams.insert(
-                    stream_id,
+
+            SwarmEvent::Behaviour(
+                AviBehaviourEvent::Gossipsub(gossipsub::Event::Message {
+                    propagation_source,
+                    message_id,
+                    message,
+                })
+                | AviBehaviourEvent::Telemetry(gossipsub::Event::Message {
+                    propagation_source,
+                    message_id,
+                    message,
+                }),
+            ) => {
+                let wire_topic = message.topic.as_str();
+                let Some(wire_topic) = self.strip_namespace(wire_topic) else {
+                    return;
+                };
+                let topic = match self.compact_topics.get(wire_topic) {
+                    Some(name) => name.clone(),
+                    None => wire_topic.to_string(),
+                };
+
+                let author = message.source.unwrap_or(propagation_source);
+                if self.reputation.is_banned(&author) {
+                    return;
+                }
+                let permission = if topic == CONTEXT_UPDATES_TOPIC {
+                    self.authorize(&author, Operation::ContextWrite, None)
+                } else {
+                    self.authorize(&author, Operation::Publish, Some(&topic))
+                };
+                if let Err(e) = permission {
+                    debug!("Dropping message on {} from {}: {}", topic, author, e);
+                    return;
+                }
+
+                if let Some(limit) = self.topic_rate_limits.get(&topic) {
+                    let limiter = self
+                        .delivery_limiters
+                        .entry((topic.clone(), author))
+                        .or_insert_with(|| RateLimiter::new(limit));
+                    match limiter.admit(message.data.len()) {
+                        Admission::Allowed => {}
+                        Admission::Limited { first } => {
+                            debug!("Dropping rate limited message on {} from {}", topic, author);
+                            if first {
+                                let _ = self
+                                    .event_tx
+                                    .send(AviEvent::RateLimited {
+                                        from: PeerId::from(author),
+                                        topic,
+                                    })
+                                    .await;
+                            }
+                            return;
+                        }
+                    }
+                }
+
+                if topic == CONTEXT_UPDATES_TOPIC {
+                    if let Ok(signed) = serde_json::from_slice::<SignedContext>(&message.data) {
+                        self.merge_remote_context(author, signed).await;
+                    } else if let Ok(notice) =
+                        serde_json::from_slice::<ContextInvalidation>(&message.data)
+                    {
+                        self.invalidate_peer_context(author, notice).await;
+                    } else {
+                        self.penalize(author, Violation::DecodeFailure).await;
+                    }
+                    return;
+                }
+
+                // Already taken from a peer's history while catching up
+                if self.history.contains(&message_id.0) {
+                    return;
+                }
+                let data = message.data.clone();
+                if self
+                    .deliver_message(
+                        author,
+                        propagation_source,
+                        topic.clone(),
+                        message.data,
+                        false,
+                    )
+                    .await
+                {
+                    self.history.record(
+                        &topic,
+                        author.to_string(),
+                        message_id.0,
+                        data,
+                        Duration::ZERO,
+                    );
+                }
+            }
+            SwarmEvent::Behaviour(AviBehaviourEvent::Stream(
+                request_response::Event::Message { peer, message },
+            )) => match message {
+                request_response::Message::Request {
+                    request, channel, ..
+                } => {
+                    self.handle_stream_message(peer, request).await;
+                    let _ = self.swarm.behaviour_mut().stream.send_response(channel, ());
+                }
+                request_response::Message::Response { request_id, .. } => {
+                    match self.pending_requests.remove(&request_id) {
+                        Some(PendingRequest::Outbox(id)) => {
+                            if let Some(outbox) = &mut self.outbox {
+                                outbox.delivered(id);
+                            }
+                        }
+                        Some(PendingRequest::Direct(respond_to)) => {
+                            let _ = respond_to.send(Ok(()));
+                        }
+                        _ => {}
+                    }
+                }
+            },
+            SwarmEvent::Behaviour(AviBehaviourEvent::Stream(
+                request_response::Event::OutboundFailure {
+                    peer,
+                    request_id,
+                    error,
+                },
+            )) => {
+                let mut pending = self.pending_requests.remove(&request_id);
+                if let (Some(PendingRequest::Outbox(id)), Some(outbox)) =
+                    (&pending, &mut self.outbox)
+                {
+                    outbox.failed(*id);
+                }
+                if let Some(PendingRequest::Direct(respond_to)) = pending.take() {
+                    let _ = respond_to.send(Err(AviP2pError::NetworkError(error.to_string())));
+                    return;
+                }
+                if let (
+                    request_response::OutboundFailure::Timeout,
+                    Some(PendingRequest::Stream(id)),
+                ) = (&error, pending)
+                {
+                    // A lost control message or data chunk leaves the stream
+                    // in an unknown state, so close it
+                    if self.streams.remove(&id).is_some() {
+                        self.finish_stream_open(
+                            id,
+                            Err(AviP2pError::StreamOpenTimeout(StreamId(id))),
+                        );
+                        let _ = self
+                            .event_tx
+                            .send(AviEvent::StreamClosed {
+                                peer_id: PeerId::from(peer),
+                                stream_id: StreamId(id),
+                                reason: StreamCloseReason::Timeout,
+                            })
+                            .await;
+                    }
+                } else {
+                    debug!("Stream protocol request to {} failed: {}", peer, error);
+                }
+            }
+            SwarmEvent::Behaviour(AviBehaviourEvent::Extension(
+                request_response::Event::Message { peer, message },
+            )) => match message {
+                request_response::Message::Request {
+                    request, channel, ..
+                } => {
+                    let handler = self.extension_handlers.get(&request.name).cloned();
+                    let reply = match handler {
+                        _ if !self.is_trusted(&peer) => {
+                            Err("peer is not authenticated".to_string())
+                        }
+                        None => Err(format!("no extension protocol named {}", request.name)),
+                        Some(handler) => {
+                            let replies = self.extension_replies_tx.clone();
+                            tokio::spawn(async move {
+                                let reply =
+                                    handler.on_request(PeerId::from(peer), request.data).await;
+                                let _ = replies.send((channel, reply));
+                            });
+                            return;
+                        }
+                    };
+                    let _ = self
+                        .swarm
+                        .behaviour_mut()
+                        .extension
+                        .send_response(channel, reply);
+                }
+                request_response::Message::Response {
+                    request_id,
+                    response,
+                } => {
+                    if let Some(respond_to) = self.extension_requests.remove(&request_id) {
+                        let _ = respond_to.send(response.map_err(AviP2pError::Extension));
+                    }
+                }
+            },
+            SwarmEvent::Behaviour(AviBehaviourEvent::Extension(
+                request_response::Event::OutboundFailure {
+                    request_id, error, ..
+                },
+            )) => {
+                if let Some(respond_to) = self.extension_requests.remove(&request_id) {
+                    let _ = respond_to.send(Err(AviP2pError::NetworkError(error.to_string())));
+                }
+            }
+            SwarmEvent::Behaviour(AviBehaviourEvent::Rendezvous(
+                request_response::Event::Message { peer, message },
+            )) => match message {
+                request_response::Message::Request {
+                    request, channel, ..
+                } => {
+                    let response = self.handle_rendezvous_request(peer, request);
+                    let _ = self
+                        .swarm
+                        .behaviour_mut()
+                        .rendezvous
+                        .send_response(channel, response);
+                }
+                request_response::Message::Response {
+                    request_id,
+                    response,
+                } => {
+                    let lookup = self.rendezvous_requests.remove(&request_id).flatten();
+                    let found = match response {
+                        RendezvousResponse::Registrations(registrations) => {
+                            let mut found = Vec::new();
+                            for registration in registrations {
+                                if let Some(peer_id) = self.dial_registration(registration).await {
+                                    found.push(peer_id);
+                                }
+                            }
+                            found
+                        }
+                        RendezvousResponse::Error(e) => {
+                            debug!("Rendezvous point {} refused: {}", peer, e);
+                            Vec::new()
+                        }
+                        _ => Vec::new(),
+                    };
+                    if let Some(lookup) = lookup {
+                        self.finish_rendezvous_lookup(lookup, found);
+                    }
+                }
+            },
+            SwarmEvent::Behaviour(AviBehaviourEvent::Rendezvous(
+                request_response::Event::OutboundFailure {
+                    peer,
+                    request_id,
+                    error,
+                },
+            )) => {
+                debug!("Rendezvous request to {} failed: {}", peer, error);
+                if let Some(Some(lookup)) = self.rendezvous_requests.remove(&request_id) {
+                    self.finish_rendezvous_lookup(lookup, Vec::new());
+                }
+            }
+            SwarmEvent::Behaviour(
+                AviBehaviourEvent::Stream(request_response::Event::InboundFailure {
+                    peer,
+                    error: request_response::InboundFailure::Io(e),
+                    ..
+                })
+                | AviBehaviourEvent::Extension(request_response::Event::InboundFailure {
+                    peer,
+                    error: request_response::InboundFailure::Io(e),
+                    ..
+                }),
+            ) if e.kind() == std::io::ErrorKind::InvalidData => {
+                debug!("Undecodable request from {}: {}", peer, e);
+                self.penalize(peer, Violation::DecodeFailure).await;
+            }
+            _ => {}
+        }
+    }
+
+    /// Count a protocol violation against `peer`, banning it over the threshold
+    async fn penalize(&mut self, peer: LibPeerId, violation: Violation) {
+        debug!("{:?} from {}", violation, peer);
+        if let Some(duration) = self.reputation.record(peer, violation) {
+            self.enforce_ban(peer, Some(violation), Some(duration))
+                .await;
+        }
+    }
+
+    async fn enforce_ban(
+        &mut self,
+        peer: LibPeerId,
+        violation: Option<Violation>,
+        duration: Option<Duration>,
+    ) {
+        info!("Banning peer {} ({:?})", peer, violation);
+        let _ = self.swarm.disconnect_peer_id(peer);
+        let _ = self
+            .event_tx
+            .send(AviEvent::PeerBanned {
+                peer_id: PeerId::from(peer),
+                violation,
+                duration,
+            })
+            .await;
+    }
+
+    /// Answer a rendezvous request; only rendezvous points hold registrations
+    fn handle_rendezvous_request(
+        &mut self,
+        peer: LibPeerId,
+        request: RendezvousRequest,
+    ) -> RendezvousResponse {
+        let Some(registry) = &mut self.rendezvous_registry else {
+            return RendezvousResponse::Error("not a rendezvous point".to_string());
+        };
+        let now = Instant::now();
+        match request {
+            RendezvousRequest::Register {
+                namespace,
+                addrs,
+                ttl_secs,
+            } => match registry.register(
+                &namespace,
+                &peer.to_string(),
+                addrs,
+                Duration::from_secs(ttl_secs),
+                now,
+            ) {
+                Ok(ttl) => RendezvousResponse::Registered {
+                    ttl_secs: ttl.as_secs(),
+                },
+                Err(e) => RendezvousResponse::Error(e),
+            },
+            RendezvousRequest::Unregister { namespace } => {
+                registry.unregister(&namespace, &peer.to_string());
+                RendezvousResponse::Unregistered
+            }
+            RendezvousRequest::Discover { namespace } => RendezvousResponse::Registrations(
+                registry.discover(&namespace, &peer.to_string(), now),
+            ),
+        }
+    }
+
+    /// Take ownership of a DHT entry and publish it; a local store error
+    /// (e.g. value too large) is returned and the entry dropped
+    fn own_dht_entry(
+        &mut self,
+        key: String,
+        kind: DhtEntryKind,
+        value: Option<Vec<u8>>,
+    ) -> Result<(), AviP2pError> {
+        let entry = (key, kind);
+        self.owned_dht.insert(
+            entry.clone(),
+            OwnedDhtEntry {
+                value,
+                next_publish: Instant::now(),
+            },
+        );
+        let res = self.publish_dht_entry(&entry);
+        if res.is_err() {
+            self.owned_dht.remove(&entry);
+        }
+        res
+    }
+
+    fn publish_dht_entry(&mut self, entry: &(String, DhtEntryKind)) -> Result<(), AviP2pError> {
+        let jitter = self.republish_jitter.mul_f64(rand::random::<f64>());
+        let next_publish = Instant::now() + self.republish_interval + jitter;
+        let key = self.dht_key(&entry.0);
+        let Some(owned) = self.owned_dht.get_mut(entry) else {
+            return Ok(());
+        };
+        owned.next_publish = next_publish;
+
+        let kad = &mut self.swarm.behaviour_mut().kad;
+        let query = match &owned.value {
+            Some(value) => kad.put_record(kad::Record::new(key, value.clone()), kad::Quorum::One),
+            None => kad.start_providing(key),
+        }
+        .map_err(|e| AviP2pError::NetworkError(e.to_string()))?;
+        self.dht_publishes.insert(query, entry.clone());
+        Ok(())
+    }
+
+    /// Publish owned DHT entries again before they expire at other peers
+    async fn republish_dht(&mut self) {
+        let now = Instant::now();
+        let due: Vec<(String, DhtEntryKind)> = self
+            .owned_dht
+            .iter()
+            .filter(|(_, owned)| owned.next_publish <= now)
+            .map(|(entry, _)| entry.clone())
+            .collect();
+
+        for entry in due {
+            if let Err(e) = self.publish_dht_entry(&entry) {
+                self.report_dht_failure(entry, e.to_string()).await;
+            }
+        }
+    }
+
+    async fn finish_dht_publish(&mut self, query: kad::QueryId, error: Option<String>) {
+        let Some(entry) = self.dht_publishes.remove(&query) else {
+            return;
+        };
+        // Entries given up since the publish started are not reported
+        if let Some(error) = error.filter(|_| self.owned_dht.contains_key(&entry)) {
+            self.report_dht_failure(entry, error).await;
+        }
+    }
+
+    async fn report_dht_failure(&mut self, (key, kind): (String, DhtEntryKind), error: String) {
+        debug!("Publishing DHT {:?} {} failed: {}", kind, key, error);
+        let _ = self
+            .event_tx
+            .send(AviEvent::DhtPublishFailed { key, kind, error })
+            .await;
+    }
+
+    /// Renew our registration and look for new peers at every connected point
+    fn refresh_rendezvous(&mut self) {
+        let Some(rendezvous) = &self.rendezvous else {
+            return;
+        };
+        self.rendezvous_next_refresh = Instant::now() + rendezvous.refresh_interval;
+        let points: Vec<LibPeerId> = self
+            .rendezvous_points
+            .iter()
+            .filter(|p| self.swarm.is_connected(p))
+            .copied()
+            .collect();
+        for point in points {
+            self.register_at(point);
+        }
+    }
+
+    /// Register under the configured namespace at `point`, then discover
+    fn register_at(&mut self, point: LibPeerId) {
+        let Some(rendezvous) = &self.rendezvous else {
+            return;
+        };
+        let namespace = rendezvous.namespace.clone();
+        let ttl_secs = rendezvous.ttl.as_secs();
+
+        // Prefer addresses confirmed reachable from outside over raw listeners
+        let external: Vec<String> = self
+            .swarm
+            .external_addresses()
+            .map(|a| a.to_string())
+            .collect();
+        let addrs = if external.is_empty() {
+            self.listen_addresses
+                .iter()
+                .map(|a| a.to_string())
+                .collect()
+        } else {
+            external
+        };
+
+        let behaviour = &mut self.swarm.behaviour_mut().rendezvous;
+        behaviour.send_request(
+            &point,
+            RendezvousRequest::Register {
+                namespace: namespace.clone(),
+                addrs,
+                ttl_secs,
+            },
+        );
+        let request_id = behaviour.send_request(&point, RendezvousRequest::Discover { namespace });
+        self.rendezvous_requests.insert(request_id, None);
+    }
+
+    /// Remember and dial a peer found through rendezvous
+    async fn dial_registration(
+        &mut self,
+        registration: crate::protocols::rendezvous::Registration,
+    ) -> Option<PeerId> {
+        let peer_id = LibPeerId::from_str(&registration.peer_id).ok()?;
+        if peer_id == *self.swarm.local_peer_id() {
+            return None;
+        }
+        for addr in registration
+            .addrs
+            .iter()
+            .filter_map(|a| Multiaddr::from_str(a).ok())
+        {
+            self.swarm
+                .behaviour_mut()
+                .kad
+                .add_address(&peer_id, addr.clone());
+            self.known_peers.insert(peer_id, addr.clone());
+            if !self.swarm.is_connected(&peer_id) {
+                let _ = self.swarm.dial(addr);
+            }
+        }
+        self.emit_peer_discovered(peer_id).await;
+        Some(PeerId::from(peer_id))
+    }
+
+    fn finish_rendezvous_lookup(&mut self, lookup: u64, found: Vec<PeerId>) {
+        let Some(pending) = self.rendezvous_lookups.get_mut(&lookup) else {
+            return;
+        };
+        for peer in found {
+            if !pending.found.contains(&peer) {
+                pending.found.push(peer);
+            }
+        }
+        pending.remaining -= 1;
+        if pending.remaining == 0 {
+            if let Some(pending) = self.rendezvous_lookups.remove(&lookup) {
+                let _ = pending.respond_to.send(Ok(pending.found));
+            }
+        }
+    }
+
+    /// Topics the application subscribed to, without internal ones
+    fn subscriptions(&self) -> Vec<String> {
+        let mut topics: Vec<String> = self
+            .topics
+            .iter()
+            .filter(|t| t.as_str() != CONTEXT_UPDATES_TOPIC)
+            .cloned()
+            .collect();
+        topics.sort();
+        topics
+    }
+
+    fn save_subscriptions(&self) {
+        let Some(path) = &self.subscription_store else {
+            return;
+        };
+        let res = serde_json::to_vec(&self.subscriptions())
+            .map_err(|e| e.to_string())
+            .and_then(|data| std::fs::write(path, data).map_err(|e| e.to_string()));
+        if let Err(e) = res {
+            debug!("Failed to save subscriptions to {}: {}", path.display(), e);
+        }
+    }
+
+    /// Subscribe again to the topics saved before a restart
+    fn restore_subscriptions(&mut self) {
+        let Some(path) = &self.subscription_store else {
+            return;
+        };
+        let topics: Vec<String> = match std::fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_default(),
+            Err(_) => return,
+        };
+        for topic in topics {
+            let topic_hash = self.wire_topic(&topic);
+            match self.gossip_for(&topic).subscribe(&topic_hash) {
+                Ok(_) => {
+                    self.topics.insert(topic);
+                }
+                Err(e) => debug!("Failed to restore subscription to {}: {}", topic, e),
+            }
+        }
+    }
+
+    fn export_snapshot(&self) -> NodeSnapshot {
+        let identity = self
+            .local_key
+            .clone()
+            .try_into_ed25519()
+            .ok()
+            .and_then(|key| key.secret().as_ref().try_into().ok())
+            .map(IdentitySecret);
+        let mut compact_topics: Vec<String> = self.compact_topics.values().cloned().collect();
+        compact_topics.sort();
+        NodeSnapshot {
+            format: SNAPSHOT_FORMAT,
+            identity,
+            context: self.local_context.clone(),
+            known_peers: self
+                .known_peers
+                .iter()
+                .map(|(peer_id, addr)| (peer_id.to_string(), addr.to_string()))
+                .collect(),
+            subscriptions: self.subscriptions(),
+            compact_topics,
+        }
+    }
+
+    /// Take over a snapshot from this node's previous hardware: its context
+    /// replaces ours (with a clock past both, so peers accept it), and its
+    /// peers, subscriptions and compact topics are added to ours
+    fn import_snapshot(&mut self, snapshot: NodeSnapshot) -> Result<(), AviP2pError> {
+        let local = *self.swarm.local_peer_id();
+        if snapshot.context.device_id != local.to_string() {
+            return Err(AviP2pError::SnapshotIdentity(PeerId::new(
+                &snapshot.context.device_id,
+            )));
+        }
+        self.authorize(&local, Operation::ContextWrite, None)?;
+
+        for topic in snapshot.compact_topics {
+            self.compact_topics.insert(compact_topic_id(&topic), topic);
+        }
+        for topic in snapshot.subscriptions {
+            let topic_hash = self.wire_topic(&topic);
+            match self.gossip_for(&topic).subscribe(&topic_hash) {
+                Ok(_) => {
+                    self.topics.insert(topic);
+                }
+                Err(e) => debug!("Failed to import subscription to {}: {}", topic, e),
+            }
+        }
+        self.save_subscriptions();
+
+        for (peer_id, addr) in snapshot.known_peers {
+            if let (Ok(peer_id), Ok(addr)) = (LibPeerId::from_str(&peer_id), addr.parse()) {
+                if peer_id != local {
+                    self.known_peers.insert(peer_id, addr);
+                }
+            }
+        }
+        self.redial_known_peers();
+
+        let mut context = snapshot.context;
+        context.vector_clock.merge(&self.local_context.vector_clock);
+        self.record_audit(
+            &local.to_string(),
+            AuditAction::ContextModified {
+                keys: top_level_keys(&context.data),
+            },
+        );
+        let before = std::mem::replace(&mut self.local_context, context).data;
+        self.commit_local_write(&before);
+        self.broadcast_local_context(Vec::new())
+    }
+
+    fn redial_known_peers(&mut self) {
+        for (peer_id, addr) in &self.known_peers {
+            if !self.swarm.is_connected(peer_id) {
+                let _ = self.swarm.dial(addr.clone());
+            }
+        }
+    }
+
+    /// Publish an application message: authorize, rate limit, tag, audit
+    fn publish(
+        &mut self,
+        topic: String,
+        data: Vec<u8>,
+        message_id: Option<String>,
+    ) -> Result<(), AviP2pError> {
+        let local = *self.swarm.local_peer_id();
+        let size = data.len();
+        self.admit_publish(&local, &topic, size)?;
+        let data = match &message_id {
+            Some(id) => dedupe::tag(data, id),
+            None => data,
+        };
+        self.gossip_publish(&topic, data)?;
+        self.audit_command(&local, &topic, size);
+        Ok(())
+    }
+
+    /// Authorize a publish and take its rate limit tokens
+    fn admit_publish(
+        &mut self,
+        local: &LibPeerId,
+        topic: &str,
+        size: usize,
+    ) -> Result<(), AviP2pError> {
+        self.authorize(local, Operation::Publish, Some(topic))?;
+        if let Some(limit) = self.topic_rate_limits.get(topic) {
+            let limiter = self
+                .publish_limiters
+                .entry(topic.to_string())
+                .or_insert_with(|| RateLimiter::new(limit));
+            if let Admission::Limited { .. } = limiter.admit(size) {
+                return Err(AviP2pError::RateLimited(topic.to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Publish every message or none. All of them are authorized, rate
+    /// limited and checked for a peer to take them before the first is
+    /// handed to gossip; a refusal puts back the rate limit tokens already
+    /// taken. Gossip cannot take back a sent message, so a failure while
+    /// sending (rare once the checks passed) reports how many went out.
+    fn publish_atomic(&mut self, messages: Vec<(String, Vec<u8>)>) -> Result<(), AviP2pError> {
+        let local = *self.swarm.local_peer_id();
+        // Limiter state before this publish, for topics it touched
+        let mut saved: HashMap<String, Option<RateLimiter>> = HashMap::new();
+        for (index, (topic, data)) in messages.iter().enumerate() {
+            if !saved.contains_key(topic) {
+                saved.insert(topic.clone(), self.publish_limiters.get(topic).cloned());
+            }
+            let admitted = self
+                .admit_publish(&local, topic, data.len())
+                .and_then(|()| self.check_gossip_peers(topic));
+            if let Err(reason) = admitted {
+                for (topic, limiter) in saved {
+                    match limiter {
+                        Some(limiter) => self.publish_limiters.insert(topic, limiter),
+                        None => self.publish_limiters.remove(&topic),
+                    };
+                }
+                return Err(AviP2pError::PublishRolledBack {
+                    index,
+                    topic: topic.clone(),
+                    reason: Box::new(reason),
+                });
+            }
+        }
+
+        for (sent, (topic, data)) in messages.into_iter().enumerate() {
+            let size = data.len();
+            if let Err(reason) = self.gossip_publish(&topic, data) {
+                return Err(AviP2pError::PublishPartiallySent {
+                    sent,
+                    topic,
+                    reason: Box::new(reason),
+                });
+            }
+            self.audit_command(&local, &topic, size);
+        }
+        Ok(())
+    }
+
+    /// Whether gossip would find a peer to send a message on `topic` to.
+    /// Always fine while paused, as publishes are buffered.
+    fn check_gossip_peers(&mut self, topic: &str) -> Result<(), AviP2pError> {
+        if self.paused {
+            return Ok(());
+        }
+        let hash = self.wire_topic(topic).hash();
+        let subscribed = self
+            .gossip_for(topic)
+            .all_peers()
+            .any(|(_, topics)| topics.contains(&&hash));
+        if subscribed {
+            Ok(())
+        } else {
+            Err(AviP2pError::NetworkError(format!(
+                "No peers subscribed to {}",
+                topic
+            )))
+        }
+    }
+
+    /// Ask the node behind `payload` for its invitation, dialing it first
+    /// unless already connected
+    fn fetch_invitation(
+        &mut self,
+        payload: PairingPayload,
+        respond_to: oneshot::Sender<Result<MeshInvitation, AviP2pError>>,
+    ) {
+        let peer = match LibPeerId::try_from(payload.peer_id.clone()) {
+            Ok(peer) => peer,
+            Err(_) => {
+                let _ = respond_to.send(Err(AviP2pError::PeerNotFound(payload.peer_id)));
+                return;
+            }
+        };
+        let connected = self.swarm.is_connected(&peer);
+        self.pairing_fetches.insert(
+            peer,
+            PairingFetch {
+                token: payload.token.clone(),
+                respond_to,
+            },
+        );
+        if connected {
+            self.send_stream_message(
+                &peer,
+                StreamMessage::PairingRequest {
+                    token: payload.token,
+                },
+            );
+            return;
+        }
+        // Pinning the peer id keeps an impostor at the address from
+        // answering in its place
+        for addr in &payload.addresses {
+            match Multiaddr::from_str(&format!("{}/p2p/{}", addr, peer)) {
+                Ok(addr) => {
+                    if let Err(e) = self.swarm.dial(addr) {
+                        debug!("Dialing pairing peer {} failed: {}", peer, e);
+                    }
+                }
+                Err(e) => debug!("Skipping pairing address {}: {}", addr, e),
+            }
+        }
+    }
+
+    /// Mesh settings and addresses to put in an invitation
+    fn mesh_details(&self) -> Result<MeshDetails, AviP2pError> {
+        let Some(auth) = &self.auth else {
+            return Err(AviP2pError::Config(
+                "Invitations need household authentication (AviP2pConfig::auth)".to_string(),
+            ));
+        };
+        Ok(MeshDetails {
+            peer_id: PeerId::from(*self.swarm.local_peer_id()),
+            listen_addresses: self
+                .listen_addresses
+                .iter()
+                .map(|addr| addr.to_string())
+                .collect(),
+            mesh_namespace: self.mesh_namespace.clone(),
+            network_keys: self
+                .keyring
+                .current_keys()
+                .into_iter()
+                .map(|(scope, epoch, key)| NetworkKey { scope, epoch, key })
+                .collect(),
+            guest_topics: auth.guest_topics.clone(),
+        })
+    }
+
+    /// Publish every scheduled message that is due, oldest first
+    async fn send_scheduled_publishes(&mut self) {
+        let now = Instant::now();
+        let mut due: Vec<(CorrelationId, Instant)> = self
+            .scheduled_publishes
+            .iter()
+            .filter(|(_, p)| p.due <= now)
+            .map(|(id, p)| (*id, p.due))
+            .collect();
+        due.sort_by_key(|(id, at)| (*at, *id));
+
+        for (correlation_id, _) in due {
+            let Some(scheduled) = self.scheduled_publishes.remove(&correlation_id) else {
+                continue;
+            };
+            let topic = scheduled.topic.clone();
+            if let Err(e) = self.publish(scheduled.topic, scheduled.data, None) {
+                debug!("Scheduled publish on {} failed: {}", topic, e);
+                let _ = self
+                    .event_tx
+                    .send(AviEvent::ScheduledPublishFailed {
+                        correlation_id,
+                        topic,
+                        error: e.to_string(),
+                    })
+                    .await;
+            }
+        }
+    }
+
+    /// Publish to gossip, or buffer while paused (dropping the oldest
+    /// buffered message once `pause_buffer_limit` is reached)
+    fn gossip_publish(&mut self, topic: &str, data: Vec<u8>) -> Result<(), AviP2pError> {
+        // Stamped now so time spent buffered counts toward the message's age
+        let data = if self.topic_max_age.contains_key(topic) {
+            expiry::stamp(data, expiry::now_ms())
+        } else {
+            data
+        };
+        if self.paused {
+            if self.paused_publishes.len() >= self.pause_buffer_limit {
+                self.paused_publishes.pop_front();
+            }
+            if self.pause_buffer_limit > 0 {
+                self.paused_publishes.push_back((topic.to_string(), data));
+            }
+            return Ok(());
+        }
+        let wire_topic = self.wire_topic(topic);
+        let message_id = self
+            .gossip_for(topic)
+            .publish(wire_topic, data.clone())
+            .map_err(|e| AviP2pError::NetworkError(e.to_string()))?;
+        // Our own messages are history for peers catching up too
+        if topic != CONTEXT_UPDATES_TOPIC {
+            let local = self.swarm.local_peer_id().to_string();
+            self.history
+                .record(topic, local, message_id.0, data, Duration::ZERO);
+        }
+        Ok(())
+    }
+
+    /// Gossipsub topic a topic travels under: its compact id if configured,
+    /// within the mesh namespace
+    fn wire_topic(&self, topic: &str) -> gossipsub::IdentTopic {
+        let compact = compact_topic_id(topic);
+        match self.compact_topics.get(&compact) {
+            Some(name) if name == topic => gossipsub::IdentTopic::new(self.namespaced(&compact)),
+            _ => gossipsub::IdentTopic::new(self.namespaced(topic)),
+        }
+    }
+
+    /// Gossip instance carrying `topic`, per its `TopicProfile`
+    fn gossip_for(&mut self, topic: &str) -> &mut gossipsub::Behaviour {
+        let behaviour = self.swarm.behaviour_mut();
+        match topic_profile(&self.topic_profiles, topic) {
+            TopicProfile::Control => &mut behaviour.gossipsub,
+            TopicProfile::Telemetry => &mut behaviour.telemetry,
+        }
+    }
+
+    fn dht_key(&self, key: &str) -> kad::RecordKey {
+        kad::RecordKey::new(&self.namespaced(key))
+    }
+
+    fn namespaced(&self, name: &str) -> String {
+        match &self.mesh_namespace {
+            Some(namespace) => format!("{}/{}", namespace, name),
+            None => name.to_string(),
+        }
+    }
+
+    /// `name` without our namespace prefix; None if it belongs to another
+    fn strip_namespace<'a>(&self, name: &'a str) -> Option<&'a str> {
+        match &self.mesh_namespace {
+            Some(namespace) => name.strip_prefix(namespace.as_str())?.strip_prefix('/'),
+            None => Some(name),
+        }
+    }
+
+    /// Send publishes buffered during a pause once the mesh is back
+    fn flush_paused_publishes(&mut self) {
+        while let Some((topic, data)) = self.paused_publishes.pop_front() {
+            let wire_topic = self.wire_topic(&topic);
+            let res = self.gossip_for(&topic).publish(wire_topic, data.clone());
+            match res {
+                Ok(_) => {}
+                Err(gossipsub::PublishError::InsufficientPeers) => {
+                    self.paused_publishes.push_front((topic, data));
+                    return;
+                }
+                Err(e) => debug!("Dropping buffered publish to {}: {}", topic, e),
+            }
+        }
+    }
+
+    /// Time spent handling one command or swarm event is time the swarm
+    /// is not being polled; keep a moving average and the worst case
+    fn record_poll_latency(&mut self, elapsed: Duration) {
+        self.poll_latency = (self.poll_latency * 7 + elapsed) / 8;
+        self.max_poll_latency = self.max_poll_latency.max(elapsed);
+    }
+
+    /// Everything after gossip-level checks for a topic message: mutes,
+    /// expiry, message id dedupe, decryption, then delivery. False if the
+    /// message was dropped, so it is kept out of the topic history.
+    async fn deliver_message(
+        &mut self,
+        author: LibPeerId,
+        from: LibPeerId,
+        topic: String,
+        data: Vec<u8>,
+        replayed: bool,
+    ) -> bool {
+        if self.is_muted(&author, &topic) {
+            debug!("Dropping message on {} from muted {}", topic, author);
+            return false;
+        }
+        let (published_ms, data) = expiry::unstamp(data);
+        if let (Some(published_ms), Some(max_age)) = (published_ms, self.topic_max_age.get(&topic))
+        {
+            let age = expiry::now_ms().saturating_sub(published_ms);
+            if age > max_age.as_millis() as u64 {
+                debug!("Dropping {} ms old message on {}", age, topic);
+                return false;
+            }
+        }
+
+        let (message_id, mut data) = dedupe::untag(data);
+        if let (Some(id), Some(cache)) = (message_id, &mut self.dedupe) {
+            if !cache.insert((topic.clone(), id)) {
+                debug!("Dropping repeated message on {}", topic);
+                return false;
+            }
+        }
+
+        // Open payloads on topics we hold keys for; plaintext passes through
+        if self.keyring.has_scope(&topic) {
+            if let Ok(sealed) = serde_json::from_slice::<EncryptedPayload>(&data) {
+                match self.keyring.decrypt(&sealed) {
+                    Ok(plain) => data = plain,
+                    Err(e) => {
+                        debug!("Dropping undecryptable message on {}: {}", topic, e);
+                        return false;
+                    }
+                }
+            }
+        }
+
+        self.audit_command(&author, &topic, data.len());
+
+        let _ = self
+            .event_tx
+            .send(AviEvent::Message {
+                from: PeerId::from(from),
+                topic,
+                data,
+                replayed,
+            })
+            .await;
+        true
+    }
+
+    fn is_muted(&self, author: &LibPeerId, topic: &str) -> bool {
+        !self.muted.is_empty()
+            && (self.muted.contains(&(*author, None))
+                || self.muted.contains(&(*author, Some(topic.to_string()))))
+    }
+
+    /// Ask a peer connecting after an outage for the messages missed on
+    /// subscribed topics
+    fn backfill_from(&mut self, peer: LibPeerId) {
+        if let Some(started) = self.outage_started.take() {
+            let outage = started.elapsed();
+            if self.backfill_after_outage.is_some_and(|max| outage <= max) {
+                self.backfill = Some(Backfill {
+                    until: Instant::now() + BACKFILL_PEERS_FOR,
+                    window: outage + BACKFILL_MARGIN,
+                });
+            }
+        }
+        let window = match &self.backfill {
+            Some(backfill) if Instant::now() <= backfill.until => backfill.window,
+            Some(_) => {
+                self.backfill = None;
+                return;
+            }
+            None => return,
+        };
+        let topics: Vec<String> = self
+            .topics
+            .iter()
+            .filter(|topic| *topic != CONTEXT_UPDATES_TOPIC)
+            .cloned()
+            .collect();
+        if topics.is_empty() {
+            return;
+        }
+        self.send_stream_message(
+            &peer,
+            StreamMessage::HistoryRequest {
+                topics,
+                window_ms: window.as_millis() as u64,
+                limit: self.backfill_limit as u32,
+            },
+        );
+    }
+
+    /// Deliver a message from `peer`'s history unless it is already known
+    async fn replay(&mut self, peer: LibPeerId, entry: HistoryEntry) {
+        if entry.topic == CONTEXT_UPDATES_TOPIC || !self.topics.contains(&entry.topic) {
+            return;
+        }
+        if entry.data.len() > self.max_publish_size {
+            self.penalize(peer, Violation::OversizedPayload).await;
+            return;
+        }
+        let Ok(author) = LibPeerId::from_str(&entry.author) else {
+            self.penalize(peer, Violation::DecodeFailure).await;
+            return;
+        };
+        if self.reputation.is_banned(&author)
+            || self
+                .authorize(&author, Operation::Publish, Some(&entry.topic))
+                .is_err()
+        {
+            return;
+        }
+        if self.history.contains(&entry.id) {
+            return;
+        }
+        if self
+            .deliver_message(
+                author,
+                author,
+                entry.topic.clone(),
+                entry.data.clone(),
+                true,
+            )
+            .await
+        {
+            self.history.record(
+                &entry.topic,
+                entry.author,
+                entry.id,
+                entry.data,
+                Duration::from_millis(entry.age_ms),
+            );
+        }
+    }
+
+    /// Send a stream-protocol request, tracking it until answered or failed
+    fn send_stream_message(
+        &mut self,
+        peer: &LibPeerId,
+        msg: StreamMessage,
+    ) -> request_response::OutboundRequestId {
+        let pending = match &msg {
+            StreamMessage::RequestStream { stream_id, .. }
+            | StreamMessage::AcceptStream { stream_id, .. }
+            | StreamMessage::StreamData { stream_id, .. } => PendingRequest::Stream(*stream_id),
+            _ => PendingRequest::Other,
+        };
+        let request_id = self.swarm.behaviour_mut().stream.send_request(peer, msg);
+        self.pending_requests.insert(request_id, pending);
+        request_id
+    }
+
+    /// Hand everything queued for `peer` to the connection; entries leave
+    /// the outbox only once the peer acknowledges them
+    fn flush_outbox(&mut self, peer: LibPeerId) {
+        let Some(outbox) = &mut self.outbox else {
+            return;
+        };
+        for entry in outbox.take_pending(&peer.to_string()) {
+            let request_id =
+                self.send_stream_message(&peer, StreamMessage::Direct { data: entry.data });
+            self.pending_requests
+                .insert(request_id, PendingRequest::Outbox(entry.id));
+        }
+    }
+
+    async fn handle_stream_message(&mut self, peer: LibPeerId, msg: StreamMessage) {
+        let peer_wrap = PeerId::from(peer);
+
+        let operation = match msg {
+            // Pairing peers are not members yet; the token is their credential
+            StreamMessage::AuthChallenge { .. }
+            | StreamMessage::AuthResponse { .. }
+            | StreamMessage::PairingRequest { .. }
+            | StreamMessage::PairingResponse { .. } => None,
+            StreamMessage::SyncContext(_) | StreamMessage::ContextSummary { .. } => {
+                Some(Operation::ContextWrite)
+            }
+            StreamMessage::ContextRequest => Some(Operation::Subscribe),
+            StreamMessage::Rekey { .. } => Some(Operation::KeyManagement),
+            StreamMessage::Direct { .. } | StreamMessage::HistoryResponse { .. } => {
+                Some(Operation::Publish)
+            }
+            StreamMessage::HistoryRequest { .. } => Some(Operation::Subscribe),
+            _ => Some(Operation::Stream),
+        };
+        if let Some(operation) = operation {
+            if let Err(e) = self.authorize(&peer, operation, None) {
+                if let StreamMessage::RequestStream { stream_id, .. } = msg {
+                    self.send_stream_message(
+                        &peer,
+                        StreamMessage::RejectStream {
+                            stream_id,
+                            reason: "unauthorized".to_string(),
+                            version: None,
+                        },
+                    );
+                }
+                debug!("Ignoring stream message from {}: {}", peer, e);
+                return;
+            }
+        }
+
+        match msg {
+            StreamMessage::AuthChallenge { nonce } => {
+                let response = StreamMessage::AuthResponse {
+                    signature: auth::sign_challenge(&self.local_key, &nonce),
+                    nonce,
+                    public_key: self.local_key.public().encode_protobuf(),
+                    certificate: self.auth.as_ref().and_then(|a| a.certificate.clone()),
+                };
+                self.send_stream_message(&peer, response);
+
+                // Mutual authentication: challenge back if we haven't already
+                if !self.pending_challenges.contains_key(&peer) {
+                    self.challenge_peer(peer);
+                }
+            }
+            StreamMessage::AuthResponse {
+                nonce,
+                public_key,
+                signature,
+                certificate,
+            } => {
+                self.handle_auth_response(peer, nonce, public_key, signature, certificate)
+                    .await;
+            }
+            StreamMessage::Direct { data } if data.len() > self.max_publish_size => {
+                self.penalize(peer, Violation::OversizedPayload).await;
+            }
+            StreamMessage::Direct { data } => {
+                let _ = self
+                    .event_tx
+                    .send(AviEvent::DirectMessage {
+                        from: peer_wrap,
+                        data,
+                    })
+                    .await;
+            }
+            StreamMessage::HistoryRequest {
+                topics,
+                window_ms,
+                limit,
+            } => {
+                let topics: Vec<String> = topics
+                    .into_iter()
+                    .filter(|topic| {
+                        self.authorize(&peer, Operation::Subscribe, Some(topic))
+                            .is_ok()
+                    })
+                    .collect();
+                let messages =
+                    self.history
+                        .recent(&topics, Duration::from_millis(window_ms), limit as usize);
+                if !messages.is_empty() {
+                    self.send_stream_message(&peer, StreamMessage::HistoryResponse { messages });
+                }
+            }
+            StreamMessage::PairingRequest { token } => {
+                // Each token is good for one invitation
+                let invitation = match self.pairings.remove(&token) {
+                    Some((invitation, expires)) if expires > Instant::now() => {
+                        info!("Handing invitation to pairing peer {}", peer);
+                        invitation.to_bytes().ok()
+                    }
+                    _ => {
+                        debug!("Unknown or expired pairing token from {}", peer);
+                        None
+                    }
+                };
+                self.send_stream_message(&peer, StreamMessage::PairingResponse { invitation });
+            }
+            StreamMessage::PairingResponse { invitation } => {
+                let Some(fetch) = self.pairing_fetches.remove(&peer) else {
+                    return;
+                };
+                let res = match invitation {
+                    Some(blob) => MeshInvitation::from_bytes(&blob),
+                    None => Err(AviP2pError::Authentication(
+                        "Pairing token unknown, used or expired".to_string(),
+                    )),
+                };
+                let _ = fetch.respond_to.send(res);
+            }
+            // Only taken while catching up
+            StreamMessage::HistoryResponse { .. } if self.backfill.is_none() => {}
+            StreamMessage::HistoryResponse { messages } => {
+                for entry in messages {
+                    self.replay(peer, entry).await;
+                }
+            }
+            StreamMessage::Rekey { scope, epoch, key } => {
+                let Ok(key) = <[u8; 32]>::try_from(key.as_slice()) else {
+                    return;
+                };
+                if self.keyring.install(&scope, epoch, key) {
+                    let _ = self
+                        .event_tx
+                        .send(AviEvent::KeyRotated {
+                            from: peer_wrap,
+                            scope,
+                            epoch,
+                        })
+                        .await;
+                }
+            }
+            StreamMessage::SyncContext(signed) => {
+                let updated = self.merge_remote_context(peer, signed).await;
+                if self.context_behind.remove(&peer) {
+                    let _ = self
+                        .event_tx
+                        .send(AviEvent::ContextSynced {
+                            peer_id: peer_wrap,
+                            updated,
+                        })
+                        .await;
+                }
+            }
+            StreamMessage::ContextSummary { clock } => {
+                self.reconcile_context(peer, clock).await;
+            }
+            StreamMessage::ContextRequest => match self.sign_local_context() {
+                Ok(signed) => {
+                    self.send_stream_message(&peer, StreamMessage::SyncContext(signed));
+                }
+                Err(e) => debug!("Failed to sign context for {}: {}", peer, e),
+            },
+            StreamMessage::RequestStream {
+                stream_id,
+                reason,
+                version,
+            } if reason.starts_with(RELAY_PREFIX) => {
+                self.start_relay(peer, stream_id, &reason, version);
+            }
+            StreamMessage::RequestStream {
+                stream_id,
+                reason,
+                version,
+            } => {
+                let Some(version) = negotiate_stream_version(version) else {
+                    self.send_stream_message(
+                        &peer,
+                        StreamMessage::RejectStream {
+                            stream_id,
+                            reason: "incompatible stream version".to_string(),
+                            version: Some(STREAM_VERSION),
+                        },
+                    );
+                    return;
+                };
+                self.streams.insert(
+                    stream_id,
                     StreamState {
                         peer,
                         reason: reason.clone(),
+                        version,
                         status: StreamStatus::Requested,
                         direction: StreamDirection::Inbound,
                     },
@@ -548,32 +2885,197 @@ impl Runtime {
                     })
                     .await;
             }
-            StreamMessage::AcceptStream { stream_id } => {
+            StreamMessage::AcceptStream {
+                payload: Some(payload),
+                ..
+            } if payload.len() > self.max_stream_chunk_size => {
+                self.penalize(peer, Violation::OversizedPayload).await;
+            }
+            StreamMessage::StreamData { data, .. }
+                if data.len() > secure::sealed_len(self.max_stream_chunk_size) =>
+            {
+                self.penalize(peer, Violation::OversizedPayload).await;
+            }
+            StreamMessage::AcceptStream { stream_id, .. }
+            | StreamMessage::RejectStream { stream_id, .. }
+            | StreamMessage::StreamData { stream_id, .. }
+            | StreamMessage::CloseStream { stream_id }
+                if self
+                    .streams
+                    .get(&stream_id)
+                    .map_or(self.relays.contains_key(&stream_id), |s| s.peer != peer) =>
+            {
+                // Only the peer a stream (or relay leg) was opened with may
+                // accept, reject, feed or close it
+                self.penalize(peer, Violation::StreamAbuse).await;
+            }
+            StreamMessage::AcceptStream {
+                stream_id,
+                version,
+                payload,
+            } if self.relays.contains_key(&stream_id) => {
+                // The target took the relayed stream; accept toward the origin
+                self.finish_stream_open(stream_id, Ok(StreamId(stream_id)));
+                let origin = self.relays[&stream_id];
+                for id in [stream_id, origin] {
+                    if let Some(state) = self.streams.get_mut(&id) {
+                        state.status = StreamStatus::Active;
+                    }
+                }
+                if let Some(state) = self.streams.get(&origin) {
+                    let origin_peer = state.peer;
+                    self.send_stream_message(
+                        &origin_peer,
+                        StreamMessage::AcceptStream {
+                            stream_id: origin,
+                            version,
+                            payload,
+                        },
+                    );
+                }
+            }
+            StreamMessage::RejectStream {
+                stream_id,
+                reason,
+                version,
+            } if self.relays.contains_key(&stream_id) => {
+                self.finish_stream_open(
+                    stream_id,
+                    Err(AviP2pError::StreamRejected(reason.clone())),
+                );
+                self.streams.remove(&stream_id);
+                if let Some(origin) = self.relays.remove(&stream_id) {
+                    self.relays.remove(&origin);
+                    if let Some(state) = self.streams.remove(&origin) {
+                        self.send_stream_message(
+                            &state.peer,
+                            StreamMessage::RejectStream {
+                                stream_id: origin,
+                                reason,
+                                version,
+                            },
+                        );
+                    }
+                }
+            }
+            StreamMessage::StreamData { stream_id, data }
+                if self.relays.contains_key(&stream_id) =>
+            {
+                let other = self.relays[&stream_id];
+                if let Some(state) = self.streams.get(&other) {
+                    let other_peer = state.peer;
+                    self.send_stream_message(
+                        &other_peer,
+                        StreamMessage::StreamData {
+                            stream_id: other,
+                            data,
+                        },
+                    );
+                }
+            }
+            StreamMessage::CloseStream { stream_id } if self.relays.contains_key(&stream_id) => {
+                self.streams.remove(&stream_id);
+                self.close_relay(stream_id);
+            }
+            StreamMessage::AcceptStream {
+                stream_id, version, ..
+            } if !(MIN_STREAM_VERSION..=STREAM_VERSION).contains(&version) => {
+                // The peer picked a version we never offered or cannot speak
+                if self.streams.remove(&stream_id).is_some() {
+                    self.send_stream_message(&peer, StreamMessage::CloseStream { stream_id });
+                    let correlation_id = self.finish_stream_open(
+                        stream_id,
+                        Err(AviP2pError::IncompatibleStreamVersion {
+                            local: STREAM_VERSION,
+                            remote: version,
+                        }),
+                    );
+                    let _ = self
+                        .event_tx
+                        .send(AviEvent::StreamRejected {
+                            peer_id: peer_wrap,
+                            stream_id: StreamId(stream_id),
+                            reason: "incompatible stream version".to_string(),
+                            correlation_id,
+                        })
+                        .await;
+                }
+            }
+            StreamMessage::AcceptStream {
+                stream_id,
+                version,
+                payload,
+            } => {
                 if let Some(state) = self.streams.get_mut(&stream_id) {
                     state.status = StreamStatus::Active;
+                    state.version = version;
+                    if is_secure_reason(&state.reason) {
+                        // The open completes with the handshake, against the
+                        // peer meant to be reached even if a relay is between
+                        let pinned = relay_target(&state.reason).unwrap_or(state.peer);
+                        self.start_secure_stream(peer, stream_id, pinned).await;
+                        return;
+                    }
+                    let correlation_id =
+                        self.finish_stream_open(stream_id, Ok(StreamId(stream_id)));
+                    self.record_audit(
+                        &peer.to_string(),
+                        AuditAction::StreamAccepted {
+                            stream_id,
+                            peer: peer.to_string(),
+                        },
+                    );
                     let _ = self
                         .event_tx
                         .send(AviEvent::StreamAccepted {
                             peer_id: peer_wrap,
                             stream_id: StreamId(stream_id),
+                            correlation_id,
+                            payload,
                         })
                         .await;
                 }
             }
-            StreamMessage::RejectStream { stream_id, reason } => {
+            StreamMessage::RejectStream {
+                stream_id,
+                reason,
+                version,
+            } => {
                 if let Some(_state) = self.streams.remove(&stream_id) {
+                    let error = match version {
+                        Some(remote) => AviP2pError::IncompatibleStreamVersion {
+                            local: STREAM_VERSION,
+                            remote,
+                        },
+                        None => AviP2pError::StreamRejected(reason.clone()),
+                    };
+                    let correlation_id = self.finish_stream_open(stream_id, Err(error));
+                    self.record_audit(
+                        &peer.to_string(),
+                        AuditAction::StreamRejected {
+                            stream_id,
+                            peer: peer.to_string(),
+                            reason: reason.clone(),
+                        },
+                    );
                     let _ = self
                         .event_tx
                         .send(AviEvent::StreamRejected {
                             peer_id: peer_wrap,
                             stream_id: StreamId(stream_id),
                             reason,
+                            correlation_id,
                         })
                         .await;
                 }
             }
             StreamMessage::StreamData { stream_id, data } => {
-                if self.streams.contains_key(&stream_id) {
+                if self.streams.get(&stream_id).is_none_or(|s| s.peer != peer) {
+                    self.penalize(peer, Violation::StreamAbuse).await;
+                } else if self.secure_streams.contains_key(&stream_id) {
+                    self.receive_secure_chunk(peer, stream_id, data).await;
+                } else {
+                    self.record_stream_chunk(stream_id, StreamDirection::Inbound, &data);
                     let _ = self
                         .event_tx
                         .send(AviEvent::StreamData {
@@ -586,6 +3088,8 @@ impl Runtime {
             }
             StreamMessage::CloseStream { stream_id } => {
                 self.streams.remove(&stream_id);
+                self.recordings.remove(&stream_id);
+                self.secure_streams.remove(&stream_id);
                 let _ = self
                     .event_tx
                     .send(AviEvent::StreamClosed {
@@ -598,6 +3102,834 @@ impl Runtime {
         }
     }
 
+    fn is_trusted(&self, peer: &LibPeerId) -> bool {
+        self.auth.is_none()
+            || peer == self.swarm.local_peer_id()
+            || self.authenticated_peers.contains_key(peer)
+    }
+
+    /// Check `operation` against the role of `peer` (which may be ourselves).
+    /// Without an `AuthConfig` every operation is allowed.
+    fn authorize(
+        &self,
+        peer: &LibPeerId,
+        operation: Operation,
+        topic: Option<&str>,
+    ) -> Result<(), AviP2pError> {
+        let Some(config) = &self.auth else {
+            return Ok(());
+        };
+
+        let role = if peer == self.swarm.local_peer_id() {
+            config.certificate.as_ref().map(|c| c.role)
+        } else {
+            self.authenticated_peers.get(peer).copied()
+        }
+        .unwrap_or(Role::Guest);
+
+        let topic_allowed = match (role, topic) {
+            (Role::Guest, Some(topic)) => config.guest_topics.iter().any(|t| t == topic),
+            _ => true,
+        };
+
+        if config.policy.is_allowed(role, operation) && topic_allowed {
+            Ok(())
+        } else {
+            Err(AviP2pError::Unauthorized { role, operation })
+        }
+    }
+
+    /// Peers allowed to hold shared encryption keys
+    fn is_key_recipient(&self, peer: &LibPeerId) -> bool {
+        match &self.auth {
+            None => true,
+            Some(_) => matches!(
+                self.authenticated_peers.get(peer),
+                Some(Role::Admin) | Some(Role::Device)
+            ),
+        }
+    }
+
+    fn send_key(&mut self, peer: LibPeerId, scope: &str, epoch: u32, key: [u8; 32]) {
+        self.send_stream_message(
+            &peer,
+            StreamMessage::Rekey {
+                scope: scope.to_string(),
+                epoch,
+                key: key.to_vec(),
+            },
+        );
+    }
+
+    /// Hand our current keys to a newly trusted peer, if we manage keys
+    fn share_keys_with(&mut self, peer: LibPeerId) {
+        let local = *self.swarm.local_peer_id();
+        if self
+            .authorize(&local, Operation::KeyManagement, None)
+            .is_err()
+            || !self.is_key_recipient(&peer)
+        {
+            return;
+        }
+        for (scope, epoch, key) in self.keyring.current_keys() {
+            self.send_key(peer, &scope, epoch, key);
+        }
+    }
+
+    /// Open an anti-entropy round: both sides send their clock summary and
+    /// each pushes its context only if the other is missing updates
+    fn sync_context_with(&mut self, peer: LibPeerId) {
+        // Lazy contexts are fetched when read, not replicated up front
+        if self.context_replication == ContextReplication::Lazy || !self.synced_peers.insert(peer) {
+            return;
+        }
+        let clock = self.local_context.vector_clock.clone();
+        self.send_stream_message(&peer, StreamMessage::ContextSummary { clock });
+    }
+
+    async fn reconcile_context(&mut self, peer: LibPeerId, remote: VectorClock) {
+        let local = &self.local_context.vector_clock;
+        let peer_behind = local.has_unseen_by(&remote);
+        let we_behind = remote.has_unseen_by(local);
+
+        if peer_behind {
+            match self.sign_local_context() {
+                Ok(signed) => {
+                    self.send_stream_message(&peer, StreamMessage::SyncContext(signed));
+                }
+                Err(e) => debug!("Failed to sign context for {}: {}", peer, e),
+            }
+        }
+
+        if we_behind {
+            // The peer sees our summary too and pushes what we lack
+            self.context_behind.insert(peer);
+        } else {
+            let _ = self
+                .event_tx
+                .send(AviEvent::ContextSynced {
+                    peer_id: PeerId::from(peer),
+                    updated: false,
+                })
+                .await;
+        }
+    }
+
+    /// Bump our clock entry after a write to the local context and sign
+    /// what we have written so far; `before` is the data ahead of the write
+    fn commit_local_write(&mut self, before: &serde_json::Value) {
+        let my_id = self.local_context.device_id.clone();
+        self.local_context.vector_clock.increment(&my_id);
+        let mut state = self
+            .origins
+            .remove(&my_id)
+            .map_or_else(|| OriginState::new(&my_id), |(state, _)| state);
+        state.record(before, &self.local_context);
+        match SignedOrigin::sign(&state, &self.local_key) {
+            Ok(signed) => {
+                self.origins.insert(my_id, (state, signed));
+            }
+            Err(e) => debug!("Failed to sign our context writes: {}", e),
+        }
+    }
+
+    fn sign_local_context(&self) -> Result<SignedContext, AviP2pError> {
+        SignedContext::sign(&self.local_context, &self.origins, &self.local_key)
+    }
+
+    /// Sign the local context and gossip it to the mesh, or under lazy
+    /// replication just the `paths` that changed (empty = all of it)
+    fn broadcast_local_context(&mut self, paths: Vec<String>) -> Result<(), AviP2pError> {
+        self.refresh_context_views();
+        let data = match self.context_replication {
+            ContextReplication::Eager => {
+                let signed = self.sign_local_context()?;
+                self.check_context_quota(signed.payload_len());
+                serde_json::to_vec(&signed)
+            }
+            ContextReplication::Lazy => {
+                if self.local_context_warn_size.is_some() {
+                    let size = serde_json::to_vec(&self.local_context).map_or(0, |c| c.len());
+                    self.check_context_quota(size);
+                }
+                serde_json::to_vec(&ContextInvalidation {
+                    device_id: self.local_context.device_id.clone(),
+                    paths,
+                    clock: self.local_context.vector_clock.clone(),
+                })
+            }
+        }
+        .map_err(|e| AviP2pError::Serialization(e.to_string()))?;
+
+        let topic = self.wire_topic(CONTEXT_UPDATES_TOPIC);
+        if !self.topics.contains(CONTEXT_UPDATES_TOPIC) {
+            let _ = self.swarm.behaviour_mut().gossipsub.subscribe(&topic);
+            self.topics.insert(CONTEXT_UPDATES_TOPIC.to_string());
+        }
+
+        match self.swarm.behaviour_mut().gossipsub.publish(topic, data) {
+            Ok(_) => Ok(()),
+            Err(gossipsub::PublishError::InsufficientPeers) => {
+                info!("Context changed locally (broadcasting postponed: no peers yet)");
+                Ok(())
+            }
+            Err(e) => Err(AviP2pError::NetworkError(e.to_string())),
+        }
+    }
+
+    /// Warn once when the local context grows past its quota
+    fn check_context_quota(&mut self, size: usize) {
+        let Some(limit) = self.local_context_warn_size else {
+            return;
+        };
+        let over = size > limit;
+        if over && !self.context_over_quota {
+            info!(
+                "Local context is {} bytes, over the {} byte quota",
+                size, limit
+            );
+            let _ = self
+                .event_tx
+                .try_send(AviEvent::ContextQuotaWarning { size, limit });
+        }
+        self.context_over_quota = over;
+    }
+
+    /// Apply a local patch, bump our clock and gossip the result
+    fn update_local_context(
+        &mut self,
+        patch: serde_json::Value,
+        ttl: Option<Duration>,
+    ) -> Result<(), AviP2pError> {
+        let local = *self.swarm.local_peer_id();
+        self.authorize(&local, Operation::ContextWrite, None)?;
+        let keys = top_level_keys(&patch);
+        self.record_audit(
+            &local.to_string(),
+            AuditAction::ContextModified { keys: keys.clone() },
+        );
+        let before = self.local_context.data.clone();
+        match ttl {
+            Some(ttl) => self.local_context.apply_patch_with_ttl(patch, ttl),
+            None => self.local_context.apply_patch(patch),
+        }
+        self.commit_local_write(&before);
+
+        self.broadcast_local_context(keys)
+    }
+
+    /// Apply a transaction's ops with a single clock tick and broadcast,
+    /// so no other write can land between them
+    fn apply_local_context_ops(&mut self, ops: Vec<ContextOp>) -> Result<(), AviP2pError> {
+        let local = *self.swarm.local_peer_id();
+        self.authorize(&local, Operation::ContextWrite, None)?;
+        let mut keys: Vec<String> = ops
+            .iter()
+            .map(|op| op.path().split('.').next().unwrap_or_default().to_string())
+            .collect();
+        keys.sort();
+        keys.dedup();
+        let before = self.local_context.data.clone();
+        self.local_context.apply_ops(ops)?;
+        self.record_audit(
+            &local.to_string(),
+            AuditAction::ContextModified { keys: keys.clone() },
+        );
+        self.commit_local_write(&before);
+
+        self.broadcast_local_context(keys)
+    }
+
+    /// Accept a `relay:<target>[:<reason>]` stream by opening a stream
+    /// toward the target; the origin is accepted once the target accepts
+    fn start_relay(&mut self, origin_peer: LibPeerId, origin: u64, reason: &str, version: u32) {
+        let (target, inner) = parse_relay_reason(reason).unwrap_or_default();
+
+        let target = match LibPeerId::from_str(target) {
+            Ok(target) if self.stream_relay && self.swarm.is_connected(&target) => target,
+            Ok(_) | Err(_) => {
+                let reason = if self.stream_relay {
+                    "relay target unreachable"
+                } else {
+                    "relaying disabled"
+                };
+                self.send_stream_message(
+                    &origin_peer,
+                    StreamMessage::RejectStream {
+                        stream_id: origin,
+                        reason: reason.to_string(),
+                        version: None,
+                    },
+                );
+                return;
+            }
+        };
+
+        let onward = generate_stream_id().0;
+        self.streams.insert(
+            origin,
+            StreamState {
+                peer: origin_peer,
+                reason: reason.to_string(),
+                version,
+                status: StreamStatus::Requested,
+                direction: StreamDirection::Inbound,
+            },
+        );
+        self.streams.insert(
+            onward,
+            StreamState {
+                peer: target,
+                reason: inner.to_string(),
+                version,
+                status: StreamStatus::Requested,
+                direction: StreamDirection::Outbound,
+            },
+        );
+        self.relays.insert(origin, onward);
+        self.relays.insert(onward, origin);
+        self.stream_opens.insert(
+            onward,
+            PendingOpen {
+                deadline: Instant::now() + self.stream_open_timeout,
+                respond_to: None,
+                correlation_id: None,
+            },
+        );
+        self.send_stream_message(
+            &target,
+            StreamMessage::RequestStream {
+                stream_id: onward,
+                reason: inner.to_string(),
+                version,
+            },
+        );
+    }
+
+    /// One side of a relayed stream ended; close the other side
+    fn close_relay(&mut self, stream_id: u64) {
+        let Some(other) = self.relays.remove(&stream_id) else {
+            return;
+        };
+        self.relays.remove(&other);
+        self.stream_opens.remove(&other);
+
+        if let Some(state) = self.streams.remove(&other) {
+            let msg = match state.status {
+                StreamStatus::Requested => StreamMessage::RejectStream {
+                    stream_id: other,
+                    reason: "relay target closed".to_string(),
+                    version: None,
+                },
+                _ => StreamMessage::CloseStream { stream_id: other },
+            };
+            self.send_stream_message(&state.peer, msg);
+        }
+    }
+
+    /// Begin the handshake on an outbound `e2e` stream the peer accepted
+    async fn start_secure_stream(&mut self, peer: LibPeerId, stream_id: u64, pinned: LibPeerId) {
+        match SecureStream::initiate(&self.local_key, pinned) {
+            Ok((secure, first)) => {
+                self.secure_streams.insert(stream_id, secure);
+                self.send_stream_message(
+                    &peer,
+                    StreamMessage::StreamData {
+                        stream_id,
+                        data: first,
+                    },
+                );
+            }
+            Err(e) => self.fail_secure_stream(peer, stream_id, e).await,
+        }
+    }
+
+    /// Feed a chunk to an `e2e` stream: handshake messages are answered,
+    /// sealed data is delivered opened
+    async fn receive_secure_chunk(&mut self, peer: LibPeerId, stream_id: u64, chunk: Vec<u8>) {
+        let Some(secure) = self.secure_streams.get_mut(&stream_id) else {
+            return;
+        };
+        let (received, pending) = match secure.receive(&chunk) {
+            Ok(received) if received.secured.is_some() => match secure.take_pending() {
+                Ok(pending) => (received, pending),
+                Err(e) => return self.fail_secure_stream(peer, stream_id, e).await,
+            },
+            Ok(received) => (received, Vec::new()),
+            Err(e) => return self.fail_secure_stream(peer, stream_id, e).await,
+        };
+
+        if let Some(reply) = received.reply {
+            self.send_stream_message(
+                &peer,
+                StreamMessage::StreamData {
+                    stream_id,
+                    data: reply,
+                },
+            );
+        }
+
+        if let Some(remote) = received.secured {
+            if self.stream_opens.contains_key(&stream_id) {
+                let correlation_id = self.finish_stream_open(stream_id, Ok(StreamId(stream_id)));
+                self.record_audit(
+                    &peer.to_string(),
+                    AuditAction::StreamAccepted {
+                        stream_id,
+                        peer: peer.to_string(),
+                    },
+                );
+                let _ = self
+                    .event_tx
+                    .send(AviEvent::StreamAccepted {
+                        peer_id: PeerId::from(peer),
+                        stream_id: StreamId(stream_id),
+                        correlation_id,
+                        payload: None,
+                    })
+                    .await;
+            }
+            let _ = self
+                .event_tx
+                .send(AviEvent::StreamSecured {
+                    peer_id: PeerId::from(remote),
+                    stream_id: StreamId(stream_id),
+                })
+                .await;
+            for data in pending {
+                self.send_stream_message(&peer, StreamMessage::StreamData { stream_id, data });
+            }
+        }
+
+        if let Some(data) = received.data {
+            self.record_stream_chunk(stream_id, StreamDirection::Inbound, &data);
+            let _ = self
+                .event_tx
+                .send(AviEvent::StreamData {
+                    from: PeerId::from(peer),
+                    stream_id: StreamId(stream_id),
+                    data,
+                })
+                .await;
+        }
+    }
+
+    /// Close an `e2e` stream whose handshake or decryption failed
+    async fn fail_secure_stream(&mut self, peer: LibPeerId, stream_id: u64, error: AviP2pError) {
+        self.secure_streams.remove(&stream_id);
+        self.recordings.remove(&stream_id);
+        if self.streams.remove(&stream_id).is_none() {
+            return;
+        }
+        debug!("Closing encrypted stream {}: {}", stream_id, error);
+        self.send_stream_message(&peer, StreamMessage::CloseStream { stream_id });
+        let reason = error.to_string();
+        self.finish_stream_open(stream_id, Err(error));
+        let _ = self
+            .event_tx
+            .send(AviEvent::StreamClosed {
+                peer_id: PeerId::from(peer),
+                stream_id: StreamId(stream_id),
+                reason: StreamCloseReason::Error(reason),
+            })
+            .await;
+    }
+
+    /// Settle a pending open; returns the correlation id of the call that
+    /// started it
+    fn finish_stream_open(
+        &mut self,
+        stream_id: u64,
+        result: Result<StreamId, AviP2pError>,
+    ) -> Option<CorrelationId> {
+        let open = self.stream_opens.remove(&stream_id)?;
+        if let Some(respond_to) = open.respond_to {
+            let _ = respond_to.send(result);
+        }
+        open.correlation_id
+    }
+
+    /// Write a chunk to the stream's recording, if one is running
+    fn record_stream_chunk(&mut self, stream_id: u64, direction: StreamDirection, data: &[u8]) {
+        if let Some(recording) = self.recordings.get(&stream_id) {
+            if let Err(e) = recording.record(direction, data) {
+                debug!("Stopped recording stream {}: {}", stream_id, e);
+                self.recordings.remove(&stream_id);
+            }
+        }
+    }
+
+    /// Drop outbound streams the peer never answered
+    async fn expire_stream_opens(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<u64> = self
+            .stream_opens
+            .iter()
+            .filter(|(_, open)| open.deadline <= now)
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in expired {
+            let Some(state) = self.streams.remove(&id) else {
+                self.stream_opens.remove(&id);
+                continue;
+            };
+            let correlation_id =
+                self.finish_stream_open(id, Err(AviP2pError::StreamOpenTimeout(StreamId(id))));
+            // Let the peer drop its half-open state if it is still there
+            self.send_stream_message(&state.peer, StreamMessage::CloseStream { stream_id: id });
+            if self.relays.contains_key(&id) {
+                self.close_relay(id);
+                continue;
+            }
+            let _ = self
+                .event_tx
+                .send(AviEvent::StreamOpenTimeout {
+                    peer_id: PeerId::from(state.peer),
+                    stream_id: StreamId(id),
+                    correlation_id,
+                })
+                .await;
+        }
+    }
+
+    /// Abort dials that outlived `dial_timeout`, unless the peer connected
+    /// some other way meanwhile
+    fn expire_dials(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<(ConnectionId, LibPeerId)> = self
+            .pending_dials
+            .iter()
+            .filter(|(_, (_, deadline))| *deadline <= now)
+            .map(|(id, (peer, _))| (*id, *peer))
+            .collect();
+
+        for (id, peer) in expired {
+            self.pending_dials.remove(&id);
+            if !self.swarm.is_connected(&peer) {
+                debug!("Dial to {} timed out", peer);
+                // Aborts every pending dial to the peer
+                let _ = self.swarm.disconnect_peer_id(peer);
+                self.pending_dials.retain(|_, (p, _)| *p != peer);
+            }
+        }
+    }
+
+    /// Run a counter or collection operation on the local context, then
+    /// bump our clock and gossip the result
+    fn write_local_crdt<T>(
+        &mut self,
+        path: &str,
+        write: impl FnOnce(&mut AviContext) -> Result<T, AviP2pError>,
+    ) -> Result<T, AviP2pError> {
+        let local = *self.swarm.local_peer_id();
+        self.authorize(&local, Operation::ContextWrite, None)?;
+        self.record_audit(
+            &local.to_string(),
+            AuditAction::ContextModified {
+                keys: vec![path.split('.').next().unwrap_or_default().to_string()],
+            },
+        );
+        let before = self.local_context.data.clone();
+        let result = write(&mut self.local_context)?;
+        self.commit_local_write(&before);
+
+        self.broadcast_local_context(vec![path.to_string()])?;
+        Ok(result)
+    }
+
+    /// Drop ephemeral context keys past their TTL. Every replica expires
+    /// them on its own clock, so nothing is broadcast.
+    async fn expire_context_keys(&mut self) {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let before = self.local_context.data.clone();
+        if self.local_context.remove_expired(now).is_empty() {
+            return;
+        }
+        self.refresh_context_views();
+        let _ = self
+            .event_tx
+            .send(AviEvent::ContextUpdated {
+                peer_id: PeerId::from(*self.swarm.local_peer_id()),
+                context: self.local_context.data.clone(),
+                changes: diff_context(&before, &self.local_context.data),
+            })
+            .await;
+    }
+
+    /// Report connected peers whose context has gone quiet, once per silence
+    async fn report_stale_contexts(&mut self) {
+        let mut stale = Vec::new();
+        for peer in self.swarm.connected_peers() {
+            let Some(cached) = self.peer_contexts.get_mut(&peer.to_string()) else {
+                continue;
+            };
+            let since = cached.updated.elapsed();
+            if !cached.stale_reported && since > self.context_stale_after {
+                cached.stale_reported = true;
+                stale.push((PeerId::from(*peer), since));
+            }
+        }
+        for (peer_id, since) in stale {
+            let _ = self
+                .event_tx
+                .send(AviEvent::ContextStale { peer_id, since })
+                .await;
+        }
+    }
+
+    /// Drop the cached context of a peer that announced a change we have
+    /// not seen, so the next read fetches it
+    async fn invalidate_peer_context(&mut self, author: LibPeerId, notice: ContextInvalidation) {
+        if notice.device_id != author.to_string() {
+            debug!(
+                "Dropping context invalidation for {} from {}",
+                notice.device_id, author
+            );
+            return;
+        }
+        if !notice.clock.has_unseen_by(&self.local_context.vector_clock) {
+            return;
+        }
+        if let Some(cached) = self.peer_contexts.get_mut(&notice.device_id) {
+            cached.invalidated = true;
+            cached.updated = Instant::now();
+            cached.stale_reported = false;
+        }
+        let _ = self
+            .event_tx
+            .send(AviEvent::ContextInvalidated {
+                peer_id: PeerId::from(author),
+                paths: notice.paths,
+            })
+            .await;
+    }
+
+    /// Verify the origin signature of a received context and merge it;
+    /// returns whether the local context changed
+    async fn merge_remote_context(&mut self, from: LibPeerId, signed: SignedContext) -> bool {
+        let size = signed.payload_len();
+        if size > self.max_peer_context_size {
+            self.reject_oversized_context(from, size).await;
+            return false;
+        }
+        let (incoming_ctx, carried) = match signed.verify() {
+            Ok(verified) => verified,
+            Err(e) => {
+                self.penalize(from, Violation::BadSignature).await;
+                self.reject_context(from, e).await;
+                return false;
+            }
+        };
+        // Not penalized: a peer that lags behind a rewrite can pass on a
+        // value its author has since replaced
+        if let Err(e) =
+            check_provenance(&incoming_ctx, &carried, &self.local_context, &self.origins)
+        {
+            self.reject_context(from, e).await;
+            return false;
+        }
+        for (actor, (state, signed)) in carried {
+            if self
+                .origins
+                .get(&actor)
+                .is_none_or(|(held, _)| held.counter < state.counter)
+            {
+                self.origins.insert(actor, (state, signed));
+            }
+        }
+
+        let peer_id_str = incoming_ctx.device_id.clone();
+        for waiter in self
+            .context_fetches
+            .remove(&peer_id_str)
+            .unwrap_or_default()
+        {
+            let _ = waiter.send(Ok(incoming_ctx.data.clone()));
+        }
+        self.peer_contexts.insert(
+            peer_id_str.clone(),
+            CachedContext {
+                data: incoming_ctx.data.clone(),
+                updated: Instant::now(),
+                stale_reported: false,
+                invalidated: false,
+            },
+        );
+
+        let keys = top_level_keys(&incoming_ctx.data);
+        let before = self.local_context.data.clone();
+        let merged = self.local_context.merge(incoming_ctx);
+        self.refresh_context_views();
+        if !merged {
+            return false;
+        }
+        self.record_audit(&peer_id_str, AuditAction::ContextModified { keys });
+        let _ = self
+            .event_tx
+            .send(AviEvent::ContextUpdated {
+                peer_id: PeerId::new(&peer_id_str),
+                context: self.local_context.data.clone(),
+                changes: diff_context(&before, &self.local_context.data),
+            })
+            .await;
+        true
+    }
+
+    async fn reject_context(&mut self, from: LibPeerId, error: AviP2pError) {
+        let _ = self
+            .event_tx
+            .send(AviEvent::ContextRejected {
+                from: PeerId::from(from),
+                reason: error.to_string(),
+            })
+            .await;
+    }
+
+    /// The local context and every cached peer context, by peer
+    fn known_contexts(&self) -> impl Iterator<Item = (PeerId, &serde_json::Value)> {
+        let local = &self.local_context;
+        std::iter::once((PeerId::new(&local.device_id), &local.data)).chain(
+            self.peer_contexts
+                .iter()
+                .filter(move |(id, _)| **id != local.device_id)
+                .map(|(id, cached)| (PeerId::new(id), &cached.data)),
+        )
+    }
+
+    /// Bring every open `ContextView` up to date
+    fn refresh_context_views(&mut self) {
+        self.context_views.retain(|view| !view.is_closed());
+        for view in &self.context_views {
+            view.update(self.known_contexts());
+        }
+    }
+
+    /// Refuse a context over `max_peer_context_size` and evict what is
+    /// cached of the sender's, so one bloated node cannot fill every other
+    async fn reject_oversized_context(&mut self, from: LibPeerId, size: usize) {
+        self.penalize(from, Violation::OversizedPayload).await;
+        let peer = from.to_string();
+        if self.peer_contexts.remove(&peer).is_some() {
+            self.refresh_context_views();
+        }
+        for waiter in self.context_fetches.remove(&peer).unwrap_or_default() {
+            let _ = waiter.send(Err(AviP2pError::PayloadTooLarge {
+                limit: self.max_peer_context_size,
+                actual: size,
+            }));
+        }
+        let _ = self
+            .event_tx
+            .send(AviEvent::ContextRejected {
+                from: PeerId::from(from),
+                reason: format!(
+                    "context of {} bytes is over the {} byte limit",
+                    size, self.max_peer_context_size
+                ),
+            })
+            .await;
+    }
+
+    fn record_audit(&mut self, actor: &str, action: AuditAction) {
+        if let Some(log) = &mut self.audit {
+            log.record(actor, action);
+        }
+    }
+
+    /// Log messages on the configured actuator command topics
+    fn audit_command(&mut self, actor: &LibPeerId, topic: &str, size: usize) {
+        if let Some(log) = &mut self.audit {
+            if log.is_command_topic(topic) {
+                log.record(
+                    &actor.to_string(),
+                    AuditAction::Command {
+                        topic: topic.to_string(),
+                        size,
+                    },
+                );
+            }
+        }
+    }
+
+    fn challenge_peer(&mut self, peer: LibPeerId) {
+        if self.auth.is_none() || self.authenticated_peers.contains_key(&peer) {
+            return;
+        }
+        let nonce = auth::generate_nonce();
+        self.pending_challenges.insert(peer, nonce.clone());
+        self.send_stream_message(&peer, StreamMessage::AuthChallenge { nonce });
+    }
+
+    async fn handle_auth_response(
+        &mut self,
+        peer: LibPeerId,
+        nonce: Vec<u8>,
+        public_key: Vec<u8>,
+        signature: Vec<u8>,
+        certificate: Option<auth::MembershipCertificate>,
+    ) {
+        let Some(config) = &self.auth else {
+            return;
+        };
+        if self.pending_challenges.get(&peer) != Some(&nonce) {
+            return;
+        }
+        self.pending_challenges.remove(&peer);
+
+        let event = match auth::verify_response(
+            config,
+            &peer,
+            &nonce,
+            &public_key,
+            &signature,
+            certificate.as_ref(),
+        ) {
+            Ok(role) => {
+                self.authenticated_peers.insert(peer, role);
+                self.sync_context_with(peer);
+                self.share_keys_with(peer);
+                AviEvent::PeerAuthenticated {
+                    peer_id: PeerId::from(peer),
+                    role,
+                }
+            }
+            Err(e) => AviEvent::PeerAuthenticationFailed {
+                peer_id: PeerId::from(peer),
+                reason: e.to_string(),
+            },
+        };
+        let _ = self.event_tx.send(event).await;
+    }
+
+    fn set_membership_certificate(
+        &mut self,
+        certificate: auth::MembershipCertificate,
+    ) -> Result<(), AviP2pError> {
+        let config = self.auth.as_mut().ok_or_else(|| {
+            AviP2pError::Authentication("Authentication is not enabled".to_string())
+        })?;
+
+        if certificate.peer_id != self.swarm.local_peer_id().to_base58() {
+            return Err(AviP2pError::Authentication(
+                "Certificate issued to a different peer".to_string(),
+            ));
+        }
+        certificate.verify(&config.ca_public_key)?;
+        config.certificate = Some(certificate);
+
+        // Let connected peers re-run the handshake against the new certificate
+        let peers: Vec<LibPeerId> = self.peers.keys().copied().collect();
+        for peer in peers {
+            self.pending_challenges.remove(&peer);
+            self.challenge_peer(peer);
+        }
+        Ok(())
+    }
+
     async fn emit_peer_discovered(&mut self, peer_id: LibPeerId) {
         if self.discovered_peers.contains(&peer_id) {
             return;
@@ -612,3 +3944,38 @@ impl Runtime {
             .await;
     }
 }
+
+fn top_level_keys(value: &serde_json::Value) -> Vec<String> {
+    value
+        .as_object()
+        .map(|obj| obj.keys().cloned().collect())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relay_reason_names_target_and_onward_reason() {
+        let target = LibPeerId::random();
+        let parse = |reason: String| parse_relay_reason(&reason).map(|(t, r)| (t.to_string(), r));
+
+        assert_eq!(
+            parse(format!("relay:{}:audio;e2e", target)),
+            Some((target.to_string(), "audio;e2e".to_string()))
+        );
+        assert_eq!(
+            parse(format!("relay:{}", target)),
+            Some((target.to_string(), "relayed".to_string()))
+        );
+        assert_eq!(
+            parse(format!("relay:{};bridged_device=7", target)),
+            Some((target.to_string(), "relayed;bridged_device=7".to_string()))
+        );
+        assert_eq!(parse("audio".to_string()), None);
+
+        assert_eq!(relay_target(&format!("relay:{};e2e", target)), Some(target));
+        assert_eq!(relay_target("relay:not-a-peer:audio"), None);
+    }
+}
@@ -0,0 +1,345 @@
+//! In-process multi-node simulation on top of the memory transport.
+//!
+//! `SimNetwork` starts N nodes that all know each other, records every event
+//! they emit, and lets a test script the network: partition groups of nodes,
+//! add latency to links, crash and restart nodes.
+
+use crate::config::AviP2pConfig;
+use crate::error::AviP2pError;
+use crate::events::AviEvent;
+use crate::node::{AviP2p, AviP2pHandle};
+use futures::io::{AsyncRead, AsyncWrite};
+use libp2p::core::ConnectedPoint;
+use libp2p::multiaddr::Protocol;
+use libp2p::Multiaddr;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+/// Link conditions between simulated nodes, keyed by listen-port pairs
+#[derive(Default)]
+pub(crate) struct LinkConditions {
+    partitioned: HashSet<(u64, u64)>,
+    latency: HashMap<(u64, u64), Duration>,
+}
+
+fn link_key(a: u64, b: u64) -> (u64, u64) {
+    (a.min(b), a.max(b))
+}
+
+pub(crate) type SharedConditions = Arc<RwLock<LinkConditions>>;
+
+/// Memory-transport connection that honours the scripted link conditions.
+/// Conditions are enforced on the dialing side, which knows both ports;
+/// a cut link fails the dialer's reads and writes, closing the connection.
+pub(crate) struct SimLink<T> {
+    inner: T,
+    link: Option<(u64, u64)>,
+    conditions: Option<SharedConditions>,
+    delay: Option<Pin<Box<tokio::time::Sleep>>>,
+    delayed: bool,
+}
+
+impl<T> SimLink<T> {
+    /// Wrap a freshly established connection; fails if the link is partitioned
+    pub(crate) fn establish(
+        inner: T,
+        local_port: u64,
+        endpoint: &ConnectedPoint,
+        conditions: Option<SharedConditions>,
+    ) -> io::Result<Self> {
+        let link = match endpoint {
+            ConnectedPoint::Dialer { address, .. } => {
+                memory_port(address).map(|remote| link_key(local_port, remote))
+            }
+            ConnectedPoint::Listener { .. } => None,
+        };
+
+        let link = Self {
+            inner,
+            link,
+            conditions,
+            delay: None,
+            delayed: false,
+        };
+        if link.is_cut() {
+            return Err(io::ErrorKind::ConnectionRefused.into());
+        }
+        Ok(link)
+    }
+
+    fn is_cut(&self) -> bool {
+        match (&self.link, &self.conditions) {
+            (Some(link), Some(conditions)) => conditions
+                .read()
+                .map(|c| c.partitioned.contains(link))
+                .unwrap_or(false),
+            _ => false,
+        }
+    }
+
+    fn latency(&self) -> Option<Duration> {
+        match (&self.link, &self.conditions) {
+            (Some(link), Some(conditions)) => conditions
+                .read()
+                .ok()
+                .and_then(|c| c.latency.get(link).copied()),
+            _ => None,
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for SimLink<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.is_cut() {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for SimLink<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.is_cut() {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        }
+
+        if !self.delayed {
+            if let Some(latency) = self.latency() {
+                let delay = self
+                    .delay
+                    .get_or_insert_with(|| Box::pin(tokio::time::sleep(latency)));
+                if delay.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                self.delay = None;
+                self.delayed = true;
+            }
+        }
+
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if res.is_ready() {
+            self.delayed = false;
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+fn memory_port(addr: &Multiaddr) -> Option<u64> {
+    addr.iter().find_map(|p| match p {
+        Protocol::Memory(port) => Some(port),
+        _ => None,
+    })
+}
+
+struct SimNode {
+    config: AviP2pConfig,
+    port: u64,
+    node: Option<AviP2p>,
+    events: Arc<Mutex<Vec<AviEvent>>>,
+}
+
+/// A set of in-process nodes with scriptable network conditions
+pub struct SimNetwork {
+    nodes: Vec<SimNode>,
+    conditions: SharedConditions,
+}
+
+impl SimNetwork {
+    /// Start `n` nodes named `sim-0` .. `sim-{n-1}`, each dialing all the others
+    pub async fn start(n: usize) -> Result<Self, AviP2pError> {
+        let configs = (0..n)
+            .map(|i| AviP2pConfig::new(&format!("sim-{}", i)))
+            .collect();
+        Self::start_with(configs).await
+    }
+
+    /// Start one node per config. Listen ports and bootstrap peers are
+    /// assigned by the simulation; everything else is kept.
+    pub async fn start_with(configs: Vec<AviP2pConfig>) -> Result<Self, AviP2pError> {
+        // Memory ports are process-global, so keep concurrent simulations apart
+        let base_port = rand::random::<u32>() as u64 + 1;
+
+        let mut sim = Self {
+            nodes: configs
+                .into_iter()
+                .enumerate()
+                .map(|(i, config)| SimNode {
+                    config,
+                    port: base_port + i as u64,
+                    node: None,
+                    events: Arc::new(Mutex::new(Vec::new())),
+                })
+                .collect(),
+            conditions: Arc::new(RwLock::new(LinkConditions::default())),
+        };
+
+        for i in 0..sim.nodes.len() {
+            sim.launch(i, i).await?;
+        }
+        Ok(sim)
+    }
+
+    /// Start node `i`, dialing the first `dial_count` live nodes
+    async fn launch(&mut self, i: usize, dial_count: usize) -> Result<(), AviP2pError> {
+        let bootstrap: Vec<String> = self
+            .nodes
+            .iter()
+            .enumerate()
+            .filter(|(j, n)| *j != i && *j < dial_count && n.node.is_some())
+            .map(|(_, n)| format!("/memory/{}", n.port))
+            .collect();
+
+        let sim_node = &mut self.nodes[i];
+        let mut config = sim_node.config.clone();
+        config.bootstrap_peers = bootstrap;
+
+        let (node, mut events_rx) =
+            AviP2p::start_memory(config, sim_node.port, Some(self.conditions.clone()))?;
+
+        let events = sim_node.events.clone();
+        tokio::spawn(async move {
+            while let Some(event) = events_rx.recv().await {
+                if let Ok(mut events) = events.lock() {
+                    events.push(event);
+                }
+            }
+        });
+
+        sim_node.node = Some(node);
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Handle of node `i`, or `None` while it is crashed
+    pub fn handle(&self, i: usize) -> Option<AviP2pHandle> {
+        self.nodes.get(i)?.node.as_ref().map(|n| n.handle())
+    }
+
+    /// Cut every link between the two groups. Open connections fail on
+    /// their next read or write and redials are refused until `heal`.
+    pub fn partition(&self, a: &[usize], b: &[usize]) {
+        if let Ok(mut conditions) = self.conditions.write() {
+            for &i in a {
+                for &j in b {
+                    conditions
+                        .partitioned
+                        .insert(link_key(self.nodes[i].port, self.nodes[j].port));
+                }
+            }
+        }
+    }
+
+    /// Remove all partitions; nodes reconnect on their next heartbeat
+    pub fn heal(&self) {
+        if let Ok(mut conditions) = self.conditions.write() {
+            conditions.partitioned.clear();
+        }
+    }
+
+    /// Delay every write on the link between `a` and `b` (zero removes it).
+    /// Applies to connections dialed after the call as well as open ones.
+    pub fn set_latency(&self, a: usize, b: usize, latency: Duration) {
+        if let Ok(mut conditions) = self.conditions.write() {
+            let key = link_key(self.nodes[a].port, self.nodes[b].port);
+            if latency.is_zero() {
+                conditions.latency.remove(&key);
+            } else {
+                conditions.latency.insert(key, latency);
+            }
+        }
+    }
+
+    /// Stop node `i` abruptly
+    pub async fn crash(&mut self, i: usize) -> Result<(), AviP2pError> {
+        if let Some(node) = self.nodes[i].node.take() {
+            node.shutdown().await?;
+        }
+        Ok(())
+    }
+
+    /// Bring a crashed node back on the same address (with a fresh identity)
+    pub async fn restart(&mut self, i: usize) -> Result<(), AviP2pError> {
+        if self.nodes[i].node.is_some() {
+            return Ok(());
+        }
+        let count = self.nodes.len();
+        self.launch(i, count).await
+    }
+
+    /// Snapshot of everything node `i` has emitted so far
+    pub fn events(&self, i: usize) -> Vec<AviEvent> {
+        self.nodes[i]
+            .events
+            .lock()
+            .map(|e| e.clone())
+            .unwrap_or_default()
+    }
+
+    /// Wait until node `i` has emitted an event matching `predicate`
+    pub async fn wait_for<F>(&self, i: usize, timeout: Duration, predicate: F) -> Option<AviEvent>
+    where
+        F: Fn(&AviEvent) -> bool,
+    {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            if let Some(event) = self.events(i).into_iter().find(|e| predicate(e)) {
+                return Some(event);
+            }
+            if tokio::time::Instant::now() >= deadline {
+                return None;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    /// Wait until every live node is connected to all other live nodes
+    pub async fn wait_for_full_mesh(&self, timeout: Duration) -> bool {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let live: Vec<AviP2pHandle> = (0..self.len()).filter_map(|i| self.handle(i)).collect();
+            let mut meshed = true;
+            for handle in &live {
+                let peers = handle.connected_peers().await.map(|p| p.len()).unwrap_or(0);
+                if peers + 1 < live.len() {
+                    meshed = false;
+                    break;
+                }
+            }
+            if meshed {
+                return true;
+            }
+            if tokio::time::Instant::now() >= deadline {
+                return false;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+}
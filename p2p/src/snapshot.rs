@@ -0,0 +1,60 @@
+//! Portable snapshots of a node's state.
+//!
+//! `AviP2pHandle::export_state` captures what a node has built up at
+//! runtime, so a replacement (e.g. a gateway rebuilt from a failed one's SD
+//! card) can take over with the same identity and state:
+//!
+//! ```ignore
+//! let snapshot = NodeSnapshot::from_bytes(&blob)?;
+//! let mut config = AviP2pConfig::default();
+//! config.identity = snapshot.identity();
+//! let (node, _events) = AviP2p::start(config).await?;
+//! node.handle().import_state(&blob).await?;
+//! ```
+
+use crate::config::IdentitySecret;
+use crate::error::AviP2pError;
+use crate::protocols::context::AviContext;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Snapshot layout version; blobs from a newer layout are refused
+pub const SNAPSHOT_FORMAT: u32 = 1;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeSnapshot {
+    pub format: u32,
+    /// Ed25519 secret of the node identity (None if it is not ed25519)
+    pub(crate) identity: Option<IdentitySecret>,
+    /// The node's own context, with its clock and replicated counters
+    pub context: AviContext,
+    /// Peer id -> last address it was reached at
+    pub known_peers: HashMap<String, String>,
+    /// Topics the application subscribed to
+    pub subscriptions: Vec<String>,
+    /// Topics sent under their compact id (see `AviP2pConfig::compact_topics`)
+    pub compact_topics: Vec<String>,
+}
+
+impl NodeSnapshot {
+    /// The identity to start the replacement node with
+    pub fn identity(&self) -> Option<IdentitySecret> {
+        self.identity.clone()
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, AviP2pError> {
+        serde_json::to_vec(self).map_err(|e| AviP2pError::Serialization(e.to_string()))
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, AviP2pError> {
+        let snapshot: Self =
+            serde_json::from_slice(data).map_err(|e| AviP2pError::Serialization(e.to_string()))?;
+        if snapshot.format > SNAPSHOT_FORMAT {
+            return Err(AviP2pError::Serialization(format!(
+                "Snapshot format {} is newer than {}",
+                snapshot.format, SNAPSHOT_FORMAT
+            )));
+        }
+        Ok(snapshot)
+    }
+}
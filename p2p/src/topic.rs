@@ -0,0 +1,117 @@
+//! Typed topic names.
+//!
+//! The bridge and applications build topics through `Topic` instead of
+//! formatting strings on each side, so both agree on the layout:
+//! `Topic::device(7).sensor("temp")` is `device/7/sensor/temp`.
+
+use std::fmt;
+use std::ops::Deref;
+
+/// A mesh topic name. Derefs to `&str`, so it can be passed straight to
+/// `subscribe`, `publish` and friends.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Topic(String);
+
+impl Topic {
+    /// A topic outside the built-in layouts
+    pub fn new(name: &str) -> Self {
+        Self(name.to_string())
+    }
+
+    /// Topics belonging to one (usually bridged) device
+    pub fn device(device_id: u64) -> DeviceTopics {
+        DeviceTopics { device_id }
+    }
+
+    /// This topic with `segment` appended (`lights` -> `lights/kitchen`)
+    pub fn child(&self, segment: &str) -> Self {
+        Self(format!("{}/{}", self.0, segment))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Builder for the topics of one device, from `Topic::device`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceTopics {
+    device_id: u64,
+}
+
+impl DeviceTopics {
+    fn topic(&self, leaf: &str) -> Topic {
+        Topic(format!("device/{}/{}", self.device_id, leaf))
+    }
+
+    pub fn button(&self) -> Topic {
+        self.topic("button")
+    }
+
+    pub fn sensor(&self, sensor: &str) -> Topic {
+        self.topic(&format!("sensor/{}", sensor))
+    }
+
+    pub fn input(&self, input: u32) -> Topic {
+        self.topic(&format!("input/{}", input))
+    }
+
+    /// Online/offline announcements
+    pub fn status(&self) -> Topic {
+        self.topic("status")
+    }
+
+    /// Commands for the device
+    pub fn command(&self) -> Topic {
+        self.topic("command")
+    }
+
+    /// Replies to commands, published by the device
+    pub fn command_reply(&self) -> Topic {
+        self.topic("command/reply")
+    }
+}
+
+impl Deref for Topic {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for Topic {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Topic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<Topic> for String {
+    fn from(topic: Topic) -> Self {
+        topic.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_device_topics_follow_the_shared_layout() {
+        let device = Topic::device(7);
+        assert_eq!(device.sensor("temp").as_str(), "device/7/sensor/temp");
+        assert_eq!(device.input(2).as_str(), "device/7/input/2");
+        assert_eq!(device.command().as_str(), "device/7/command");
+        assert_eq!(device.command_reply().as_str(), "device/7/command/reply");
+        assert_eq!(
+            Topic::new("lights").child("kitchen").to_string(),
+            "lights/kitchen"
+        );
+    }
+}
@@ -0,0 +1,186 @@
+//! Read-only projections of peer contexts.
+//!
+//! A `ContextView` follows a fixed set of context paths across every peer
+//! whose context the node holds. The runtime swaps in a new snapshot
+//! whenever one of those values changes, so readers such as dashboards
+//! take the current one without a round trip per peer:
+//!
+//! ```ignore
+//! let mut view = handle.context_view(&["room", "audio.volume"]).await?;
+//! loop {
+//!     render(&view.snapshot());
+//!     view.changed().await?;
+//! }
+//! ```
+
+use crate::error::AviP2pError;
+use crate::events::PeerId;
+use crate::protocols::context::get_nested_value;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// The viewed paths' values, per peer (the local node included). Peers
+/// holding none of the paths are left out.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ContextProjection {
+    peers: BTreeMap<PeerId, BTreeMap<String, Value>>,
+}
+
+impl ContextProjection {
+    /// Value of `path` in `peer`'s context
+    pub fn get(&self, peer: &PeerId, path: &str) -> Option<&Value> {
+        self.peers.get(peer)?.get(path)
+    }
+
+    /// The viewed values of one peer, by path
+    pub fn peer(&self, peer: &PeerId) -> Option<&BTreeMap<String, Value>> {
+        self.peers.get(peer)
+    }
+
+    pub fn peers(&self) -> impl Iterator<Item = (&PeerId, &BTreeMap<String, Value>)> {
+        self.peers.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+}
+
+/// A continuously maintained `ContextProjection`, from
+/// `AviP2pHandle::context_view`. Clones share the same snapshots.
+#[derive(Debug, Clone)]
+pub struct ContextView {
+    paths: Arc<[String]>,
+    snapshots: watch::Receiver<Arc<ContextProjection>>,
+}
+
+impl ContextView {
+    /// Paths this view follows
+    pub fn paths(&self) -> &[String] {
+        &self.paths
+    }
+
+    /// The current projection; cheap to take and to hold on to
+    pub fn snapshot(&self) -> Arc<ContextProjection> {
+        self.snapshots.borrow().clone()
+    }
+
+    /// Wait for the projection to change since this view was created or
+    /// last returned from `changed`. Fails once the node has shut down.
+    pub async fn changed(&mut self) -> Result<(), AviP2pError> {
+        self.snapshots
+            .changed()
+            .await
+            .map_err(|_| AviP2pError::ChannelClosed)
+    }
+}
+
+/// Runtime side of a `ContextView`
+pub(crate) struct ViewPublisher {
+    paths: Arc<[String]>,
+    snapshots: watch::Sender<Arc<ContextProjection>>,
+}
+
+impl ViewPublisher {
+    /// A publisher for `paths`, starting from the given contexts, and the
+    /// view it feeds
+    pub fn new<'a>(
+        paths: Vec<String>,
+        contexts: impl Iterator<Item = (PeerId, &'a Value)>,
+    ) -> (Self, ContextView) {
+        let paths: Arc<[String]> = paths.into();
+        let (snapshots, receiver) = watch::channel(Arc::new(project(&paths, contexts)));
+        let view = ContextView {
+            paths: paths.clone(),
+            snapshots: receiver,
+        };
+        (Self { paths, snapshots }, view)
+    }
+
+    /// Publish a new projection if it differs from the current one
+    pub fn update<'a>(&self, contexts: impl Iterator<Item = (PeerId, &'a Value)>) {
+        let projection = project(&self.paths, contexts);
+        self.snapshots.send_if_modified(|current| {
+            if **current == projection {
+                return false;
+            }
+            *current = Arc::new(projection);
+            true
+        });
+    }
+
+    /// Whether every view fed by this publisher has been dropped
+    pub fn is_closed(&self) -> bool {
+        self.snapshots.is_closed()
+    }
+}
+
+fn project<'a>(
+    paths: &[String],
+    contexts: impl Iterator<Item = (PeerId, &'a Value)>,
+) -> ContextProjection {
+    let mut peers = BTreeMap::new();
+    for (peer, data) in contexts {
+        let values: BTreeMap<String, Value> = paths
+            .iter()
+            .filter_map(|path| Some((path.clone(), get_nested_value(data, path)?.clone())))
+            .collect();
+        if !values.is_empty() {
+            peers.insert(peer, values);
+        }
+    }
+    ContextProjection { peers }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn entries(contexts: &[(PeerId, Value)]) -> Vec<(PeerId, &Value)> {
+        contexts
+            .iter()
+            .map(|(peer, data)| (peer.clone(), data))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_view_follows_only_its_paths() {
+        let kitchen = PeerId::new("kitchen");
+        let hall = PeerId::new("hall");
+        let mut contexts = vec![
+            (
+                kitchen.clone(),
+                json!({ "audio": { "volume": 3 }, "mode": "day" }),
+            ),
+            (hall.clone(), json!({ "mode": "night" })),
+        ];
+        let (publisher, mut view) = ViewPublisher::new(
+            vec!["audio.volume".to_string()],
+            entries(&contexts).into_iter(),
+        );
+        let first = view.snapshot();
+        assert_eq!(first.get(&kitchen, "audio.volume"), Some(&json!(3)));
+        assert!(first.peer(&hall).is_none());
+
+        // A change elsewhere in the context leaves the snapshot alone
+        contexts[0].1["mode"] = json!("night");
+        publisher.update(entries(&contexts).into_iter());
+        assert!(!view.snapshots.has_changed().unwrap());
+
+        contexts[0].1["audio"]["volume"] = json!(5);
+        publisher.update(entries(&contexts).into_iter());
+        view.changed().await.unwrap();
+        assert_eq!(
+            view.snapshot().get(&kitchen, "audio.volume"),
+            Some(&json!(5))
+        );
+        // Snapshots already taken keep their values
+        assert_eq!(first.get(&kitchen, "audio.volume"), Some(&json!(3)));
+
+        drop(view);
+        assert!(publisher.is_closed());
+    }
+}
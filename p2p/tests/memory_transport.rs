@@ -0,0 +1,2614 @@
+#![cfg(feature = "memory-transport")]
+
+use async_trait::async_trait;
+use avi_p2p::{
+    is_secure_reason, AuthConfig, AviEvent, AviP2p, AviP2pConfig, AviP2pError, BridgeBinding,
+    BridgeConfig, BridgeContext, BridgeHandle, BridgeHandler, ContextReplication, CorrelationId,
+    DhtEntryKind, EmbeddedBridge, ExtensionHandler, ExtensionProtocol, HandlerOutcome, HouseholdCa,
+    InterceptScope, NodeSnapshot, Operation, OutboundTarget, OutboxConfig, PairingPayload, PeerId,
+    RendezvousConfig, Role,
+};
+use avi_p2p_protocol::{
+    DownlinkMessage, PressType, SensorValue, UplinkMessage, ERROR_SESSION_LIMIT,
+    ERROR_UNKNOWN_SENSOR, MAX_PACKET_SIZE,
+};
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+
+#[tokio::test]
+async fn test_pubsub_over_memory_transport() {
+    let mut config_a = AviP2pConfig::new("node-a");
+    config_a.listen_port = 4101;
+    let (node_a, mut events_a) = AviP2p::start_in_memory(config_a).await.unwrap();
+
+    let mut config_b = AviP2pConfig::new("node-b");
+    config_b.bootstrap_peers = vec!["/memory/4101".to_string()];
+    let (node_b, _events_b) = AviP2p::start_in_memory(config_b).await.unwrap();
+
+    node_a.handle().subscribe("test/topic").await.unwrap();
+    node_b.handle().subscribe("test/topic").await.unwrap();
+
+    // Publish until the gossipsub mesh has formed and the message gets through
+    let received = timeout(Duration::from_secs(10), async {
+        loop {
+            let _ = node_b
+                .handle()
+                .publish("test/topic", b"ping".to_vec())
+                .await;
+            if let Ok(Some(AviEvent::Message { topic, data, .. })) =
+                timeout(Duration::from_millis(200), events_a.recv()).await
+            {
+                return (topic, data);
+            }
+        }
+    })
+    .await
+    .expect("message over memory transport");
+
+    assert_eq!(received, ("test/topic".to_string(), b"ping".to_vec()));
+}
+
+async fn started_peer_id(config: AviP2pConfig) -> String {
+    let (node, mut events) = AviP2p::start_in_memory(config).await.unwrap();
+    let peer_id = timeout(Duration::from_secs(5), async {
+        loop {
+            if let Some(AviEvent::Started { local_peer_id, .. }) = events.recv().await {
+                return local_peer_id.to_string();
+            }
+        }
+    })
+    .await
+    .expect("node did not start");
+    node.shutdown().await.unwrap();
+    peer_id
+}
+
+#[tokio::test]
+async fn test_identity_seed_is_deterministic() {
+    let seeded = || AviP2pConfig::new("seeded").with_identity_seed(b"device-0042");
+
+    let first = started_peer_id(seeded()).await;
+    let second = started_peer_id(seeded()).await;
+    let random = started_peer_id(AviP2pConfig::new("random")).await;
+
+    assert_eq!(first, second);
+    assert_ne!(first, random);
+}
+
+#[tokio::test]
+async fn test_publishes_buffered_while_paused_are_sent_on_resume() {
+    let mut config_a = AviP2pConfig::new("node-a");
+    config_a.listen_port = 4102;
+    let (node_a, mut events_a) = AviP2p::start_in_memory(config_a).await.unwrap();
+
+    let mut config_b = AviP2pConfig::new("node-b");
+    config_b.bootstrap_peers = vec!["/memory/4102".to_string()];
+    let (node_b, _events_b) = AviP2p::start_in_memory(config_b).await.unwrap();
+
+    node_a.handle().subscribe("test/paused").await.unwrap();
+    node_b.handle().subscribe("test/paused").await.unwrap();
+
+    let b = node_b.handle();
+    b.pause().await.unwrap();
+    b.publish("test/paused", b"queued".to_vec()).await.unwrap();
+    assert!(b.connected_peers().await.unwrap().is_empty());
+
+    b.resume().await.unwrap();
+    let received = timeout(Duration::from_secs(15), async {
+        loop {
+            if let Some(AviEvent::Message { topic, data, .. }) = events_a.recv().await {
+                return (topic, data);
+            }
+        }
+    })
+    .await
+    .expect("buffered publish was not delivered after resume");
+
+    assert_eq!(received, ("test/paused".to_string(), b"queued".to_vec()));
+}
+
+#[tokio::test]
+async fn test_outbox_delivers_when_peer_comes_online() {
+    let config_b = || AviP2pConfig::new("node-b").with_identity_seed(b"outbox-target");
+    let peer_b = PeerId::new(&started_peer_id(config_b()).await);
+
+    let mut config_a = AviP2pConfig::new("node-a");
+    config_a.outbox = Some(OutboxConfig::default());
+    config_a.listen_port = 4103;
+    let (node_a, _events_a) = AviP2p::start_in_memory(config_a).await.unwrap();
+    node_a
+        .handle()
+        .send_via_outbox(&peer_b, b"unlock".to_vec(), None)
+        .await
+        .unwrap();
+
+    // B boots after the message was queued and connects to A
+    let mut config = config_b();
+    config.bootstrap_peers = vec!["/memory/4103".to_string()];
+    let (_node_b, mut events_b) = AviP2p::start_in_memory(config).await.unwrap();
+    let data = timeout(Duration::from_secs(15), async {
+        loop {
+            if let Some(AviEvent::DirectMessage { data, .. }) = events_b.recv().await {
+                return data;
+            }
+        }
+    })
+    .await
+    .expect("queued message was not delivered");
+
+    assert_eq!(data, b"unlock");
+}
+
+#[tokio::test]
+async fn test_send_to_delivers_direct_message() {
+    let mut config_a = AviP2pConfig::new("node-a");
+    config_a.listen_port = 4104;
+    let (node_a, mut events_a) = AviP2p::start_in_memory(config_a).await.unwrap();
+
+    let mut config_b = AviP2pConfig::new("node-b");
+    config_b.bootstrap_peers = vec!["/memory/4104".to_string()];
+    let (node_b, _events_b) = AviP2p::start_in_memory(config_b).await.unwrap();
+
+    let peer_a = timeout(Duration::from_secs(5), async {
+        loop {
+            if let Some(peer) = node_b.handle().connected_peers().await.unwrap().pop() {
+                return peer;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("nodes did not connect");
+
+    node_b
+        .handle()
+        .send_to(&peer_a, b"hello".to_vec())
+        .await
+        .unwrap();
+
+    let data = timeout(Duration::from_secs(5), async {
+        loop {
+            if let Some(AviEvent::DirectMessage { data, .. }) = events_a.recv().await {
+                return data;
+            }
+        }
+    })
+    .await
+    .expect("direct message was not delivered");
+    assert_eq!(data, b"hello");
+    let _ = node_a;
+}
+
+#[tokio::test]
+async fn test_update_context_if_only_one_claim_wins() {
+    let mut config = AviP2pConfig::new("speaker");
+    config.listen_port = 4105;
+    let (node, _events) = AviP2p::start_in_memory(config).await.unwrap();
+    let handle = node.handle();
+
+    let claim = |who: &str| {
+        let handle = handle.clone();
+        let patch = serde_json::json!({ "audio": { "active_speaker": who } });
+        async move {
+            handle
+                .update_context_if("audio.active_speaker", serde_json::Value::Null, patch)
+                .await
+                .unwrap()
+        }
+    };
+    let (a, b) = tokio::join!(claim("kitchen"), claim("office"));
+    assert!(a ^ b);
+
+    let winner = if a { "kitchen" } else { "office" };
+    assert_eq!(
+        handle.get_ctx("audio.active_speaker").await.unwrap(),
+        winner
+    );
+}
+
+#[tokio::test]
+async fn test_fetch_context_asks_the_peer() {
+    let mut config_a = AviP2pConfig::new("node-a");
+    config_a.listen_port = 4106;
+    let (node_a, _events_a) = AviP2p::start_in_memory(config_a).await.unwrap();
+    node_a
+        .handle()
+        .update_context(serde_json::json!({ "room": "kitchen" }))
+        .await
+        .unwrap();
+
+    let mut config_b = AviP2pConfig::new("node-b");
+    config_b.bootstrap_peers = vec!["/memory/4106".to_string()];
+    let (node_b, _events_b) = AviP2p::start_in_memory(config_b).await.unwrap();
+
+    let peer_a = timeout(Duration::from_secs(5), async {
+        loop {
+            if let Some(peer) = node_b.handle().connected_peers().await.unwrap().pop() {
+                return peer;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("nodes did not connect");
+
+    let context = timeout(
+        Duration::from_secs(5),
+        node_b.handle().fetch_context(peer_a, true),
+    )
+    .await
+    .expect("context fetch hung")
+    .unwrap();
+    assert_eq!(context["room"], "kitchen");
+}
+
+#[tokio::test]
+async fn test_unanswered_stream_open_times_out() {
+    let mut config_a = AviP2pConfig::new("node-a");
+    config_a.listen_port = 4107;
+    let (_node_a, _events_a) = AviP2p::start_in_memory(config_a).await.unwrap();
+
+    let mut config_b = AviP2pConfig::new("node-b");
+    config_b.bootstrap_peers = vec!["/memory/4107".to_string()];
+    config_b.stream_open_timeout = Duration::from_millis(300);
+    let (node_b, mut events_b) = AviP2p::start_in_memory(config_b).await.unwrap();
+
+    let peer_a = timeout(Duration::from_secs(5), async {
+        loop {
+            if let Some(peer) = node_b.handle().connected_peers().await.unwrap().pop() {
+                return peer;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("nodes did not connect");
+
+    // Node A never accepts
+    let res = node_b
+        .handle()
+        .open_stream(peer_a.clone(), "audio".to_string())
+        .await;
+    assert!(matches!(res, Err(AviP2pError::StreamOpenTimeout(_))));
+
+    let event = timeout(Duration::from_secs(1), async {
+        loop {
+            if let Some(AviEvent::StreamOpenTimeout { stream_id, .. }) = events_b.recv().await {
+                return stream_id;
+            }
+        }
+    })
+    .await;
+    assert!(event.is_ok());
+
+    // The outcome event names the call it answers
+    let correlation = CorrelationId::next();
+    let requested = node_b
+        .handle()
+        .request_stream_correlated(peer_a, "video".to_string(), correlation)
+        .await
+        .unwrap();
+    let correlation_id = timeout(Duration::from_secs(2), async {
+        loop {
+            if let Some(AviEvent::StreamOpenTimeout {
+                stream_id,
+                correlation_id,
+                ..
+            }) = events_b.recv().await
+            {
+                if stream_id == requested {
+                    return correlation_id;
+                }
+            }
+        }
+    })
+    .await
+    .expect("open timeout event");
+    assert_eq!(correlation_id, Some(correlation));
+}
+
+async fn local_peer_id(events: &mut tokio::sync::mpsc::Receiver<AviEvent>) -> PeerId {
+    timeout(Duration::from_secs(5), async {
+        loop {
+            if let Some(AviEvent::Started { local_peer_id, .. }) = events.recv().await {
+                return local_peer_id;
+            }
+        }
+    })
+    .await
+    .expect("node did not start")
+}
+
+#[tokio::test]
+async fn test_stream_relayed_through_intermediary() {
+    let mut config_relay = AviP2pConfig::new("relay");
+    config_relay.listen_port = 4108;
+    config_relay.stream_relay = true;
+    let (node_relay, mut events_relay) = AviP2p::start_in_memory(config_relay).await.unwrap();
+    let relay_id = local_peer_id(&mut events_relay).await;
+
+    let mut config_target = AviP2pConfig::new("target");
+    config_target.bootstrap_peers = vec!["/memory/4108".to_string()];
+    let (node_target, mut events_target) = AviP2p::start_in_memory(config_target).await.unwrap();
+    let target_id = local_peer_id(&mut events_target).await;
+
+    let mut config_origin = AviP2pConfig::new("origin");
+    config_origin.bootstrap_peers = vec!["/memory/4108".to_string()];
+    let (node_origin, mut events_origin) = AviP2p::start_in_memory(config_origin).await.unwrap();
+
+    let stream_id = timeout(Duration::from_secs(5), async {
+        loop {
+            let relay_peers = node_relay.handle().connected_peers().await.unwrap();
+            if relay_peers.len() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        node_origin
+            .handle()
+            .request_stream_via(relay_id, &target_id, "audio")
+            .await
+            .unwrap()
+    })
+    .await
+    .expect("relay did not see both peers");
+
+    let onward = timeout(Duration::from_secs(5), async {
+        loop {
+            if let Some(AviEvent::StreamRequested {
+                stream_id, reason, ..
+            }) = events_target.recv().await
+            {
+                assert_eq!(reason, "audio");
+                return stream_id;
+            }
+        }
+    })
+    .await
+    .expect("target never saw the relayed stream");
+    node_target
+        .handle()
+        .accept_stream(onward, Some(b"codec=opus".to_vec()))
+        .await
+        .unwrap();
+
+    timeout(Duration::from_secs(5), async {
+        loop {
+            if let Some(AviEvent::StreamAccepted {
+                stream_id: id,
+                payload,
+                ..
+            }) = events_origin.recv().await
+            {
+                assert_eq!(id, stream_id);
+                assert_eq!(payload.as_deref(), Some(&b"codec=opus"[..]));
+                return;
+            }
+        }
+    })
+    .await
+    .expect("origin never saw the acceptance");
+
+    node_origin
+        .handle()
+        .send_stream_data(stream_id, b"frame".to_vec())
+        .await
+        .unwrap();
+    let data = timeout(Duration::from_secs(5), async {
+        loop {
+            if let Some(AviEvent::StreamData { data, .. }) = events_target.recv().await {
+                return data;
+            }
+        }
+    })
+    .await
+    .expect("relayed data never arrived");
+    assert_eq!(data, b"frame");
+}
+
+#[tokio::test]
+async fn test_compact_topics_arrive_under_their_name() {
+    let topic = "home/living-room/sensor/temperature";
+    let mut config_a = AviP2pConfig::new("node-a");
+    config_a.listen_port = 4109;
+    config_a.compact_topics = vec![topic.to_string()];
+    let (node_a, mut events_a) = AviP2p::start_in_memory(config_a).await.unwrap();
+
+    let mut config_b = AviP2pConfig::new("node-b");
+    config_b.bootstrap_peers = vec!["/memory/4109".to_string()];
+    config_b.compact_topics = vec![topic.to_string()];
+    let (node_b, _events_b) = AviP2p::start_in_memory(config_b).await.unwrap();
+
+    node_a.handle().subscribe(topic).await.unwrap();
+    node_b.handle().subscribe(topic).await.unwrap();
+
+    let received = timeout(Duration::from_secs(10), async {
+        loop {
+            let _ = node_b.handle().publish(topic, b"21.5".to_vec()).await;
+            if let Ok(Some(AviEvent::Message { topic, data, .. })) =
+                timeout(Duration::from_millis(200), events_a.recv()).await
+            {
+                return (topic, data);
+            }
+        }
+    })
+    .await
+    .expect("message on a compact topic");
+
+    assert_eq!(received, (topic.to_string(), b"21.5".to_vec()));
+    assert!(avi_p2p::compact_topic_id(topic).len() < topic.len());
+}
+
+#[tokio::test]
+async fn test_repeated_message_ids_are_delivered_once() {
+    let mut config_a = AviP2pConfig::new("node-a");
+    config_a.listen_port = 4110;
+    config_a.dedupe_window = Some(Duration::from_secs(60));
+    let (node_a, mut events_a) = AviP2p::start_in_memory(config_a).await.unwrap();
+
+    let mut config_b = AviP2pConfig::new("node-b");
+    config_b.bootstrap_peers = vec!["/memory/4110".to_string()];
+    let (node_b, _events_b) = AviP2p::start_in_memory(config_b).await.unwrap();
+
+    node_a.handle().subscribe("lights").await.unwrap();
+    node_b.handle().subscribe("lights").await.unwrap();
+
+    // Retransmit until the mesh carries the first copy
+    let first = timeout(Duration::from_secs(10), async {
+        loop {
+            let _ = node_b
+                .handle()
+                .publish_with_id("lights", b"on".to_vec(), "cmd-1")
+                .await;
+            if let Ok(Some(AviEvent::Message { data, .. })) =
+                timeout(Duration::from_millis(200), events_a.recv()).await
+            {
+                return data;
+            }
+        }
+    })
+    .await
+    .expect("first copy");
+    assert_eq!(first, b"on".to_vec());
+
+    node_b
+        .handle()
+        .publish_with_id("lights", b"on".to_vec(), "cmd-1")
+        .await
+        .unwrap();
+    node_b
+        .handle()
+        .publish_with_id("lights", b"off".to_vec(), "cmd-2")
+        .await
+        .unwrap();
+
+    let next = timeout(Duration::from_secs(5), async {
+        loop {
+            if let Some(AviEvent::Message { data, .. }) = events_a.recv().await {
+                return data;
+            }
+        }
+    })
+    .await
+    .expect("next message");
+    assert_eq!(next, b"off".to_vec());
+}
+
+#[tokio::test]
+async fn test_gateways_find_each_other_through_rendezvous() {
+    let mut config_point = AviP2pConfig::new("rendezvous");
+    config_point.listen_port = 4111;
+    config_point.rendezvous_server = true;
+    let (_node_point, mut events_point) = AviP2p::start_in_memory(config_point).await.unwrap();
+    let point = format!(
+        "/memory/4111/p2p/{}",
+        local_peer_id(&mut events_point).await
+    );
+
+    let mut config_a = AviP2pConfig::new("gateway-a");
+    config_a.listen_port = 4112;
+    config_a.rendezvous = Some(RendezvousConfig::new("household-1234", vec![point.clone()]));
+    let (_node_a, mut events_a) = AviP2p::start_in_memory(config_a).await.unwrap();
+    let a_id = local_peer_id(&mut events_a).await;
+
+    let mut config_b = AviP2pConfig::new("gateway-b");
+    config_b.listen_port = 4113;
+    config_b.rendezvous = Some(RendezvousConfig::new("household-1234", vec![point]));
+    let (node_b, mut events_b) = AviP2p::start_in_memory(config_b).await.unwrap();
+    let b_id = local_peer_id(&mut events_b).await;
+
+    let found = timeout(Duration::from_secs(10), async {
+        loop {
+            if let Ok(peers) = node_b.handle().rendezvous_discover("household-1234").await {
+                if !peers.is_empty() {
+                    return peers;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .expect("gateway-a registered");
+    assert_eq!(found, vec![a_id]);
+
+    let connected = timeout(Duration::from_secs(10), async {
+        loop {
+            if let Some(AviEvent::PeerConnected { peer_id, .. }) = events_a.recv().await {
+                if peer_id == b_id {
+                    return;
+                }
+            }
+        }
+    })
+    .await;
+    assert!(connected.is_ok());
+}
+
+struct Shout;
+
+#[async_trait::async_trait]
+impl ExtensionHandler for Shout {
+    async fn on_request(&self, _from: PeerId, request: Vec<u8>) -> Result<Vec<u8>, String> {
+        Ok(request.to_ascii_uppercase())
+    }
+}
+
+#[tokio::test]
+async fn test_extension_protocol_round_trip() {
+    let mut config_a = AviP2pConfig::new("node-a");
+    config_a.listen_port = 4114;
+    config_a.extensions = vec![ExtensionProtocol::new("acme/shout/1", Shout)];
+    let (_node_a, mut events_a) = AviP2p::start_in_memory(config_a).await.unwrap();
+    let a_id = local_peer_id(&mut events_a).await;
+
+    let mut config_b = AviP2pConfig::new("node-b");
+    config_b.bootstrap_peers = vec!["/memory/4114".to_string()];
+    config_b.extensions = vec![
+        ExtensionProtocol::new("acme/shout/1", Shout),
+        ExtensionProtocol::new("acme/whisper/1", Shout),
+    ];
+    let (node_b, _events_b) = AviP2p::start_in_memory(config_b).await.unwrap();
+
+    assert!(node_b.handle().extension("acme/unknown/1").is_err());
+
+    let shout = node_b.handle().extension("acme/shout/1").unwrap();
+    let reply = timeout(Duration::from_secs(10), async {
+        loop {
+            if let Ok(reply) = shout.request(&a_id, b"hello".to_vec()).await {
+                return reply;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .expect("extension reply");
+    assert_eq!(reply, b"HELLO".to_vec());
+
+    // Node a never registered this one
+    let whisper = node_b.handle().extension("acme/whisper/1").unwrap();
+    assert!(matches!(
+        whisper.request(&a_id, b"psst".to_vec()).await,
+        Err(AviP2pError::Extension(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_subscriptions_survive_restart() {
+    let store = std::env::temp_dir().join(format!("avi-subs-{}.json", rand::random::<u64>()));
+    let mut config = AviP2pConfig::new("node-a");
+    config.subscription_store = Some(store.clone());
+
+    let (node, _events) = AviP2p::start_in_memory(config.clone()).await.unwrap();
+    node.handle().subscribe("lights").await.unwrap();
+    node.handle().subscribe("doors").await.unwrap();
+    assert_eq!(
+        node.handle().subscriptions().await.unwrap(),
+        vec!["doors".to_string(), "lights".to_string()]
+    );
+    node.shutdown().await.unwrap();
+
+    let (node, _events) = AviP2p::start_in_memory(config).await.unwrap();
+    assert_eq!(
+        node.handle().subscriptions().await.unwrap(),
+        vec!["doors".to_string(), "lights".to_string()]
+    );
+    node.handle().unsubscribe_all().await.unwrap();
+    assert!(node.handle().subscriptions().await.unwrap().is_empty());
+
+    let _ = std::fs::remove_file(&store);
+}
+
+#[tokio::test]
+async fn test_failed_dht_publish_is_reported() {
+    // A lone node has no peers to store the record at
+    let (node, mut events) = AviP2p::start_in_memory(AviP2pConfig::new("node-a"))
+        .await
+        .unwrap();
+    node.handle()
+        .put_record("capability/speaker", b"living-room".to_vec())
+        .await
+        .unwrap();
+
+    let (key, kind) = timeout(Duration::from_secs(10), async {
+        loop {
+            if let Some(AviEvent::DhtPublishFailed { key, kind, .. }) = events.recv().await {
+                return (key, kind);
+            }
+        }
+    })
+    .await
+    .expect("publish failure event");
+    assert_eq!(key, "capability/speaker");
+    assert_eq!(kind, DhtEntryKind::Record);
+}
+
+#[tokio::test]
+async fn test_oversized_payloads_are_refused_with_their_size() {
+    let mut config = AviP2pConfig::new("node-a");
+    config.max_publish_size = 1024;
+    let (node, _events) = AviP2p::start_in_memory(config).await.unwrap();
+
+    assert!(matches!(
+        node.handle().publish("test/big", vec![0; 1025]).await,
+        Err(AviP2pError::PayloadTooLarge {
+            limit: 1024,
+            actual: 1025
+        })
+    ));
+    // At the limit it gets as far as gossipsub (which has no peers here)
+    assert!(!matches!(
+        node.handle().publish("test/big", vec![0; 1024]).await,
+        Err(AviP2pError::PayloadTooLarge { .. })
+    ));
+}
+
+#[tokio::test]
+async fn test_bulk_stream_write_delivers_every_chunk() {
+    let mut config_a = AviP2pConfig::new("node-a");
+    config_a.listen_port = 4115;
+    let (node_a, mut events_a) = AviP2p::start_in_memory(config_a).await.unwrap();
+    let a_id = local_peer_id(&mut events_a).await;
+
+    let mut config_b = AviP2pConfig::new("node-b");
+    config_b.bootstrap_peers = vec!["/memory/4115".to_string()];
+    let (node_b, _events_b) = AviP2p::start_in_memory(config_b).await.unwrap();
+
+    let handle_a = node_a.handle();
+    let accepting = tokio::spawn(async move {
+        loop {
+            if let Some(AviEvent::StreamRequested { stream_id, .. }) = events_a.recv().await {
+                handle_a.accept_stream(stream_id, None).await.unwrap();
+                return events_a;
+            }
+        }
+    });
+    let stream_id = timeout(Duration::from_secs(5), async {
+        while node_b.handle().connected_peers().await.unwrap().is_empty() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        node_b
+            .handle()
+            .open_stream(a_id, "file".into())
+            .await
+            .unwrap()
+    })
+    .await
+    .expect("stream never opened");
+    let mut events_a = accepting.await.unwrap();
+
+    let chunks: Vec<Vec<u8>> = (0..100u8).map(|i| vec![i; 64]).collect();
+    let sent = node_b
+        .handle()
+        .send_stream_data_all(stream_id, futures::stream::iter(chunks.clone()))
+        .await
+        .unwrap();
+    assert_eq!(sent, 100 * 64);
+
+    let received = timeout(Duration::from_secs(10), async {
+        let mut received = Vec::new();
+        while received.len() < chunks.len() {
+            if let Some(AviEvent::StreamData { data, .. }) = events_a.recv().await {
+                received.push(data);
+            }
+        }
+        received
+    })
+    .await
+    .expect("chunks never arrived");
+    // Each chunk is its own request, so arrival order is not guaranteed
+    let mut received = received;
+    received.sort();
+    assert_eq!(received, chunks);
+}
+
+#[tokio::test]
+async fn test_exported_state_moves_to_replacement_node() {
+    let mut config = AviP2pConfig::new("gateway").with_identity_seed(b"gateway-sd-card");
+    config.compact_topics = vec!["sensors/temperature".to_string()];
+    let (old, _events) = AviP2p::start_in_memory(config).await.unwrap();
+    old.handle().subscribe("lights").await.unwrap();
+    old.handle()
+        .update_context(serde_json::json!({ "mode": "away" }))
+        .await
+        .unwrap();
+    let blob = old.handle().export_state().await.unwrap();
+    old.shutdown().await.unwrap();
+
+    // A node with another identity cannot take the state over
+    let (stranger, _events) = AviP2p::start_in_memory(AviP2pConfig::new("stranger"))
+        .await
+        .unwrap();
+    assert!(matches!(
+        stranger.handle().import_state(&blob).await,
+        Err(AviP2pError::SnapshotIdentity(_))
+    ));
+
+    let snapshot = NodeSnapshot::from_bytes(&blob).unwrap();
+    let mut config = AviP2pConfig::new("gateway");
+    config.identity = snapshot.identity();
+    let (replacement, _events) = AviP2p::start_in_memory(config).await.unwrap();
+    replacement.handle().import_state(&blob).await.unwrap();
+
+    assert_eq!(
+        replacement.handle().get_ctx("mode").await.unwrap(),
+        serde_json::json!("away")
+    );
+    assert_eq!(
+        replacement.handle().subscriptions().await.unwrap(),
+        vec!["lights".to_string()]
+    );
+    assert_eq!(
+        snapshot.compact_topics,
+        vec!["sensors/temperature".to_string()]
+    );
+}
+
+#[tokio::test]
+async fn test_mesh_namespaces_do_not_cross_talk() {
+    let mut config_a = AviP2pConfig::new("node-a").with_mesh_namespace("home-a");
+    config_a.listen_port = 4116;
+    let (node_a, mut events_a) = AviP2p::start_in_memory(config_a).await.unwrap();
+
+    let mut config_b = AviP2pConfig::new("node-b").with_mesh_namespace("home-a");
+    config_b.bootstrap_peers = vec!["/memory/4116".to_string()];
+    let (node_b, _events_b) = AviP2p::start_in_memory(config_b).await.unwrap();
+
+    // Shares the infrastructure, but belongs to another deployment
+    let mut config_c = AviP2pConfig::new("node-c").with_mesh_namespace("home-b");
+    config_c.bootstrap_peers = vec!["/memory/4116".to_string()];
+    let (node_c, mut events_c) = AviP2p::start_in_memory(config_c).await.unwrap();
+
+    for node in [&node_a, &node_b, &node_c] {
+        node.handle().subscribe("lights").await.unwrap();
+    }
+
+    timeout(Duration::from_secs(10), async {
+        loop {
+            let _ = node_b.handle().publish("lights", b"on".to_vec()).await;
+            if let Ok(Some(AviEvent::Message { topic, .. })) =
+                timeout(Duration::from_millis(200), events_a.recv()).await
+            {
+                assert_eq!(topic, "lights");
+                return;
+            }
+        }
+    })
+    .await
+    .expect("message within the namespace");
+
+    while let Ok(event) = events_c.try_recv() {
+        assert!(!matches!(event, AviEvent::Message { .. }));
+    }
+}
+
+#[tokio::test]
+async fn test_telemetry_topics_gossip_on_their_own_mesh() {
+    let sensor = "device/7/sensor/temp";
+    let command = "device/7/command";
+    let mut config_a = AviP2pConfig::new("node-a");
+    config_a.listen_port = 4117;
+    let (node_a, mut events_a) = AviP2p::start_in_memory(config_a).await.unwrap();
+
+    let mut config_b = AviP2pConfig::new("node-b");
+    config_b.bootstrap_peers = vec!["/memory/4117".to_string()];
+    let (node_b, _events_b) = AviP2p::start_in_memory(config_b).await.unwrap();
+
+    for topic in [sensor, command] {
+        node_a.handle().subscribe(topic).await.unwrap();
+        node_b.handle().subscribe(topic).await.unwrap();
+    }
+
+    // Both profiles get through, each over its own gossip protocol
+    let mut pending: Vec<&str> = vec![sensor, command];
+    timeout(Duration::from_secs(15), async {
+        while !pending.is_empty() {
+            for topic in &pending {
+                let _ = node_b.handle().publish(topic, b"1".to_vec()).await;
+            }
+            if let Ok(Some(AviEvent::Message { topic, .. })) =
+                timeout(Duration::from_millis(200), events_a.recv()).await
+            {
+                pending.retain(|pending| *pending != topic);
+            }
+        }
+    })
+    .await
+    .expect("messages on telemetry and control topics");
+}
+
+#[tokio::test]
+async fn test_banned_peer_is_disconnected_until_unbanned() {
+    let mut config_a = AviP2pConfig::new("node-a");
+    config_a.listen_port = 4118;
+    let (node_a, mut events_a) = AviP2p::start_in_memory(config_a).await.unwrap();
+
+    let mut config_b = AviP2pConfig::new("node-b");
+    config_b.bootstrap_peers = vec!["/memory/4118".to_string()];
+    let (_node_b, _events_b) = AviP2p::start_in_memory(config_b).await.unwrap();
+
+    let peer_b = timeout(Duration::from_secs(5), async {
+        loop {
+            if let Some(AviEvent::PeerConnected { peer_id, .. }) = events_a.recv().await {
+                return peer_id;
+            }
+        }
+    })
+    .await
+    .expect("peers did not connect");
+
+    node_a.handle().ban_peer(&peer_b, None).await.unwrap();
+    let mut banned = false;
+    timeout(Duration::from_secs(5), async {
+        loop {
+            match events_a.recv().await {
+                Some(AviEvent::PeerBanned {
+                    peer_id,
+                    violation,
+                    duration,
+                }) => {
+                    assert_eq!((&peer_id, violation, duration), (&peer_b, None, None));
+                    banned = true;
+                }
+                Some(AviEvent::PeerDisconnected { peer_id }) if peer_id == peer_b => return,
+                _ => {}
+            }
+        }
+    })
+    .await
+    .expect("banned peer stayed connected");
+    assert!(banned);
+
+    let listed = node_a.handle().banned_peers().await.unwrap();
+    assert_eq!(listed, vec![(peer_b.clone(), None)]);
+    assert!(node_a.handle().unban_peer(&peer_b).await.unwrap());
+    assert!(!node_a.handle().unban_peer(&peer_b).await.unwrap());
+    assert!(node_a.handle().banned_peers().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_secure_stream_through_relay() {
+    let mut config_relay = AviP2pConfig::new("relay");
+    config_relay.listen_port = 4119;
+    config_relay.stream_relay = true;
+    let (node_relay, mut events_relay) = AviP2p::start_in_memory(config_relay).await.unwrap();
+    let relay_id = local_peer_id(&mut events_relay).await;
+
+    let mut config_target = AviP2pConfig::new("target");
+    config_target.bootstrap_peers = vec!["/memory/4119".to_string()];
+    let (node_target, mut events_target) = AviP2p::start_in_memory(config_target).await.unwrap();
+    let target_id = local_peer_id(&mut events_target).await;
+
+    let mut config_origin = AviP2pConfig::new("origin");
+    config_origin.bootstrap_peers = vec!["/memory/4119".to_string()];
+    let (node_origin, mut events_origin) = AviP2p::start_in_memory(config_origin).await.unwrap();
+    let origin_id = local_peer_id(&mut events_origin).await;
+
+    timeout(Duration::from_secs(5), async {
+        while node_relay.handle().connected_peers().await.unwrap().len() < 2 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("relay did not see both peers");
+
+    let origin = node_origin.handle();
+    let opening = tokio::spawn(async move {
+        origin
+            .open_secure_stream_via(relay_id, &target_id, "audio")
+            .await
+    });
+
+    let onward = timeout(Duration::from_secs(5), async {
+        loop {
+            if let Some(AviEvent::StreamRequested {
+                stream_id, reason, ..
+            }) = events_target.recv().await
+            {
+                assert!(is_secure_reason(&reason));
+                return stream_id;
+            }
+        }
+    })
+    .await
+    .expect("target never saw the relayed stream");
+    node_target
+        .handle()
+        .accept_stream(onward, None)
+        .await
+        .unwrap();
+    // Sent before the handshake is done; held until it is
+    node_target
+        .handle()
+        .send_stream_data(onward, b"hello".to_vec())
+        .await
+        .unwrap();
+
+    // The target sees the origin's identity, not the relay's
+    let secured_by = timeout(Duration::from_secs(5), async {
+        loop {
+            if let Some(AviEvent::StreamSecured { peer_id, .. }) = events_target.recv().await {
+                return peer_id;
+            }
+        }
+    })
+    .await
+    .expect("target never finished the handshake");
+    assert_eq!(secured_by, origin_id);
+
+    let stream_id = timeout(Duration::from_secs(5), opening)
+        .await
+        .expect("secure stream never opened")
+        .unwrap()
+        .unwrap();
+    let greeting = timeout(Duration::from_secs(5), async {
+        loop {
+            if let Some(AviEvent::StreamData { data, .. }) = events_origin.recv().await {
+                return data;
+            }
+        }
+    })
+    .await
+    .expect("held data never arrived");
+    assert_eq!(greeting, b"hello");
+
+    node_origin
+        .handle()
+        .send_stream_data(stream_id, b"frame".to_vec())
+        .await
+        .unwrap();
+    let data = timeout(Duration::from_secs(5), async {
+        loop {
+            if let Some(AviEvent::StreamData { data, .. }) = events_target.recv().await {
+                return data;
+            }
+        }
+    })
+    .await
+    .expect("relayed data never arrived");
+    assert_eq!(data, b"frame");
+}
+
+#[tokio::test]
+async fn test_lazy_context_is_fetched_after_invalidation() {
+    let mut config_a = AviP2pConfig::new("node-a");
+    config_a.listen_port = 4120;
+    config_a.context_replication = ContextReplication::Lazy;
+    let (node_a, mut events_a) = AviP2p::start_in_memory(config_a).await.unwrap();
+    let peer_a = local_peer_id(&mut events_a).await;
+
+    let mut config_b = AviP2pConfig::new("node-b");
+    config_b.bootstrap_peers = vec!["/memory/4120".to_string()];
+    config_b.context_replication = ContextReplication::Lazy;
+    let (node_b, mut events_b) = AviP2p::start_in_memory(config_b).await.unwrap();
+
+    let paths = timeout(Duration::from_secs(10), async {
+        loop {
+            let _ = node_a
+                .handle()
+                .update_context(serde_json::json!({ "room": "kitchen" }))
+                .await;
+            if let Ok(Some(AviEvent::ContextInvalidated { peer_id, paths })) =
+                timeout(Duration::from_millis(200), events_b.recv()).await
+            {
+                assert_eq!(peer_id, peer_a);
+                return paths;
+            }
+        }
+    })
+    .await
+    .expect("no invalidation arrived");
+    assert_eq!(paths, vec!["room".to_string()]);
+
+    // Only the notice travelled; the context comes when it is read
+    let local = node_b.handle().get_context(None).await.unwrap();
+    assert!(local.get("room").is_none());
+    let context = node_b.handle().get_context(Some(peer_a)).await.unwrap();
+    assert_eq!(context["room"], "kitchen");
+}
+
+#[tokio::test]
+async fn test_oversized_context_is_refused() {
+    let mut config_a = AviP2pConfig::new("node-a");
+    config_a.listen_port = 4121;
+    config_a.local_context_warn_size = Some(1024);
+    let (node_a, mut events_a) = AviP2p::start_in_memory(config_a).await.unwrap();
+    let peer_a = local_peer_id(&mut events_a).await;
+    node_a
+        .handle()
+        .update_context(serde_json::json!({ "blob": "x".repeat(2048) }))
+        .await
+        .unwrap();
+    let warning = timeout(Duration::from_secs(5), async {
+        loop {
+            if let Some(AviEvent::ContextQuotaWarning { size, limit }) = events_a.recv().await {
+                return (size, limit);
+            }
+        }
+    })
+    .await
+    .expect("no quota warning");
+    assert!(warning.0 > 2048);
+    assert_eq!(warning.1, 1024);
+
+    let mut config_b = AviP2pConfig::new("node-b");
+    config_b.bootstrap_peers = vec!["/memory/4121".to_string()];
+    config_b.max_peer_context_size = 1024;
+    let (node_b, _events_b) = AviP2p::start_in_memory(config_b).await.unwrap();
+    timeout(Duration::from_secs(5), async {
+        while node_b.handle().connected_peers().await.unwrap().is_empty() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("nodes did not connect");
+
+    let fetched = node_b.handle().fetch_context(peer_a, true).await;
+    assert!(matches!(
+        fetched,
+        Err(AviP2pError::PayloadTooLarge { limit: 1024, .. })
+    ));
+}
+
+#[tokio::test]
+async fn test_context_view_follows_peer_updates() {
+    let mut config_a = AviP2pConfig::new("node-a");
+    config_a.listen_port = 4122;
+    let (node_a, mut events_a) = AviP2p::start_in_memory(config_a).await.unwrap();
+    let peer_a = local_peer_id(&mut events_a).await;
+
+    let mut config_b = AviP2pConfig::new("node-b");
+    config_b.bootstrap_peers = vec!["/memory/4122".to_string()];
+    let (node_b, _events_b) = AviP2p::start_in_memory(config_b).await.unwrap();
+
+    let mut view = node_b.handle().context_view(&["room"]).await.unwrap();
+    assert!(view.snapshot().get(&peer_a, "room").is_none());
+
+    timeout(Duration::from_secs(10), async {
+        loop {
+            let _ = node_a
+                .handle()
+                .update_context(serde_json::json!({ "room": "kitchen" }))
+                .await;
+            let _ = timeout(Duration::from_millis(200), view.changed()).await;
+            if view.snapshot().get(&peer_a, "room").is_some() {
+                return;
+            }
+        }
+    })
+    .await
+    .expect("view never saw the update");
+    assert_eq!(
+        view.snapshot().get(&peer_a, "room"),
+        Some(&serde_json::json!("kitchen"))
+    );
+}
+
+#[tokio::test]
+async fn test_messages_missed_during_outage_are_replayed() {
+    let mut config_a = AviP2pConfig::new("node-a");
+    config_a.listen_port = 4123;
+    let (node_a, mut events_a) = AviP2p::start_in_memory(config_a).await.unwrap();
+
+    let mut config_b = AviP2pConfig::new("node-b");
+    config_b.bootstrap_peers = vec!["/memory/4123".to_string()];
+    let (node_b, mut events_b) = AviP2p::start_in_memory(config_b).await.unwrap();
+
+    let mut config_c = AviP2pConfig::new("node-c");
+    config_c.bootstrap_peers = vec!["/memory/4123".to_string()];
+    let (node_c, _events_c) = AviP2p::start_in_memory(config_c).await.unwrap();
+
+    node_a.handle().subscribe("test/alarms").await.unwrap();
+    node_b.handle().subscribe("test/alarms").await.unwrap();
+    let c = node_c.handle();
+
+    // Wait for the mesh to carry messages to B
+    timeout(Duration::from_secs(10), async {
+        loop {
+            let _ = c.publish("test/alarms", b"ping".to_vec()).await;
+            if let Ok(Some(AviEvent::Message { replayed, .. })) =
+                timeout(Duration::from_millis(200), events_b.recv()).await
+            {
+                assert!(!replayed);
+                return;
+            }
+        }
+    })
+    .await
+    .expect("mesh did not form");
+
+    // B drops off the network while the door opens
+    let b = node_b.handle();
+    b.pause().await.unwrap();
+    timeout(Duration::from_secs(10), async {
+        loop {
+            let _ = c.publish("test/alarms", b"door open".to_vec()).await;
+            if let Ok(Some(AviEvent::Message { data, .. })) =
+                timeout(Duration::from_millis(200), events_a.recv()).await
+            {
+                if data == b"door open" {
+                    return;
+                }
+            }
+        }
+    })
+    .await
+    .expect("A did not hear the alarm");
+
+    b.resume().await.unwrap();
+    timeout(Duration::from_secs(15), async {
+        loop {
+            if let Some(AviEvent::Message {
+                topic,
+                data,
+                replayed: true,
+                ..
+            }) = events_b.recv().await
+            {
+                if data == b"door open" {
+                    assert_eq!(topic, "test/alarms");
+                    return;
+                }
+            }
+        }
+    })
+    .await
+    .expect("missed alarm was not replayed");
+}
+
+#[tokio::test]
+async fn test_scheduled_publish_is_sent_by_the_runtime() {
+    let mut config_a = AviP2pConfig::new("node-a");
+    config_a.listen_port = 4124;
+    let (node_a, mut events_a) = AviP2p::start_in_memory(config_a).await.unwrap();
+
+    let mut config_b = AviP2pConfig::new("node-b");
+    config_b.bootstrap_peers = vec!["/memory/4124".to_string()];
+    let (node_b, _events_b) = AviP2p::start_in_memory(config_b).await.unwrap();
+
+    node_a.handle().subscribe("test/lights").await.unwrap();
+    node_b.handle().subscribe("test/lights").await.unwrap();
+    let b = node_b.handle();
+
+    timeout(Duration::from_secs(10), async {
+        loop {
+            let _ = b.publish("test/lights", b"ping".to_vec()).await;
+            if let Ok(Some(AviEvent::Message { .. })) =
+                timeout(Duration::from_millis(200), events_a.recv()).await
+            {
+                return;
+            }
+        }
+    })
+    .await
+    .expect("mesh did not form");
+
+    // The scheduling task ends long before the message is due
+    let scheduler = b.clone();
+    tokio::spawn(async move {
+        scheduler
+            .publish_after("test/lights", b"off".to_vec(), Duration::from_millis(800))
+            .await
+            .unwrap();
+    })
+    .await
+    .unwrap();
+    let cancelled = b
+        .publish_after(
+            "test/lights",
+            b"cancelled".to_vec(),
+            Duration::from_millis(400),
+        )
+        .await
+        .unwrap();
+    assert!(b.cancel_scheduled_publish(cancelled).await.unwrap());
+    let scheduled = tokio::time::Instant::now();
+
+    let data = timeout(Duration::from_secs(5), async {
+        loop {
+            if let Some(AviEvent::Message { data, .. }) = events_a.recv().await {
+                if data != b"ping" {
+                    return data;
+                }
+            }
+        }
+    })
+    .await
+    .expect("scheduled message did not arrive");
+    assert_eq!(data, b"off");
+    assert!(scheduled.elapsed() >= Duration::from_millis(700));
+    assert!(!b.cancel_scheduled_publish(cancelled).await.unwrap());
+}
+
+#[tokio::test]
+async fn test_atomic_publish_sends_all_or_nothing() {
+    let mut config_a = AviP2pConfig::new("node-a");
+    config_a.listen_port = 4125;
+    let (node_a, mut events_a) = AviP2p::start_in_memory(config_a).await.unwrap();
+
+    let mut config_b = AviP2pConfig::new("node-b");
+    config_b.bootstrap_peers = vec!["/memory/4125".to_string()];
+    let (node_b, _events_b) = AviP2p::start_in_memory(config_b).await.unwrap();
+
+    let a = node_a.handle();
+    let b = node_b.handle();
+    a.subscribe("test/scene/lights").await.unwrap();
+    b.subscribe("test/scene/lights").await.unwrap();
+    timeout(Duration::from_secs(10), async {
+        loop {
+            let _ = b.publish("test/scene/lights", b"ping".to_vec()).await;
+            if let Ok(Some(AviEvent::Message { .. })) =
+                timeout(Duration::from_millis(200), events_a.recv()).await
+            {
+                return;
+            }
+        }
+    })
+    .await
+    .expect("mesh did not form");
+
+    let scene = vec![
+        ("test/scene/lights".to_string(), b"dim".to_vec()),
+        ("test/scene/blinds".to_string(), b"down".to_vec()),
+    ];
+    // Nobody listens for blinds yet, so the lights are left alone too
+    match b.publish_atomic(scene.clone()).await {
+        Err(AviP2pError::PublishRolledBack { index, topic, .. }) => {
+            assert_eq!((index, topic.as_str()), (1, "test/scene/blinds"));
+        }
+        other => panic!("expected a rollback, got {:?}", other),
+    }
+    let lights_changed = timeout(Duration::from_millis(500), async {
+        loop {
+            if let Some(AviEvent::Message { data, .. }) = events_a.recv().await {
+                if data == b"dim" {
+                    return;
+                }
+            }
+        }
+    })
+    .await;
+    assert!(lights_changed.is_err());
+
+    a.subscribe("test/scene/blinds").await.unwrap();
+    timeout(Duration::from_secs(10), async {
+        while b.publish_atomic(scene.clone()).await.is_err() {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .expect("blinds subscription did not reach B");
+
+    let mut received = Vec::new();
+    while received.len() < 2 {
+        match timeout(Duration::from_secs(5), events_a.recv()).await {
+            Ok(Some(AviEvent::Message { topic, data, .. })) if data != b"ping" => {
+                received.push((topic, data));
+            }
+            Ok(Some(_)) => {}
+            _ => panic!("scene arrived incomplete: {:?}", received),
+        }
+    }
+    // Order across topics is up to gossip
+    received.sort();
+    assert_eq!(received, vec![scene[1].clone(), scene[0].clone()]);
+}
+
+#[tokio::test]
+async fn test_muted_peer_stays_connected_but_unheard() {
+    let mut config_a = AviP2pConfig::new("node-a");
+    config_a.listen_port = 4126;
+    let (node_a, mut events_a) = AviP2p::start_in_memory(config_a).await.unwrap();
+
+    let mut config_b = AviP2pConfig::new("node-b");
+    config_b.bootstrap_peers = vec!["/memory/4126".to_string()];
+    let (node_b, mut events_b) = AviP2p::start_in_memory(config_b).await.unwrap();
+    let peer_b = local_peer_id(&mut events_b).await;
+
+    let a = node_a.handle();
+    let b = node_b.handle();
+    for topic in ["test/chat", "test/status"] {
+        a.subscribe(topic).await.unwrap();
+        b.subscribe(topic).await.unwrap();
+    }
+    a.mute_peer(&peer_b, Some("test/chat")).await.unwrap();
+    assert_eq!(
+        a.muted_peers().await.unwrap(),
+        vec![(peer_b.clone(), Some("test/chat".to_string()))]
+    );
+
+    // Status messages get through while chat is dropped
+    timeout(Duration::from_secs(10), async {
+        loop {
+            let _ = b.publish("test/chat", b"muted".to_vec()).await;
+            let _ = b.publish("test/status", b"online".to_vec()).await;
+            match timeout(Duration::from_millis(200), events_a.recv()).await {
+                Ok(Some(AviEvent::Message { topic, .. })) if topic == "test/status" => return,
+                Ok(Some(AviEvent::Message { topic, .. })) => panic!("heard muted {}", topic),
+                Ok(Some(AviEvent::PeerDisconnected { .. })) => panic!("muted peer dropped"),
+                _ => {}
+            }
+        }
+    })
+    .await
+    .expect("status did not arrive");
+
+    assert!(a.unmute_peer(&peer_b, Some("test/chat")).await.unwrap());
+    assert!(!a.unmute_peer(&peer_b, Some("test/chat")).await.unwrap());
+    timeout(Duration::from_secs(10), async {
+        loop {
+            let _ = b.publish("test/chat", b"hello".to_vec()).await;
+            if let Ok(Some(AviEvent::Message { topic, data, .. })) =
+                timeout(Duration::from_millis(200), events_a.recv()).await
+            {
+                if topic == "test/chat" {
+                    assert_eq!(data, b"hello");
+                    return;
+                }
+            }
+        }
+    })
+    .await
+    .expect("chat did not arrive after unmute");
+}
+
+#[tokio::test]
+async fn test_node_joins_from_an_invitation() {
+    let ca = HouseholdCa::generate();
+    let peer_a = started_peer_id(AviP2pConfig::new("probe").with_identity([3; 32])).await;
+    let mut config_a = AviP2pConfig::new("node-a").with_identity([3; 32]);
+    config_a.listen_port = 4127;
+    let mut auth = AuthConfig::new(ca.public_key());
+    auth.certificate = Some(ca.issue(&PeerId::new(&peer_a), Role::Admin, None));
+    config_a.auth = Some(auth);
+    let (node_a, mut events_a) = AviP2p::start_in_memory(config_a).await.unwrap();
+    let a = node_a.handle();
+    a.rotate_key("test/secret").await.unwrap();
+
+    // Wait for the listener so the invitation has an address to give out
+    let invitation = timeout(Duration::from_secs(5), async {
+        loop {
+            let invitation = a
+                .create_invitation(&ca, Role::Device, Some(Duration::from_secs(60)))
+                .await
+                .unwrap();
+            if !invitation.bootstrap_peers.is_empty() {
+                return invitation;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("node A did not listen");
+    assert!(invitation.bootstrap_peers[0].starts_with("/memory/4127/p2p/"));
+    assert_eq!(invitation.network_keys()[0].scope, "test/secret");
+
+    let config_b =
+        AviP2pConfig::from_invitation("node-b", &invitation.to_bytes().unwrap()).unwrap();
+    let (node_b, mut events_b) = AviP2p::start_in_memory(config_b).await.unwrap();
+    assert_eq!(local_peer_id(&mut events_b).await, invitation.peer_id());
+
+    timeout(Duration::from_secs(10), async {
+        loop {
+            if let Some(AviEvent::PeerAuthenticated { peer_id, role }) = events_a.recv().await {
+                assert_eq!((peer_id, role), (invitation.peer_id(), Role::Device));
+                return;
+            }
+        }
+    })
+    .await
+    .expect("invited node did not authenticate");
+
+    node_b.handle().subscribe("test/secret").await.unwrap();
+    a.subscribe("test/secret").await.unwrap();
+    let data = timeout(Duration::from_secs(10), async {
+        loop {
+            let _ = a.publish_encrypted("test/secret", b"code".to_vec()).await;
+            if let Ok(Some(AviEvent::Message { data, .. })) =
+                timeout(Duration::from_millis(200), events_b.recv()).await
+            {
+                return data;
+            }
+        }
+    })
+    .await
+    .expect("encrypted message did not arrive");
+    assert_eq!(data, b"code");
+}
+
+#[tokio::test]
+async fn test_pairing_token_is_traded_for_an_invitation_once() {
+    let ca = HouseholdCa::generate();
+    let peer_hub = started_peer_id(AviP2pConfig::new("probe").with_identity([4; 32])).await;
+    let mut config_hub = AviP2pConfig::new("hub").with_identity([4; 32]);
+    config_hub.listen_port = 4128;
+    let mut auth = AuthConfig::new(ca.public_key());
+    auth.certificate = Some(ca.issue(&PeerId::new(&peer_hub), Role::Admin, None));
+    config_hub.auth = Some(auth);
+    let (hub, _events_hub) = AviP2p::start_in_memory(config_hub).await.unwrap();
+
+    let payload = timeout(Duration::from_secs(5), async {
+        loop {
+            let payload = hub
+                .handle()
+                .create_pairing(&ca, Role::Device, Duration::from_secs(60))
+                .await
+                .unwrap();
+            if !payload.addresses.is_empty() {
+                return payload;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("hub did not listen");
+    // What the phone scans
+    let scanned: PairingPayload = payload.to_string().parse().unwrap();
+    assert_eq!(scanned.addresses, vec!["/memory/4128".to_string()]);
+
+    let (joiner, _events_joiner) = AviP2p::start_in_memory(AviP2pConfig::new("speaker"))
+        .await
+        .unwrap();
+    let invitation = joiner.handle().fetch_invitation(&scanned).await.unwrap();
+    assert_eq!(invitation.certificate.role, Role::Device);
+    assert_eq!(invitation.ca_public_key, ca.public_key());
+    assert!(invitation.bootstrap_peers[0].ends_with(&peer_hub));
+
+    // The token is spent
+    assert!(matches!(
+        joiner.handle().fetch_invitation(&scanned).await,
+        Err(AviP2pError::Authentication(_))
+    ));
+}
+
+/// Stream protocol wire format (length-prefixed JSON), for a peer that
+/// sends messages a well-behaved node never would
+#[derive(Clone, Default)]
+struct RawStreamCodec;
+
+#[async_trait::async_trait]
+impl libp2p::request_response::Codec for RawStreamCodec {
+    type Protocol = libp2p::StreamProtocol;
+    type Request = serde_json::Value;
+    type Response = ();
+
+    async fn read_request<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+    ) -> std::io::Result<Self::Request>
+    where
+        T: futures::AsyncRead + Unpin + Send,
+    {
+        use futures::AsyncReadExt;
+        let mut len = [0u8; 4];
+        io.read_exact(&mut len).await?;
+        let mut buffer = vec![0u8; u32::from_be_bytes(len) as usize];
+        io.read_exact(&mut buffer).await?;
+        serde_json::from_slice(&buffer).map_err(std::io::Error::other)
+    }
+
+    async fn read_response<T>(&mut self, _: &Self::Protocol, _: &mut T) -> std::io::Result<()>
+    where
+        T: futures::AsyncRead + Unpin + Send,
+    {
+        Ok(())
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+        req: Self::Request,
+    ) -> std::io::Result<()>
+    where
+        T: futures::AsyncWrite + Unpin + Send,
+    {
+        use futures::AsyncWriteExt;
+        let encoded = serde_json::to_vec(&req).map_err(std::io::Error::other)?;
+        io.write_all(&(encoded.len() as u32).to_be_bytes()).await?;
+        io.write_all(&encoded).await?;
+        io.flush().await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+        _: (),
+    ) -> std::io::Result<()>
+    where
+        T: futures::AsyncWrite + Unpin + Send,
+    {
+        use futures::AsyncWriteExt;
+        io.flush().await
+    }
+}
+
+/// A peer that speaks the stream protocol by hand
+struct RawPeer {
+    key: libp2p::identity::Keypair,
+    swarm: libp2p::Swarm<libp2p::request_response::Behaviour<RawStreamCodec>>,
+    node: libp2p::PeerId,
+    /// Requests the node sent us, in arrival order
+    received: Vec<serde_json::Value>,
+}
+
+impl RawPeer {
+    /// Dial the node listening on `/memory/<port>`
+    async fn connect(port: u64) -> Self {
+        use futures::StreamExt;
+        use libp2p::core::{transport::MemoryTransport, upgrade, Transport};
+        use libp2p::request_response::{self, ProtocolSupport};
+        use libp2p::swarm::SwarmEvent;
+        use libp2p::{noise, yamux, StreamProtocol, SwarmBuilder};
+
+        let key = libp2p::identity::Keypair::generate_ed25519();
+        let mut swarm = SwarmBuilder::with_existing_identity(key.clone())
+            .with_tokio()
+            .with_other_transport(|key| {
+                Ok(MemoryTransport::default()
+                    .upgrade(upgrade::Version::V1)
+                    .authenticate(noise::Config::new(key)?)
+                    .multiplex(yamux::Config::default()))
+            })
+            .unwrap()
+            .with_behaviour(|_| {
+                request_response::Behaviour::<RawStreamCodec>::new(
+                    std::iter::once((
+                        StreamProtocol::new("/avi/stream/1.0.0"),
+                        ProtocolSupport::Full,
+                    )),
+                    request_response::Config::default(),
+                )
+            })
+            .unwrap()
+            .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60)))
+            .build();
+        swarm
+            .dial(
+                format!("/memory/{}", port)
+                    .parse::<libp2p::Multiaddr>()
+                    .unwrap(),
+            )
+            .unwrap();
+
+        let node = timeout(Duration::from_secs(5), async {
+            loop {
+                if let SwarmEvent::ConnectionEstablished { peer_id, .. } =
+                    swarm.select_next_some().await
+                {
+                    return peer_id;
+                }
+            }
+        })
+        .await
+        .expect("raw peer could not connect");
+        Self {
+            key,
+            swarm,
+            node,
+            received: Vec::new(),
+        }
+    }
+
+    fn peer_id(&self) -> PeerId {
+        PeerId::new(&self.key.public().to_peer_id().to_base58())
+    }
+
+    /// Send `messages` and return once the node has handled them all
+    async fn send(&mut self, messages: Vec<serde_json::Value>) {
+        use libp2p::request_response::{Event, Message};
+        use libp2p::swarm::SwarmEvent;
+
+        let mut pending = messages.len();
+        for message in messages {
+            self.swarm.behaviour_mut().send_request(&self.node, message);
+        }
+        timeout(Duration::from_secs(5), async {
+            while pending > 0 {
+                match self.next_event().await {
+                    SwarmEvent::Behaviour(Event::Message {
+                        message: Message::Response { .. },
+                        ..
+                    })
+                    | SwarmEvent::Behaviour(Event::OutboundFailure { .. }) => pending -= 1,
+                    _ => {}
+                }
+            }
+        })
+        .await
+        .expect("raw peer never got its messages through");
+    }
+
+    /// Wait for the node to send a request matching `pick`
+    async fn wait_for<T>(&mut self, pick: impl Fn(&serde_json::Value) -> Option<T>) -> T {
+        timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(found) = self.received.iter().find_map(&pick) {
+                    return found;
+                }
+                self.next_event().await;
+            }
+        })
+        .await
+        .expect("node never sent the expected request")
+    }
+
+    async fn next_event(
+        &mut self,
+    ) -> libp2p::swarm::SwarmEvent<libp2p::request_response::Event<serde_json::Value, ()>> {
+        use futures::StreamExt;
+        use libp2p::request_response::{Event, Message};
+        use libp2p::swarm::SwarmEvent;
+
+        let event = self.swarm.select_next_some().await;
+        if let SwarmEvent::Behaviour(Event::Message {
+            message: Message::Request {
+                request, channel, ..
+            },
+            ..
+        }) = event
+        {
+            let _ = self.swarm.behaviour_mut().send_response(channel, ());
+            self.received.push(request);
+            return self.swarm.select_next_some().await;
+        }
+        event
+    }
+}
+
+#[tokio::test]
+async fn test_relayed_stream_ignores_third_peers() {
+    let mut config_relay = AviP2pConfig::new("relay");
+    config_relay.listen_port = 4129;
+    config_relay.stream_relay = true;
+    let (node_relay, mut events_relay) = AviP2p::start_in_memory(config_relay).await.unwrap();
+    let relay_id = local_peer_id(&mut events_relay).await;
+
+    let mut config_target = AviP2pConfig::new("target");
+    config_target.bootstrap_peers = vec!["/memory/4129".to_string()];
+    let (node_target, mut events_target) = AviP2p::start_in_memory(config_target).await.unwrap();
+    let target_id = local_peer_id(&mut events_target).await;
+
+    let mut config_origin = AviP2pConfig::new("origin");
+    config_origin.bootstrap_peers = vec!["/memory/4129".to_string()];
+    let (node_origin, mut events_origin) = AviP2p::start_in_memory(config_origin).await.unwrap();
+
+    let stream_id = timeout(Duration::from_secs(5), async {
+        while node_relay.handle().connected_peers().await.unwrap().len() < 2 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        node_origin
+            .handle()
+            .request_stream_via(relay_id, &target_id, "audio")
+            .await
+            .unwrap()
+    })
+    .await
+    .expect("relay did not see both peers");
+    let onward = timeout(Duration::from_secs(5), async {
+        loop {
+            if let Some(AviEvent::StreamRequested { stream_id, .. }) = events_target.recv().await {
+                return stream_id;
+            }
+        }
+    })
+    .await
+    .expect("target never saw the relayed stream");
+    node_target
+        .handle()
+        .accept_stream(onward, None)
+        .await
+        .unwrap();
+    timeout(Duration::from_secs(5), async {
+        while !matches!(
+            events_origin.recv().await,
+            Some(AviEvent::StreamAccepted { .. })
+        ) {}
+    })
+    .await
+    .expect("origin never saw the acceptance");
+
+    // A third peer that knows both relay stream ids
+    let mut forged = Vec::new();
+    for id in [stream_id.0, onward.0] {
+        forged.push(serde_json::json!({
+            "StreamData": { "stream_id": id, "data": b"forged".to_vec() }
+        }));
+        forged.push(serde_json::json!({ "CloseStream": { "stream_id": id } }));
+    }
+    RawPeer::connect(4129).await.send(forged).await;
+
+    // The relayed stream still carries the origin's data, and only that
+    node_origin
+        .handle()
+        .send_stream_data(stream_id, b"frame".to_vec())
+        .await
+        .unwrap();
+    let data = timeout(Duration::from_secs(5), async {
+        loop {
+            match events_target.recv().await {
+                Some(AviEvent::StreamData { data, .. }) => return data,
+                Some(AviEvent::StreamClosed { .. }) => panic!("relayed stream was closed"),
+                _ => {}
+            }
+        }
+    })
+    .await
+    .expect("relayed data never arrived");
+    assert_eq!(data, b"frame");
+    while let Ok(event) = events_origin.try_recv() {
+        assert!(!matches!(
+            event,
+            AviEvent::StreamData { .. } | AviEvent::StreamClosed { .. }
+        ));
+    }
+}
+
+#[tokio::test]
+async fn test_unauthenticated_peer_only_reaches_guest_topics() {
+    let ca = HouseholdCa::generate();
+    let peer_a = started_peer_id(AviP2pConfig::new("probe").with_identity([5; 32])).await;
+    let mut config_a = AviP2pConfig::new("hub").with_identity([5; 32]);
+    config_a.listen_port = 4130;
+    let mut auth = AuthConfig::new(ca.public_key());
+    auth.certificate = Some(ca.issue(&PeerId::new(&peer_a), Role::Admin, None));
+    auth.guest_topics = vec!["guest/doorbell".to_string()];
+    config_a.auth = Some(auth);
+    let (node_a, mut events_a) = AviP2p::start_in_memory(config_a).await.unwrap();
+
+    // No certificate: the hub's challenge fails and it stays a guest
+    let mut config_b = AviP2pConfig::new("visitor");
+    config_b.bootstrap_peers = vec!["/memory/4130".to_string()];
+    let (node_b, _events_b) = AviP2p::start_in_memory(config_b).await.unwrap();
+
+    for topic in ["guest/doorbell", "home/lock"] {
+        node_a.handle().subscribe(topic).await.unwrap();
+        node_b.handle().subscribe(topic).await.unwrap();
+    }
+    timeout(Duration::from_secs(10), async {
+        loop {
+            if let Some(AviEvent::PeerAuthenticationFailed { .. }) = events_a.recv().await {
+                return;
+            }
+        }
+    })
+    .await
+    .expect("hub never challenged the visitor");
+
+    let topic = timeout(Duration::from_secs(10), async {
+        loop {
+            let b = node_b.handle();
+            let _ = b.publish("home/lock", b"unlock".to_vec()).await;
+            let _ = b.publish("guest/doorbell", b"ring".to_vec()).await;
+            if let Ok(Some(AviEvent::Message { topic, .. })) =
+                timeout(Duration::from_millis(200), events_a.recv()).await
+            {
+                return topic;
+            }
+        }
+    })
+    .await
+    .expect("guest topic never came through");
+    assert_eq!(topic, "guest/doorbell");
+    while let Ok(event) = events_a.try_recv() {
+        if let AviEvent::Message { topic, .. } = event {
+            assert_eq!(topic, "guest/doorbell");
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_auth_response_only_counts_for_the_pending_nonce() {
+    let ca = HouseholdCa::generate();
+    let peer_a = started_peer_id(AviP2pConfig::new("probe").with_identity([6; 32])).await;
+    let mut config_a = AviP2pConfig::new("hub").with_identity([6; 32]);
+    config_a.listen_port = 4131;
+    let mut auth = AuthConfig::new(ca.public_key());
+    auth.certificate = Some(ca.issue(&PeerId::new(&peer_a), Role::Admin, None));
+    config_a.auth = Some(auth);
+    let (_node_a, mut events_a) = AviP2p::start_in_memory(config_a).await.unwrap();
+    local_peer_id(&mut events_a).await;
+
+    let mut raw = RawPeer::connect(4131).await;
+    let certificate = ca.issue(&raw.peer_id(), Role::Device, None);
+    let nonce: Vec<u8> = raw
+        .wait_for(|request| serde_json::from_value(request["AuthChallenge"]["nonce"].clone()).ok())
+        .await;
+    let answer = |nonce: &[u8]| {
+        let signature = raw
+            .key
+            .sign(&[&b"avi-auth-challenge-v1"[..], nonce].concat())
+            .unwrap();
+        serde_json::json!({ "AuthResponse": {
+            "nonce": nonce,
+            "public_key": raw.key.public().encode_protobuf(),
+            "signature": signature,
+            "certificate": certificate,
+        }})
+    };
+
+    // A correctly signed answer to a challenge the hub never sent, the
+    // real answer, then the real answer again
+    let made_up = answer(&[7; 32]);
+    let real = answer(&nonce);
+    raw.send(vec![made_up, real.clone(), real]).await;
+
+    let mut authenticated = 0;
+    while let Ok(Some(event)) = timeout(Duration::from_millis(500), events_a.recv()).await {
+        match event {
+            AviEvent::PeerAuthenticated { peer_id, role } => {
+                assert_eq!((peer_id, role), (raw.peer_id(), Role::Device));
+                authenticated += 1;
+            }
+            AviEvent::PeerAuthenticationFailed { reason, .. } => panic!("{}", reason),
+            _ => {}
+        }
+    }
+    assert_eq!(authenticated, 1);
+}
+
+#[tokio::test]
+async fn test_roles_limit_what_a_node_may_do() {
+    let ca = HouseholdCa::generate();
+    let sensor = started_peer_id(AviP2pConfig::new("probe").with_identity([8; 32])).await;
+    let mut config = AviP2pConfig::new("sensor").with_identity([8; 32]);
+    let mut auth = AuthConfig::new(ca.public_key());
+    auth.certificate = Some(ca.issue(&PeerId::new(&sensor), Role::Device, None));
+    config.auth = Some(auth);
+    let (node, _events) = AviP2p::start_in_memory(config).await.unwrap();
+
+    // A compromised sensor cannot rewrite shared context
+    let refused = node
+        .handle()
+        .update_context(serde_json::json!({ "mode": "away" }))
+        .await;
+    assert!(matches!(
+        refused,
+        Err(AviP2pError::Unauthorized {
+            role: Role::Device,
+            operation: Operation::ContextWrite,
+        })
+    ));
+    node.handle().subscribe("home/lock").await.unwrap();
+
+    // Without a certificate a node is a guest, held to the guest topics
+    let mut config = AviP2pConfig::new("visitor");
+    let mut auth = AuthConfig::new(ca.public_key());
+    auth.guest_topics = vec!["guest/doorbell".to_string()];
+    config.auth = Some(auth);
+    let (guest, _events) = AviP2p::start_in_memory(config).await.unwrap();
+    assert!(matches!(
+        guest.handle().subscribe("home/lock").await,
+        Err(AviP2pError::Unauthorized {
+            role: Role::Guest,
+            ..
+        })
+    ));
+    guest.handle().subscribe("guest/doorbell").await.unwrap();
+}
+
+#[tokio::test]
+async fn test_only_the_requested_peer_settles_a_stream_open() {
+    let mut config_a = AviP2pConfig::new("node-a");
+    config_a.listen_port = 4132;
+    let (node_a, mut events_a) = AviP2p::start_in_memory(config_a).await.unwrap();
+
+    let mut config_b = AviP2pConfig::new("node-b");
+    config_b.bootstrap_peers = vec!["/memory/4132".to_string()];
+    let (node_b, mut events_b) = AviP2p::start_in_memory(config_b).await.unwrap();
+    let b_id = local_peer_id(&mut events_b).await;
+
+    let stream_id = timeout(Duration::from_secs(5), async {
+        loop {
+            if let Ok(id) = node_a
+                .handle()
+                .request_stream(b_id.clone(), "audio".to_string())
+                .await
+            {
+                return id;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("could not reach node B");
+
+    // A third peer that guessed the id answers first
+    let mut raw = RawPeer::connect(4132).await;
+    raw.send(vec![
+        serde_json::json!({ "AcceptStream": { "stream_id": stream_id.0, "version": 99 } }),
+        serde_json::json!({ "AcceptStream": { "stream_id": stream_id.0 } }),
+        serde_json::json!({ "RejectStream": { "stream_id": stream_id.0, "reason": "no" } }),
+    ])
+    .await;
+
+    let onward = timeout(Duration::from_secs(5), async {
+        loop {
+            if let Some(AviEvent::StreamRequested { stream_id, .. }) = events_b.recv().await {
+                return stream_id;
+            }
+        }
+    })
+    .await
+    .expect("node B never saw the request");
+    node_b.handle().accept_stream(onward, None).await.unwrap();
+
+    let accepted_by = timeout(Duration::from_secs(5), async {
+        loop {
+            match events_a.recv().await {
+                Some(AviEvent::StreamAccepted { peer_id, .. }) => return peer_id,
+                Some(AviEvent::StreamRejected { reason, .. }) => panic!("rejected: {}", reason),
+                _ => {}
+            }
+        }
+    })
+    .await
+    .expect("node B's acceptance never arrived");
+    assert_eq!(accepted_by, b_id);
+}
+
+#[tokio::test]
+async fn test_health_reports_a_live_node_until_shutdown() {
+    let mut config_a = AviP2pConfig::new("node-a");
+    config_a.listen_port = 4133;
+    let (node_a, _events_a) = AviP2p::start_in_memory(config_a).await.unwrap();
+
+    let mut config_b = AviP2pConfig::new("node-b");
+    config_b.bootstrap_peers = vec!["/memory/4133".to_string()];
+    let (node_b, _events_b) = AviP2p::start_in_memory(config_b).await.unwrap();
+
+    let a = node_a.handle();
+    let report = timeout(Duration::from_secs(10), async {
+        loop {
+            let report = a.health().await;
+            if report.connected_peers == 1 {
+                return report;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("node B never connected");
+    assert!(report.runtime_alive);
+    assert!(report
+        .listen_addresses
+        .iter()
+        .any(|addr| addr.contains("4133")));
+    assert!(report.command_queue.capacity > 0);
+    assert!(report.is_healthy());
+
+    // A stopped runtime is reported, not an error
+    let b = node_b.handle();
+    node_b.shutdown().await.unwrap();
+    let report = timeout(Duration::from_secs(10), async {
+        loop {
+            let report = b.health().await;
+            if !report.runtime_alive {
+                return report;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("stopped runtime still reported alive");
+    assert!(report.listen_addresses.is_empty());
+    assert!(!report.is_healthy());
+}
+
+/// A node fronting devices through a UDP bridge on `udp_port`. Its
+/// publishes are recorded as they leave, so tests need no gossip mesh.
+struct TestGateway {
+    node: AviP2p,
+    bridge: BridgeHandle,
+    published: Arc<Mutex<Vec<(String, Vec<u8>)>>>,
+}
+
+impl TestGateway {
+    async fn start(udp_port: u16, configure: impl FnOnce(&mut BridgeConfig)) -> Self {
+        Self::start_with(AviP2pConfig::new("gateway"), udp_port, configure).await
+    }
+
+    /// Like `start`, with the gateway's own node configured by the test
+    async fn start_with(
+        node_config: AviP2pConfig,
+        udp_port: u16,
+        configure: impl FnOnce(&mut BridgeConfig),
+    ) -> Self {
+        let (node, _events) = AviP2p::start_in_memory(node_config).await.unwrap();
+        let published = Arc::new(Mutex::new(Vec::new()));
+        let record = published.clone();
+        node.handle().add_outbound_interceptor(
+            InterceptScope::Global,
+            move |target: &OutboundTarget, data: Vec<u8>| {
+                if let OutboundTarget::Publish { topic } = target {
+                    record.lock().unwrap().push((topic.clone(), data.clone()));
+                }
+                Ok(data)
+            },
+        );
+
+        let mut config = BridgeConfig {
+            bind: vec![BridgeBinding::new(Ipv4Addr::LOCALHOST.into()).with_port(udp_port)],
+            ..Default::default()
+        };
+        configure(&mut config);
+        let bridge = EmbeddedBridge::start(node.handle(), config).await.unwrap();
+        Self {
+            node,
+            bridge,
+            published,
+        }
+    }
+
+    /// Payloads published on `topic` so far
+    fn published_on(&self, topic: &str) -> Vec<serde_json::Value> {
+        self.published
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(published, _)| published == topic)
+            .filter_map(|(_, data)| serde_json::from_slice(data).ok())
+            .collect()
+    }
+
+    /// First payload published on `topic`, waiting for it
+    async fn wait_for_publish(&self, topic: &str) -> serde_json::Value {
+        timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(payload) = self.published_on(topic).into_iter().next() {
+                    return payload;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("nothing was published on {}", topic))
+    }
+}
+
+/// A bridged device speaking postcard over UDP
+struct TestDevice {
+    socket: UdpSocket,
+}
+
+impl TestDevice {
+    async fn connect(udp_port: u16) -> Self {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        socket
+            .connect((Ipv4Addr::LOCALHOST, udp_port))
+            .await
+            .unwrap();
+        Self { socket }
+    }
+
+    /// Connect and open a session as `device_id`
+    async fn hello(udp_port: u16, device_id: u64) -> Self {
+        let device = Self::connect(udp_port).await;
+        device.send(&UplinkMessage::Hello { device_id }).await;
+        device
+            .wait_for(|msg| matches!(msg, DownlinkMessage::Welcome).then_some(()))
+            .await;
+        device
+    }
+
+    async fn send(&self, msg: &UplinkMessage<'_>) {
+        let mut buf = [0u8; MAX_PACKET_SIZE];
+        let packet = postcard::to_slice(msg, &mut buf).unwrap();
+        self.socket.send(packet).await.unwrap();
+    }
+
+    /// The first downlink message `pick` accepts, skipping others
+    async fn wait_for<T>(&self, mut pick: impl FnMut(DownlinkMessage) -> Option<T>) -> T {
+        timeout(Duration::from_secs(5), async {
+            let mut buf = [0u8; MAX_PACKET_SIZE];
+            loop {
+                let len = self.socket.recv(&mut buf).await.unwrap();
+                if let Some(picked) = postcard::from_bytes(&buf[..len]).ok().and_then(&mut pick) {
+                    return picked;
+                }
+            }
+        })
+        .await
+        .expect("the gateway never sent the expected message")
+    }
+}
+
+#[tokio::test]
+async fn test_bridge_sensor_ids_stand_for_their_registered_names() {
+    let gateway = TestGateway::start(47101, |_| {}).await;
+    let device = TestDevice::hello(47101, 7).await;
+
+    device
+        .send(&UplinkMessage::RegisterSensor {
+            id: 1,
+            name: "temp",
+        })
+        .await;
+    device
+        .send(&UplinkMessage::SensorUpdateById {
+            id: 1,
+            data: SensorValue::Temperature(21.5),
+            custom_data: "",
+        })
+        .await;
+    let reading = gateway.wait_for_publish("device/7/sensor/temp").await;
+    assert_eq!(reading["name"], "temp");
+    assert_eq!(reading["data"]["value"], 21.5);
+    let handle = gateway.node.handle();
+    timeout(Duration::from_secs(5), async {
+        while handle.get_ctx("avi.sensors.7.temp.data.value").await.ok() != Some(21.5.into()) {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("the reading was not mirrored into the context");
+
+    // An id the device never registered is refused, not guessed at
+    device
+        .send(&UplinkMessage::SensorUpdateById {
+            id: 9,
+            data: SensorValue::Raw(1),
+            custom_data: "",
+        })
+        .await;
+    let reason = device
+        .wait_for(|msg| match msg {
+            DownlinkMessage::Error { reason } => Some(reason),
+            _ => None,
+        })
+        .await;
+    assert_eq!(reason, ERROR_UNKNOWN_SENSOR);
+}
+
+#[tokio::test]
+async fn test_bridge_publishes_only_changed_digital_inputs() {
+    let gateway = TestGateway::start(47102, |_| {}).await;
+    let device = TestDevice::hello(47102, 8).await;
+
+    // Inputs 0 and 2 are high, but only 0 and 1 changed
+    device
+        .send(&UplinkMessage::DigitalInputs {
+            bitmap: 0b101,
+            changed_mask: 0b011,
+        })
+        .await;
+    let first = gateway.wait_for_publish("device/8/input/0").await;
+    let second = gateway.wait_for_publish("device/8/input/1").await;
+    assert_eq!(
+        (first["input"].clone(), first["state"].clone()),
+        (0.into(), true.into())
+    );
+    assert_eq!(
+        (second["input"].clone(), second["state"].clone()),
+        (1.into(), false.into())
+    );
+
+    // Packets are handled in order, so anything for input 2 came before this
+    device
+        .send(&UplinkMessage::Publish {
+            topic: "test/done",
+            data: b"{}",
+        })
+        .await;
+    gateway.wait_for_publish("test/done").await;
+    assert!(gateway.published_on("device/8/input/2").is_empty());
+}
+
+#[tokio::test]
+async fn test_bridge_answers_gateway_discovery_without_a_session() {
+    let _gateway = TestGateway::start(47103, |config| config.name = "attic".to_string()).await;
+    let device = TestDevice::connect(47103).await;
+
+    device.send(&UplinkMessage::DiscoverGateway).await;
+    let (port, name) = device
+        .wait_for(|msg| match msg {
+            DownlinkMessage::GatewayAnnounce { port, name } => Some((port, name.to_string())),
+            _ => None,
+        })
+        .await;
+    assert_eq!((port, name.as_str()), (47103, "attic"));
+}
+
+#[tokio::test]
+async fn test_bridge_announces_devices_online_and_offline() {
+    let gateway = TestGateway::start(47104, |_| {}).await;
+    let mut events = gateway.node.handle().subscribe_events().await.unwrap();
+    let _device = TestDevice::hello(47104, 11).await;
+
+    let online = gateway.wait_for_publish("device/11/status").await;
+    assert_eq!(online["status"], "online");
+    let (device_id, address) = timeout(Duration::from_secs(5), async {
+        loop {
+            if let Some(AviEvent::BridgedDeviceOnline { device_id, address }) = events.recv().await
+            {
+                return (device_id, address);
+            }
+        }
+    })
+    .await
+    .expect("the device was not reported online");
+    assert_eq!(device_id, 11);
+    assert!(address.starts_with("127.0.0.1:"));
+
+    gateway.bridge.stop().await;
+    let statuses: Vec<_> = gateway
+        .published_on("device/11/status")
+        .into_iter()
+        .map(|payload| payload["status"].clone())
+        .collect();
+    assert_eq!(statuses, ["online", "offline"]);
+    let offline = timeout(Duration::from_secs(5), async {
+        loop {
+            if let Some(AviEvent::BridgedDeviceOffline { device_id }) = events.recv().await {
+                return device_id;
+            }
+        }
+    })
+    .await
+    .expect("the device was not reported offline");
+    assert_eq!(offline, 11);
+}
+
+#[tokio::test]
+async fn test_bridge_forwards_subscribed_mesh_messages_to_devices() {
+    let mut config_peer = AviP2pConfig::new("node-a");
+    config_peer.listen_port = 4135;
+    let (peer, _events_peer) = AviP2p::start_in_memory(config_peer).await.unwrap();
+    peer.handle().subscribe("lights/kitchen").await.unwrap();
+
+    let mut config_gateway = AviP2pConfig::new("gateway");
+    config_gateway.bootstrap_peers = vec!["/memory/4135".to_string()];
+    let _gateway = TestGateway::start_with(config_gateway, 47105, |_| {}).await;
+    let device = TestDevice::hello(47105, 12).await;
+
+    device
+        .send(&UplinkMessage::Subscribe {
+            topic: "lights/kitchen",
+        })
+        .await;
+    device
+        .wait_for(|msg| {
+            matches!(msg, DownlinkMessage::SubscribeAck { topic } if topic == "lights/kitchen")
+                .then_some(())
+        })
+        .await;
+    let topic_id = device
+        .wait_for(|msg| match msg {
+            DownlinkMessage::TopicAlias { topic_id, topic } if topic == "lights/kitchen" => {
+                Some(topic_id)
+            }
+            _ => None,
+        })
+        .await;
+
+    // Publish until the gossipsub mesh has formed and messages get through
+    let publisher = peer.handle();
+    let publishing = tokio::spawn(async move {
+        for n in 0.. {
+            let _ = publisher
+                .publish("lights/kitchen", format!("on {}", n).into_bytes())
+                .await;
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+    });
+
+    // The full topic is used until the device confirms the alias
+    let data = device
+        .wait_for(|msg| match msg {
+            DownlinkMessage::Message { topic, data } if topic == "lights/kitchen" => {
+                Some(data.to_vec())
+            }
+            _ => None,
+        })
+        .await;
+    assert!(data.starts_with(b"on "));
+
+    device
+        .send(&UplinkMessage::TopicAliasAck { topic_id })
+        .await;
+    let data = device
+        .wait_for(|msg| match msg {
+            DownlinkMessage::AliasedMessage { topic_id: id, data } if id == topic_id => {
+                Some(data.to_vec())
+            }
+            _ => None,
+        })
+        .await;
+    assert!(data.starts_with(b"on "));
+    publishing.abort();
+}
+
+/// Answers presses of one button locally, leaving the rest to the bridge
+struct LocalButton {
+    button: u8,
+}
+
+#[async_trait]
+impl BridgeHandler for LocalButton {
+    async fn on_uplink(&self, ctx: &BridgeContext, msg: &UplinkMessage<'_>) -> HandlerOutcome {
+        match msg {
+            UplinkMessage::ButtonPress { button_id, .. } if *button_id == self.button => {
+                assert_eq!(ctx.device_id, Some(13));
+                let ack = DownlinkMessage::Message {
+                    topic: "local/button",
+                    data: b"handled",
+                };
+                ctx.reply(&ack).await.unwrap();
+                HandlerOutcome::Handled
+            }
+            _ => HandlerOutcome::Continue,
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_bridge_handlers_answer_packets_before_the_built_in_handling() {
+    let gateway = TestGateway::start(47106, |config| {
+        config.handlers.push(Arc::new(LocalButton { button: 1 }));
+    })
+    .await;
+    let device = TestDevice::hello(47106, 13).await;
+
+    let press = |button_id| UplinkMessage::ButtonPress {
+        button_id,
+        press_type: PressType::Single,
+        custom_data: "",
+    };
+    device.send(&press(1)).await;
+    let data = device
+        .wait_for(|msg| match msg {
+            DownlinkMessage::Message { topic, data } if topic == "local/button" => {
+                Some(data.to_vec())
+            }
+            _ => None,
+        })
+        .await;
+    assert_eq!(data, b"handled");
+
+    // Packets are handled in order, so only the second press reached the mesh
+    device.send(&press(2)).await;
+    gateway.wait_for_publish("device/13/button").await;
+    let presses: Vec<_> = gateway
+        .published_on("device/13/button")
+        .into_iter()
+        .map(|payload| payload["button_id"].clone())
+        .collect();
+    assert_eq!(presses, [2]);
+}
+
+#[tokio::test]
+async fn test_bridge_evicts_the_least_recently_seen_session_when_full() {
+    let gateway = TestGateway::start(47107, |config| config.max_sessions = 2).await;
+    let _first = TestDevice::hello(47107, 21).await;
+    let _second = TestDevice::hello(47107, 22).await;
+    let _third = TestDevice::hello(47107, 23).await;
+
+    assert!(gateway.bridge.device_stats(21).await.is_none());
+    assert!(gateway.bridge.device_stats(22).await.is_some());
+    assert!(gateway.bridge.device_stats(23).await.is_some());
+    timeout(Duration::from_secs(5), async {
+        while !gateway
+            .published_on("device/21/status")
+            .iter()
+            .any(|payload| payload["status"] == "offline")
+        {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("the evicted device was not announced offline");
+}
+
+#[tokio::test]
+async fn test_bridge_refuses_hellos_past_the_per_ip_limit() {
+    let gateway = TestGateway::start(47108, |config| config.max_sessions_per_ip = 1).await;
+    let _first = TestDevice::hello(47108, 24).await;
+
+    let second = TestDevice::connect(47108).await;
+    second.send(&UplinkMessage::Hello { device_id: 25 }).await;
+    let reason = second
+        .wait_for(|msg| match msg {
+            DownlinkMessage::Error { reason } => Some(reason),
+            _ => None,
+        })
+        .await;
+    assert_eq!(reason, ERROR_SESSION_LIMIT);
+    assert!(gateway.bridge.device_stats(25).await.is_none());
+    assert!(gateway.bridge.device_stats(24).await.is_some());
+    assert_eq!(
+        gateway.node.handle().health().await.bridge_rejected_hellos,
+        1
+    );
+}
+
+#[tokio::test]
+async fn test_bridge_loopback_echoes_streams_and_keeps_publishes_local() {
+    let gateway = TestGateway::start(47109, |config| config.loopback = true).await;
+    let device = TestDevice::hello(47109, 14).await;
+
+    device
+        .send(&UplinkMessage::Publish {
+            topic: "test/loopback",
+            data: b"{}",
+        })
+        .await;
+    device
+        .send(&UplinkMessage::StreamStart {
+            local_stream_id: 5,
+            target_peer_id: "",
+            reason: "camera",
+        })
+        .await;
+    device
+        .send(&UplinkMessage::StreamData {
+            local_stream_id: 5,
+            data: b"frame",
+        })
+        .await;
+    let echoed = device
+        .wait_for(|msg| match msg {
+            DownlinkMessage::StreamData {
+                local_stream_id,
+                data,
+            } => Some((local_stream_id, data.to_vec())),
+            _ => None,
+        })
+        .await;
+    assert_eq!(echoed, (5, b"frame".to_vec()));
+
+    // Packets are handled in order, so the publish was already dealt with
+    let published = gateway.published.lock().unwrap().len();
+    assert_eq!(published, 0, "loopback mode published on the mesh");
+}
+
+#[tokio::test]
+async fn test_bridge_counts_traffic_per_device() {
+    let gateway = TestGateway::start(47110, |_| {}).await;
+    let device = TestDevice::hello(47110, 15).await;
+    let hello = UplinkMessage::Hello { device_id: 15 };
+    let publish = UplinkMessage::Publish {
+        topic: "test/stats",
+        data: b"{}",
+    };
+    let mut buf = [0u8; MAX_PACKET_SIZE];
+    let hello_len = postcard::to_slice(&hello, &mut buf).unwrap().len() as u64;
+    let publish_len = postcard::to_slice(&publish, &mut buf).unwrap().len() as u64;
+
+    device.socket.send(&[0xff; 8]).await.unwrap();
+    device.send(&publish).await;
+    gateway.wait_for_publish("test/stats").await;
+
+    let stats = gateway.bridge.device_stats(15).await.unwrap();
+    assert_eq!(stats.device_id, 15);
+    assert_eq!(stats.packets_in, 2);
+    assert_eq!(stats.bytes_in, hello_len + publish_len);
+    assert_eq!(stats.decode_failures, 1);
+    assert!(stats.packets_out >= 1, "the Welcome was not counted");
+    assert_eq!(gateway.bridge.all_device_stats().await.len(), 1);
+    assert!(gateway.bridge.device_stats(16).await.is_none());
+}
+
+/// Holds every `Extension` packet until the test releases it
+struct HeldExtensions {
+    entered: Arc<tokio::sync::Notify>,
+    release: Arc<tokio::sync::Notify>,
+}
+
+#[async_trait]
+impl BridgeHandler for HeldExtensions {
+    async fn on_uplink(&self, ctx: &BridgeContext, msg: &UplinkMessage<'_>) -> HandlerOutcome {
+        let UplinkMessage::Extension { .. } = msg else {
+            return HandlerOutcome::Continue;
+        };
+        self.entered.notify_one();
+        self.release.notified().await;
+        let done = DownlinkMessage::Message {
+            topic: "local/extension",
+            data: b"released",
+        };
+        ctx.reply(&done).await.unwrap();
+        HandlerOutcome::Handled
+    }
+}
+
+#[tokio::test]
+async fn test_bridge_handles_each_device_without_waiting_on_the_others() {
+    let entered = Arc::new(tokio::sync::Notify::new());
+    let release = Arc::new(tokio::sync::Notify::new());
+    let handler = HeldExtensions {
+        entered: entered.clone(),
+        release: release.clone(),
+    };
+    let _gateway = TestGateway::start(47111, |config| {
+        config.handlers.push(Arc::new(handler));
+    })
+    .await;
+
+    let slow = TestDevice::hello(47111, 31).await;
+    slow.send(&UplinkMessage::Extension {
+        kind: 1,
+        data: b"slow",
+    })
+    .await;
+    timeout(Duration::from_secs(5), entered.notified())
+        .await
+        .expect("the handler never saw the packet");
+
+    // The held packet does not keep another device from being welcomed
+    let _other = TestDevice::hello(47111, 32).await;
+
+    release.notify_one();
+    let data = slow
+        .wait_for(|msg| match msg {
+            DownlinkMessage::Message { topic, data } if topic == "local/extension" => {
+                Some(data.to_vec())
+            }
+            _ => None,
+        })
+        .await;
+    assert_eq!(data, b"released");
+}
+
+#[tokio::test]
+async fn test_runtime_stats_follow_the_runtime_working() {
+    let mut config_a = AviP2pConfig::new("node-a");
+    config_a.listen_port = 4134;
+    let (node_a, _events_a) = AviP2p::start_in_memory(config_a).await.unwrap();
+
+    let mut config_b = AviP2pConfig::new("node-b");
+    config_b.bootstrap_peers = vec!["/memory/4134".to_string()];
+    let (node_b, _events_b) = AviP2p::start_in_memory(config_b).await.unwrap();
+    let b = node_b.handle();
+
+    let idle = b.runtime_stats().await.unwrap();
+    assert_eq!(idle.pending_outbound_requests, 0);
+    assert_eq!(idle.command_queue.used, 0);
+    assert!(idle.command_queue.capacity > 0);
+    assert!(idle.event_queue.capacity > 0);
+
+    let peer_a = timeout(Duration::from_secs(5), async {
+        loop {
+            if let Some(peer) = b.connected_peers().await.unwrap().pop() {
+                return peer;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("nodes did not connect");
+    for _ in 0..5 {
+        b.send_to(&peer_a, b"hello".to_vec()).await.unwrap();
+    }
+
+    // Every answered request is forgotten, and the handling time is tracked
+    let busy = b.runtime_stats().await.unwrap();
+    assert_eq!(busy.pending_outbound_requests, 0);
+    assert!(busy.max_poll_latency > Duration::ZERO);
+    assert!(busy.max_poll_latency >= idle.max_poll_latency);
+    assert!(busy.poll_latency <= busy.max_poll_latency);
+    drop(node_a);
+}
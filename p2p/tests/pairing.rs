@@ -0,0 +1,57 @@
+use avi_p2p::{AuthConfig, AviEvent, AviP2p, AviP2pConfig, HouseholdCa, PeerId, Role};
+use std::time::Duration;
+use tokio::time::timeout;
+
+#[tokio::test]
+async fn test_device_joins_via_pairing_payload() {
+    let ca = HouseholdCa::generate();
+    let mut config_hub = AviP2pConfig::new("hub").with_identity([5; 32]);
+    config_hub.enable_mdns = false;
+    let (probe, mut probe_events) = AviP2p::start(config_hub.clone()).await.unwrap();
+    let peer_hub = timeout(Duration::from_secs(5), async {
+        loop {
+            if let Some(AviEvent::Started { local_peer_id, .. }) = probe_events.recv().await {
+                return local_peer_id;
+            }
+        }
+    })
+    .await
+    .expect("hub did not start");
+    probe.shutdown().await.unwrap();
+
+    let mut auth = AuthConfig::new(ca.public_key());
+    auth.certificate = Some(ca.issue(&peer_hub, Role::Admin, None));
+    config_hub.auth = Some(auth);
+    let (hub, mut events_hub) = AviP2p::start(config_hub).await.unwrap();
+    let payload = timeout(Duration::from_secs(5), async {
+        loop {
+            let payload = hub
+                .handle()
+                .create_pairing(&ca, Role::Device, Duration::from_secs(60))
+                .await
+                .unwrap();
+            if !payload.addresses.is_empty() {
+                return payload;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("hub did not listen");
+
+    let (_speaker, _events_speaker) = AviP2p::join_via_pairing("speaker", &payload).await.unwrap();
+    let joined: PeerId = timeout(Duration::from_secs(10), async {
+        loop {
+            if let Some(AviEvent::PeerAuthenticated {
+                peer_id,
+                role: Role::Device,
+            }) = events_hub.recv().await
+            {
+                return peer_id;
+            }
+        }
+    })
+    .await
+    .expect("speaker did not authenticate with the hub");
+    assert_ne!(joined, peer_hub);
+}
@@ -0,0 +1,56 @@
+use avi_p2p::{AviEvent, AviP2p, AviP2pConfig, TransportKind};
+use std::time::Duration;
+use tokio::time::timeout;
+
+fn quic_only(name: &str) -> AviP2pConfig {
+    let mut config = AviP2pConfig::new(name);
+    config.transports = vec![TransportKind::Quic];
+    config.enable_mdns = false;
+    config
+}
+
+#[tokio::test]
+async fn test_pubsub_over_quic() {
+    let (node_a, mut events_a) = AviP2p::start(quic_only("node-a")).await.unwrap();
+    let address = timeout(Duration::from_secs(5), async {
+        loop {
+            if let Some(AviEvent::Started {
+                listen_addresses, ..
+            }) = events_a.recv().await
+            {
+                if let Some(address) = listen_addresses
+                    .into_iter()
+                    .find(|a| a.starts_with("/ip4/127.0.0.1/"))
+                {
+                    return address;
+                }
+            }
+        }
+    })
+    .await
+    .expect("node A did not listen on loopback");
+    assert!(address.ends_with("/quic-v1"));
+
+    let mut config_b = quic_only("node-b");
+    config_b.bootstrap_peers = vec![address];
+    let (node_b, _events_b) = AviP2p::start(config_b).await.unwrap();
+
+    node_a.handle().subscribe("test/topic").await.unwrap();
+    node_b.handle().subscribe("test/topic").await.unwrap();
+    let data = timeout(Duration::from_secs(10), async {
+        loop {
+            let _ = node_b
+                .handle()
+                .publish("test/topic", b"ping".to_vec())
+                .await;
+            if let Ok(Some(AviEvent::Message { data, .. })) =
+                timeout(Duration::from_millis(200), events_a.recv()).await
+            {
+                return data;
+            }
+        }
+    })
+    .await
+    .expect("message over QUIC");
+    assert_eq!(data, b"ping");
+}
@@ -0,0 +1,63 @@
+#![cfg(feature = "memory-transport")]
+
+use avi_p2p::{AviEvent, AviP2pConfig, AviP2pError, SimNetwork};
+use serde_json::json;
+use std::time::Duration;
+
+#[tokio::test]
+async fn test_context_converges_after_partition_heals() {
+    let sim = SimNetwork::start(3).await.unwrap();
+    assert!(sim.wait_for_full_mesh(Duration::from_secs(10)).await);
+
+    sim.partition(&[0], &[1, 2]);
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let writer = sim.handle(1).unwrap();
+    writer
+        .update_context(json!({ "lights": { "kitchen": "on" } }))
+        .await
+        .unwrap();
+
+    let is_kitchen_update = |e: &AviEvent| matches!(e, AviEvent::ContextUpdated { context, .. } if context["lights"]["kitchen"] == "on");
+    assert!(sim
+        .wait_for(2, Duration::from_secs(5), is_kitchen_update)
+        .await
+        .is_some());
+    assert!(sim
+        .wait_for(0, Duration::from_millis(500), is_kitchen_update)
+        .await
+        .is_none());
+
+    sim.heal();
+    assert!(sim
+        .wait_for(0, Duration::from_secs(15), is_kitchen_update)
+        .await
+        .is_some());
+    assert!(sim
+        .wait_for(0, Duration::from_secs(5), |e| matches!(
+            e,
+            AviEvent::ContextSynced { updated: true, .. }
+        ))
+        .await
+        .is_some());
+}
+
+#[tokio::test]
+async fn test_context_fetch_times_out_on_slow_peer() {
+    let configs = (0..2)
+        .map(|i| {
+            let mut config = AviP2pConfig::new(&format!("sim-{}", i));
+            config.context_fetch_timeout = Duration::from_millis(300);
+            config
+        })
+        .collect();
+    let sim = SimNetwork::start_with(configs).await.unwrap();
+    assert!(sim.wait_for_full_mesh(Duration::from_secs(10)).await);
+
+    sim.set_latency(0, 1, Duration::from_secs(2));
+    let handle = sim.handle(0).unwrap();
+    let peer = handle.connected_peers().await.unwrap().pop().unwrap();
+
+    let res = handle.fetch_context(peer, true).await;
+    assert!(matches!(res, Err(AviP2pError::ContextFetchTimeout(_))));
+}
@@ -0,0 +1,244 @@
+//! Framing for byte-stream transports (UART, TCP).
+//!
+//! Each frame is `COBS(payload || CRC-16) || 0x00`. COBS guarantees the
+//! payload contains no zero bytes, so the `0x00` delimiter always marks a
+//! frame boundary: after a corrupt or truncated frame the `Decoder` simply
+//! drops everything up to the next delimiter and carries on.
+
+const CRC_LEN: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FrameError {
+    /// Output buffer can't hold the encoded frame
+    BufferTooSmall,
+    /// Frame longer than the decoder buffer; dropped up to the next delimiter
+    Overflow,
+    /// Invalid COBS encoding
+    Corrupt,
+    /// CRC mismatch
+    BadChecksum,
+}
+
+/// Worst-case encoded size of a frame carrying `payload_len` bytes
+pub const fn max_frame_len(payload_len: usize) -> usize {
+    let n = payload_len + CRC_LEN;
+    n + n / 254 + 2
+}
+
+/// Encode `payload` as a delimited frame into `out`, returning the frame length
+pub fn encode_frame(payload: &[u8], out: &mut [u8]) -> Result<usize, FrameError> {
+    let crc = crc16(payload).to_be_bytes();
+
+    let mut code_idx = 0;
+    let mut out_idx = 1;
+    let mut code = 1u8;
+
+    for &byte in payload.iter().chain(crc.iter()) {
+        if byte == 0 {
+            *out.get_mut(code_idx).ok_or(FrameError::BufferTooSmall)? = code;
+            code_idx = out_idx;
+            out_idx += 1;
+            code = 1;
+            continue;
+        }
+
+        *out.get_mut(out_idx).ok_or(FrameError::BufferTooSmall)? = byte;
+        out_idx += 1;
+        code += 1;
+        if code == 0xFF {
+            *out.get_mut(code_idx).ok_or(FrameError::BufferTooSmall)? = code;
+            code_idx = out_idx;
+            out_idx += 1;
+            code = 1;
+        }
+    }
+
+    *out.get_mut(code_idx).ok_or(FrameError::BufferTooSmall)? = code;
+    *out.get_mut(out_idx).ok_or(FrameError::BufferTooSmall)? = 0;
+    Ok(out_idx + 1)
+}
+
+/// Incremental frame decoder holding at most `N` encoded bytes per frame.
+/// Feed it bytes as they arrive; it yields each complete frame's payload.
+/// An empty frame (a lone `0x00`) is ignored, so senders may emit one to
+/// flush any garbage the receiver has buffered.
+pub struct Decoder<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+    discarding: bool,
+}
+
+impl<const N: usize> Decoder<N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+            discarding: false,
+        }
+    }
+
+    /// Push one byte. Returns `Some` when a delimiter completes a frame,
+    /// with either its payload or the reason it was dropped.
+    pub fn feed(&mut self, byte: u8) -> Option<Result<&[u8], FrameError>> {
+        if byte != 0 {
+            if self.discarding {
+                return None;
+            }
+            if self.len == N {
+                self.len = 0;
+                self.discarding = true;
+                return Some(Err(FrameError::Overflow));
+            }
+            self.buf[self.len] = byte;
+            self.len += 1;
+            return None;
+        }
+
+        let len = core::mem::take(&mut self.len);
+        if core::mem::take(&mut self.discarding) || len == 0 {
+            return None;
+        }
+        Some(decode_in_place(&mut self.buf[..len]))
+    }
+
+    /// Drop any partially received frame
+    pub fn reset(&mut self) {
+        self.len = 0;
+        self.discarding = false;
+    }
+}
+
+impl<const N: usize> Default for Decoder<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// COBS-decode `buf` in place and verify the trailing CRC
+fn decode_in_place(buf: &mut [u8]) -> Result<&[u8], FrameError> {
+    let len = buf.len();
+    let mut read = 0;
+    let mut write = 0;
+
+    while read < len {
+        let code = buf[read] as usize;
+        if code == 0 || read + code > len {
+            return Err(FrameError::Corrupt);
+        }
+        read += 1;
+
+        for _ in 1..code {
+            buf[write] = buf[read];
+            write += 1;
+            read += 1;
+        }
+
+        if code < 0xFF && read < len {
+            buf[write] = 0;
+            write += 1;
+        }
+    }
+
+    if write < CRC_LEN {
+        return Err(FrameError::Corrupt);
+    }
+
+    let (payload, crc) = buf[..write].split_at(write - CRC_LEN);
+    if crc16(payload).to_be_bytes() != crc {
+        return Err(FrameError::BadChecksum);
+    }
+    Ok(payload)
+}
+
+/// CRC-16/CCITT-FALSE
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all<const N: usize>(
+        decoder: &mut Decoder<N>,
+        bytes: &[u8],
+        mut on_frame: impl FnMut(Result<&[u8], FrameError>),
+    ) {
+        for &b in bytes {
+            if let Some(frame) = decoder.feed(b) {
+                on_frame(frame);
+            }
+        }
+    }
+
+    #[test]
+    fn test_round_trip_with_zeros() {
+        let payload = [0u8, 1, 0, 0, 2, 3, 0];
+        let mut out = [0u8; max_frame_len(7)];
+        let n = encode_frame(&payload, &mut out).unwrap();
+        assert!(!out[..n - 1].contains(&0));
+
+        let mut decoder = Decoder::<64>::new();
+        let mut frames = 0;
+        decode_all(&mut decoder, &out[..n], |frame| {
+            assert_eq!(frame, Ok(&payload[..]));
+            frames += 1;
+        });
+        assert_eq!(frames, 1);
+    }
+
+    #[test]
+    fn test_resync_after_corruption() {
+        let mut first = [0u8; 32];
+        let n1 = encode_frame(b"hello", &mut first).unwrap();
+        first[2] ^= 0x55;
+
+        let mut second = [0u8; 32];
+        let n2 = encode_frame(b"world", &mut second).unwrap();
+
+        let mut decoder = Decoder::<64>::new();
+        let mut results = [None, None];
+        let mut i = 0;
+        for chunk in [&first[..n1], &second[..n2]] {
+            decode_all(&mut decoder, chunk, |frame| {
+                results[i] = Some(frame.map(|p| p == b"world"));
+                i += 1;
+            });
+        }
+        assert_eq!(
+            results,
+            [Some(Err(FrameError::BadChecksum)), Some(Ok(true))]
+        );
+    }
+
+    #[test]
+    fn test_long_payload_and_overflow() {
+        let payload = [7u8; 600];
+        let mut out = [0u8; max_frame_len(600)];
+        let n = encode_frame(&payload, &mut out).unwrap();
+
+        let mut big = Decoder::<700>::new();
+        let mut ok = false;
+        decode_all(&mut big, &out[..n], |frame| ok = frame == Ok(&payload[..]));
+        assert!(ok);
+
+        let mut small = Decoder::<100>::new();
+        let mut overflowed = false;
+        decode_all(&mut small, &out[..n], |frame| {
+            overflowed = frame == Err(FrameError::Overflow)
+        });
+        assert!(overflowed);
+    }
+}
@@ -1,9 +1,37 @@
 #![no_std]
 use serde::{Deserialize, Serialize};
 
+pub mod framing;
+pub use framing::{encode_frame, max_frame_len, Decoder, FrameError};
+
 pub const MAX_PACKET_SIZE: usize = 1024;
 
+/// UDP port gateways listen on unless configured otherwise;
+/// devices broadcast `DiscoverGateway` to it
+pub const DEFAULT_GATEWAY_PORT: u16 = 8888;
+
+/// `DownlinkMessage::Error` reason: a `SensorUpdateById` used an id that
+/// was never registered in this session (re-send `RegisterSensor`)
+pub const ERROR_UNKNOWN_SENSOR: u8 = 1;
+
+/// `DownlinkMessage::Error` reason: the gateway refused a `Hello` because
+/// too many devices are connected from the same address
+pub const ERROR_SESSION_LIMIT: u8 = 2;
+
+/// `DownlinkMessage::Error` reason: `HelloCodec` asked for a codec the
+/// gateway does not have (fall back to a plain `Hello`)
+pub const ERROR_UNSUPPORTED_CODEC: u8 = 3;
+
+/// `DownlinkMessage::Error` reason: a `PublishById` used a topic id that
+/// was never registered in this session (re-send `RegisterTopic`)
+pub const ERROR_UNKNOWN_TOPIC: u8 = 4;
+
+/// Wire codec ids for `HelloCodec`
+pub const CODEC_POSTCARD: u8 = 0;
+pub const CODEC_CBOR: u8 = 1;
+
 #[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum PressType {
     Single,
     Double,
@@ -11,6 +39,7 @@ pub enum PressType {
 }
 
 #[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum SensorValue {
     Temperature(f32),
     Humidity(f32),
@@ -20,6 +49,7 @@ pub enum SensorValue {
 }
 
 #[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum UplinkMessage<'a> {
     Hello {
         device_id: u64,
@@ -65,9 +95,74 @@ pub enum UplinkMessage<'a> {
         data: SensorValue,
         custom_data: &'a str,
     },
+
+    // Compact sensor ids: register a name once, then update by id
+    RegisterSensor {
+        id: u16,
+        name: &'a str,
+    },
+    SensorUpdateById {
+        id: u16,
+        data: SensorValue,
+        custom_data: &'a str,
+    },
+
+    // Up to 32 binary inputs (bit n = input n); only bits in `changed_mask` are published
+    DigitalInputs {
+        bitmap: u32,
+        changed_mask: u32,
+    },
+
+    // Discovery (sent to the broadcast/multicast address, no session needed)
+    DiscoverGateway,
+
+    // The device stored a `TopicAlias`; the gateway may now use `AliasedMessage`
+    TopicAliasAck {
+        topic_id: u16,
+    },
+
+    // Vendor-specific payload, handled by application `BridgeHandler`s
+    Extension {
+        kind: u16,
+        #[serde(with = "serde_bytes")]
+        data: &'a [u8],
+    },
+
+    // `Hello` that also selects the wire codec (`CODEC_*`) for the session
+    HelloCodec {
+        device_id: u64,
+        codec: u8,
+    },
+
+    // Link quality as the device measures it, for the gateway's diagnostics
+    LinkQuality {
+        rssi: i8,
+        latency_ms: u16,
+    },
+
+    // Compact publish topics: register a topic once, then publish by id
+    RegisterTopic {
+        topic_id: u16,
+        topic: &'a str,
+    },
+    PublishById {
+        topic_id: u16,
+        #[serde(with = "serde_bytes")]
+        data: &'a [u8],
+    },
+
+    // `Publish` the gateway forwards only once per `message_id`, so a
+    // device that missed the ack can send it again
+    PublishWithId {
+        message_id: u32,
+        topic: &'a str,
+        #[serde(with = "serde_bytes")]
+        data: &'a [u8],
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum DownlinkMessage<'a> {
     Welcome,
     Error {
@@ -87,4 +182,59 @@ pub enum DownlinkMessage<'a> {
     UnsubscribeAck {
         topic: &'a str,
     },
+
+    // Streams (routed by the id the device chose in `StreamStart`)
+    StreamData {
+        local_stream_id: u8,
+        #[serde(with = "serde_bytes")]
+        data: &'a [u8],
+    },
+    StreamClosed {
+        local_stream_id: u8,
+    },
+
+    // Reply to `DiscoverGateway`; the gateway address is the packet's source IP
+    GatewayAnnounce {
+        port: u16,
+        name: &'a str,
+    },
+
+    // Compact topic ids for subscribed topics: the gateway offers an alias
+    // and only sends `AliasedMessage` once the device answers `TopicAliasAck`
+    TopicAlias {
+        topic_id: u16,
+        topic: &'a str,
+    },
+    AliasedMessage {
+        topic_id: u16,
+        #[serde(with = "serde_bytes")]
+        data: &'a [u8],
+    },
+    // Flow control for a bridged stream: the gateway's buffer towards the
+    // mesh passed its high watermark (`slow_down`) or drained again
+    Throttle {
+        local_stream_id: u8,
+        slow_down: bool,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// With `defmt` on, firmware can log messages as they are sent and received
+    #[cfg(feature = "defmt")]
+    #[test]
+    fn test_messages_can_be_logged_with_defmt() {
+        fn loggable<T: defmt::Format>(_: &T) {}
+        loggable(&UplinkMessage::SensorUpdate {
+            sensor_name: "temp",
+            data: SensorValue::Temperature(21.5),
+            custom_data: "",
+        });
+        loggable(&DownlinkMessage::Error {
+            reason: ERROR_UNKNOWN_SENSOR,
+        });
+        loggable(&PressType::Double);
+    }
 }
@@ -0,0 +1,222 @@
+//! Shared topics and message shapes for voice assistant nodes.
+//!
+//! Wake-word detectors, intent recognizers and speakers built by
+//! different teams meet on three topics:
+//!
+//! - `assistant/wake_word`: a node heard its wake word
+//! - `assistant/intent`: an utterance was understood
+//! - `assistant/speak`: something should be said out loud
+//!
+//! Payloads are the JSON form of the types below. Publish with
+//! `AviDevice::publish_assistant` (or `publish` here, given a handle) and
+//! take them in through `AviDevice::on_assistant_event`.
+
+use avi_p2p::{AviP2pError, AviP2pHandle, Topic};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+pub const WAKE_WORD_TOPIC: &str = "assistant/wake_word";
+pub const INTENT_TOPIC: &str = "assistant/intent";
+pub const SPEAK_TOPIC: &str = "assistant/speak";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WakeWordDetected {
+    /// Peer id of the node that heard it
+    pub node: String,
+    pub wake_word: String,
+    /// Detector confidence, from 0.0 to 1.0
+    pub confidence: f32,
+    /// Room the node stands in, when it knows
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room: Option<String>,
+    /// Unix time in milliseconds; lets nodes that heard the same wake
+    /// word pick one responder
+    pub ts: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IntentRecognized {
+    /// Peer id of the node the utterance was spoken to
+    pub node: String,
+    /// Intent name, e.g. "lights.turn_on"
+    pub intent: String,
+    /// Extracted parameters, e.g. `{"room": "kitchen"}`
+    #[serde(default)]
+    pub slots: BTreeMap<String, Value>,
+    /// What was said, when a transcript is available
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub utterance: Option<String>,
+    pub confidence: f32,
+    pub ts: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SpeakRequest {
+    pub text: String,
+    /// Peer id of the node that should speak (None = whichever nodes
+    /// take unaddressed requests)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    /// BCP 47 language tag, e.g. "en-US"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub voice: Option<String>,
+    /// Cut off whatever the node is saying instead of queueing behind it
+    #[serde(default)]
+    pub interrupt: bool,
+}
+
+impl SpeakRequest {
+    pub fn new(text: &str) -> Self {
+        Self {
+            text: text.to_string(),
+            target: None,
+            language: None,
+            voice: None,
+            interrupt: false,
+        }
+    }
+
+    pub fn to(mut self, node: &str) -> Self {
+        self.target = Some(node.to_string());
+        self
+    }
+
+    pub fn language(mut self, language: &str) -> Self {
+        self.language = Some(language.to_string());
+        self
+    }
+
+    pub fn voice(mut self, voice: &str) -> Self {
+        self.voice = Some(voice.to_string());
+        self
+    }
+
+    pub fn interrupt(mut self) -> Self {
+        self.interrupt = true;
+        self
+    }
+
+    /// Whether the node `node` should act on this request
+    pub fn is_for(&self, node: &str) -> bool {
+        self.target.as_deref().is_none_or(|target| target == node)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AssistantEvent {
+    WakeWord(WakeWordDetected),
+    Intent(IntentRecognized),
+    Speak(SpeakRequest),
+}
+
+impl AssistantEvent {
+    /// All assistant topics, for subscribing
+    pub fn topics() -> [Topic; 3] {
+        [
+            Topic::new(WAKE_WORD_TOPIC),
+            Topic::new(INTENT_TOPIC),
+            Topic::new(SPEAK_TOPIC),
+        ]
+    }
+
+    /// Topic this event is published on
+    pub fn topic(&self) -> Topic {
+        Topic::new(match self {
+            AssistantEvent::WakeWord(_) => WAKE_WORD_TOPIC,
+            AssistantEvent::Intent(_) => INTENT_TOPIC,
+            AssistantEvent::Speak(_) => SPEAK_TOPIC,
+        })
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, AviP2pError> {
+        match self {
+            AssistantEvent::WakeWord(event) => serde_json::to_vec(event),
+            AssistantEvent::Intent(event) => serde_json::to_vec(event),
+            AssistantEvent::Speak(event) => serde_json::to_vec(event),
+        }
+        .map_err(|e| AviP2pError::Serialization(e.to_string()))
+    }
+
+    /// The event carried by a message, if `topic` is an assistant topic
+    /// and `data` has its shape
+    pub fn decode(topic: &str, data: &[u8]) -> Option<Self> {
+        match topic {
+            WAKE_WORD_TOPIC => serde_json::from_slice(data).ok().map(Self::WakeWord),
+            INTENT_TOPIC => serde_json::from_slice(data).ok().map(Self::Intent),
+            SPEAK_TOPIC => serde_json::from_slice(data).ok().map(Self::Speak),
+            _ => None,
+        }
+    }
+}
+
+impl From<WakeWordDetected> for AssistantEvent {
+    fn from(event: WakeWordDetected) -> Self {
+        AssistantEvent::WakeWord(event)
+    }
+}
+
+impl From<IntentRecognized> for AssistantEvent {
+    fn from(event: IntentRecognized) -> Self {
+        AssistantEvent::Intent(event)
+    }
+}
+
+impl From<SpeakRequest> for AssistantEvent {
+    fn from(event: SpeakRequest) -> Self {
+        AssistantEvent::Speak(event)
+    }
+}
+
+/// Publish `event` on its topic
+pub async fn publish(
+    handle: &AviP2pHandle,
+    event: impl Into<AssistantEvent>,
+) -> Result<(), AviP2pError> {
+    let event = event.into();
+    handle.publish(&event.topic(), event.encode()?).await
+}
+
+/// Unix time in milliseconds, for the `ts` fields
+pub fn now_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_events_round_trip_through_their_topics() {
+        let intent = AssistantEvent::Intent(IntentRecognized {
+            node: "kitchen-speaker".to_string(),
+            intent: "lights.turn_on".to_string(),
+            slots: BTreeMap::from([("room".to_string(), json!("kitchen"))]),
+            utterance: Some("turn on the kitchen lights".to_string()),
+            confidence: 0.92,
+            ts: 1_700_000_000_000,
+        });
+        let data = intent.encode().unwrap();
+        assert_eq!(intent.topic().as_str(), INTENT_TOPIC);
+        assert_eq!(AssistantEvent::decode(INTENT_TOPIC, &data), Some(intent));
+
+        // Shapes only decode on their own topic
+        assert_eq!(AssistantEvent::decode(SPEAK_TOPIC, &data), None);
+        assert_eq!(AssistantEvent::decode("lights/kitchen", &data), None);
+
+        // Optional fields may be left out by other implementations
+        let speak = AssistantEvent::decode(SPEAK_TOPIC, br#"{"text":"Done"}"#).unwrap();
+        let AssistantEvent::Speak(speak) = speak else {
+            panic!("not a speak request");
+        };
+        assert_eq!(speak, SpeakRequest::new("Done"));
+        assert!(speak.is_for("hall"));
+        assert!(!SpeakRequest::new("Done").to("kitchen").is_for("hall"));
+    }
+}
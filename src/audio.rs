@@ -0,0 +1,359 @@
+//! Audio over logical streams.
+//!
+//! The stream reason carries the audio format (`audio;codec=opus;rate=48000;...`),
+//! every chunk is one encoded frame behind a small header with its sequence
+//! number and sample timestamp, and the receiving side reorders frames in a
+//! jitter buffer before playout. Encoding and decoding stay with the caller.
+
+use crate::stream::StreamContext;
+use avi_p2p::{AviP2pHandle, PeerId, StreamId};
+use std::collections::BTreeMap;
+
+/// Base stream reason for audio; handlers register under this name
+pub const AUDIO_STREAM_REASON: &str = "audio";
+
+const FRAME_HEADER_LEN: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioCodec {
+    Opus,
+    /// Raw little-endian 16-bit PCM
+    Pcm16,
+}
+
+impl AudioCodec {
+    fn as_str(&self) -> &'static str {
+        match self {
+            AudioCodec::Opus => "opus",
+            AudioCodec::Pcm16 => "pcm16",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "opus" => Some(AudioCodec::Opus),
+            "pcm16" => Some(AudioCodec::Pcm16),
+            _ => None,
+        }
+    }
+}
+
+/// Audio parameters agreed in the stream handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AudioFormat {
+    pub codec: AudioCodec,
+    pub sample_rate: u32,
+    pub channels: u8,
+    /// Duration of one frame in milliseconds
+    pub frame_ms: u16,
+}
+
+impl Default for AudioFormat {
+    /// Opus, 48 kHz mono, 20 ms frames
+    fn default() -> Self {
+        Self {
+            codec: AudioCodec::Opus,
+            sample_rate: 48_000,
+            channels: 1,
+            frame_ms: 20,
+        }
+    }
+}
+
+impl AudioFormat {
+    /// Stream reason announcing this format
+    pub fn to_reason(&self) -> String {
+        format!(
+            "{};codec={};rate={};channels={};frame_ms={}",
+            AUDIO_STREAM_REASON,
+            self.codec.as_str(),
+            self.sample_rate,
+            self.channels,
+            self.frame_ms
+        )
+    }
+
+    /// Parse a stream reason; a bare `audio` yields the default format
+    pub fn from_reason(reason: &str) -> Option<Self> {
+        let mut parts = reason.split(';');
+        if parts.next()? != AUDIO_STREAM_REASON {
+            return None;
+        }
+
+        let mut format = Self::default();
+        for param in parts {
+            let (key, value) = param.split_once('=')?;
+            match key {
+                "codec" => format.codec = AudioCodec::parse(value)?,
+                "rate" => format.sample_rate = value.parse().ok()?,
+                "channels" => format.channels = value.parse().ok()?,
+                "frame_ms" => format.frame_ms = value.parse().ok()?,
+                _ => {}
+            }
+        }
+        Some(format)
+    }
+
+    /// Samples per channel in one frame
+    pub fn samples_per_frame(&self) -> u32 {
+        self.sample_rate * self.frame_ms as u32 / 1000
+    }
+}
+
+/// One encoded audio frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AudioFrame {
+    pub sequence: u32,
+    /// Position of the frame's first sample, in samples since stream start
+    pub timestamp: u32,
+    pub payload: Vec<u8>,
+}
+
+impl AudioFrame {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(FRAME_HEADER_LEN + self.payload.len());
+        buf.extend_from_slice(&self.sequence.to_be_bytes());
+        buf.extend_from_slice(&self.timestamp.to_be_bytes());
+        buf.extend_from_slice(&self.payload);
+        buf
+    }
+
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        if data.len() < FRAME_HEADER_LEN {
+            return None;
+        }
+        Some(Self {
+            sequence: u32::from_be_bytes(data[0..4].try_into().ok()?),
+            timestamp: u32::from_be_bytes(data[4..8].try_into().ok()?),
+            payload: data[FRAME_HEADER_LEN..].to_vec(),
+        })
+    }
+}
+
+/// What the jitter buffer hands to the decoder next
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Playout {
+    Frame(AudioFrame),
+    /// The frame never arrived in time; conceal it
+    Lost {
+        sequence: u32,
+    },
+}
+
+/// Reorders frames and holds back `depth` frames to absorb network jitter
+pub struct JitterBuffer {
+    depth: usize,
+    frames: BTreeMap<u32, AudioFrame>,
+    next_sequence: Option<u32>,
+}
+
+impl JitterBuffer {
+    pub fn new(depth: usize) -> Self {
+        Self {
+            depth: depth.max(1),
+            frames: BTreeMap::new(),
+            next_sequence: None,
+        }
+    }
+
+    /// Queue a received frame; frames older than the playout point are dropped
+    pub fn push(&mut self, frame: AudioFrame) {
+        if let Some(next) = self.next_sequence {
+            if frame.sequence < next {
+                return;
+            }
+        }
+        self.frames.insert(frame.sequence, frame);
+    }
+
+    /// Next frame in order once enough are buffered, or `Lost` for a gap
+    /// the buffer has given up waiting on
+    pub fn pop(&mut self) -> Option<Playout> {
+        let next = match self.next_sequence {
+            Some(next) => next,
+            None if self.frames.len() >= self.depth => *self.frames.keys().next()?,
+            None => return None,
+        };
+
+        if let Some(frame) = self.frames.remove(&next) {
+            self.next_sequence = Some(next.wrapping_add(1));
+            return Some(Playout::Frame(frame));
+        }
+        if self.frames.len() >= self.depth {
+            self.next_sequence = Some(next.wrapping_add(1));
+            return Some(Playout::Lost { sequence: next });
+        }
+        None
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+}
+
+/// Sending side of an audio stream: numbers and timestamps each frame
+pub struct AudioStream {
+    handle: AviP2pHandle,
+    stream_id: StreamId,
+    format: AudioFormat,
+    next_sequence: u32,
+}
+
+impl AudioStream {
+    /// Open an audio stream to `peer`, announcing `format`, and wait until
+    /// the peer accepts it
+    pub async fn open(
+        handle: &AviP2pHandle,
+        peer: PeerId,
+        format: AudioFormat,
+    ) -> Result<Self, String> {
+        let stream_id = handle
+            .open_stream(peer, format.to_reason())
+            .await
+            .map_err(|e| format!("Failed to open audio stream: {}", e))?;
+        Ok(Self::new(handle.clone(), stream_id, format))
+    }
+
+    /// Wrap an already accepted stream, e.g. from a `StreamHandler`
+    pub fn from_context(ctx: &StreamContext) -> Option<Self> {
+        let format = AudioFormat::from_reason(&ctx.reason)?;
+        Some(Self::new(ctx.handle.clone(), ctx.stream_id, format))
+    }
+
+    fn new(handle: AviP2pHandle, stream_id: StreamId, format: AudioFormat) -> Self {
+        Self {
+            handle,
+            stream_id,
+            format,
+            next_sequence: 0,
+        }
+    }
+
+    pub fn stream_id(&self) -> StreamId {
+        self.stream_id
+    }
+
+    pub fn format(&self) -> AudioFormat {
+        self.format
+    }
+
+    /// Send one encoded frame of `format.frame_ms` audio
+    pub async fn send_frame(&mut self, payload: Vec<u8>) -> Result<(), String> {
+        let sequence = self.next_sequence;
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+
+        let frame = AudioFrame {
+            sequence,
+            timestamp: sequence.wrapping_mul(self.format.samples_per_frame()),
+            payload,
+        };
+        self.handle
+            .send_stream_data(self.stream_id, frame.encode())
+            .await
+            .map_err(|e| format!("Failed to send audio frame: {}", e))
+    }
+
+    pub async fn close(self) -> Result<(), String> {
+        self.handle
+            .close_stream(self.stream_id)
+            .await
+            .map_err(|e| format!("Failed to close audio stream: {}", e))
+    }
+}
+
+/// Receiving side of an audio stream: decodes chunks into a jitter buffer
+pub struct AudioReceiver {
+    format: AudioFormat,
+    buffer: JitterBuffer,
+}
+
+impl AudioReceiver {
+    pub fn new(format: AudioFormat, jitter_depth: usize) -> Self {
+        Self {
+            format,
+            buffer: JitterBuffer::new(jitter_depth),
+        }
+    }
+
+    /// Receiver for the format announced in the stream's reason
+    pub fn from_context(ctx: &StreamContext, jitter_depth: usize) -> Option<Self> {
+        AudioFormat::from_reason(&ctx.reason).map(|format| Self::new(format, jitter_depth))
+    }
+
+    pub fn format(&self) -> AudioFormat {
+        self.format
+    }
+
+    /// Feed a chunk from `StreamHandler::on_data`; malformed chunks are ignored
+    pub fn push(&mut self, data: &[u8]) {
+        if let Some(frame) = AudioFrame::decode(data) {
+            self.buffer.push(frame);
+        }
+    }
+
+    /// Next frame to decode, if one is due
+    pub fn next_playout(&mut self) -> Option<Playout> {
+        self.buffer.pop()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(sequence: u32) -> AudioFrame {
+        AudioFrame {
+            sequence,
+            timestamp: sequence * 960,
+            payload: vec![sequence as u8],
+        }
+    }
+
+    #[test]
+    fn test_format_round_trips_through_reason() {
+        let format = AudioFormat {
+            codec: AudioCodec::Pcm16,
+            sample_rate: 16_000,
+            channels: 2,
+            frame_ms: 10,
+        };
+        assert_eq!(AudioFormat::from_reason(&format.to_reason()), Some(format));
+        assert_eq!(
+            AudioFormat::from_reason("audio"),
+            Some(AudioFormat::default())
+        );
+        assert_eq!(AudioFormat::from_reason("video"), None);
+    }
+
+    #[test]
+    fn test_jitter_buffer_reorders_and_reports_loss() {
+        let mut buffer = JitterBuffer::new(2);
+        buffer.push(frame(1));
+        assert_eq!(buffer.pop(), None);
+        buffer.push(frame(0));
+        buffer.push(frame(3));
+
+        assert_eq!(buffer.pop(), Some(Playout::Frame(frame(0))));
+        assert_eq!(buffer.pop(), Some(Playout::Frame(frame(1))));
+        // Frame 2 is missing; only one frame is buffered behind it
+        assert_eq!(buffer.pop(), None);
+        buffer.push(frame(4));
+        assert_eq!(buffer.pop(), Some(Playout::Lost { sequence: 2 }));
+        assert_eq!(buffer.pop(), Some(Playout::Frame(frame(3))));
+
+        // A frame that arrives after its slot was played is dropped
+        buffer.push(frame(2));
+        assert_eq!(buffer.len(), 1);
+    }
+
+    #[test]
+    fn test_frame_encoding() {
+        let original = frame(7);
+        assert_eq!(AudioFrame::decode(&original.encode()), Some(original));
+        assert_eq!(AudioFrame::decode(&[0, 1]), None);
+    }
+}
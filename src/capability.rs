@@ -1,5 +1,10 @@
+use avi_p2p::ContextChange;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
+
+/// Context path under which each device advertises its capabilities,
+/// keyed by its peer id
+pub const CAPABILITIES_PATH: &str = "avi.device.caps";
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct DeviceCapabilities {
@@ -11,6 +16,90 @@ pub struct DeviceCapabilities {
     pub display: Option<DisplayCapability>,
     pub audio: Option<AudioCapability>,
     pub extended: HashMap<String, ExtendedCapability>,
+    /// Where the device is installed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<Location>,
+}
+
+/// Physical placement of a device, from coarsest to finest. Every level is
+/// optional; names compare case-insensitively, so "Kitchen" and "kitchen"
+/// are the same room.
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
+pub struct Location {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub building: Option<String>,
+    /// 0 for the ground floor, negative below it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub floor: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room: Option<String>,
+    /// Area within the room, e.g. "desk" or "sofa"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub zone: Option<String>,
+}
+
+impl Location {
+    pub fn room(room: impl Into<String>) -> Self {
+        Self {
+            room: Some(room.into()),
+            ..Default::default()
+        }
+    }
+
+    pub fn building(mut self, building: impl Into<String>) -> Self {
+        self.building = Some(building.into());
+        self
+    }
+
+    pub fn floor(mut self, floor: i32) -> Self {
+        self.floor = Some(floor);
+        self
+    }
+
+    pub fn zone(mut self, zone: impl Into<String>) -> Self {
+        self.zone = Some(zone.into());
+        self
+    }
+
+    /// Whether this location lies within `area`: every level `area` names
+    /// matches, and levels it leaves out match anything
+    pub fn within(&self, area: &Location) -> bool {
+        fn same(name: &Option<String>, wanted: &Option<String>) -> bool {
+            match (name, wanted) {
+                (_, None) => true,
+                (Some(name), Some(wanted)) => name.eq_ignore_ascii_case(wanted),
+                (None, Some(_)) => false,
+            }
+        }
+        same(&self.building, &area.building)
+            && (area.floor.is_none() || self.floor == area.floor)
+            && same(&self.room, &area.room)
+            && same(&self.zone, &area.zone)
+    }
+}
+
+/// Devices whose capability documents a context update touched, with
+/// their capabilities as of `context`. Withdrawn or unreadable documents
+/// are left out.
+pub fn changed_capabilities(
+    changes: &[ContextChange],
+    context: &serde_json::Value,
+) -> Vec<(String, DeviceCapabilities)> {
+    let prefix = format!("{}.", CAPABILITIES_PATH);
+    let devices: BTreeSet<&str> = changes
+        .iter()
+        .filter_map(|change| change.path.strip_prefix(&prefix))
+        .filter_map(|rest| rest.split('.').next())
+        .collect();
+    devices
+        .into_iter()
+        .filter_map(|device| {
+            let caps = context
+                .pointer(&format!("/avi/device/caps/{}", device))?
+                .clone();
+            Some((device.to_string(), serde_json::from_value(caps).ok()?))
+        })
+        .collect()
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -231,6 +320,7 @@ pub struct CapabilityBuilder {
     display: Option<DisplayCapability>,
     audio: Option<AudioCapability>,
     extended: HashMap<String, ExtendedCapability>,
+    location: Option<Location>,
 }
 
 impl CapabilityBuilder {
@@ -244,6 +334,7 @@ impl CapabilityBuilder {
             display: None,
             audio: None,
             extended: HashMap::new(),
+            location: None,
         }
     }
 
@@ -287,6 +378,11 @@ impl CapabilityBuilder {
         self
     }
 
+    pub fn location(mut self, location: Location) -> Self {
+        self.location = Some(location);
+        self
+    }
+
     pub fn build(self) -> DeviceCapabilities {
         DeviceCapabilities {
             compute: self.compute,
@@ -297,6 +393,7 @@ impl CapabilityBuilder {
             display: self.display,
             audio: self.audio,
             extended: self.extended,
+            location: self.location,
         }
     }
 }
@@ -402,4 +499,36 @@ mod tests {
         let deserialized: DeviceCapabilities = serde_json::from_str(&json).unwrap();
         assert!(deserialized.compute.is_some());
     }
+
+    #[test]
+    fn test_changed_capabilities_groups_by_device() {
+        let kitchen = CapabilityBuilder::new()
+            .extended("relay", ExtendedCapability::Boolean(true))
+            .build();
+        let context = serde_json::json!({
+            "avi": { "device": { "caps": {
+                "kitchen": serde_json::to_value(&kitchen).unwrap(),
+                "hall": "not a capability document",
+            } } },
+            "room": "kitchen",
+        });
+        let change = |path: &str| ContextChange {
+            path: path.to_string(),
+            old: None,
+            new: Some(serde_json::json!(true)),
+        };
+        let changed = changed_capabilities(
+            &[
+                change("avi.device.caps.kitchen.extended.relay"),
+                change("avi.device.caps.kitchen.power"),
+                change("avi.device.caps.hall"),
+                change("avi.device.caps.gone.sensors"),
+                change("room"),
+            ],
+            &context,
+        );
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].0, "kitchen");
+        assert!(changed[0].1.extended.contains_key("relay"));
+    }
 }
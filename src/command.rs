@@ -0,0 +1,346 @@
+//! Typed device commands with idempotency keys.
+//!
+//! A `DeviceCommand` travels as JSON on `device/<id>/command` and is
+//! answered by a `CommandReply` with the same key on
+//! `device/<id>/command/reply`. A sender that hears nothing within its
+//! timeout publishes the command again under the same key; the device
+//! runs each key once and answers repeats from a `CommandDedupe`, so a
+//! retried "toggle" does not flip the actuator back.
+//!
+//! Commands that take a while (firmware updates, calibration) report
+//! `CommandProgress` under the same key before their reply. Each report
+//! restarts the sender's timeout, so a long command is not resent while
+//! it is visibly making progress.
+
+use avi_p2p::{AviEvent, AviP2pError, AviP2pHandle, Topic};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How long devices remember command keys unless told otherwise
+pub const DEFAULT_DEDUPE_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceCommand {
+    /// Idempotency key; retries of one command share it
+    pub key: String,
+    pub name: String,
+    #[serde(default)]
+    pub args: Value,
+}
+
+impl DeviceCommand {
+    /// A command under a fresh key
+    pub fn new(name: &str, args: Value) -> Self {
+        Self::with_key(&new_key(), name, args)
+    }
+
+    /// A command under a caller-chosen key, e.g. one derived from the
+    /// automation run that issues it
+    pub fn with_key(key: &str, name: &str, args: Value) -> Self {
+        Self {
+            key: key.to_string(),
+            name: name.to_string(),
+            args,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CommandReply {
+    /// Key of the command answered
+    pub key: String,
+    pub result: Result<Value, String>,
+}
+
+/// Interim report on a command that is still running, published on the
+/// reply topic
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CommandProgress {
+    /// Key of the command running
+    pub key: String,
+    /// Fraction done, from 0.0 to 1.0
+    pub progress: f32,
+    /// What the device is doing, e.g. "verifying image"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+}
+
+impl CommandProgress {
+    pub fn new(key: &str, progress: f32) -> Self {
+        Self {
+            key: key.to_string(),
+            progress: progress.clamp(0.0, 1.0),
+            status: None,
+        }
+    }
+
+    pub fn status(mut self, status: &str) -> Self {
+        self.status = Some(status.to_string());
+        self
+    }
+}
+
+/// Answer a command, from the device it was sent to
+pub async fn publish_reply(
+    handle: &AviP2pHandle,
+    device_id: u64,
+    reply: &CommandReply,
+) -> Result<(), AviP2pError> {
+    publish_json(handle, device_id, reply).await
+}
+
+/// Tell the sender of a command how far along it is, from the device it
+/// was sent to
+pub async fn publish_progress(
+    handle: &AviP2pHandle,
+    device_id: u64,
+    progress: &CommandProgress,
+) -> Result<(), AviP2pError> {
+    publish_json(handle, device_id, progress).await
+}
+
+async fn publish_json(
+    handle: &AviP2pHandle,
+    device_id: u64,
+    message: &impl Serialize,
+) -> Result<(), AviP2pError> {
+    let data =
+        serde_json::to_vec(message).map_err(|e| AviP2pError::Serialization(e.to_string()))?;
+    handle
+        .publish(&Topic::device(device_id).command_reply(), data)
+        .await
+}
+
+fn new_key() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    format!(
+        "{:x}-{:x}-{:x}",
+        nanos,
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// What a device should do with a command it received
+#[derive(Debug, Clone, PartialEq)]
+pub enum Delivery {
+    /// First time the key is seen: run the command
+    First,
+    /// A repeat: send this reply again, or nothing if the first delivery
+    /// is still running (its reply will answer the retry too)
+    Repeat(Option<CommandReply>),
+}
+
+/// Command keys a device has run within the last `window`, with their
+/// replies
+pub struct CommandDedupe {
+    window: Duration,
+    replies: HashMap<String, Option<CommandReply>>,
+    order: VecDeque<(Instant, String)>,
+}
+
+impl CommandDedupe {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            replies: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Record that `key` arrived
+    pub fn accept(&mut self, key: &str) -> Delivery {
+        let now = Instant::now();
+        while let Some((at, _)) = self.order.front() {
+            if now.duration_since(*at) < self.window {
+                break;
+            }
+            if let Some((_, expired)) = self.order.pop_front() {
+                self.replies.remove(&expired);
+            }
+        }
+        if let Some(reply) = self.replies.get(key) {
+            return Delivery::Repeat(reply.clone());
+        }
+        self.replies.insert(key.to_string(), None);
+        self.order.push_back((now, key.to_string()));
+        Delivery::First
+    }
+
+    /// Keep the reply to a command for repeats of its key
+    pub fn complete(&mut self, reply: &CommandReply) {
+        if let Some(slot) = self.replies.get_mut(&reply.key) {
+            *slot = Some(reply.clone());
+        }
+    }
+}
+
+impl Default for CommandDedupe {
+    fn default() -> Self {
+        Self::new(DEFAULT_DEDUPE_WINDOW)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CommandOptions {
+    /// How long to wait for a reply before sending again
+    pub timeout: Duration,
+    /// Sends in total, the first included
+    pub attempts: u32,
+}
+
+impl Default for CommandOptions {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(2),
+            attempts: 3,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CommandError {
+    /// No reply after every attempt
+    Timeout {
+        attempts: u32,
+    },
+    /// The device ran the command and reported a failure
+    Failed(String),
+    Network(AviP2pError),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::Timeout { attempts } => {
+                write!(f, "No reply to command after {} attempts", attempts)
+            }
+            CommandError::Failed(reason) => write!(f, "Command failed: {}", reason),
+            CommandError::Network(e) => write!(f, "Network error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+impl From<AviP2pError> for CommandError {
+    fn from(e: AviP2pError) -> Self {
+        CommandError::Network(e)
+    }
+}
+
+/// Send `command` to `device_id` and wait for its reply, resending under
+/// the same key after each timeout
+pub async fn send_command(
+    handle: &AviP2pHandle,
+    device_id: u64,
+    command: &DeviceCommand,
+    options: CommandOptions,
+) -> Result<Value, CommandError> {
+    send_command_with_progress(handle, device_id, command, options, |_| {}).await
+}
+
+/// `send_command`, passing each progress report to `on_progress` while
+/// waiting for the reply
+pub async fn send_command_with_progress(
+    handle: &AviP2pHandle,
+    device_id: u64,
+    command: &DeviceCommand,
+    options: CommandOptions,
+    mut on_progress: impl FnMut(CommandProgress),
+) -> Result<Value, CommandError> {
+    let topics = Topic::device(device_id);
+    let mut events = handle
+        .subscribe_events()
+        .await
+        .map_err(AviP2pError::NetworkError)?;
+    // The reply topic is only left again if this call joined it
+    let reply_topic = topics.command_reply();
+    let joined = !handle
+        .subscriptions()
+        .await?
+        .iter()
+        .any(|topic| topic == reply_topic.as_str());
+    if joined {
+        match handle.subscribe(&reply_topic).await {
+            Ok(()) | Err(AviP2pError::AlreadySubscribed(_)) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let result = async {
+        let payload =
+            serde_json::to_vec(command).map_err(|e| AviP2pError::Serialization(e.to_string()))?;
+
+        for _ in 0..options.attempts.max(1) {
+            handle.publish(&topics.command(), payload.clone()).await?;
+            let mut deadline = tokio::time::Instant::now() + options.timeout;
+            while let Ok(Some(event)) = tokio::time::timeout_at(deadline, events.recv()).await {
+                let AviEvent::Message { topic, data, .. } = event else {
+                    continue;
+                };
+                if topic != reply_topic.as_str() {
+                    continue;
+                }
+                match serde_json::from_slice::<CommandReply>(&data) {
+                    Ok(reply) if reply.key == command.key => {
+                        return reply.result.map_err(CommandError::Failed);
+                    }
+                    Ok(_) => continue,
+                    Err(_) => {}
+                }
+                match serde_json::from_slice::<CommandProgress>(&data) {
+                    Ok(progress) if progress.key == command.key => {
+                        deadline = tokio::time::Instant::now() + options.timeout;
+                        on_progress(progress);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Err(CommandError::Timeout {
+            attempts: options.attempts.max(1),
+        })
+    }
+    .await;
+
+    if joined {
+        let _ = handle.unsubscribe(&reply_topic).await;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_repeats_get_the_first_reply() {
+        let mut dedupe = CommandDedupe::default();
+        let toggle = DeviceCommand::new("toggle", Value::Null);
+        assert_ne!(toggle.key, DeviceCommand::new("toggle", Value::Null).key);
+
+        assert_eq!(dedupe.accept(&toggle.key), Delivery::First);
+        // Retried while the first delivery is still running
+        assert_eq!(dedupe.accept(&toggle.key), Delivery::Repeat(None));
+
+        let reply = CommandReply {
+            key: toggle.key.clone(),
+            result: Ok(json!({ "on": true })),
+        };
+        dedupe.complete(&reply);
+        assert_eq!(dedupe.accept(&toggle.key), Delivery::Repeat(Some(reply)));
+
+        let mut forgetful = CommandDedupe::new(Duration::ZERO);
+        assert_eq!(forgetful.accept(&toggle.key), Delivery::First);
+        assert_eq!(forgetful.accept(&toggle.key), Delivery::First);
+    }
+}
@@ -1,16 +1,28 @@
-use crate::capability::DeviceCapabilities;
+use crate::assistant::{self, AssistantEvent};
+use crate::capability::{changed_capabilities, DeviceCapabilities, CAPABILITIES_PATH};
+use crate::command::{self, CommandError, CommandOptions, CommandProgress, DeviceCommand};
+use crate::frame::FrameStream;
 use crate::stream::{StreamDispatcher, StreamHandlerFactory};
 use crate::DeviceQuery;
 use avi_p2p::{
     set_nested_value, AviEvent, AviP2p, AviP2pConfig, AviP2pError, AviP2pHandle, BridgeConfig,
-    EmbeddedBridge, PeerId, StreamId,
+    ContextTransaction, EmbeddedBridge, PeerId, StreamId,
 };
 use futures::future::BoxFuture;
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::mpsc::Receiver;
 use tokio::sync::{Mutex, RwLock};
 
+/// Handler for a message sent straight to this device: (device, sender, data)
+type DirectMessageHandler =
+    Arc<dyn Fn(AviDevice, String, Vec<u8>) -> BoxFuture<'static, ()> + Send + Sync>;
+
+/// Handler for a peer's new capabilities: (device, peer, capabilities)
+type CapabilitiesHandler =
+    Arc<dyn Fn(AviDevice, String, DeviceCapabilities) -> BoxFuture<'static, ()> + Send + Sync>;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AviDeviceType {
     CORE = 0,
@@ -44,6 +56,9 @@ pub struct AviDevice {
 
     peer_id: Arc<RwLock<Option<PeerId>>>,
 
+    /// Capabilities currently advertised, starting from the config's
+    capabilities: Arc<RwLock<DeviceCapabilities>>,
+
     stream_dispatcher: Arc<StreamDispatcher>,
 
     subscription_handlers: Arc<
@@ -70,6 +85,8 @@ pub struct AviDevice {
     >,
     on_peer_disconnected:
         Arc<RwLock<Option<Arc<dyn Fn(AviDevice, String) -> BoxFuture<'static, ()> + Send + Sync>>>>,
+    on_direct_message: Arc<RwLock<Option<DirectMessageHandler>>>,
+    on_capabilities_changed: Arc<RwLock<Option<CapabilitiesHandler>>>,
 }
 
 impl AviDevice {
@@ -77,8 +94,14 @@ impl AviDevice {
         match AviP2p::start(AviP2pConfig::new(&config.node_name)).await {
             Ok((node, events)) => {
                 if config.can_gateway_embedded {
-                    match EmbeddedBridge::start(node.handle(), BridgeConfig { udp_port: 8888 })
-                        .await
+                    match EmbeddedBridge::start(
+                        node.handle(),
+                        BridgeConfig {
+                            udp_port: 8888,
+                            ..Default::default()
+                        },
+                    )
+                    .await
                     {
                         Ok(..) => {}
                         Err(e) => println!("Failed to start embedded bridge: {}", e),
@@ -86,6 +109,7 @@ impl AviDevice {
                 }
 
                 Ok(Self {
+                    capabilities: Arc::new(RwLock::new(config.capabilities.clone())),
                     config: Arc::new(config),
                     handler: node.handle(),
                     stream_dispatcher: Arc::new(StreamDispatcher::new(node.handle())),
@@ -97,6 +121,8 @@ impl AviDevice {
                     on_peer_discovered: Arc::new(RwLock::new(None)),
                     on_peer_connected: Arc::new(RwLock::new(None)),
                     on_peer_disconnected: Arc::new(RwLock::new(None)),
+                    on_direct_message: Arc::new(RwLock::new(None)),
+                    on_capabilities_changed: Arc::new(RwLock::new(None)),
                 })
             }
             Err(e) => Err(format!("Failed to start AVI P2P node: {}", e)),
@@ -169,7 +195,9 @@ impl AviDevice {
                 }
             }
 
-            AviEvent::Message { from, topic, data } => {
+            AviEvent::Message {
+                from, topic, data, ..
+            } => {
                 let handlers_map = self.subscription_handlers.read().await;
                 if let Some(handlers) = handlers_map.get(&topic) {
                     for handler in handlers {
@@ -178,11 +206,47 @@ impl AviDevice {
                 }
             }
 
-            AviEvent::ContextUpdated { .. } => {}
+            AviEvent::DirectMessage { from, data } => {
+                let handler = self.on_direct_message.read().await;
+                if let Some(handler) = &*handler {
+                    handler(self.clone(), from.to_string(), data).await;
+                }
+            }
+            AviEvent::ContextUpdated {
+                context, changes, ..
+            } => {
+                let handler = self.on_capabilities_changed.read().await;
+                if let Some(handler) = &*handler {
+                    let local_id = { self.peer_id.read().await.clone() };
+                    for (device, caps) in changed_capabilities(&changes, &context) {
+                        if local_id.as_ref().is_some_and(|id| id.as_str() == device) {
+                            continue;
+                        }
+                        handler(self.clone(), device, caps).await;
+                    }
+                }
+            }
+            AviEvent::ContextRejected { .. }
+            | AviEvent::ContextSynced { .. }
+            | AviEvent::ContextInvalidated { .. }
+            | AviEvent::ContextQuotaWarning { .. }
+            | AviEvent::ContextStale { .. } => {}
+            AviEvent::KeyRotated { .. } => {}
+            AviEvent::DhtPublishFailed { .. } => {}
+            AviEvent::ScheduledPublishFailed { .. } => {}
+            AviEvent::RateLimited { .. } => {}
+            AviEvent::PeerBanned { .. } | AviEvent::PeerUnbanned { .. } => {}
+            AviEvent::BridgedDeviceOnline { .. }
+            | AviEvent::BridgedDeviceOffline { .. }
+            | AviEvent::BridgeStats { .. } => {}
+            AviEvent::PeerAuthenticated { .. } | AviEvent::PeerAuthenticationFailed { .. } => {}
+            AviEvent::PeerIdentified { .. } => {}
+            AviEvent::StreamSecured { .. } => {}
             AviEvent::StreamRejected {
                 peer_id,
                 stream_id,
                 reason,
+                ..
             } => {
                 if let Err(e) = self
                     .stream_dispatcher
@@ -192,6 +256,17 @@ impl AviDevice {
                     eprintln!("Error handling stream rejected: {}", e);
                 }
             }
+            AviEvent::StreamOpenTimeout {
+                peer_id, stream_id, ..
+            } => {
+                if let Err(e) = self
+                    .stream_dispatcher
+                    .handle_stream_rejected(peer_id, stream_id, "timeout".to_string())
+                    .await
+                {
+                    eprintln!("Error handling stream open timeout: {}", e);
+                }
+            }
             AviEvent::StreamRequested {
                 from,
                 stream_id,
@@ -206,7 +281,9 @@ impl AviDevice {
                 }
             }
 
-            AviEvent::StreamAccepted { peer_id, stream_id } => {
+            AviEvent::StreamAccepted {
+                peer_id, stream_id, ..
+            } => {
                 if let Err(e) = self
                     .stream_dispatcher
                     .handle_stream_accepted(peer_id, stream_id)
@@ -248,8 +325,8 @@ impl AviDevice {
     async fn update_capabilities(&self, local_peer_id: String) {
         match self
             .update_ctx(
-                &format!("avi.device.caps.{}", local_peer_id),
-                self.get_caps_as_json(),
+                &format!("{}.{}", CAPABILITIES_PATH, local_peer_id),
+                self.get_caps_as_json().await,
             )
             .await
         {
@@ -257,8 +334,36 @@ impl AviDevice {
             Err(e) => println!("Failed to update device capabilities: {}", e),
         };
     }
-    fn get_caps_as_json(&self) -> serde_json::Value {
-        serde_json::to_value(self.config.capabilities.clone()).unwrap()
+    async fn get_caps_as_json(&self) -> serde_json::Value {
+        serde_json::to_value(self.capabilities.read().await.clone()).unwrap()
+    }
+
+    /// Capabilities this device currently advertises
+    pub async fn capabilities(&self) -> DeviceCapabilities {
+        self.capabilities.read().await.clone()
+    }
+
+    /// Advertise new capabilities (a feature toggled, a module plugged in);
+    /// controllers hear about it through `on_capabilities_changed`
+    pub async fn set_capabilities(
+        &self,
+        capabilities: DeviceCapabilities,
+    ) -> Result<(), AviP2pError> {
+        let value = serde_json::to_value(&capabilities)
+            .map_err(|e| AviP2pError::Serialization(e.to_string()))?;
+        *self.capabilities.write().await = capabilities;
+        let local_id = { self.peer_id.read().await.clone() };
+        match local_id {
+            // Replaced whole, so removed sensors and modules go too
+            Some(id) => {
+                self.ctx_transaction()
+                    .set(&format!("{}.{}", CAPABILITIES_PATH, id), value)
+                    .commit()
+                    .await
+            }
+            // Advertised once the node has started
+            None => Ok(()),
+        }
     }
 
     ///To call use
@@ -286,6 +391,66 @@ impl AviDevice {
         self.handler.publish(topic, data).await
     }
 
+    /// Publish a wake word, intent or speak request on its assistant topic
+    pub async fn publish_assistant(
+        &self,
+        event: impl Into<AssistantEvent>,
+    ) -> Result<(), AviP2pError> {
+        assistant::publish(&self.handler, event).await
+    }
+
+    /// Subscribe to the assistant topics; messages that are not valid
+    /// assistant events are skipped
+    pub async fn on_assistant_event<F, Fut>(&self, handler: F) -> Result<(), AviP2pError>
+    where
+        F: Fn(PeerId, AssistantEvent) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = ()> + Send + 'static,
+    {
+        let handler = Arc::new(handler);
+        for topic in AssistantEvent::topics() {
+            let handler = handler.clone();
+            self.subscribe_async(&topic, move |from, topic, data| {
+                let event = AssistantEvent::decode(&topic, &data);
+                let handler = handler.clone();
+                async move {
+                    if let Some(event) = event {
+                        handler(from, event).await;
+                    }
+                }
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Send `command` to a (usually bridged) device and wait for its reply,
+    /// retrying under the same idempotency key so it runs at most once
+    pub async fn send_command(
+        &self,
+        device_id: u64,
+        command: &DeviceCommand,
+        options: CommandOptions,
+    ) -> Result<serde_json::Value, CommandError> {
+        command::send_command(&self.handler, device_id, command, options).await
+    }
+
+    /// `send_command` for long-running commands (firmware updates,
+    /// calibration), passing each progress report to `on_progress`
+    pub async fn send_command_with_progress(
+        &self,
+        device_id: u64,
+        command: &DeviceCommand,
+        options: CommandOptions,
+        on_progress: impl FnMut(CommandProgress),
+    ) -> Result<serde_json::Value, CommandError> {
+        command::send_command_with_progress(&self.handler, device_id, command, options, on_progress)
+            .await
+    }
+
+    pub async fn send_to(&self, peer_id: &str, data: Vec<u8>) -> Result<(), AviP2pError> {
+        self.handler.send_to(&PeerId::new(peer_id), data).await
+    }
+
     pub async fn subscribe(
         &self,
         topic: &str,
@@ -333,6 +498,58 @@ impl AviDevice {
         self.handler.update_context(current_ctx).await
     }
 
+    /// Set `path` to `value` until `ttl` passes, then remove it everywhere
+    pub async fn update_ctx_with_ttl(
+        &self,
+        path: &str,
+        value: serde_json::Value,
+        ttl: Duration,
+    ) -> Result<(), AviP2pError> {
+        let mut patch = serde_json::Value::Object(serde_json::Map::new());
+
+        set_nested_value(&mut patch, path, value)?;
+
+        self.handler.update_context_with_ttl(patch, ttl).await
+    }
+
+    /// Set `path` to `value` only if it currently holds `expected`
+    /// (`Value::Null` for unset); returns whether the write happened
+    pub async fn update_ctx_if(
+        &self,
+        path: &str,
+        expected: serde_json::Value,
+        value: serde_json::Value,
+    ) -> Result<bool, AviP2pError> {
+        let mut patch = serde_json::Value::Object(serde_json::Map::new());
+
+        set_nested_value(&mut patch, path, value)?;
+
+        self.handler.update_context_if(path, expected, patch).await
+    }
+
+    /// Add `delta` to the replicated counter at `path`; returns the new value
+    pub async fn ctx_incr(&self, path: &str, delta: i64) -> Result<i64, AviP2pError> {
+        self.handler.ctx_incr(path, delta).await
+    }
+
+    /// Add `value` to the replicated set at `path`
+    pub async fn ctx_add_to_set(
+        &self,
+        path: &str,
+        value: serde_json::Value,
+    ) -> Result<(), AviP2pError> {
+        self.handler.ctx_add_to_set(path, value).await
+    }
+
+    /// Remove `value` from the replicated set at `path`
+    pub async fn ctx_remove_from_set(
+        &self,
+        path: &str,
+        value: serde_json::Value,
+    ) -> Result<bool, AviP2pError> {
+        self.handler.ctx_remove_from_set(path, value).await
+    }
+
     pub async fn replace_ctx(
         &self,
         path: &str,
@@ -345,6 +562,12 @@ impl AviDevice {
         self.handler.replace_context(current_ctx).await
     }
 
+    /// Batch context writes to apply atomically, e.g.
+    /// `ctx_transaction().set("a", v).delete("b").commit()`
+    pub fn ctx_transaction(&self) -> ContextTransaction {
+        self.handler.ctx_transaction()
+    }
+
     pub async fn delete_ctx(&self, path: &str) -> Result<(), AviP2pError> {
         self.handler.delete_ctx(path).await
     }
@@ -391,6 +614,17 @@ impl AviDevice {
         self.stream_dispatcher.request_stream(peer_id, reason).await
     }
 
+    /// Open a frame-oriented stream (e.g. camera snapshots as `image/jpeg`).
+    /// At most `queue_capacity` frames wait to be sent; older ones are dropped.
+    pub async fn open_frame_stream(
+        &self,
+        peer_id: PeerId,
+        content_type: &str,
+        queue_capacity: usize,
+    ) -> Result<FrameStream, String> {
+        FrameStream::open(&self.handler, peer_id, content_type, queue_capacity).await
+    }
+
     pub async fn close_stream(&self, stream_id: StreamId) -> Result<(), String> {
         self.stream_dispatcher.close_stream(stream_id).await
     }
@@ -454,6 +688,30 @@ impl AviDevice {
         }));
     }
 
+    pub async fn on_direct_message<F, Fut>(&self, handler: F)
+    where
+        F: Fn(AviDevice, String, Vec<u8>) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = ()> + Send + 'static,
+    {
+        let mut lock = self.on_direct_message.write().await;
+        *lock = Some(Arc::new(move |device, peer_id, data| {
+            Box::pin(handler(device, peer_id, data))
+        }));
+    }
+
+    /// Called with a device's peer id and capabilities whenever another
+    /// device advertises changed capabilities
+    pub async fn on_capabilities_changed<F, Fut>(&self, handler: F)
+    where
+        F: Fn(AviDevice, String, DeviceCapabilities) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = ()> + Send + 'static,
+    {
+        let mut lock = self.on_capabilities_changed.write().await;
+        *lock = Some(Arc::new(move |device, peer_id, caps| {
+            Box::pin(handler(device, peer_id, caps))
+        }));
+    }
+
     pub fn start_event_loop(self: &Arc<Self>) {
         let device = Arc::clone(self);
         tokio::spawn(async move {
@@ -0,0 +1,184 @@
+//! Frame-oriented streams for low-rate media such as camera snapshots.
+//!
+//! Each stream chunk is one complete frame (e.g. a JPEG) behind a header with
+//! its sequence number and capture time. The sender keeps only the newest
+//! frames when the network falls behind: a stale snapshot is worth less than
+//! a fresh one, so the oldest queued frame is dropped.
+
+use avi_p2p::{AviP2pHandle, PeerId, StreamId};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::Notify;
+
+/// Base stream reason for frame streams; handlers register under this name
+pub const FRAME_STREAM_REASON: &str = "frames";
+
+const FRAME_HEADER_LEN: usize = 12;
+
+/// One complete frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub sequence: u32,
+    /// Capture time in milliseconds since the stream opened
+    pub timestamp_ms: u64,
+    pub data: Vec<u8>,
+}
+
+impl Frame {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(FRAME_HEADER_LEN + self.data.len());
+        buf.extend_from_slice(&self.sequence.to_be_bytes());
+        buf.extend_from_slice(&self.timestamp_ms.to_be_bytes());
+        buf.extend_from_slice(&self.data);
+        buf
+    }
+
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        if data.len() < FRAME_HEADER_LEN {
+            return None;
+        }
+        Some(Self {
+            sequence: u32::from_be_bytes(data[0..4].try_into().ok()?),
+            timestamp_ms: u64::from_be_bytes(data[4..12].try_into().ok()?),
+            data: data[FRAME_HEADER_LEN..].to_vec(),
+        })
+    }
+}
+
+/// Content type announced in a frame stream's reason (`frames;type=image/jpeg`)
+pub fn frame_stream_content_type(reason: &str) -> Option<&str> {
+    let mut parts = reason.split(';');
+    if parts.next()? != FRAME_STREAM_REASON {
+        return None;
+    }
+    parts.find_map(|p| p.strip_prefix("type="))
+}
+
+struct Queue {
+    frames: Mutex<VecDeque<Frame>>,
+    capacity: usize,
+    ready: Notify,
+    dropped: AtomicU64,
+}
+
+/// Sending side of a frame stream. Frames are queued and sent in the
+/// background; when `queue_capacity` frames are waiting the oldest is dropped.
+pub struct FrameStream {
+    handle: AviP2pHandle,
+    stream_id: StreamId,
+    queue: Arc<Queue>,
+    next_sequence: u32,
+    opened: Instant,
+    sender: tokio::task::JoinHandle<()>,
+}
+
+impl FrameStream {
+    /// Open a frame stream to `peer` carrying `content_type` (e.g. `image/jpeg`)
+    /// and wait until the peer accepts it
+    pub async fn open(
+        handle: &AviP2pHandle,
+        peer: PeerId,
+        content_type: &str,
+        queue_capacity: usize,
+    ) -> Result<Self, String> {
+        let reason = format!("{};type={}", FRAME_STREAM_REASON, content_type);
+        let stream_id = handle
+            .open_stream(peer, reason)
+            .await
+            .map_err(|e| format!("Failed to open frame stream: {}", e))?;
+
+        let queue = Arc::new(Queue {
+            frames: Mutex::new(VecDeque::new()),
+            capacity: queue_capacity.max(1),
+            ready: Notify::new(),
+            dropped: AtomicU64::new(0),
+        });
+        let sender = tokio::spawn(Self::send_loop(handle.clone(), stream_id, queue.clone()));
+
+        Ok(Self {
+            handle: handle.clone(),
+            stream_id,
+            queue,
+            next_sequence: 0,
+            opened: Instant::now(),
+            sender,
+        })
+    }
+
+    async fn send_loop(handle: AviP2pHandle, stream_id: StreamId, queue: Arc<Queue>) {
+        loop {
+            let next = queue.frames.lock().unwrap().pop_front();
+            match next {
+                Some(frame) => {
+                    if handle
+                        .send_stream_data(stream_id, frame.encode())
+                        .await
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+                None => queue.ready.notified().await,
+            }
+        }
+    }
+
+    pub fn stream_id(&self) -> StreamId {
+        self.stream_id
+    }
+
+    /// Queue a complete frame for sending; never waits
+    pub fn push(&mut self, data: Vec<u8>) {
+        let frame = Frame {
+            sequence: self.next_sequence,
+            timestamp_ms: self.opened.elapsed().as_millis() as u64,
+            data,
+        };
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+
+        let mut frames = self.queue.frames.lock().unwrap();
+        if frames.len() >= self.queue.capacity {
+            frames.pop_front();
+            self.queue.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        frames.push_back(frame);
+        drop(frames);
+        self.queue.ready.notify_one();
+    }
+
+    /// Frames discarded because the network could not keep up
+    pub fn dropped(&self) -> u64 {
+        self.queue.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Stop sending (queued frames are discarded) and close the stream
+    pub async fn close(self) -> Result<(), String> {
+        self.sender.abort();
+        self.handle
+            .close_stream(self.stream_id)
+            .await
+            .map_err(|e| format!("Failed to close frame stream: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_encoding_and_reason() {
+        let frame = Frame {
+            sequence: 3,
+            timestamp_ms: 1_500,
+            data: vec![0xff, 0xd8],
+        };
+        assert_eq!(Frame::decode(&frame.encode()), Some(frame));
+        assert_eq!(
+            frame_stream_content_type("frames;type=image/jpeg"),
+            Some("image/jpeg")
+        );
+        assert_eq!(frame_stream_content_type("audio;type=x"), None);
+    }
+}
@@ -1,9 +1,19 @@
+pub mod assistant;
+pub mod audio;
 pub mod capability;
+pub mod command;
 pub mod device;
+pub mod frame;
 pub mod query;
 pub mod stream;
+#[cfg(feature = "testing")]
+pub mod testing;
 
-pub use avi_p2p::{PeerId, StreamCloseReason, StreamId};
-pub use capability::DeviceCapabilities;
+pub use assistant::{AssistantEvent, IntentRecognized, SpeakRequest, WakeWordDetected};
+pub use audio::{AudioFormat, AudioReceiver, AudioStream};
+pub use avi_p2p::{PeerId, StreamCloseReason, StreamId, Topic};
+pub use capability::{DeviceCapabilities, Location};
+pub use command::{CommandError, CommandOptions, CommandProgress, DeviceCommand};
+pub use frame::{Frame, FrameStream};
 pub use query::DeviceQuery;
 pub use stream::{StreamContext, StreamHandler, StreamHandlerFactory};
@@ -18,10 +18,14 @@ let query = DeviceQuery::all()
     .power(|p| p.battery_pct.map(|pct| pct < 30).unwrap_or(false))
     .health(|h| h.thermal_headroom_pct > 50);
 let critical_devices = device.execute_query(query);
+
+// Everything in the kitchen on the ground floor
+let query = DeviceQuery::all().within(Location::room("kitchen").floor(0));
+let kitchen = device.execute_query(query);
 */
 use crate::capability::{
     AudioCapability, ComputeCapability, ConnectivityCapability, DisplayCapability,
-    HealthCapability, PowerCapability, SensorCapability,
+    HealthCapability, Location, PowerCapability, SensorCapability,
 };
 use crate::DeviceCapabilities;
 use std::collections::HashMap;
@@ -36,6 +40,7 @@ pub enum CapabilityType {
     Sensor(String),
     Connectivity(String),
     Extended(String),
+    Location,
     Any,
 }
 
@@ -126,6 +131,20 @@ impl CapabilityFilter {
         })
     }
 
+    pub fn location<F>(predicate: F) -> Self
+    where
+        F: Fn(&Location) -> bool + 'static,
+    {
+        Self::new(CapabilityType::Location, move |caps| {
+            caps.location.as_ref().is_some_and(&predicate)
+        })
+    }
+
+    /// Devices located within `area` (see `Location::within`)
+    pub fn within(area: Location) -> Self {
+        Self::location(move |location| location.within(&area))
+    }
+
     pub fn apply(&self, devices: &HashMap<String, DeviceCapabilities>) -> Vec<String> {
         devices
             .iter()
@@ -144,6 +163,7 @@ impl CapabilityFilter {
             CapabilityType::Sensor(name) => caps.sensors.contains_key(name),
             CapabilityType::Connectivity(name) => caps.connectivity.contains_key(name),
             CapabilityType::Extended(key) => caps.extended.contains_key(key),
+            CapabilityType::Location => caps.location.is_some(),
             CapabilityType::Any => true,
         };
 
@@ -224,6 +244,42 @@ impl DeviceQuery {
         self.filter(CapabilityFilter::connectivity(conn_name, predicate))
     }
 
+    pub fn location<F>(self, predicate: F) -> Self
+    where
+        F: Fn(&Location) -> bool + 'static,
+    {
+        self.filter(CapabilityFilter::location(predicate))
+    }
+
+    pub fn within(self, area: Location) -> Self {
+        self.filter(CapabilityFilter::within(area))
+    }
+
+    pub fn in_building(self, building: impl Into<String>) -> Self {
+        self.within(Location {
+            building: Some(building.into()),
+            ..Default::default()
+        })
+    }
+
+    pub fn on_floor(self, floor: i32) -> Self {
+        self.within(Location {
+            floor: Some(floor),
+            ..Default::default()
+        })
+    }
+
+    pub fn in_room(self, room: impl Into<String>) -> Self {
+        self.within(Location::room(room))
+    }
+
+    pub fn in_zone(self, zone: impl Into<String>) -> Self {
+        self.within(Location {
+            zone: Some(zone.into()),
+            ..Default::default()
+        })
+    }
+
     pub fn execute(&self, devices: &HashMap<String, DeviceCapabilities>) -> Vec<String> {
         devices
             .iter()
@@ -243,3 +299,42 @@ impl DeviceQuery {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capability::CapabilityBuilder;
+
+    #[test]
+    fn test_location_queries() {
+        let placed = |location: Location| CapabilityBuilder::new().location(location).build();
+        let devices = HashMap::from([
+            (
+                "lamp".to_string(),
+                placed(Location::room("Kitchen").floor(0).zone("counter")),
+            ),
+            (
+                "speaker".to_string(),
+                placed(Location::room("kitchen").floor(1)),
+            ),
+            (
+                "thermostat".to_string(),
+                placed(Location::room("hall").floor(0)),
+            ),
+            ("unplaced".to_string(), CapabilityBuilder::new().build()),
+        ]);
+
+        let mut kitchen = DeviceQuery::all().in_room("kitchen").execute(&devices);
+        kitchen.sort();
+        assert_eq!(kitchen, vec!["lamp", "speaker"]);
+
+        let mut ground = DeviceQuery::all().on_floor(0).execute(&devices);
+        ground.sort();
+        assert_eq!(ground, vec!["lamp", "thermostat"]);
+
+        let counter = DeviceQuery::all()
+            .within(Location::room("kitchen").zone("COUNTER"))
+            .execute(&devices);
+        assert_eq!(counter, vec!["lamp"]);
+    }
+}
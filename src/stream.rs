@@ -8,6 +8,8 @@ pub struct StreamContext {
     pub handle: AviP2pHandle,
     pub stream_id: StreamId,
     pub peer_id: PeerId,
+    /// Reason the stream was opened with, including any `;key=value` parameters
+    pub reason: String,
 }
 
 impl StreamContext {
@@ -66,11 +68,11 @@ impl StreamDispatcher {
     ) -> Result<(), String> {
         let factories = self.factories.read().await;
 
-        if let Some(factory) = factories.get(&reason) {
+        if let Some(factory) = lookup_factory(&factories, &reason) {
             println!("✅ Accepting stream {} (reason: {})", stream_id, reason);
 
             self.handle
-                .accept_stream(stream_id)
+                .accept_stream(stream_id, None)
                 .await
                 .map_err(|e| format!("Failed to accept stream: {}", e))?;
 
@@ -80,6 +82,7 @@ impl StreamDispatcher {
                 handle: self.handle.clone(),
                 stream_id,
                 peer_id: from.clone(),
+                reason: reason.clone(),
             };
             handler.on_accepted(&ctx).await;
 
@@ -109,13 +112,14 @@ impl StreamDispatcher {
     ) -> Result<(), String> {
         let mut active = self.active_handlers.write().await;
 
-        if let Some((_, stored_peer, handler)) = active.get_mut(&stream_id) {
+        if let Some((reason, stored_peer, handler)) = active.get_mut(&stream_id) {
             *stored_peer = peer_id.clone();
 
             let ctx = StreamContext {
                 handle: self.handle.clone(),
                 stream_id,
                 peer_id,
+                reason: reason.clone(),
             };
             handler.on_accepted(&ctx).await;
             Ok(())
@@ -148,11 +152,12 @@ impl StreamDispatcher {
     ) -> Result<(), String> {
         let mut active = self.active_handlers.write().await;
 
-        if let Some((_, peer_id, handler)) = active.get_mut(&stream_id) {
+        if let Some((reason, peer_id, handler)) = active.get_mut(&stream_id) {
             let ctx = StreamContext {
                 handle: self.handle.clone(),
                 stream_id,
                 peer_id: peer_id.to_owned(),
+                reason: reason.clone(),
             };
             handler.on_data(&ctx, data).await;
             Ok(())
@@ -184,7 +189,7 @@ impl StreamDispatcher {
     ) -> Result<StreamId, String> {
         let factories = self.factories.read().await;
 
-        if let Some(factory) = factories.get(&reason) {
+        if let Some(factory) = lookup_factory(&factories, &reason) {
             let stream_id = self
                 .handle
                 .request_stream(peer_id.clone(), reason.clone())
@@ -209,6 +214,18 @@ impl StreamDispatcher {
     }
 }
 
+/// Factory for `reason`, falling back to its base name so that
+/// `audio;rate=48000` is handled by the factory registered as `audio`
+fn lookup_factory<'a>(
+    factories: &'a HashMap<String, Arc<dyn StreamHandlerFactory>>,
+    reason: &str,
+) -> Option<&'a Arc<dyn StreamHandlerFactory>> {
+    factories.get(reason).or_else(|| {
+        let base = reason.split(';').next().unwrap_or(reason);
+        factories.get(base)
+    })
+}
+
 /*
 // ============================================================================
 // EXEMPLO DE IMPLEMENTAÇÃO: Handler de Áudio (COM envio de dados)
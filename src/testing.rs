@@ -0,0 +1,788 @@
+//! Simulated devices for testing automations without hardware.
+//!
+//! A `SimulatedDevice` sits on a mesh node (usually one of a
+//! `SimNetwork`) and behaves like a bridged device: it advertises its
+//! capabilities in the context, answers commands on
+//! `device/<id>/command` (plain text, or `DeviceCommand`s run once per
+//! key), publishes sensor readings on
+//! `device/<id>/sensor/<name>` in the bridge's payload format and serves
+//! streams through scripted handlers.
+//!
+//! ```ignore
+//! let net = SimNetwork::start(2).await?;
+//! let thermostat = SimulatedDevice::builder(7)
+//!     .respond("status", b"ok".to_vec())
+//!     .sensor("temperature", "C", SensorCurve::Ramp { from: 18.0, to: 22.0, over: Duration::from_secs(60) })
+//!     .start(net.handle(0).unwrap())
+//!     .await?;
+//! // ... drive the automation under test from node 1 ...
+//! assert_eq!(thermostat.commands_received(), vec![b"heat".to_vec()]);
+//! ```
+
+use crate::capability::{DeviceCapabilities, CAPABILITIES_PATH};
+use crate::command::{
+    publish_progress, publish_reply, CommandDedupe, CommandProgress, CommandReply, Delivery,
+    DeviceCommand, DEFAULT_DEDUPE_WINDOW,
+};
+use crate::stream::{StreamContext, StreamDispatcher, StreamHandler, StreamHandlerFactory};
+use async_trait::async_trait;
+use avi_p2p::{AviEvent, AviP2pError, AviP2pHandle, PeerId, StreamCloseReason, StreamId, Topic};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+
+pub use avi_p2p::SimNetwork;
+
+/// Reply published for a command (None = stay silent)
+type CommandBehavior = Arc<dyn Fn(&[u8]) -> Option<Vec<u8>> + Send + Sync>;
+
+/// Values a simulated sensor reports over time
+#[derive(Debug, Clone, PartialEq)]
+pub enum SensorCurve {
+    Constant(f64),
+    /// `offset + amplitude * sin(2π t / period)`
+    Sine {
+        offset: f64,
+        amplitude: f64,
+        period: Duration,
+    },
+    /// Linear from `from` to `to` over `over`, then holds `to`
+    Ramp {
+        from: f64,
+        to: f64,
+        over: Duration,
+    },
+    /// One value per reading, starting over after the last
+    Sequence(Vec<f64>),
+}
+
+impl SensorCurve {
+    /// Value of reading number `reading`, taken `elapsed` after the start
+    pub fn value_at(&self, elapsed: Duration, reading: usize) -> f64 {
+        match self {
+            SensorCurve::Constant(value) => *value,
+            SensorCurve::Sine {
+                offset,
+                amplitude,
+                period,
+            } => {
+                let phase = elapsed.as_secs_f64() / period.as_secs_f64().max(f64::EPSILON);
+                offset + amplitude * (std::f64::consts::TAU * phase).sin()
+            }
+            SensorCurve::Ramp { from, to, over } => {
+                let progress =
+                    (elapsed.as_secs_f64() / over.as_secs_f64().max(f64::EPSILON)).min(1.0);
+                from + (to - from) * progress
+            }
+            SensorCurve::Sequence(values) if values.is_empty() => 0.0,
+            SensorCurve::Sequence(values) => values[reading % values.len()],
+        }
+    }
+}
+
+struct SimulatedSensor {
+    unit: String,
+    curve: SensorCurve,
+    interval: Duration,
+}
+
+/// Scripted behaviour of a `SimulatedDevice`, from `SimulatedDevice::builder`
+pub struct SimulatedDeviceBuilder {
+    device_id: u64,
+    capabilities: DeviceCapabilities,
+    commands: HashMap<String, CommandBehavior>,
+    fallback: Option<CommandBehavior>,
+    sensors: HashMap<String, SimulatedSensor>,
+    streams: Vec<(String, ScriptedStream)>,
+    dedupe_window: Duration,
+    slow: HashMap<String, SlowCommand>,
+}
+
+/// Progress a slow `DeviceCommand` reports before its reply
+#[derive(Clone, Copy)]
+struct SlowCommand {
+    steps: u32,
+    interval: Duration,
+}
+
+impl SimulatedDeviceBuilder {
+    /// Capability document advertised under `avi.device.caps.<device id>`
+    pub fn capabilities(mut self, capabilities: DeviceCapabilities) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    /// Answer the command `command` (the whole payload as text, or a
+    /// `DeviceCommand`'s name) with `reply`
+    pub fn respond(self, command: &str, reply: Vec<u8>) -> Self {
+        self.on_command(command, move |_| Some(reply.clone()))
+    }
+
+    /// Answer the command `command` with whatever `behavior` returns for
+    /// its payload (a `DeviceCommand`'s args, as JSON)
+    pub fn on_command<F>(mut self, command: &str, behavior: F) -> Self
+    where
+        F: Fn(&[u8]) -> Option<Vec<u8>> + Send + Sync + 'static,
+    {
+        self.commands
+            .insert(command.to_string(), Arc::new(behavior));
+        self
+    }
+
+    /// Behaviour for commands without one of their own
+    pub fn on_any_command<F>(mut self, behavior: F) -> Self
+    where
+        F: Fn(&[u8]) -> Option<Vec<u8>> + Send + Sync + 'static,
+    {
+        self.fallback = Some(Arc::new(behavior));
+        self
+    }
+
+    /// Publish a reading from `curve` every second
+    pub fn sensor(self, name: &str, unit: &str, curve: SensorCurve) -> Self {
+        self.sensor_every(name, unit, curve, Duration::from_secs(1))
+    }
+
+    pub fn sensor_every(
+        mut self,
+        name: &str,
+        unit: &str,
+        curve: SensorCurve,
+        interval: Duration,
+    ) -> Self {
+        self.sensors.insert(
+            name.to_string(),
+            SimulatedSensor {
+                unit: unit.to_string(),
+                curve,
+                interval,
+            },
+        );
+        self
+    }
+
+    /// Make the `DeviceCommand` `command` take a while: it reports
+    /// progress `steps` times, `interval` apart, before its reply goes out
+    pub fn progress(mut self, command: &str, steps: u32, interval: Duration) -> Self {
+        self.slow
+            .insert(command.to_string(), SlowCommand { steps, interval });
+        self
+    }
+
+    /// How long `DeviceCommand` keys are remembered, so retries within it
+    /// are answered without running the command again
+    pub fn dedupe_window(mut self, window: Duration) -> Self {
+        self.dedupe_window = window;
+        self
+    }
+
+    /// Accept streams opened with `reason` and serve them with `stream`
+    pub fn stream(mut self, reason: &str, stream: ScriptedStream) -> Self {
+        self.streams.push((reason.to_string(), stream));
+        self
+    }
+
+    /// Bring the device up on `handle`'s node: subscribe to its command
+    /// topic, advertise its capabilities, announce it online and start
+    /// its sensors
+    pub async fn start(self, handle: AviP2pHandle) -> Result<SimulatedDevice, AviP2pError> {
+        let topics = Topic::device(self.device_id);
+        let events = handle
+            .subscribe_events()
+            .await
+            .map_err(AviP2pError::NetworkError)?;
+        handle.subscribe(&topics.command()).await?;
+
+        let capabilities = serde_json::to_value(&self.capabilities)
+            .map_err(|e| AviP2pError::Serialization(e.to_string()))?;
+        handle
+            .ctx_transaction()
+            .set(
+                &format!("{}.{}", CAPABILITIES_PATH, self.device_id),
+                capabilities,
+            )
+            .commit()
+            .await?;
+        publish_status(&handle, self.device_id, true).await;
+
+        let dispatcher = StreamDispatcher::new(handle.clone());
+        for (reason, stream) in self.streams {
+            dispatcher.register_handler(reason, stream).await;
+        }
+
+        let shared = Arc::new(Shared {
+            received: Mutex::new(Vec::new()),
+            sensors: Mutex::new(HashMap::new()),
+            dedupe: Mutex::new(CommandDedupe::new(self.dedupe_window)),
+        });
+        let mut device = SimulatedDevice {
+            device_id: self.device_id,
+            handle: handle.clone(),
+            shared: shared.clone(),
+            tasks: Vec::new(),
+        };
+        for (name, sensor) in self.sensors {
+            device.start_sensor(name, sensor);
+        }
+
+        let commands = CommandTable {
+            topic: topics.command().to_string(),
+            reply_topic: topics.command_reply().to_string(),
+            behaviors: self.commands,
+            fallback: self.fallback,
+            device_id: self.device_id,
+            slow: self.slow,
+        };
+        device.tasks.push(tokio::spawn(run_events(
+            handle, events, commands, dispatcher, shared,
+        )));
+        Ok(device)
+    }
+}
+
+struct Shared {
+    /// Command payloads run, in the order they arrived
+    received: Mutex<Vec<Vec<u8>>>,
+    /// Running sensor tasks by name
+    sensors: Mutex<HashMap<String, JoinHandle<()>>>,
+    /// `DeviceCommand` keys already run, with their replies
+    dedupe: Mutex<CommandDedupe>,
+}
+
+struct CommandTable {
+    device_id: u64,
+    topic: String,
+    reply_topic: String,
+    behaviors: HashMap<String, CommandBehavior>,
+    fallback: Option<CommandBehavior>,
+    slow: HashMap<String, SlowCommand>,
+}
+
+impl CommandTable {
+    fn behavior(&self, command: &str) -> Option<&CommandBehavior> {
+        self.behaviors
+            .get(command.trim())
+            .or(self.fallback.as_ref())
+    }
+
+    fn run(&self, command: DeviceCommand) -> CommandReply {
+        let args = serde_json::to_vec(&command.args).unwrap_or_default();
+        let result = match self.behavior(&command.name) {
+            Some(behavior) => Ok(behavior(&args)
+                .map(|reply| {
+                    serde_json::from_slice(&reply).unwrap_or_else(|_| {
+                        Value::String(String::from_utf8_lossy(&reply).into_owned())
+                    })
+                })
+                .unwrap_or(Value::Null)),
+            None => Err(format!("Unknown command '{}'", command.name)),
+        };
+        CommandReply {
+            key: command.key,
+            result,
+        }
+    }
+}
+
+/// A scripted device on a mesh node. Dropping it stops its sensors and
+/// command handling without announcing it offline; `stop` does both.
+pub struct SimulatedDevice {
+    device_id: u64,
+    handle: AviP2pHandle,
+    shared: Arc<Shared>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl SimulatedDevice {
+    pub fn builder(device_id: u64) -> SimulatedDeviceBuilder {
+        SimulatedDeviceBuilder {
+            device_id,
+            capabilities: DeviceCapabilities::default(),
+            commands: HashMap::new(),
+            fallback: None,
+            sensors: HashMap::new(),
+            streams: Vec::new(),
+            dedupe_window: DEFAULT_DEDUPE_WINDOW,
+            slow: HashMap::new(),
+        }
+    }
+
+    pub fn device_id(&self) -> u64 {
+        self.device_id
+    }
+
+    /// Command payloads run so far, oldest first. Repeats of a
+    /// `DeviceCommand` key are answered but not run, so they are not listed.
+    pub fn commands_received(&self) -> Vec<Vec<u8>> {
+        self.shared.received.lock().unwrap().clone()
+    }
+
+    /// Start, or replace, a sensor while the device runs
+    pub fn set_sensor(&mut self, name: &str, unit: &str, curve: SensorCurve, interval: Duration) {
+        self.start_sensor(
+            name.to_string(),
+            SimulatedSensor {
+                unit: unit.to_string(),
+                curve,
+                interval,
+            },
+        );
+    }
+
+    /// Stop publishing a sensor; false if there was none by that name
+    pub fn remove_sensor(&self, name: &str) -> bool {
+        match self.shared.sensors.lock().unwrap().remove(name) {
+            Some(task) => {
+                task.abort();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Publish one reading right away, outside any curve
+    pub async fn emit(&self, sensor: &str, unit: &str, value: f64) -> Result<(), AviP2pError> {
+        publish_reading(&self.handle, self.device_id, sensor, unit, value).await
+    }
+
+    /// Stop the device and announce it offline
+    pub async fn stop(mut self) {
+        self.abort();
+        publish_status(&self.handle, self.device_id, false).await;
+    }
+
+    fn start_sensor(&mut self, name: String, sensor: SimulatedSensor) {
+        let handle = self.handle.clone();
+        let device_id = self.device_id;
+        let task_name = name.clone();
+        let task = tokio::spawn(async move {
+            let started = Instant::now();
+            let mut ticker = tokio::time::interval(sensor.interval);
+            for reading in 0.. {
+                ticker.tick().await;
+                let value = sensor.curve.value_at(started.elapsed(), reading);
+                let _ = publish_reading(&handle, device_id, &task_name, &sensor.unit, value).await;
+            }
+        });
+        if let Some(previous) = self.shared.sensors.lock().unwrap().insert(name, task) {
+            previous.abort();
+        }
+    }
+
+    fn abort(&mut self) {
+        for task in self.tasks.drain(..) {
+            task.abort();
+        }
+        for (_, task) in self.shared.sensors.lock().unwrap().drain() {
+            task.abort();
+        }
+    }
+}
+
+impl Drop for SimulatedDevice {
+    fn drop(&mut self) {
+        self.abort();
+    }
+}
+
+async fn run_events(
+    handle: AviP2pHandle,
+    mut events: avi_p2p::EventSubscription,
+    commands: CommandTable,
+    dispatcher: StreamDispatcher,
+    shared: Arc<Shared>,
+) {
+    while let Some(event) = events.recv().await {
+        match event {
+            AviEvent::Message { topic, data, .. } if topic == commands.topic => {
+                let Ok(command) = serde_json::from_slice::<DeviceCommand>(&data) else {
+                    shared.received.lock().unwrap().push(data.clone());
+                    let command = String::from_utf8_lossy(&data);
+                    let reply = commands
+                        .behavior(&command)
+                        .and_then(|behavior| behavior(&data));
+                    if let Some(reply) = reply {
+                        let _ = handle.publish(&commands.reply_topic, reply).await;
+                    }
+                    continue;
+                };
+                let delivery = shared.dedupe.lock().unwrap().accept(&command.key);
+                match delivery {
+                    Delivery::Repeat(Some(reply)) => {
+                        let _ = publish_reply(&handle, commands.device_id, &reply).await;
+                    }
+                    Delivery::Repeat(None) => {}
+                    Delivery::First => {
+                        shared.received.lock().unwrap().push(data);
+                        let slow = commands.slow.get(&command.name).copied();
+                        let reply = commands.run(command);
+                        match slow {
+                            Some(slow) => {
+                                tokio::spawn(finish_slowly(
+                                    handle.clone(),
+                                    commands.device_id,
+                                    reply,
+                                    slow,
+                                    shared.clone(),
+                                ));
+                            }
+                            None => {
+                                shared.dedupe.lock().unwrap().complete(&reply);
+                                let _ = publish_reply(&handle, commands.device_id, &reply).await;
+                            }
+                        }
+                    }
+                }
+            }
+            AviEvent::StreamRequested {
+                from,
+                stream_id,
+                reason,
+            } => {
+                let _ = dispatcher
+                    .handle_stream_requested(from, stream_id, reason)
+                    .await;
+            }
+            AviEvent::StreamData {
+                from,
+                stream_id,
+                data,
+            } => {
+                let _ = dispatcher.handle_stream_data(from, stream_id, data).await;
+            }
+            AviEvent::StreamClosed {
+                peer_id,
+                stream_id,
+                reason,
+            } => {
+                let _ = dispatcher
+                    .handle_stream_closed(peer_id, stream_id, reason)
+                    .await;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Report a slow command's progress, then send its reply
+async fn finish_slowly(
+    handle: AviP2pHandle,
+    device_id: u64,
+    reply: CommandReply,
+    slow: SlowCommand,
+    shared: Arc<Shared>,
+) {
+    for step in 1..=slow.steps {
+        tokio::time::sleep(slow.interval).await;
+        let progress = CommandProgress::new(&reply.key, step as f32 / (slow.steps + 1) as f32);
+        let _ = publish_progress(&handle, device_id, &progress).await;
+    }
+    tokio::time::sleep(slow.interval).await;
+    shared.dedupe.lock().unwrap().complete(&reply);
+    let _ = publish_reply(&handle, device_id, &reply).await;
+}
+
+fn timestamp() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Same payload the embedded bridge publishes for a sensor update
+async fn publish_reading(
+    handle: &AviP2pHandle,
+    device_id: u64,
+    sensor: &str,
+    unit: &str,
+    value: f64,
+) -> Result<(), AviP2pError> {
+    let payload = json!({
+        "name": sensor,
+        "data": { "value": value, "unit": unit, "custom": "" },
+        "ts": timestamp(),
+    });
+    handle
+        .publish(
+            &Topic::device(device_id).sensor(sensor),
+            serde_json::to_vec(&payload).unwrap(),
+        )
+        .await
+}
+
+async fn publish_status(handle: &AviP2pHandle, device_id: u64, online: bool) {
+    let payload = json!({
+        "status": if online { "online" } else { "offline" },
+        "ts": timestamp(),
+    });
+    let _ = handle
+        .publish(
+            &Topic::device(device_id).status(),
+            serde_json::to_vec(&payload).unwrap(),
+        )
+        .await;
+}
+
+/// Reply to a chunk received on a scripted stream (None = no reply)
+type StreamBehavior = Arc<dyn Fn(&[u8]) -> Option<Vec<u8>> + Send + Sync>;
+
+/// Stream handler factory for `SimulatedDeviceBuilder::stream`. Clones
+/// share the record of what was received.
+#[derive(Clone)]
+pub struct ScriptedStream {
+    behavior: StreamBehavior,
+    received: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl ScriptedStream {
+    /// Send every chunk back
+    pub fn echo() -> Self {
+        Self::reply(|data| Some(data.to_vec()))
+    }
+
+    /// Take chunks without answering
+    pub fn sink() -> Self {
+        Self::reply(|_| None)
+    }
+
+    /// Answer each chunk with what `behavior` returns for it
+    pub fn reply<F>(behavior: F) -> Self
+    where
+        F: Fn(&[u8]) -> Option<Vec<u8>> + Send + Sync + 'static,
+    {
+        Self {
+            behavior: Arc::new(behavior),
+            received: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Chunks received across all streams served, oldest first
+    pub fn received(&self) -> Vec<Vec<u8>> {
+        self.received.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl StreamHandlerFactory for ScriptedStream {
+    async fn create_handler(&self) -> Box<dyn StreamHandler> {
+        Box::new(self.clone())
+    }
+}
+
+#[async_trait]
+impl StreamHandler for ScriptedStream {
+    async fn on_accepted(&mut self, _ctx: &StreamContext) {}
+
+    async fn on_rejected(&mut self, _peer_id: PeerId, _stream_id: StreamId, _reason: String) {}
+
+    async fn on_data(&mut self, ctx: &StreamContext, data: Vec<u8>) {
+        let reply = (self.behavior)(&data);
+        self.received.lock().unwrap().push(data);
+        if let Some(reply) = reply {
+            let _ = ctx.send(reply).await;
+        }
+    }
+
+    async fn on_closed(
+        &mut self,
+        _peer_id: PeerId,
+        _stream_id: StreamId,
+        _reason: StreamCloseReason,
+    ) {
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sensor_curves() {
+        let ramp = SensorCurve::Ramp {
+            from: 18.0,
+            to: 22.0,
+            over: Duration::from_secs(40),
+        };
+        assert_eq!(ramp.value_at(Duration::ZERO, 0), 18.0);
+        assert_eq!(ramp.value_at(Duration::from_secs(10), 1), 19.0);
+        assert_eq!(ramp.value_at(Duration::from_secs(90), 2), 22.0);
+
+        let sine = SensorCurve::Sine {
+            offset: 50.0,
+            amplitude: 10.0,
+            period: Duration::from_secs(4),
+        };
+        assert!((sine.value_at(Duration::from_secs(1), 0) - 60.0).abs() < 1e-9);
+
+        let sequence = SensorCurve::Sequence(vec![1.0, 2.0]);
+        assert_eq!(sequence.value_at(Duration::ZERO, 3), 2.0);
+    }
+
+    #[tokio::test]
+    async fn test_simulated_device_answers_commands_and_reports() {
+        let net = SimNetwork::start(2).await.unwrap();
+        assert!(net.wait_for_full_mesh(Duration::from_secs(10)).await);
+        let device = SimulatedDevice::builder(7)
+            .respond("status", b"ok".to_vec())
+            .sensor_every(
+                "temperature",
+                "C",
+                SensorCurve::Constant(21.5),
+                Duration::from_millis(100),
+            )
+            .start(net.handle(0).unwrap())
+            .await
+            .unwrap();
+
+        let controller = net.handle(1).unwrap();
+        let topics = Topic::device(7);
+        let reply_topic = topics.command_reply();
+        controller.subscribe(&reply_topic).await.unwrap();
+        controller
+            .subscribe(&topics.sensor("temperature"))
+            .await
+            .unwrap();
+        let mut events = controller.subscribe_events().await.unwrap();
+
+        let (mut replied, mut reading) = (None, None);
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while replied.is_none() || reading.is_none() {
+                let _ = controller
+                    .publish(&topics.command(), b"status".to_vec())
+                    .await;
+                let Ok(Some(AviEvent::Message { topic, data, .. })) =
+                    tokio::time::timeout(Duration::from_millis(200), events.recv()).await
+                else {
+                    continue;
+                };
+                if topic == reply_topic.as_str() {
+                    replied = Some(data);
+                } else {
+                    reading = serde_json::from_slice::<serde_json::Value>(&data).ok();
+                }
+            }
+        })
+        .await
+        .expect("device never answered");
+
+        assert_eq!(replied.unwrap(), b"ok");
+        assert_eq!(reading.unwrap()["data"]["value"], 21.5);
+        assert!(device.commands_received().contains(&b"status".to_vec()));
+        device.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_retried_commands_run_once() {
+        use crate::command::{send_command, CommandOptions};
+        use std::sync::atomic::{AtomicU32, Ordering};
+
+        let net = SimNetwork::start(2).await.unwrap();
+        assert!(net.wait_for_full_mesh(Duration::from_secs(10)).await);
+        let toggles = Arc::new(AtomicU32::new(0));
+        let counter = toggles.clone();
+        let device = SimulatedDevice::builder(8)
+            .on_command("toggle", move |_| {
+                let on = counter.fetch_add(1, Ordering::SeqCst) % 2 == 0;
+                Some(serde_json::to_vec(&json!({ "on": on })).unwrap())
+            })
+            .start(net.handle(0).unwrap())
+            .await
+            .unwrap();
+
+        // Short timeouts, so early sends lost while gossip settles are retried
+        let controller = net.handle(1).unwrap();
+        let options = CommandOptions {
+            timeout: Duration::from_millis(200),
+            attempts: 50,
+        };
+        let toggle = DeviceCommand::new("toggle", Value::Null);
+        let reply = send_command(&controller, 8, &toggle, options)
+            .await
+            .unwrap();
+        assert_eq!(reply, json!({ "on": true }));
+
+        // A late retry is answered from the first run
+        let again = send_command(&controller, 8, &toggle, options)
+            .await
+            .unwrap();
+        assert_eq!(again, json!({ "on": true }));
+        assert_eq!(toggles.load(Ordering::SeqCst), 1);
+        assert_eq!(device.commands_received().len(), 1);
+
+        let unknown = DeviceCommand::new("explode", Value::Null);
+        assert!(matches!(
+            send_command(&controller, 8, &unknown, options).await,
+            Err(crate::CommandError::Failed(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_commands_leave_the_reply_topic_they_joined() {
+        use crate::command::{send_command, CommandOptions};
+
+        let net = SimNetwork::start(2).await.unwrap();
+        assert!(net.wait_for_full_mesh(Duration::from_secs(10)).await);
+        let _device = SimulatedDevice::builder(10)
+            .respond("ping", b"\"pong\"".to_vec())
+            .start(net.handle(0).unwrap())
+            .await
+            .unwrap();
+
+        let controller = net.handle(1).unwrap();
+        let options = CommandOptions {
+            timeout: Duration::from_millis(200),
+            attempts: 50,
+        };
+        let reply_topic = Topic::device(10).command_reply().as_str().to_string();
+        let ping = DeviceCommand::new("ping", Value::Null);
+        send_command(&controller, 10, &ping, options).await.unwrap();
+        assert!(!controller
+            .subscriptions()
+            .await
+            .unwrap()
+            .contains(&reply_topic));
+
+        // A caller already listening for replies keeps its subscription
+        controller.subscribe(&reply_topic).await.unwrap();
+        let ping = DeviceCommand::new("ping", Value::Null);
+        send_command(&controller, 10, &ping, options).await.unwrap();
+        assert!(controller
+            .subscriptions()
+            .await
+            .unwrap()
+            .contains(&reply_topic));
+    }
+
+    #[tokio::test]
+    async fn test_slow_commands_report_progress() {
+        use crate::command::{send_command_with_progress, CommandOptions};
+
+        let net = SimNetwork::start(2).await.unwrap();
+        assert!(net.wait_for_full_mesh(Duration::from_secs(10)).await);
+        let _device = SimulatedDevice::builder(9)
+            .respond("update", b"\"v2\"".to_vec())
+            .progress("update", 3, Duration::from_millis(150))
+            .start(net.handle(0).unwrap())
+            .await
+            .unwrap();
+
+        // Shorter than the whole update, but longer than between reports
+        let options = CommandOptions {
+            timeout: Duration::from_millis(400),
+            attempts: 20,
+        };
+        let update = DeviceCommand::new("update", Value::Null);
+        let mut reports = Vec::new();
+        let reply =
+            send_command_with_progress(&net.handle(1).unwrap(), 9, &update, options, |progress| {
+                reports.push(progress.progress)
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(reply, json!("v2"));
+        assert_eq!(reports, vec![0.25, 0.5, 0.75]);
+    }
+}